@@ -1,16 +1,29 @@
 pub(crate) mod path;
 mod repos;
 mod settings;
+#[cfg(feature = "watch")]
+mod watch;
 
 pub use path::ConfigPath;
-pub use repos::{RepoRecord, Repos, ReposData};
-pub use settings::{Settings, SettingsData};
-
+pub use repos::{
+    MergeStrategy, RepoAuth, RepoImport, RepoImportError, RepoRecord, RepoValidationIssue, Repos,
+    ReposData, Secret, SignatureMode, REPOS_VERSION,
+};
+pub use settings::{
+    ProxySettings, Settings, SettingsData, SettingsOverrides, TimeoutSettings, SETTINGS_VERSION,
+};
+#[cfg(feature = "watch")]
+pub use watch::ConfigWatcher;
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::Write;
 use std::path::{Path, PathBuf};
 
 use thiserror::Error;
 
 use crate::defaults;
+use crate::download::HttpClientHook;
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -25,6 +38,25 @@ pub enum Error {
 
     #[error("An error occurred managing app paths")]
     PathError(#[from] pathos::Error),
+
+    #[error("{path} has version {found}, but only versions up to {supported} are supported")]
+    UnsupportedConfigVersion {
+        path: PathBuf,
+        found: u32,
+        supported: u32,
+    },
+
+    #[error("This config was not loaded from a directory, so it cannot be reloaded")]
+    NotLoaded,
+
+    /// The config was still loaded, as with [`Permission::ReadOnly`]; changes
+    /// made to it won't be saved.
+    #[error("The config directory {0} is not writable, so it was loaded read-only")]
+    ConfigReadOnlyFallback(PathBuf),
+
+    #[cfg(feature = "watch")]
+    #[error("Could not watch configuration directory")]
+    Watch(#[from] notify::Error),
 }
 
 #[derive(Debug, Error)]
@@ -49,18 +81,129 @@ pub enum FileError {
 
     #[error("Could not create directory: {1}")]
     CreateParentDir(#[source] std::io::Error, PathBuf),
+
+    #[error("Unsupported file version {0} (supported up to {1}): {2}")]
+    UnsupportedVersion(u32, u32, PathBuf),
+
+    #[error("Could not resolve path in file: {1}")]
+    Path(#[source] path::Error, PathBuf),
+}
+
+/// The `version` of a parsed config file. Files written before versioning was
+/// introduced have no version and are treated as version 0.
+fn read_version(value: &toml::Value) -> u32 {
+    match value.get("version") {
+        Some(v) => v
+            .as_integer()
+            .and_then(|v| u32::try_from(v).ok())
+            .unwrap_or(u32::MAX),
+        None => 0,
+    }
+}
+
+/// Reads the `version` of a parsed config file, failing if it is newer than `supported`.
+fn file_version(value: &toml::Value, supported: u32, path: &Path) -> Result<u32, FileError> {
+    check_version(read_version(value), supported, path)
+}
+
+fn check_version(found: u32, supported: u32, path: &Path) -> Result<u32, FileError> {
+    if found > supported {
+        return Err(FileError::UnsupportedVersion(
+            found,
+            supported,
+            path.to_path_buf(),
+        ));
+    }
+
+    Ok(found)
+}
+
+/// Whether files can be created in `dir`, creating it if it's missing.
+fn is_writable_dir(dir: &Path) -> bool {
+    let probe = dir.join(".pahkat-write-test");
+    let result = std::fs::create_dir_all(dir).and_then(|_| File::create(&probe));
+    let _ = std::fs::remove_file(&probe);
+    result.is_ok()
+}
+
+/// Writes `bytes` to a temporary file next to `path` and renames it into place,
+/// so a crash mid-write leaves either the old or the new file, never a truncated one.
+pub(crate) fn write_atomic(path: &Path, bytes: &[u8]) -> Result<(), FileError> {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".tmp");
+    let tmp_path = path.with_file_name(file_name);
+
+    let result = File::create(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(bytes)?;
+            file.sync_all()
+        })
+        .map_err(|e| FileError::Write(e, tmp_path.clone()))
+        .and_then(|_| {
+            replace_file(&tmp_path, path).map_err(|e| FileError::Write(e, path.to_path_buf()))
+        });
+
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+
+    result
+}
+
+#[cfg(not(windows))]
+pub(crate) fn replace_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    std::fs::rename(from, to)
+}
+
+/// `rename` replaces an existing file on Windows too, but fails while another
+/// process (often a virus scanner) briefly holds the target open, so retry.
+#[cfg(windows)]
+pub(crate) fn replace_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    let mut attempt = 0;
+    loop {
+        match std::fs::rename(from, to) {
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied && attempt < 5 => {
+                attempt += 1;
+                std::thread::sleep(std::time::Duration::from_millis(50));
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Adds the `repos.toml` of each directory in `layers` to `repos` as system
+/// entries. Layers without one are skipped.
+fn add_system_layers<P: AsRef<Path>>(repos: &mut Repos, layers: &[P]) -> Vec<Error> {
+    let mut errors = vec![];
+
+    for layer in layers.iter() {
+        let path = layer.as_ref().join("repos.toml");
+        match Repos::load(&path, Permission::ReadOnly) {
+            Ok(v) => repos.add_system_layer(&v),
+            Err(FileError::Read(e, _)) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => errors.push(Error::ReposFile(e)),
+        }
+    }
+
+    errors
 }
 
 #[derive(Debug, Clone)]
 pub struct Config {
     repos: Repos,
     settings: Settings,
+    /// The directories and permission passed to [`Config::load_layered`], for reloading.
+    source: Option<(Vec<PathBuf>, Permission)>,
+    http: HttpClientHook,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum Permission {
     ReadOnly,
     ReadWrite,
+    /// Changes can be made but are never written to disk.
+    Memory,
 }
 
 impl Config {
@@ -68,25 +211,85 @@ impl Config {
         Config {
             repos: Repos::read_only(),
             settings: Settings::read_only(),
+            source: None,
+            http: HttpClientHook::default(),
         }
     }
 
+    /// Loads the config from `PAHKAT_CONFIG_DIR` if set, otherwise from the
+    /// platform's default location.
     #[cfg(not(target_os = "android"))]
     pub fn load_default() -> Result<Config, Error> {
-        let path = defaults::config_path()?;
+        let path = match std::env::var_os("PAHKAT_CONFIG_DIR") {
+            Some(v) => PathBuf::from(v),
+            None => defaults::config_path()?.to_path_buf(),
+        };
         Ok(Self::load(path, Permission::ReadWrite).0)
     }
 
     pub fn load<P: AsRef<Path>>(path: P, permission: Permission) -> (Config, Vec<Error>) {
+        Self::load_layered(&[path], permission)
+    }
+
+    /// Loads the config from the last of `layers`, with the `repos.toml` of each
+    /// earlier directory beneath it as read-only system entries. See [`Repos`] for
+    /// how the layers combine. Settings only come from the last directory.
+    ///
+    /// System layers without a `repos.toml` are skipped.
+    pub fn load_layered<P: AsRef<Path>>(
+        layers: &[P],
+        permission: Permission,
+    ) -> (Config, Vec<Error>) {
+        let (config_path, system_layers) = match layers.split_last() {
+            Some(v) => v,
+            None => return (Config::read_only(), vec![]),
+        };
+        let config_path = config_path.as_ref();
+        let (mut config, mut errors) = Self::load_dir(config_path, permission);
+        errors.extend(add_system_layers(&mut config.repos, system_layers));
+
+        for issue in config.repos.validate() {
+            log::warn!("{:?}: {}", config_path.join("repos.toml"), issue);
+        }
+
+        config.source = Some((
+            layers.iter().map(|x| x.as_ref().to_path_buf()).collect(),
+            permission,
+        ));
+
+        log::trace!("Config loaded: {:#?}", &config);
+
+        (config, errors)
+    }
+
+    fn load_dir(config_path: &Path, permission: Permission) -> (Config, Vec<Error>) {
         let mut errors = vec![];
 
-        let config_path = path.as_ref();
+        let permission = if permission == Permission::ReadWrite && !is_writable_dir(config_path) {
+            log::warn!(
+                "{:?} is not writable, loading config read-only",
+                config_path
+            );
+            errors.push(Error::ConfigReadOnlyFallback(config_path.to_path_buf()));
+            Permission::ReadOnly
+        } else {
+            permission
+        };
 
         let settings_path = config_path.join("settings.toml");
 
         let settings = match Settings::load(&settings_path, permission) {
             Ok(v) => v,
-            Err(_) if permission != Permission::ReadOnly => {
+            // Never replace a file written by a newer client with defaults.
+            Err(FileError::UnsupportedVersion(found, supported, path)) => {
+                errors.push(Error::UnsupportedConfigVersion {
+                    path,
+                    found,
+                    supported,
+                });
+                Settings::read_only()
+            }
+            Err(_) if permission == Permission::ReadWrite => {
                 match Settings::create(&settings_path).map_err(Error::SettingsFile) {
                     Ok(s) => s,
                     Err(e) => {
@@ -101,11 +304,24 @@ impl Config {
             }
         };
 
+        let mut settings = settings;
+        if let Err(e) = settings.set_overrides(SettingsOverrides::from_env()) {
+            errors.push(Error::SettingsFile(e));
+        }
+
         let repos_path = config_path.join("repos.toml");
 
         let repos = match Repos::load(&repos_path, permission) {
             Ok(v) => v,
-            Err(_) if permission != Permission::ReadOnly => {
+            Err(FileError::UnsupportedVersion(found, supported, path)) => {
+                errors.push(Error::UnsupportedConfigVersion {
+                    path,
+                    found,
+                    supported,
+                });
+                Repos::read_only()
+            }
+            Err(_) if permission == Permission::ReadWrite => {
                 match Repos::create(&repos_path).map_err(Error::ReposFile) {
                     Ok(s) => s,
                     Err(e) => {
@@ -120,15 +336,77 @@ impl Config {
             }
         };
 
-        let config = Config { repos, settings };
-
-        log::trace!("Config loaded: {:#?}", &config);
+        let config = Config {
+            repos,
+            settings,
+            source: None,
+            http: HttpClientHook::default(),
+        };
 
         (config, errors)
     }
 
+    /// Watches the config directory at `path` and calls `callback` whenever
+    /// `settings.toml` or `repos.toml` changes on disk. The `repos.toml` of any
+    /// system layers this config was loaded with is watched too.
+    ///
+    /// Files are reloaded with the permissions they have in `self`. If a reload
+    /// fails, `callback` receives the error and the last good config is kept for
+    /// the next change. Watching stops when the returned watcher is dropped.
+    #[cfg(feature = "watch")]
+    pub fn watch<P, F>(&self, path: P, callback: F) -> Result<ConfigWatcher, Error>
+    where
+        P: AsRef<Path>,
+        F: Fn(Result<Config, Error>) + Send + 'static,
+    {
+        watch::watch(self.clone(), path.as_ref(), callback)
+    }
+
+    /// Re-reads the files from the directories this config was loaded from, with
+    /// the same permission, keeping any settings overrides and HTTP client hook. Under `ReadWrite`,
+    /// missing files are created again.
+    ///
+    /// If either file fails to load, `self` is left unchanged. A directory that
+    /// has become unwritable is reloaded read-only.
+    pub fn reload(&mut self) -> Result<(), Error> {
+        let (layers, permission) = self.source.clone().ok_or(Error::NotLoaded)?;
+        let (mut config, mut errors) = Config::load_layered(&layers, permission);
+        errors.retain(|e| !matches!(e, Error::ConfigReadOnlyFallback(_)));
+
+        if !errors.is_empty() {
+            return Err(errors.remove(0));
+        }
+
+        config
+            .settings
+            .set_overrides(self.settings.overrides().clone())
+            .map_err(Error::SettingsFile)?;
+        config.http = self.http.clone();
+
+        *self = config;
+        Ok(())
+    }
+
     pub fn new(settings: Settings, repos: Repos) -> Config {
-        Config { repos, settings }
+        Config {
+            repos,
+            settings,
+            source: None,
+            http: HttpClientHook::default(),
+        }
+    }
+
+    /// Builds a config that never touches the config files, however it is mutated.
+    /// Useful for tests and for embedders that manage configuration themselves.
+    pub fn in_memory(mut settings: Settings, mut repos: Repos) -> Config {
+        settings.set_permission(Permission::Memory);
+        repos.set_permission(Permission::Memory);
+        Config {
+            repos,
+            settings,
+            source: None,
+            http: HttpClientHook::default(),
+        }
     }
 
     pub fn repos(&self) -> &Repos {
@@ -143,6 +421,17 @@ impl Config {
         &self.settings
     }
 
+    pub fn http_client_hook(&self) -> &HttpClientHook {
+        &self.http
+    }
+
+    /// Customizes the HTTP clients of stores opened with this config; see
+    /// [`HttpClientHook`]. Set it before opening the store, which loads its
+    /// repositories straight away.
+    pub fn set_http_client_hook(&mut self, hook: HttpClientHook) {
+        self.http = hook;
+    }
+
     pub fn settings_mut(&mut self) -> &mut Settings {
         &mut self.settings
     }
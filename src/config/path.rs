@@ -18,6 +18,36 @@ pub enum Error {
     InvalidScheme(String),
     #[error("Invalid URL")]
     InvalidUrl,
+    #[error("Environment variable is not set: {0}")]
+    UndefinedVariable(String),
+    #[error("Invalid file path: {0}")]
+    InvalidPath(String),
+}
+
+/// Resolves a leading `~` or `$VAR` segment of a `file:` path, such as
+/// `file:~/.cache/pahkat` or `file:$XDG_CACHE_HOME/pahkat`.
+fn expand_leading(path: &str) -> Result<Option<ConfigPath>, Error> {
+    let mut segments = path.split('/');
+    let first = segments.next().unwrap_or_default();
+
+    let base = if first == "~" {
+        pathos::user::home_dir().map_err(|_| Error::UndefinedVariable("HOME".into()))?
+    } else if let Some(name) = first.strip_prefix('$') {
+        std::env::var_os(name)
+            .filter(|x| !x.is_empty())
+            .map(PathBuf::from)
+            .ok_or_else(|| Error::UndefinedVariable(name.into()))?
+    } else {
+        return Ok(None);
+    };
+
+    let base = ConfigPath::try_from(base.clone())
+        .map_err(|_| Error::InvalidPath(base.display().to_string()))?;
+    Ok(Some(
+        segments
+            .filter(|x| !x.is_empty())
+            .fold(base, |acc, x| acc.join(x)),
+    ))
 }
 
 impl ConfigPath {
@@ -27,8 +57,18 @@ impl ConfigPath {
         ConfigPath(iri)
     }
 
-    pub fn to_path_buf(&self) -> Result<PathBuf, pathos::iri::Error> {
-        self.0.to_path_buf()
+    /// Absolute paths are used as is; a leading `~` or `$VAR` is expanded first.
+    pub fn to_path_buf(&self) -> Result<PathBuf, Error> {
+        let iri = self.0.as_str();
+        if let Some(path) = iri.strip_prefix("file:") {
+            if let Some(expanded) = expand_leading(path)? {
+                return expanded.to_path_buf();
+            }
+        }
+
+        self.0
+            .to_path_buf()
+            .map_err(|e| Error::InvalidPath(format!("{:?}", e)))
     }
 }
 
@@ -78,9 +118,22 @@ impl<'de> Visitor<'de> for ConfigPathVisitor {
     where
         E: de::Error,
     {
+        // Hand-written paths starting with `~` or `$VAR` are kept unexpanded so the
+        // file stays portable; they are resolved by `ConfigPath::to_path_buf`.
+        let value = if value.starts_with('~') || value.starts_with('$') {
+            std::borrow::Cow::Owned(format!("file:{}", value))
+        } else {
+            std::borrow::Cow::Borrowed(value)
+        };
+        let value = &*value;
+
         if value.starts_with("file:") || value.starts_with("container:") {
             let url = iref::IriBuf::new(value).map_err(|_| E::custom("Invalid URL"))?;
-            if value.starts_with("file:") {
+            let path = value.strip_prefix("file:");
+            let is_expandable = path
+                .map(|x| x.starts_with('~') || x.starts_with('$'))
+                .unwrap_or(false);
+            if path.is_some() && !is_expandable {
                 url.to_path_buf()
                     .map_err(|_| E::custom("File path not absolute"))?;
             }
@@ -1,5 +1,3 @@
-use std::fs::File;
-use std::io::Write;
 use std::path::{Path, PathBuf};
 
 use indexmap::IndexMap;
@@ -9,32 +7,256 @@ use super::FileError;
 use crate::config::Permission;
 use pahkat_types::repo::RepoUrl;
 
-#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct RepoRecord {
+    /// Falls back to the repository's default channel when unset. Checked against
+    /// the channels the index declares each time it's loaded.
     pub channel: Option<String>,
+    /// When a bare package id exists in several repositories, the one with the
+    /// highest priority wins. Ties go to the repository listed first.
+    #[serde(default)]
+    pub priority: i32,
+    /// Disabled repositories are still loaded so installed packages keep their
+    /// status, but nothing new is resolved from them.
+    #[serde(default = "enabled_default")]
+    pub enabled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<RepoAuth>,
+    /// Base64 minisign public keys. When set, the index and payloads must carry a
+    /// detached signature (`<url>.minisig`) from one of them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trusted_keys: Vec<String>,
+    #[serde(default)]
+    pub signature_mode: SignatureMode,
+    /// Alternate hosts for payloads, tried in turn when a download fails. Each
+    /// replaces the payload URL's origin, and its path is prepended to the payload's.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mirrors: Vec<url::Url>,
 }
 
+/// What to do when a file from a repository with trusted keys fails verification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureMode {
+    /// Refuse the file.
+    Strict,
+    /// Log the failure and use the file anyway, for rolling out signing.
+    Permissive,
+}
+
+impl Default for SignatureMode {
+    fn default() -> SignatureMode {
+        SignatureMode::Strict
+    }
+}
+
+/// Credentials sent when fetching a repository's index, and its payloads if they
+/// are hosted on the same origin.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum RepoAuth {
+    Bearer { token: Secret },
+    Basic { username: String, password: Secret },
+}
+
+/// Where a credential is read from. `Keychain` requires the `keychain` feature;
+/// `Plain` stores the secret in `repos.toml` and should be avoided.
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Secret {
+    Keychain { service: String, account: String },
+    Env(String),
+    Plain(String),
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Secret::Keychain { service, account } => f
+                .debug_struct("Keychain")
+                .field("service", service)
+                .field("account", account)
+                .finish(),
+            Secret::Env(name) => f.debug_tuple("Env").field(name).finish(),
+            Secret::Plain(_) => f.debug_tuple("Plain").field(&"<redacted>").finish(),
+        }
+    }
+}
+
+impl Secret {
+    /// Looks up the secret, returning `None` if it is not available.
+    pub fn resolve(&self) -> Option<String> {
+        match self {
+            Secret::Keychain { service, account } => keychain_password(service, account),
+            Secret::Env(name) => std::env::var(name).ok(),
+            Secret::Plain(value) => Some(value.clone()),
+        }
+    }
+}
+
+#[cfg(feature = "keychain")]
+fn keychain_password(service: &str, account: &str) -> Option<String> {
+    match keyring::Entry::new(service, account).get_password() {
+        Ok(v) => Some(v),
+        Err(e) => {
+            log::warn!(
+                "Could not read {}/{} from keychain: {:?}",
+                service,
+                account,
+                e
+            );
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "keychain"))]
+fn keychain_password(service: &str, account: &str) -> Option<String> {
+    log::warn!(
+        "Cannot read {}/{}: built without keychain support",
+        service,
+        account
+    );
+    None
+}
+
+#[inline(always)]
+fn enabled_default() -> bool {
+    true
+}
+
+impl Default for RepoRecord {
+    fn default() -> RepoRecord {
+        RepoRecord {
+            channel: None,
+            priority: 0,
+            enabled: true,
+            auth: None,
+            trusted_keys: vec![],
+            signature_mode: SignatureMode::Strict,
+            mirrors: vec![],
+        }
+    }
+}
+
+/// Version of the `repos.toml` layout written by this client.
+///
+/// The file stays a bare table of repositories, which older clients read too,
+/// so the version is written in a leading comment rather than as a key.
+pub const REPOS_VERSION: u32 = 1;
+
+/// Starts the first line of `repos.toml`, followed by the layout version.
+const VERSION_COMMENT: &str = "# version: ";
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 #[serde(transparent)]
 pub struct ReposData(IndexMap<RepoUrl, RepoRecord>);
 
+/// A problem with an entry in `repos.toml`, from [`Repos::validate`].
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[non_exhaustive]
+pub enum RepoValidationIssue {
+    /// The entry was skipped when loading.
+    #[error("Skipped `{0}`: not a valid repository URL: {1}")]
+    InvalidUrl(String, String),
+
+    /// The entry was skipped when loading.
+    #[error("Skipped {0}: {1}")]
+    InvalidRecord(RepoUrl, String),
+
+    #[error("{0}: the channel is empty")]
+    EmptyChannel(RepoUrl),
+
+    #[error("{0}: mirror {1} is not an http or https URL")]
+    InvalidMirror(RepoUrl, url::Url),
+
+    #[error("{0}: a trusted key is empty")]
+    EmptyTrustedKey(RepoUrl),
+}
+
+impl RepoValidationIssue {
+    /// Whether the entry was left out of the loaded config.
+    pub fn is_skipped(&self) -> bool {
+        matches!(
+            self,
+            RepoValidationIssue::InvalidUrl(..) | RepoValidationIssue::InvalidRecord(..)
+        )
+    }
+}
+
+/// What [`Repos::import`] does with a repository that is already configured
+/// with a different record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MergeStrategy {
+    /// Keep the configured record.
+    SkipExisting,
+    /// Replace the configured record with the imported one.
+    Overwrite,
+    /// Import nothing and return [`RepoImportError::Conflict`].
+    ErrorOnConflict,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RepoImportError {
+    #[error("Could not parse repository list")]
+    Parse(#[source] toml::de::Error),
+
+    #[error("Unsupported repository list version {0} (supported up to {1})")]
+    UnsupportedVersion(u32, u32),
+
+    #[error("Repository is already configured differently: {0}")]
+    Conflict(RepoUrl),
+
+    #[error(transparent)]
+    File(#[from] FileError),
+}
+
+/// What [`Repos::import`] changed.
+#[derive(Debug, Clone, Default)]
+pub struct RepoImport {
+    pub added: Vec<RepoUrl>,
+    pub replaced: Vec<RepoUrl>,
+    /// Already configured, either the same or kept by [`MergeStrategy::SkipExisting`].
+    pub unchanged: Vec<RepoUrl>,
+    /// Replaced repositories whose trusted keys, signature mode or mirrors changed.
+    /// These are also in `replaced`.
+    pub trust_changed: Vec<RepoUrl>,
+    /// Entries left out because they couldn't be parsed.
+    pub invalid: Vec<RepoValidationIssue>,
+}
+
 impl ReposData {
-    fn load<P: AsRef<Path>>(path: P) -> Result<ReposData, FileError> {
+    /// Loads and upgrades the file, also returning whether an upgrade was needed.
+    ///
+    /// Entries that can't be parsed are left out and reported, rather than failing
+    /// the whole file.
+    fn load<P: AsRef<Path>>(
+        path: P,
+    ) -> Result<(ReposData, Vec<RepoValidationIssue>, bool), FileError> {
         let file = std::fs::read_to_string(&path)
             .map_err(|e| FileError::Read(e, path.as_ref().to_path_buf()))?;
-        let file = toml::from_str(&file)
+        let found = super::check_version(repos_version(&file), REPOS_VERSION, path.as_ref())?;
+        let value: toml::Value = toml::from_str(&file)
             .map_err(|e| FileError::FromToml(e, path.as_ref().to_path_buf()))?;
-        Ok(file)
+
+        let (data, skipped) =
+            parse_repos(value).map_err(|e| FileError::FromToml(e, path.as_ref().to_path_buf()))?;
+
+        Ok((data, skipped, found < REPOS_VERSION))
     }
 
     fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), FileError> {
-        let mut file =
-            File::create(&path).map_err(|e| FileError::Write(e, path.as_ref().to_path_buf()))?;
-        let b =
-            toml::to_vec(&self).map_err(|e| FileError::ToToml(e, path.as_ref().to_path_buf()))?;
-        file.write_all(&b)
-            .map_err(|e| FileError::Write(e, path.as_ref().to_path_buf()))?;
-        Ok(())
+        let data = self
+            .to_toml()
+            .map_err(|e| FileError::ToToml(e, path.as_ref().to_path_buf()))?;
+        super::write_atomic(path.as_ref(), data.as_bytes())
+    }
+
+    /// The `repos.toml` contents for these repositories, version comment included.
+    fn to_toml(&self) -> Result<String, toml::ser::Error> {
+        let repos = toml::to_string(self)?;
+        Ok(format!("{}{}\n{}", VERSION_COMMENT, REPOS_VERSION, repos))
     }
 
     fn create<P: AsRef<Path>>(path: P) -> Result<ReposData, FileError> {
@@ -57,10 +279,66 @@ impl ReposData {
     }
 }
 
+/// The layout version in the leading comment of a `repos.toml`, or 0 for a file
+/// without one.
+///
+/// Version 0 has the same layout as version 1, so it needs no migration beyond
+/// writing the comment.
+fn repos_version(file: &str) -> u32 {
+    match file
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix(VERSION_COMMENT))
+    {
+        Some(v) => v.trim().parse().unwrap_or(u32::MAX),
+        None => 0,
+    }
+}
+
+/// Reads the repositories of a parsed `repos.toml`, leaving out and reporting
+/// entries that can't be parsed.
+fn parse_repos(
+    value: toml::Value,
+) -> Result<(ReposData, Vec<RepoValidationIssue>), toml::de::Error> {
+    let file: IndexMap<String, toml::Value> = value.try_into()?;
+
+    let mut repos = IndexMap::new();
+    let mut skipped = vec![];
+    for (url, record) in file {
+        let key = match toml::Value::String(url.clone()).try_into::<RepoUrl>() {
+            Ok(v) => v,
+            Err(e) => {
+                skipped.push(RepoValidationIssue::InvalidUrl(url, e.to_string()));
+                continue;
+            }
+        };
+        match record.try_into::<RepoRecord>() {
+            Ok(record) => {
+                repos.insert(key, record);
+            }
+            Err(e) => skipped.push(RepoValidationIssue::InvalidRecord(key, e.to_string())),
+        }
+    }
+
+    Ok((ReposData(repos), skipped))
+}
+
+/// The repositories of a `repos.toml`, layered over any read-only system files
+/// added with [`Repos::add_system_layer`].
+///
+/// Entries from this file override system entries with the same URL. Only this
+/// file is ever written, so system entries can be overridden but not removed.
 #[derive(Debug, Clone)]
 pub struct Repos {
     path: PathBuf,
+    /// The entries of this file alone.
     data: ReposData,
+    /// Entries from system layers, with the file each came from.
+    system: IndexMap<RepoUrl, (PathBuf, RepoRecord)>,
+    /// System entries followed by this file's, which override them in place.
+    merged: ReposData,
+    /// Entries left out by the last load, from every layer.
+    skipped: Vec<RepoValidationIssue>,
     permission: Permission,
 }
 
@@ -68,13 +346,14 @@ impl std::ops::Deref for Repos {
     type Target = IndexMap<RepoUrl, RepoRecord>;
 
     fn deref(&self) -> &Self::Target {
-        &self.data.0
+        &self.merged.0
     }
 }
 
+/// Changes made through this are in memory only, and are lost on the next save.
 impl std::ops::DerefMut for Repos {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.data.0
+        &mut self.merged.0
     }
 }
 
@@ -83,6 +362,9 @@ impl Repos {
         Repos {
             path: PathBuf::from("/dev/null"),
             data: ReposData::default(),
+            system: IndexMap::new(),
+            merged: ReposData::default(),
+            skipped: vec![],
             permission: Permission::ReadOnly,
         }
     }
@@ -92,38 +374,66 @@ impl Repos {
 
         Ok(Repos {
             path: path.as_ref().to_path_buf(),
+            merged: data.clone(),
             data,
+            system: IndexMap::new(),
+            skipped: vec![],
             permission: Permission::ReadWrite,
         })
     }
 
     pub fn load<P: AsRef<Path>>(path: P, permission: Permission) -> Result<Repos, FileError> {
-        let data = ReposData::load(path.as_ref())?;
+        let (data, skipped, migrated) = ReposData::load(path.as_ref())?;
 
-        Ok(Repos {
+        let repos = Repos {
             path: path.as_ref().to_path_buf(),
+            merged: data.clone(),
             data,
+            system: IndexMap::new(),
+            skipped,
             permission,
-        })
+        };
+
+        if migrated && permission == Permission::ReadWrite {
+            log::info!("Upgrading {:?} to version {}", path.as_ref(), REPOS_VERSION);
+            repos.save()?;
+        }
+
+        Ok(repos)
     }
 
     fn reload(&mut self) -> Result<(), FileError> {
-        if self.permission == Permission::ReadOnly {
+        if self.permission != Permission::ReadWrite {
             return Err(FileError::ReadOnly(self.path.clone()));
         }
-        self.data = ReposData::load(&self.path)?;
+        let (data, skipped, _) = ReposData::load(&self.path)?;
+        self.data = data;
+        self.skipped = skipped;
+        self.merge();
         Ok(())
     }
 
     fn save(&self) -> Result<(), FileError> {
-        if self.permission == Permission::ReadOnly {
+        if self.permission != Permission::ReadWrite {
             return Err(FileError::ReadOnly(self.path.clone()));
         }
         self.data.save(&self.path)
     }
 
+    /// Replaces this file's entries. System entries that `data` leaves out or
+    /// repeats unchanged are kept as they are.
     pub fn set(&mut self, data: ReposData) -> Result<(), FileError> {
-        self.data = data;
+        let system = &self.system;
+        self.data = ReposData(
+            data.0
+                .into_iter()
+                .filter(|(key, record)| match system.get(key) {
+                    Some((_, system_record)) => system_record != record,
+                    None => true,
+                })
+                .collect(),
+        );
+        self.merge();
 
         if self.permission == Permission::ReadWrite {
             return self.data.save(&self.path);
@@ -134,6 +444,7 @@ impl Repos {
 
     pub fn insert(&mut self, key: RepoUrl, value: RepoRecord) -> Result<(), FileError> {
         self.data.0.insert(key, value);
+        self.merge();
 
         if self.permission == Permission::ReadWrite {
             return self.data.save(&self.path);
@@ -142,8 +453,207 @@ impl Repos {
         Ok(())
     }
 
+    /// Removing an entry that overrides a system entry restores the system one.
+    /// System entries themselves can't be removed.
     pub fn remove(&mut self, key: &RepoUrl) -> Result<bool, FileError> {
-        let result = self.data.0.remove(key).is_some();
+        let result = self.data.0.shift_remove(key).is_some();
+        if !result {
+            if let Some((path, _)) = self.system.get(key) {
+                return Err(FileError::ReadOnly(path.clone()));
+            }
+        }
+        self.merge();
+
+        if self.permission == Permission::ReadWrite {
+            self.data.save(&self.path)?;
+        }
+
+        Ok(result)
+    }
+
+    /// Sets the priority of an already configured repository. Returns `false`
+    /// if `key` is not configured.
+    pub fn set_priority(&mut self, key: &RepoUrl, priority: i32) -> Result<bool, FileError> {
+        let record = match self.record_mut(key) {
+            Some(v) => v,
+            None => return Ok(false),
+        };
+        record.priority = priority;
+        self.merge();
+
+        if self.permission == Permission::ReadWrite {
+            self.data.save(&self.path)?;
+        }
+
+        Ok(true)
+    }
+
+    /// Enables or disables an already configured repository, keeping its record.
+    /// Returns `false` if `key` is not configured.
+    pub fn set_enabled(&mut self, key: &RepoUrl, enabled: bool) -> Result<bool, FileError> {
+        let record = match self.record_mut(key) {
+            Some(v) => v,
+            None => return Ok(false),
+        };
+        record.enabled = enabled;
+        self.merge();
+
+        if self.permission == Permission::ReadWrite {
+            self.data.save(&self.path)?;
+        }
+
+        Ok(true)
+    }
+
+    /// This file's record for `key`, copied from the system layers first if needed
+    /// so the change overrides the system entry.
+    fn record_mut(&mut self, key: &RepoUrl) -> Option<&mut RepoRecord> {
+        if !self.data.0.contains_key(key) {
+            let (_, record) = self.system.get(key)?;
+            self.data.0.insert(key.clone(), record.clone());
+        }
+        self.data.0.get_mut(key)
+    }
+
+    /// Adds the entries of `layer` as read-only system entries beneath this file's.
+    /// Layers added later override earlier ones.
+    pub fn add_system_layer(&mut self, layer: &Repos) {
+        for (key, record) in layer.merged.0.iter() {
+            let path = layer
+                .system
+                .get(key)
+                .filter(|_| !layer.data.0.contains_key(key))
+                .map(|(path, _)| path.clone())
+                .unwrap_or_else(|| layer.path.clone());
+            self.system.insert(key.clone(), (path, record.clone()));
+        }
+        self.skipped.extend(layer.skipped.iter().cloned());
+        self.merge();
+    }
+
+    /// Whether `key` comes from a system layer, so it can't be removed.
+    pub fn is_system(&self, key: &RepoUrl) -> bool {
+        self.system.contains_key(key)
+    }
+
+    fn merge(&mut self) {
+        let mut merged = self
+            .system
+            .iter()
+            .map(|(key, (_, record))| (key.clone(), record.clone()))
+            .collect::<IndexMap<_, _>>();
+        for (key, record) in self.data.0.iter() {
+            merged.insert(key.clone(), record.clone());
+        }
+        self.merged = ReposData(merged);
+    }
+
+    /// The configured repositories, system layers included, in the `repos.toml`
+    /// format. Credentials are left out; see [`Repos::export_with_credentials`].
+    pub fn export(&self) -> String {
+        self.export_records(false)
+    }
+
+    /// Like [`Repos::export`], but keeps each repository's `auth`. Secrets stored
+    /// as `Plain` are included as written.
+    pub fn export_with_credentials(&self) -> String {
+        self.export_records(true)
+    }
+
+    fn export_records(&self, credentials: bool) -> String {
+        let mut repos = self.merged.clone();
+        if !credentials {
+            for record in repos.0.values_mut() {
+                record.auth = None;
+            }
+        }
+
+        repos
+            .to_toml()
+            .expect("repository records serialize to TOML")
+    }
+
+    /// Adds the repositories of `data`, as written by [`Repos::export`], to this
+    /// file. Any `auth` in `data` is ignored, so configured repositories keep their
+    /// credentials and new ones get none; see [`Repos::import_with_credentials`].
+    pub fn import(
+        &mut self,
+        data: &str,
+        strategy: MergeStrategy,
+    ) -> Result<RepoImport, RepoImportError> {
+        self.import_records(data, strategy, false)
+    }
+
+    /// Like [`Repos::import`], but uses the `auth` in `data`. Only for lists from a
+    /// trusted source, as a record can point the user's credentials at any URL.
+    pub fn import_with_credentials(
+        &mut self,
+        data: &str,
+        strategy: MergeStrategy,
+    ) -> Result<RepoImport, RepoImportError> {
+        self.import_records(data, strategy, true)
+    }
+
+    fn import_records(
+        &mut self,
+        data: &str,
+        strategy: MergeStrategy,
+        credentials: bool,
+    ) -> Result<RepoImport, RepoImportError> {
+        let found = repos_version(data);
+        if found > REPOS_VERSION {
+            return Err(RepoImportError::UnsupportedVersion(found, REPOS_VERSION));
+        }
+        let value: toml::Value = toml::from_str(data).map_err(RepoImportError::Parse)?;
+        let (imported, invalid) = parse_repos(value).map_err(RepoImportError::Parse)?;
+
+        let mut result = RepoImport {
+            invalid,
+            ..Default::default()
+        };
+        let mut changes = vec![];
+        for (key, mut record) in imported.0 {
+            if !credentials {
+                record.auth = None;
+            }
+            let existing = match self.merged.0.get(&key) {
+                Some(v) => v,
+                None => {
+                    result.added.push(key.clone());
+                    changes.push((key, record));
+                    continue;
+                }
+            };
+
+            if record.auth.is_none() {
+                record.auth = existing.auth.clone();
+            }
+            if &record == existing {
+                result.unchanged.push(key);
+                continue;
+            }
+
+            match strategy {
+                MergeStrategy::SkipExisting => result.unchanged.push(key),
+                MergeStrategy::Overwrite => {
+                    if !same_trust(&record, existing) {
+                        log::warn!("Import changes the signature settings of {}", key);
+                        result.trust_changed.push(key.clone());
+                    }
+                    result.replaced.push(key.clone());
+                    changes.push((key, record));
+                }
+                MergeStrategy::ErrorOnConflict => return Err(RepoImportError::Conflict(key)),
+            }
+        }
+
+        if changes.is_empty() {
+            return Ok(result);
+        }
+        for (key, record) in changes {
+            self.data.0.insert(key, record);
+        }
+        self.merge();
 
         if self.permission == Permission::ReadWrite {
             self.data.save(&self.path)?;
@@ -152,7 +662,185 @@ impl Repos {
         Ok(result)
     }
 
+    /// Repositories from highest to lowest priority, in config order within a priority.
+    pub fn by_priority(&self) -> Vec<(&RepoUrl, &RepoRecord)> {
+        let mut repos = self.merged.0.iter().collect::<Vec<_>>();
+        // Stable, so config order is kept for equal priorities.
+        repos.sort_by_key(|(_, record)| std::cmp::Reverse(record.priority));
+        repos
+    }
+
+    /// Problems with the configured repositories, including entries that were
+    /// skipped when loading. Skipped entries are dropped from the file when it is
+    /// next saved.
+    pub fn validate(&self) -> Vec<RepoValidationIssue> {
+        let mut issues = self.skipped.clone();
+
+        for (url, record) in self.merged.0.iter() {
+            if record.channel.as_deref().map(str::trim) == Some("") {
+                issues.push(RepoValidationIssue::EmptyChannel(url.clone()));
+            }
+            for mirror in record.mirrors.iter() {
+                if !matches!(mirror.scheme(), "http" | "https") {
+                    issues.push(RepoValidationIssue::InvalidMirror(
+                        url.clone(),
+                        mirror.clone(),
+                    ));
+                }
+            }
+            if record.trusted_keys.iter().any(|x| x.trim().is_empty()) {
+                issues.push(RepoValidationIssue::EmptyTrustedKey(url.clone()));
+            }
+        }
+
+        issues
+    }
+
+    pub(crate) fn permission(&self) -> Permission {
+        self.permission
+    }
+
+    pub(crate) fn set_permission(&mut self, permission: Permission) {
+        self.permission = permission;
+    }
+
+    /// Every configured repository, system layers included.
     pub fn data(&self) -> &ReposData {
-        &self.data
+        &self.merged
+    }
+}
+
+fn same_trust(a: &RepoRecord, b: &RepoRecord) -> bool {
+    a.trusted_keys == b.trusted_keys
+        && a.signature_mode == b.signature_mode
+        && a.mirrors == b.mirrors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V0_FILE: &str = r#"
+["https://example.com/repo/"]
+channel = "beta"
+priority = 2
+"#;
+
+    /// A path in the temp directory no other test uses, removed on drop.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, contents: &str) -> TempFile {
+            let path = std::env::temp_dir().join(format!(
+                "pahkat-repos-{}-{}.toml",
+                name,
+                std::process::id()
+            ));
+            std::fs::write(&path, contents).unwrap();
+            TempFile(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn only_record(repos: &Repos) -> (&RepoUrl, &RepoRecord) {
+        assert_eq!(repos.len(), 1);
+        repos.iter().next().unwrap()
+    }
+
+    #[test]
+    fn written_file_round_trips_and_stays_readable_by_old_clients() {
+        let value: toml::Value = toml::from_str(V0_FILE).unwrap();
+        let (data, skipped) = parse_repos(value).unwrap();
+        assert!(skipped.is_empty());
+
+        let written = data.to_toml().unwrap();
+        assert_eq!(repos_version(&written), REPOS_VERSION);
+
+        // Older clients parse the whole file as a table of repositories.
+        let old: IndexMap<RepoUrl, RepoRecord> = toml::from_str(&written).unwrap();
+        assert_eq!(old, data.0);
+
+        let (reread, _) = parse_repos(toml::from_str(&written).unwrap()).unwrap();
+        assert_eq!(reread.0, data.0);
+    }
+
+    #[test]
+    fn v0_file_is_migrated_in_place() {
+        let file = TempFile::new("v0", V0_FILE);
+        assert_eq!(repos_version(V0_FILE), 0);
+
+        let repos = Repos::load(&file.0, Permission::ReadWrite).unwrap();
+        let (url, record) = only_record(&repos);
+        assert_eq!(url.to_string(), "https://example.com/repo/");
+        assert_eq!(record.channel.as_deref(), Some("beta"));
+        assert_eq!(record.priority, 2);
+
+        let migrated = std::fs::read_to_string(&file.0).unwrap();
+        assert_eq!(repos_version(&migrated), REPOS_VERSION);
+        let reloaded = Repos::load(&file.0, Permission::ReadOnly).unwrap();
+        assert_eq!(only_record(&reloaded).1, record);
+    }
+
+    #[test]
+    fn v0_file_is_left_alone_when_read_only() {
+        let file = TempFile::new("v0-read-only", V0_FILE);
+
+        Repos::load(&file.0, Permission::ReadOnly).unwrap();
+        assert_eq!(std::fs::read_to_string(&file.0).unwrap(), V0_FILE);
+    }
+
+    #[test]
+    fn import_drops_credentials_and_reports_trust_changes() {
+        let file = TempFile::new("import", V0_FILE);
+        let mut repos = Repos::load(&file.0, Permission::ReadOnly).unwrap();
+        let shared = r#"
+["https://example.com/repo/"]
+channel = "beta"
+priority = 2
+trusted_keys = ["RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3"]
+
+["https://example.com/repo/".auth]
+type = "bearer"
+token = { plain = "shared" }
+
+["https://example.org/other/".auth]
+type = "bearer"
+token = { plain = "shared" }
+"#;
+
+        let result = repos.import(shared, MergeStrategy::Overwrite).unwrap();
+        assert_eq!(result.added.len(), 1);
+        assert_eq!(result.replaced.len(), 1);
+        assert_eq!(result.trust_changed, result.replaced);
+        assert!(repos.iter().all(|(_, record)| record.auth.is_none()));
+
+        let mut repos = Repos::load(&file.0, Permission::ReadOnly).unwrap();
+        repos
+            .import_with_credentials(shared, MergeStrategy::Overwrite)
+            .unwrap();
+        assert!(repos.iter().all(|(_, record)| record.auth.is_some()));
+    }
+
+    #[test]
+    fn newer_version_is_refused() {
+        let contents = format!("{}{}\n{}", VERSION_COMMENT, REPOS_VERSION + 1, V0_FILE);
+        let file = TempFile::new("future", &contents);
+
+        match Repos::load(&file.0, Permission::ReadWrite) {
+            Err(FileError::UnsupportedVersion(found, supported, _)) => {
+                assert_eq!(found, REPOS_VERSION + 1);
+                assert_eq!(supported, REPOS_VERSION);
+            }
+            other => panic!(
+                "expected an unsupported version, got {:?}",
+                other.map(|_| ())
+            ),
+        }
+        assert_eq!(std::fs::read_to_string(&file.0).unwrap(), contents);
     }
 }
@@ -1,13 +1,16 @@
-use std::fs::File;
-use std::io::Write;
+use std::convert::TryFrom;
 use std::path::{Path, PathBuf};
 
+use indexmap::IndexMap;
+use pahkat_types::package::Version;
+use pahkat_types::PackageKey;
 use serde::{Deserialize, Serialize};
 
 use super::path::ConfigPath;
 use super::FileError;
 use crate::config::Permission;
 use crate::defaults;
+use crate::package_store::InstallTarget;
 
 #[inline(always)]
 fn cache_dir_default() -> ConfigPath {
@@ -19,8 +22,14 @@ fn tmp_dir_default() -> ConfigPath {
     defaults::tmp_dir().expect("tmp dir")
 }
 
+/// Version of the `settings.toml` layout written by this client.
+pub const SETTINGS_VERSION: u32 = 1;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SettingsData {
+    /// Layout version of the file; missing in files that predate versioning.
+    #[serde(default)]
+    pub version: u32,
     #[serde(default = "cache_dir_default")]
     pub cache_dir: ConfigPath,
     #[serde(default = "tmp_dir_default")]
@@ -29,36 +38,169 @@ pub struct SettingsData {
     pub max_concurrent_downloads: u8,
     #[serde(default)]
     pub skip_admin_verification: bool,
+    /// Seconds a cached repository index is used without revalidating it when a
+    /// store is opened. Explicit refreshes always revalidate.
+    #[serde(default)]
+    pub repo_cache_max_age: u64,
+    /// Highest version allowed for a package, keyed by its package key without query.
+    #[serde(default)]
+    pub pinned_versions: IndexMap<PackageKey, Version>,
+    #[serde(default)]
+    pub proxy: ProxySettings,
+    #[serde(default)]
+    pub timeouts: TimeoutSettings,
+    /// Target used by the `_default` action and transaction constructors.
+    #[serde(default)]
+    pub default_install_target: InstallTarget,
+    /// Never contact a server: repositories are loaded from their cached indexes
+    /// and only payloads that were already downloaded can be installed.
+    #[serde(default)]
+    pub offline: bool,
+}
+
+/// Timeouts for repository and payload requests, in seconds. Zero disables a timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct TimeoutSettings {
+    /// How long to wait for a connection to be established.
+    #[serde(default = "connect_timeout_default")]
+    pub connect: u64,
+    /// How long to wait for a response, or for the next part of its body.
+    #[serde(default = "read_timeout_default")]
+    pub read: u64,
+}
+
+#[inline(always)]
+fn connect_timeout_default() -> u64 {
+    30
+}
+
+#[inline(always)]
+fn read_timeout_default() -> u64 {
+    60
+}
+
+impl TimeoutSettings {
+    pub fn new(connect: u64, read: u64) -> TimeoutSettings {
+        TimeoutSettings { connect, read }
+    }
+
+    pub fn connect(&self) -> Option<std::time::Duration> {
+        Some(self.connect)
+            .filter(|x| *x > 0)
+            .map(std::time::Duration::from_secs)
+    }
+
+    pub fn read(&self) -> Option<std::time::Duration> {
+        Some(self.read)
+            .filter(|x| *x > 0)
+            .map(std::time::Duration::from_secs)
+    }
+}
+
+impl Default for TimeoutSettings {
+    fn default() -> TimeoutSettings {
+        TimeoutSettings {
+            connect: connect_timeout_default(),
+            read: read_timeout_default(),
+        }
+    }
+}
+
+/// Proxies for repository and payload requests. Unset fields fall back to the
+/// `HTTP_PROXY`, `HTTPS_PROXY` and `NO_PROXY` environment variables.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct ProxySettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http: Option<url::Url>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub https: Option<url::Url>,
+    /// Hosts, domains (`.example.com`) and IP ranges to connect to directly.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub no_proxy: Vec<String>,
+}
+
+impl ProxySettings {
+    pub fn new(
+        http: Option<url::Url>,
+        https: Option<url::Url>,
+        no_proxy: Vec<String>,
+    ) -> ProxySettings {
+        ProxySettings {
+            http,
+            https,
+            no_proxy,
+        }
+    }
+
+    /// Fills unset fields from the environment.
+    fn or_env(&self) -> ProxySettings {
+        let proxy_var = |name: &str| {
+            env_var(name, |x| url::Url::parse(x).ok())
+                .or_else(|| env_var(&name.to_ascii_lowercase(), |x| url::Url::parse(x).ok()))
+        };
+
+        ProxySettings {
+            http: self.http.clone().or_else(|| proxy_var("HTTP_PROXY")),
+            https: self.https.clone().or_else(|| proxy_var("HTTPS_PROXY")),
+            no_proxy: if self.no_proxy.is_empty() {
+                std::env::var("NO_PROXY")
+                    .or_else(|_| std::env::var("no_proxy"))
+                    .map(|x| {
+                        x.split(',')
+                            .map(|x| x.trim().to_string())
+                            .filter(|x| !x.is_empty())
+                            .collect()
+                    })
+                    .unwrap_or_default()
+            } else {
+                self.no_proxy.clone()
+            },
+        }
+    }
 }
 
 impl Default for SettingsData {
     fn default() -> SettingsData {
         SettingsData {
+            version: SETTINGS_VERSION,
             cache_dir: cache_dir_default(),
             tmp_dir: tmp_dir_default(),
             max_concurrent_downloads: 0,
             skip_admin_verification: false,
+            repo_cache_max_age: 0,
+            pinned_versions: IndexMap::new(),
+            proxy: ProxySettings::default(),
+            timeouts: TimeoutSettings::default(),
+            default_install_target: InstallTarget::default(),
+            offline: false,
         }
     }
 }
 
 impl SettingsData {
-    fn load<P: AsRef<Path>>(path: P) -> Result<SettingsData, FileError> {
+    /// Loads and upgrades the file, also returning whether an upgrade was needed.
+    fn load<P: AsRef<Path>>(path: P) -> Result<(SettingsData, bool), FileError> {
         let file = std::fs::read_to_string(&path)
             .map_err(|e| FileError::Read(e, path.as_ref().to_path_buf()))?;
-        let file = toml::from_str(&file)
+        let value: toml::Value = toml::from_str(&file)
             .map_err(|e| FileError::FromToml(e, path.as_ref().to_path_buf()))?;
-        Ok(file)
+        let found = super::file_version(&value, SETTINGS_VERSION, path.as_ref())?;
+
+        // Version 0 only lacks the version field, so there is nothing to rewrite.
+        let mut data: SettingsData = value
+            .try_into()
+            .map_err(|e| FileError::FromToml(e, path.as_ref().to_path_buf()))?;
+        data.version = SETTINGS_VERSION;
+
+        Ok((data, found < SETTINGS_VERSION))
     }
 
     fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), FileError> {
-        let mut file =
-            File::create(&path).map_err(|e| FileError::Write(e, path.as_ref().to_path_buf()))?;
         let b =
             toml::to_vec(&self).map_err(|e| FileError::ToToml(e, path.as_ref().to_path_buf()))?;
-        file.write_all(&b)
-            .map_err(|e| FileError::Write(e, path.as_ref().to_path_buf()))?;
-        Ok(())
+        super::write_atomic(path.as_ref(), &b)
     }
 
     fn create<P: AsRef<Path>>(path: P) -> Result<SettingsData, FileError> {
@@ -76,10 +218,65 @@ impl SettingsData {
     }
 }
 
+/// Settings taken from the environment, which take precedence over `settings.toml`
+/// without ever being written to it.
+///
+/// | Variable                           | Overrides                  |
+/// |------------------------------------|----------------------------|
+/// | `PAHKAT_CACHE_DIR`                 | `cache_dir`                |
+/// | `PAHKAT_MAX_CONCURRENT_DOWNLOADS`  | `max_concurrent_downloads` |
+/// | `PAHKAT_SKIP_ADMIN_VERIFICATION`   | `skip_admin_verification`  |
+/// | `PAHKAT_OFFLINE`                   | `offline`                  |
+///
+/// `PAHKAT_CONFIG_DIR` is handled by [`crate::Config::load_default`].
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct SettingsOverrides {
+    pub cache_dir: Option<ConfigPath>,
+    pub max_concurrent_downloads: Option<u8>,
+    pub skip_admin_verification: Option<bool>,
+    pub offline: Option<bool>,
+}
+
+impl SettingsOverrides {
+    /// Reads overrides from the environment. Values that cannot be parsed are
+    /// logged and ignored.
+    pub fn from_env() -> SettingsOverrides {
+        SettingsOverrides {
+            cache_dir: env_var("PAHKAT_CACHE_DIR", |x| {
+                ConfigPath::try_from(PathBuf::from(x)).ok()
+            }),
+            max_concurrent_downloads: env_var("PAHKAT_MAX_CONCURRENT_DOWNLOADS", |x| {
+                x.parse().ok()
+            }),
+            skip_admin_verification: env_var("PAHKAT_SKIP_ADMIN_VERIFICATION", parse_bool),
+            offline: env_var("PAHKAT_OFFLINE", parse_bool),
+        }
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match &*value.to_ascii_lowercase() {
+        "1" | "true" | "yes" => Some(true),
+        "0" | "false" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+fn env_var<T, F: FnOnce(&str) -> Option<T>>(name: &str, parse: F) -> Option<T> {
+    let value = std::env::var(name).ok()?;
+    let result = parse(&value);
+    if result.is_none() {
+        log::warn!("Ignoring invalid value for {}: {:?}", name, value);
+    }
+    result
+}
+
 #[derive(Debug, Clone)]
 pub struct Settings {
     path: PathBuf,
     data: SettingsData,
+    overrides: SettingsOverrides,
     permission: Permission,
 }
 
@@ -88,6 +285,7 @@ impl Settings {
         Settings {
             path: PathBuf::from("/dev/null"),
             data: SettingsData::default(),
+            overrides: SettingsOverrides::default(),
             permission: Permission::ReadOnly,
         }
     }
@@ -100,29 +298,61 @@ impl Settings {
         let settings = Settings {
             path,
             data,
+            overrides: SettingsOverrides::default(),
             permission,
         };
 
-        let package_cache_dir = settings.package_cache_dir();
+        settings.create_cache_dirs()?;
+        Ok(settings)
+    }
+
+    fn create_cache_dirs(&self) -> Result<(), FileError> {
+        // Report an unset variable in the cache path here rather than panicking later.
+        self.cache_base_dir()
+            .to_path_buf()
+            .map_err(|e| FileError::Path(e, self.path.clone()))?;
+
+        let package_cache_dir = self.package_cache_dir();
 
         if !package_cache_dir.exists() {
             std::fs::create_dir_all(&*package_cache_dir)
-                .map_err(|e| FileError::Write(e, settings.path.clone()))?;
+                .map_err(|e| FileError::Write(e, self.path.clone()))?;
         }
 
-        let repo_cache_dir = settings.repo_cache_dir();
+        let repo_cache_dir = self.repo_cache_dir();
 
         if !repo_cache_dir.exists() {
             std::fs::create_dir_all(&*repo_cache_dir)
-                .map_err(|e| FileError::Write(e, settings.path.clone()))?;
+                .map_err(|e| FileError::Write(e, self.path.clone()))?;
         }
 
-        Ok(settings)
+        Ok(())
+    }
+
+    /// Layers `overrides` over the values from the file. The file is left untouched.
+    pub fn set_overrides(&mut self, overrides: SettingsOverrides) -> Result<(), FileError> {
+        self.overrides = overrides;
+        self.create_cache_dirs()
+    }
+
+    pub fn overrides(&self) -> &SettingsOverrides {
+        &self.overrides
     }
 
     pub fn load<P: AsRef<Path>>(path: P, permission: Permission) -> Result<Settings, FileError> {
-        let data = SettingsData::load(path.as_ref())?;
-        Self::new(path.as_ref().to_path_buf(), data, permission)
+        let (data, migrated) = SettingsData::load(path.as_ref())?;
+        let settings = Self::new(path.as_ref().to_path_buf(), data, permission)?;
+
+        if migrated && permission == Permission::ReadWrite {
+            log::info!(
+                "Upgrading {:?} to version {}",
+                path.as_ref(),
+                SETTINGS_VERSION
+            );
+            settings.save()?;
+        }
+
+        Ok(settings)
     }
 
     pub fn create<P: AsRef<Path>>(path: P) -> Result<Settings, FileError> {
@@ -131,15 +361,15 @@ impl Settings {
     }
 
     fn reload(&mut self) -> Result<(), FileError> {
-        if self.permission == Permission::ReadOnly {
+        if self.permission != Permission::ReadWrite {
             return Err(FileError::ReadOnly(self.path.clone()));
         }
-        self.data = SettingsData::load(&self.path)?;
+        self.data = SettingsData::load(&self.path)?.0;
         Ok(())
     }
 
     fn save(&self) -> Result<(), FileError> {
-        if self.permission == Permission::ReadOnly {
+        if self.permission != Permission::ReadWrite {
             return Err(FileError::ReadOnly(self.path.clone()));
         }
         self.data.save(&self.path)
@@ -149,9 +379,17 @@ impl Settings {
         self.path.parent().unwrap()
     }
 
+    pub(crate) fn permission(&self) -> Permission {
+        self.permission
+    }
+
+    pub(crate) fn set_permission(&mut self, permission: Permission) {
+        self.permission = permission;
+    }
+
     #[inline(always)]
     fn cache_dir(&self, path: &str) -> ConfigPath {
-        self.data.cache_dir.join(path)
+        self.cache_base_dir().join(path)
     }
 
     pub(crate) fn config_dir(&self) -> &Path {
@@ -171,15 +409,102 @@ impl Settings {
     }
 
     pub fn cache_base_dir(&self) -> ConfigPath {
-        self.data.cache_dir.to_owned()
+        self.overrides
+            .cache_dir
+            .as_ref()
+            .unwrap_or(&self.data.cache_dir)
+            .to_owned()
     }
 
     pub fn max_concurrent_downloads(&self) -> u8 {
-        self.data.max_concurrent_downloads
+        self.overrides
+            .max_concurrent_downloads
+            .unwrap_or(self.data.max_concurrent_downloads)
     }
 
     pub fn skip_admin_verification(&self) -> bool {
-        self.data.skip_admin_verification
+        self.overrides
+            .skip_admin_verification
+            .unwrap_or(self.data.skip_admin_verification)
+    }
+
+    pub fn offline(&self) -> bool {
+        self.overrides.offline.unwrap_or(self.data.offline)
+    }
+
+    pub fn set_offline(&mut self, offline: bool) -> Result<(), FileError> {
+        self.data.offline = offline;
+
+        if self.permission == Permission::ReadWrite {
+            return self.data.save(&self.path);
+        }
+
+        Ok(())
+    }
+
+    pub fn repo_cache_max_age(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.data.repo_cache_max_age)
+    }
+
+    pub fn set_repo_cache_max_age(&mut self, seconds: u64) -> Result<(), FileError> {
+        self.data.repo_cache_max_age = seconds;
+
+        if self.permission == Permission::ReadWrite {
+            return self.data.save(&self.path);
+        }
+
+        Ok(())
+    }
+
+    /// The configured proxies, with unset fields taken from the environment.
+    pub fn proxy(&self) -> ProxySettings {
+        self.data.proxy.or_env()
+    }
+
+    pub fn set_proxy(&mut self, proxy: ProxySettings) -> Result<(), FileError> {
+        self.data.proxy = proxy;
+
+        if self.permission == Permission::ReadWrite {
+            return self.data.save(&self.path);
+        }
+
+        Ok(())
+    }
+
+    pub fn timeouts(&self) -> TimeoutSettings {
+        self.data.timeouts
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: TimeoutSettings) -> Result<(), FileError> {
+        self.data.timeouts = timeouts;
+
+        if self.permission == Permission::ReadWrite {
+            return self.data.save(&self.path);
+        }
+
+        Ok(())
+    }
+
+    pub fn default_install_target(&self) -> InstallTarget {
+        self.data.default_install_target
+    }
+
+    pub fn set_default_install_target(&mut self, target: InstallTarget) -> Result<(), FileError> {
+        self.data.default_install_target = target;
+
+        if self.permission == Permission::ReadWrite {
+            return self.data.save(&self.path);
+        }
+
+        Ok(())
+    }
+
+    pub fn pinned_versions(&self) -> &IndexMap<PackageKey, Version> {
+        &self.data.pinned_versions
+    }
+
+    pub fn pinned_version(&self, key: &PackageKey) -> Option<&Version> {
+        self.data.pinned_versions.get(&pin_key(key))
     }
 
     pub fn set_cache_dir(&mut self, cache_dir: ConfigPath) -> Result<(), FileError> {
@@ -211,4 +536,120 @@ impl Settings {
 
         Ok(())
     }
+
+    /// Stores using this config honor the pin from their next resolution on.
+    pub fn pin_version(&mut self, key: &PackageKey, version: Version) -> Result<(), FileError> {
+        self.data.pinned_versions.insert(pin_key(key), version);
+
+        if self.permission == Permission::ReadWrite {
+            return self.data.save(&self.path);
+        }
+
+        Ok(())
+    }
+
+    pub fn unpin_version(&mut self, key: &PackageKey) -> Result<(), FileError> {
+        self.data.pinned_versions.remove(&pin_key(key));
+
+        if self.permission == Permission::ReadWrite {
+            return self.data.save(&self.path);
+        }
+
+        Ok(())
+    }
+}
+
+#[inline(always)]
+fn pin_key(key: &PackageKey) -> PackageKey {
+    PackageKey::new_unchecked(key.repository_url.clone(), key.id.clone(), None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// The environment is shared by every test thread.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    const VARS: &[&str] = &[
+        "PAHKAT_CACHE_DIR",
+        "PAHKAT_MAX_CONCURRENT_DOWNLOADS",
+        "PAHKAT_SKIP_ADMIN_VERIFICATION",
+        "PAHKAT_OFFLINE",
+    ];
+
+    fn settings(data: SettingsData) -> Settings {
+        Settings {
+            path: PathBuf::from("/dev/null"),
+            data,
+            overrides: SettingsOverrides::from_env(),
+            permission: Permission::ReadOnly,
+        }
+    }
+
+    fn file_data() -> SettingsData {
+        SettingsData {
+            max_concurrent_downloads: 3,
+            offline: false,
+            ..SettingsData::default()
+        }
+    }
+
+    #[test]
+    fn env_takes_precedence_over_file_and_default() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let cache_dir = std::env::temp_dir().join("pahkat-env-cache");
+        std::env::set_var("PAHKAT_CACHE_DIR", &cache_dir);
+        std::env::set_var("PAHKAT_MAX_CONCURRENT_DOWNLOADS", "7");
+        std::env::set_var("PAHKAT_SKIP_ADMIN_VERIFICATION", "true");
+        std::env::set_var("PAHKAT_OFFLINE", "yes");
+
+        let settings = settings(file_data());
+        for var in VARS {
+            std::env::remove_var(var);
+        }
+
+        assert_eq!(settings.cache_base_dir().to_path_buf().unwrap(), cache_dir);
+        assert_eq!(settings.max_concurrent_downloads(), 7);
+        assert!(settings.skip_admin_verification());
+        assert!(settings.offline());
+
+        // The file's values are kept for when the overrides go away.
+        assert_eq!(settings.data.max_concurrent_downloads, 3);
+        assert!(!settings.data.offline);
+    }
+
+    #[test]
+    fn unset_env_falls_back_to_file_then_default() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        for var in VARS {
+            std::env::remove_var(var);
+        }
+
+        let settings = settings(file_data());
+
+        assert_eq!(settings.max_concurrent_downloads(), 3);
+        assert!(!settings.offline());
+        assert_eq!(
+            settings.cache_base_dir().to_path_buf().ok(),
+            settings.data.cache_dir.to_path_buf().ok()
+        );
+        assert!(!settings.skip_admin_verification());
+    }
+
+    #[test]
+    fn invalid_env_values_are_ignored() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        std::env::set_var("PAHKAT_MAX_CONCURRENT_DOWNLOADS", "many");
+        std::env::set_var("PAHKAT_OFFLINE", "sometimes");
+
+        let overrides = SettingsOverrides::from_env();
+        for var in VARS {
+            std::env::remove_var(var);
+        }
+
+        assert_eq!(overrides.max_concurrent_downloads, None);
+        assert_eq!(overrides.offline, None);
+    }
 }
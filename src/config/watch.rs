@@ -0,0 +1,154 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use notify::{EventKind, RecursiveMode, Watcher};
+
+use super::{Config, Error, Repos, Settings};
+
+/// Editors often write a file several times per save; changes closer together
+/// than this are handled as one.
+const DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// Keeps a config directory watched until dropped.
+pub struct ConfigWatcher {
+    _watcher: notify::RecommendedWatcher,
+}
+
+impl std::fmt::Debug for ConfigWatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConfigWatcher").finish()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Changed {
+    settings: bool,
+    repos: bool,
+}
+
+impl Changed {
+    fn record(&mut self, system_layers: &[PathBuf], event: notify::Result<notify::Event>) {
+        let event = match event {
+            Ok(v) => v,
+            Err(e) => {
+                log::warn!("Config watcher error: {:?}", e);
+                return;
+            }
+        };
+
+        if let EventKind::Access(_) = event.kind {
+            return;
+        }
+
+        for path in event.paths.iter() {
+            // Settings only come from the watched directory.
+            let is_system = path
+                .parent()
+                .map(|dir| system_layers.iter().any(|layer| layer == dir))
+                .unwrap_or(false);
+
+            match path.file_name().and_then(|x| x.to_str()) {
+                Some("settings.toml") if !is_system => self.settings = true,
+                Some("repos.toml") => self.repos = true,
+                _ => {}
+            }
+        }
+    }
+}
+
+pub(super) fn watch<F>(config: Config, path: &Path, callback: F) -> Result<ConfigWatcher, Error>
+where
+    F: Fn(Result<Config, Error>) + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = tx.send(event);
+    })?;
+
+    // Watch the directory rather than the files, as atomic saves replace them.
+    watcher.watch(path, RecursiveMode::NonRecursive)?;
+
+    let system_layers = match config.source.as_ref() {
+        Some((layers, _)) => layers
+            .split_last()
+            .map(|x| x.1.to_vec())
+            .unwrap_or_default(),
+        None => vec![],
+    };
+    for layer in system_layers.iter() {
+        // A layer may not exist yet, as when it has no repos.toml.
+        if let Err(e) = watcher.watch(layer, RecursiveMode::NonRecursive) {
+            log::warn!("Could not watch system config {:?}: {:?}", layer, e);
+        }
+    }
+
+    let path = path.to_path_buf();
+
+    // Ends once the watcher, and with it the sender, is dropped.
+    std::thread::spawn(move || {
+        let mut config = config;
+
+        while let Ok(event) = rx.recv() {
+            let mut changed = Changed::default();
+            changed.record(&system_layers, event);
+
+            while let Ok(event) = rx.recv_timeout(DEBOUNCE) {
+                changed.record(&system_layers, event);
+            }
+
+            if !changed.settings && !changed.repos {
+                continue;
+            }
+
+            log::debug!("Reloading config: {:?}", &changed);
+
+            match reload(&config, &path, &system_layers, &changed) {
+                Ok(next) => {
+                    config = next.clone();
+                    callback(Ok(next));
+                }
+                Err(e) => {
+                    log::error!("Could not reload config: {:?}", &e);
+                    callback(Err(e));
+                }
+            }
+        }
+    });
+
+    Ok(ConfigWatcher { _watcher: watcher })
+}
+
+fn reload(
+    config: &Config,
+    path: &Path,
+    system_layers: &[PathBuf],
+    changed: &Changed,
+) -> Result<Config, Error> {
+    let mut next = config.clone();
+
+    if changed.settings {
+        let mut settings = Settings::load(path.join("settings.toml"), config.settings.permission())
+            .map_err(Error::SettingsFile)?;
+        settings
+            .set_overrides(config.settings.overrides().clone())
+            .map_err(Error::SettingsFile)?;
+        next.settings = settings;
+    }
+
+    if changed.repos {
+        // System layers are read again too, as a change may have been to one of them.
+        let mut repos = Repos::load(path.join("repos.toml"), config.repos.permission())
+            .map_err(Error::ReposFile)?;
+        if let Some(e) = super::add_system_layers(&mut repos, system_layers)
+            .into_iter()
+            .next()
+        {
+            return Err(e);
+        }
+        next.repos = repos;
+    }
+
+    Ok(next)
+}
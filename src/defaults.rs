@@ -146,6 +146,54 @@ pub fn uninstall_path() -> Result<PathBuf, pathos::Error> {
     Ok(pathos::user::app_data_dir(APP_PATH)?.join("uninstall"))
 }
 
+/// Language used when a package has no strings in the requested locale.
+pub(crate) const DEFAULT_LANGUAGE: &str = "en";
+
+/// Whether the process may install packages for every user.
+pub(crate) fn is_elevated() -> bool {
+    #[cfg(windows)]
+    {
+        unsafe { winapi::um::shlobj::IsUserAnAdmin() != 0 }
+    }
+
+    #[cfg(unix)]
+    {
+        unsafe { libc::geteuid() == 0 }
+    }
+
+    #[cfg(not(any(windows, unix)))]
+    {
+        false
+    }
+}
+
+/// The user's locale as a BCP-47 tag, such as `nb-NO`.
+pub fn system_locale() -> Option<String> {
+    #[cfg(windows)]
+    {
+        use winapi::um::winnls::GetUserDefaultLocaleName;
+
+        let mut buf = [0u16; 85];
+        let len = unsafe { GetUserDefaultLocaleName(buf.as_mut_ptr(), buf.len() as i32) };
+        if len > 1 {
+            return Some(String::from_utf16_lossy(&buf[..len as usize - 1]));
+        }
+    }
+
+    ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|value| !value.is_empty())
+        .and_then(|value| {
+            // POSIX locales look like `nb_NO.UTF-8@modifier`.
+            let tag = value.split(|c| c == '.' || c == '@').next().unwrap_or("");
+            match tag {
+                "" | "C" | "POSIX" => None,
+                tag => Some(tag.replace('_', "-")),
+            }
+        })
+}
+
 macro_rules! platform {
     ($name:expr) => {{
         #[cfg(target_os = $name)]
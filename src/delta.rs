@@ -0,0 +1,132 @@
+//! Rebuilding a payload from the payload of an older release and a binary delta.
+//!
+//! A delta from version `V` of a payload at `<url>` is published at
+//! `<url>.from-V.zst`. It is a zstd frame compressed with the old payload as its
+//! reference, as `zstd --patch-from=<old payload> <new payload>` makes, and must
+//! carry the content checksum zstd adds by default. The rebuilt payload is
+//! checked against that checksum and the size in the index before it is used.
+//!
+//! zstd needs the whole old payload while decompressing, so it is read into
+//! memory. The delta is streamed, and the new payload is written to a temporary
+//! file that is only renamed into place once it checks out.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+/// Set in a zstd frame header when the frame ends with a checksum of its content.
+const CONTENT_CHECKSUM_FLAG: u8 = 0x04;
+/// The largest window zstd decompresses with, so large old payloads can be
+/// referenced in full.
+const WINDOW_LOG_MAX: u32 = 31;
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum DeltaError {
+    #[error("Not a zstd delta")]
+    InvalidHeader,
+
+    #[error("Delta has no content checksum to check the rebuilt payload against")]
+    NoChecksum,
+
+    #[error("Delta built {actual} bytes, but {expected} were expected")]
+    LengthMismatch { expected: u64, actual: u64 },
+
+    #[error("IO error")]
+    Io(#[from] io::Error),
+}
+
+/// The URL of the delta from version `from` to the payload at `url`.
+pub(crate) fn delta_url(url: &url::Url, from: &str) -> Option<url::Url> {
+    url::Url::parse(&format!("{}.from-{}.zst", url.as_str(), from)).ok()
+}
+
+/// Checks the start of a delta is a zstd frame whose content is checksummed.
+fn check_header(header: &[u8]) -> Result<(), DeltaError> {
+    match header {
+        [a, b, c, d, descriptor, ..] if [*a, *b, *c, *d] == ZSTD_MAGIC => {
+            if descriptor & CONTENT_CHECKSUM_FLAG == 0 {
+                Err(DeltaError::NoChecksum)
+            } else {
+                Ok(())
+            }
+        }
+        _ => Err(DeltaError::InvalidHeader),
+    }
+}
+
+/// Writes the payload rebuilt from `old_path` and `delta_path` to `output_path`,
+/// checking it is `size` bytes long unless `size` is `0`.
+pub(crate) fn patch(
+    old_path: &Path,
+    delta_path: &Path,
+    output_path: &Path,
+    size: u64,
+) -> Result<(), DeltaError> {
+    let mut delta = BufReader::new(File::open(delta_path)?);
+    let mut header = [0u8; 5];
+    delta
+        .read_exact(&mut header)
+        .map_err(|_| DeltaError::InvalidHeader)?;
+    check_header(&header)?;
+
+    let old = fs::read(old_path)?;
+    let mut decoder =
+        zstd::stream::read::Decoder::with_ref_prefix(io::Cursor::new(header).chain(delta), &old)?;
+    decoder.window_log_max(WINDOW_LOG_MAX)?;
+
+    if let Some(parent) = output_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file_name = output_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".delta");
+    let tmp_path = output_path.with_file_name(file_name);
+
+    let result = rebuild(&mut decoder, &tmp_path, size)
+        .and_then(|()| crate::config::replace_file(&tmp_path, output_path).map_err(DeltaError::Io));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+fn rebuild(decoder: &mut impl Read, tmp_path: &Path, size: u64) -> Result<(), DeltaError> {
+    let mut file = File::create(tmp_path)?;
+
+    // Reading one byte past `size` is enough to tell the delta builds too much.
+    // Reaching the end of the frame makes the decoder check the content checksum.
+    let limit = if size == 0 { u64::MAX } else { size + 1 };
+    let actual = io::copy(&mut decoder.take(limit), &mut file)?;
+    if size != 0 && actual != size {
+        return Err(DeltaError::LengthMismatch {
+            expected: size,
+            actual,
+        });
+    }
+
+    file.flush()?;
+    file.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_checksummed_zstd_frames_are_deltas() {
+        assert!(check_header(&[0x28, 0xb5, 0x2f, 0xfd, 0x24]).is_ok());
+        assert!(matches!(
+            check_header(&[0x28, 0xb5, 0x2f, 0xfd, 0x20]),
+            Err(DeltaError::NoChecksum)
+        ));
+        assert!(matches!(
+            check_header(b"PAHKATD1"),
+            Err(DeltaError::InvalidHeader)
+        ));
+        assert!(matches!(
+            check_header(&[0x28, 0xb5]),
+            Err(DeltaError::InvalidHeader)
+        ));
+    }
+}
@@ -1,11 +1,15 @@
 use std::fs;
+use std::future::Future;
 use std::io::{BufWriter, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::thread::JoinHandle;
+use std::time::Duration;
 
 use reqwest::header;
 use url::Url;
 
+use crate::config::{ProxySettings, RepoAuth, TimeoutSettings};
 use crate::ext::PathExt;
 use crate::package_store::DownloadEvent;
 
@@ -20,9 +24,14 @@ pub trait Download {
         F: Fn(u64, u64) -> bool + Send + 'static;
 }
 
+/// How often a download reports progress, and the default for
+/// [`ProgressThrottle`](crate::ProgressThrottle).
+pub(crate) const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
 pub(crate) struct DownloadManager {
     client: reqwest::Client,
     path: PathBuf,
+    read_timeout: Option<Duration>,
     // max_concurrent_downloads: u8,
 }
 
@@ -31,25 +40,82 @@ pub(crate) struct DownloadManager {
 // >;
 
 impl DownloadManager {
-    pub fn new(path: PathBuf, _max_concurrent_downloads: u8) -> DownloadManager {
-        let client = Self::client();
+    pub fn new(
+        path: PathBuf,
+        _max_concurrent_downloads: u8,
+        proxy: &ProxySettings,
+        timeouts: &TimeoutSettings,
+        http: &HttpClientHook,
+    ) -> DownloadManager {
+        let client = Self::client(proxy, timeouts, http);
 
         DownloadManager {
             client,
             path,
+            read_timeout: timeouts.read(),
             // max_concurrent_downloads,
         }
     }
 
     #[inline]
-    fn client() -> reqwest::Client {
-        reqwest::Client::builder().build().unwrap()
+    fn client(
+        proxy: &ProxySettings,
+        timeouts: &TimeoutSettings,
+        http: &HttpClientHook,
+    ) -> reqwest::Client {
+        client_builder(proxy, timeouts, http).build().unwrap()
+    }
+
+    /// Fetches the detached signature published next to `url`, if there is one.
+    pub async fn download_signature(
+        &self,
+        url: &Url,
+        auth: Option<&RepoAuth>,
+    ) -> Result<Option<String>, DownloadError> {
+        let sig_url = format!("{}{}", url.as_str(), crate::signature::SIGNATURE_SUFFIX);
+        let req = authorize(self.client.get(&sig_url), auth)
+            .build()
+            .map_err(|e| DownloadError::ReqwestError(e, sig_url.clone()))?;
+
+        let client = self.client.clone();
+        let read_timeout = self.read_timeout;
+        let task_url = sig_url.clone();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let result = async {
+                let res = client
+                    .execute(req)
+                    .await
+                    .map_err(|e| DownloadError::from_reqwest(e, task_url.clone()))?;
+                match res.status() {
+                    reqwest::StatusCode::NOT_FOUND => Ok(None),
+                    reqwest::StatusCode::UNAUTHORIZED => {
+                        Err(DownloadError::Unauthorized(task_url.clone()))
+                    }
+                    _ => {
+                        let body = async { res.error_for_status()?.text().await };
+                        body.await
+                            .map(Some)
+                            .map_err(|e| DownloadError::from_reqwest(e, task_url.clone()))
+                    }
+                }
+            };
+            let result = with_timeout(read_timeout, result)
+                .await
+                .unwrap_or_else(|_| Err(DownloadError::TimedOut(task_url.clone())));
+            // The receiver is gone if the caller stopped waiting.
+            let _ = tx.send(result);
+        });
+
+        rx.await
+            .unwrap_or_else(|_| Err(DownloadError::Interrupted(sig_url)))
     }
 
     pub async fn download<P: AsRef<Path>>(
         &self,
         url: &Url,
         dest_path: P,
+        auth: Option<&RepoAuth>,
     ) -> Result<
         std::pin::Pin<
             Box<dyn futures::stream::Stream<Item = DownloadEvent> + Send + Sync + 'static>,
@@ -97,47 +163,68 @@ impl DownloadManager {
             })?;
         }
 
-        let tmp_dest_path = cache_dir.join(filename);
-
-        // let mut file = fs::OpenOptions::new()
-        //     .append(true)
-        //     .write(true)
-        //     .open(&tmp_dest_path)
-        //     .or_else(|_| )
-        let file = fs::File::create(&tmp_dest_path).map_err(|e| {
-            log::error!("Open temp file failed: {:?}", &e);
-            DownloadError::TempFileOpenFailed(e, tmp_dest_path.to_path_buf())
-        })?;
-        // let meta = file.metadata().map_err(|e| {
-        //     log::error!("metadata error: {:?}", &e);
-        //     DownloadError::MetadataFailed(e, tmp_dest_path.to_path_buf())
-        // })?;
-
-        // let mut downloaded_bytes = meta.len();
-        // log::debug!("Downloaded bytes: {}", downloaded_bytes);
-
-        let client = &self.client;
-        let req = client.get(url.as_str());
-        // if downloaded_bytes > 0 {
-        //     req = req.header(header::RANGE, format!("bytes={}-", downloaded_bytes));
-        // }
+        let tmp_dest_path = cache_dir.join(format!("{}.part", filename));
+        let etag_path = cache_dir.join(format!("{}.part.etag", filename));
 
-        let req = req
-            .build()
-            .map_err(|e| DownloadError::ReqwestError(e, url.as_str().to_string()))?;
+        // A partial file is only resumed if we know which version of the remote file
+        // it belongs to; `If-Range` makes the server send all of it if that changed.
+        let partial = match (fs::metadata(&tmp_dest_path), fs::read_to_string(&etag_path)) {
+            (Ok(meta), Ok(etag)) if meta.len() > 0 && !etag.is_empty() => Some((meta.len(), etag)),
+            _ => None,
+        };
 
-        // Get URL headers
-        let (tx, rx) = tokio::sync::oneshot::channel();
-        tokio::spawn(async move {
-            let response = Self::client()
-                .execute(req)
-                .await
-                .and_then(|x| x.error_for_status());
-            tx.send(response).unwrap();
-        });
-        let mut res = rx
-            .await
-            .unwrap()
+        // If the partial file can't be resumed, retry from the start.
+        let attempts = match partial {
+            Some(_) => vec![partial.clone(), None],
+            None => vec![None],
+        };
+
+        let mut res = None;
+        for range in attempts {
+            let mut req = authorize(self.client.get(url.as_str()), auth);
+            if let Some((offset, etag)) = range.as_ref() {
+                log::debug!("Resuming {} from byte {}", url, offset);
+                req = req
+                    .header(header::RANGE, format!("bytes={}-", offset))
+                    .header(header::IF_RANGE, etag.as_str());
+            }
+
+            let req = req
+                .build()
+                .map_err(|e| DownloadError::ReqwestError(e, url.as_str().to_string()))?;
+
+            // Get URL headers
+            let client = self.client.clone();
+            let read_timeout = self.read_timeout;
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            tokio::spawn(async move {
+                let response = with_timeout(read_timeout, client.execute(req)).await;
+                let _ = tx.send(response);
+            });
+            let response = match rx.await {
+                Ok(Ok(result)) => {
+                    result.map_err(|e| DownloadError::from_reqwest(e, url.as_str().to_string()))?
+                }
+                Ok(Err(_)) => return Err(DownloadError::TimedOut(url.as_str().to_string())),
+                Err(_) => return Err(DownloadError::Interrupted(url.as_str().to_string())),
+            };
+
+            // The partial file is longer than the remote one, so it can't be resumed.
+            if range.is_some() && response.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+                continue;
+            }
+
+            res = Some(response);
+            break;
+        }
+        let res = res.expect("at least one request is made");
+
+        if res.status() == reqwest::StatusCode::UNAUTHORIZED {
+            return Err(DownloadError::Unauthorized(url.as_str().to_string()));
+        }
+
+        let mut res = res
+            .error_for_status()
             .map_err(|e| DownloadError::ReqwestError(e, url.as_str().to_string()))?;
 
         // Get content length and send if exists
@@ -148,39 +235,72 @@ impl DownloadManager {
             .unwrap_or(0u64);
         log::debug!("Content length: {}", content_len);
 
-        // Check if range request was accepted!
-        // let is_partial = res.headers().get(header::CONTENT_RANGE).is_some();
-        // log::debug!("Is partial: {}", is_partial);
-
-        // let total_bytes = if !is_partial {
-        //     std::fs::remove_file(&tmp_dest_path).map_err(|e| {
-        //         log::error!("error removing temp file: {:?}", &e);
-        //         DownloadError::TempFileDeleteFailed(e, tmp_dest_path.to_path_buf())
-        //     })?;
-        //     file = fs::File::create(&tmp_dest_path)
-        //         .map_err(|e| {
-        //             log::error!("{:?}", &e);
-        //             DownloadError::TempFileOpenFailed(e, tmp_dest_path.to_path_buf())
-        //         })?;
-        //     content_len
-        // } else if content_len > 0 {
-        //     content_len + downloaded_bytes
-        // } else {
-        //     // If no content len, having downloaded bytes doesn't mean we have a known total...
-        //     0
-        // };
-
-        // log::debug!("Total bytes: {}", total_bytes);
-
-        let total_bytes = content_len;
-        let mut downloaded_bytes = 0;
+        let is_partial = res.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+        log::debug!("Is partial: {}", is_partial);
+
+        let (file, downloaded_bytes) = match partial {
+            Some((offset, _)) if is_partial => {
+                let file = fs::OpenOptions::new()
+                    .append(true)
+                    .open(&tmp_dest_path)
+                    .map_err(|e| {
+                        log::error!("Open temp file failed: {:?}", &e);
+                        DownloadError::TempFileOpenFailed(e, tmp_dest_path.to_path_buf())
+                    })?;
+                (file, offset)
+            }
+            _ => {
+                let file = fs::File::create(&tmp_dest_path).map_err(|e| {
+                    log::error!("Open temp file failed: {:?}", &e);
+                    DownloadError::TempFileOpenFailed(e, tmp_dest_path.to_path_buf())
+                })?;
+
+                // Weak validators can't be used with `If-Range`.
+                let etag = res
+                    .headers()
+                    .get(header::ETAG)
+                    .and_then(|x| x.to_str().ok())
+                    .filter(|x| !x.starts_with("W/"));
+                let result = match etag {
+                    Some(etag) => fs::write(&etag_path, etag),
+                    None => fs::remove_file(&etag_path).or_else(|e| match e.kind() {
+                        std::io::ErrorKind::NotFound => Ok(()),
+                        _ => Err(e),
+                    }),
+                };
+                if let Err(e) = result {
+                    log::warn!("Could not record ETag for {}: {:?}", url, e);
+                }
+
+                (file, 0)
+            }
+        };
+
+        let total_bytes = if content_len > 0 {
+            content_len + downloaded_bytes
+        } else {
+            // If no content len, having downloaded bytes doesn't mean we have a known total...
+            0
+        };
+        log::debug!("Total bytes: {}", total_bytes);
+
+        let mut downloaded_bytes = downloaded_bytes;
         let mut last_progress_event = std::time::Instant::now();
 
         let url = url.to_owned();
+        let read_timeout = self.read_timeout;
         let stream = async_stream::stream! {
             let mut file = BufWriter::new(file);
+
+            if downloaded_bytes > 0 {
+                yield DownloadEvent::Progress((downloaded_bytes, total_bytes));
+            }
+
             loop {
-                let chunk = res.chunk().await.map_err(|e| DownloadError::ReqwestError(e, url.as_str().to_string()));
+                let chunk = match with_timeout(read_timeout, res.chunk()).await {
+                    Ok(chunk) => chunk.map_err(|e| DownloadError::from_reqwest(e, url.as_str().to_string())),
+                    Err(_) => Err(DownloadError::TimedOut(url.as_str().to_string())),
+                };
                 match chunk {
                     Ok(v) => match v {
                         None => {
@@ -188,34 +308,38 @@ impl DownloadManager {
                         }
                         Some(v) => {
                             downloaded_bytes += v.len() as u64;
-                            let result = file.write(&*v).map_err(|e| {
+                            let result = file.write_all(&*v).map_err(|e| {
                                 log::error!("error writing output: {:?}", &e);
                                 DownloadError::WriteFailed(e, tmp_dest_path.to_path_buf())
                             });
                             match result {
                                 Ok(_) => {
-                                    // Send a progress event at most every 750ms
-                                    if downloaded_bytes == total_bytes || last_progress_event.elapsed().as_millis() >= 750 {
+                                    if downloaded_bytes == total_bytes || last_progress_event.elapsed() >= PROGRESS_INTERVAL {
                                         last_progress_event = std::time::Instant::now();
                                         yield DownloadEvent::Progress((downloaded_bytes, total_bytes));
                                     }
                                 },
                                 Err(e) => {
                                     yield DownloadEvent::Error(e);
-                                    break;
+                                    return;
                                 }
                             }
                         }
                     }
                     Err(e) => {
+                        // Keep what we have so the next attempt can resume from it.
+                        let _ = file.flush();
                         yield DownloadEvent::Error(e);
-                        break;
+                        return;
                     }
                 }
             }
 
             match file.flush() {
-                Err(e) => yield DownloadEvent::Error(DownloadError::FlushFailed(e, tmp_dest_path.to_path_buf())),
+                Err(e) => {
+                    yield DownloadEvent::Error(DownloadError::FlushFailed(e, tmp_dest_path.to_path_buf()));
+                    return;
+                }
                 _ => {}
             };
 
@@ -226,13 +350,17 @@ impl DownloadManager {
             // If it's done, move the file!
             let _ = fs::create_dir_all(dest_path);
             match fs::copy(&tmp_dest_path, &dest_file_path) {
-                Err(e) => yield DownloadEvent::Error(DownloadError::CopyFailed(e, tmp_dest_path.to_path_buf(), dest_file_path.to_path_buf())),
+                Err(e) => {
+                    yield DownloadEvent::Error(DownloadError::CopyFailed(e, tmp_dest_path.to_path_buf(), dest_file_path.to_path_buf()));
+                    return;
+                }
                 _ => {}
             };
             match fs::remove_file(&tmp_dest_path) {
                 Err(e) => yield DownloadEvent::Error(DownloadError::RemoveFailed(e, tmp_dest_path.to_path_buf())),
                 _ => {}
             };
+            let _ = fs::remove_file(&etag_path);
             yield DownloadEvent::Complete(dest_file_path);
         };
 
@@ -257,6 +385,18 @@ pub enum DownloadError {
     #[error("An internal error occurred while attempting to download: {1}")]
     ReqwestError(#[source] reqwest::Error, String),
 
+    #[error("The server stopped responding while downloading: {0}")]
+    TimedOut(String),
+
+    #[error("The request stopped before a response was received: {0}")]
+    Interrupted(String),
+
+    #[error("The server requires valid credentials to download: {0}")]
+    Unauthorized(String),
+
+    #[error("Offline, and the payload hasn't been downloaded: {0}")]
+    Offline(String),
+
     #[error("Failed to get metadata for file at path: {}", .1.display())]
     MetadataFailed(#[source] std::io::Error, PathBuf),
 
@@ -280,4 +420,172 @@ pub enum DownloadError {
 
     #[error("Could not write data to file at path: {}", .1.display())]
     WriteFailed(#[source] std::io::Error, PathBuf),
+
+    #[error("Could not read file at path: {}", .1.display())]
+    ReadFailed(#[source] std::io::Error, PathBuf),
+}
+
+impl DownloadError {
+    /// Reports timeouts as [`DownloadError::TimedOut`].
+    fn from_reqwest(e: reqwest::Error, url: String) -> DownloadError {
+        if e.is_timeout() {
+            DownloadError::TimedOut(url)
+        } else {
+            DownloadError::ReqwestError(e, url)
+        }
+    }
+
+    /// Whether the download might succeed if attempted again.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            DownloadError::TimedOut(_) => true,
+            DownloadError::ReqwestError(e, _) => {
+                e.is_timeout()
+                    || e.is_connect()
+                    || e.status().map(|s| s.is_server_error()).unwrap_or(false)
+            }
+            DownloadError::WriteFailed(e, _) | DownloadError::FlushFailed(e, _) => {
+                is_transient_io(e)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Changes made to every HTTP client a store builds, such as adding TLS roots,
+/// client certificates or middleware; set with
+/// [`Config::set_http_client_hook`](crate::Config::set_http_client_hook).
+///
+/// The hook is used for repository indexes, localized strings, payloads and their
+/// signatures. It runs after the proxy and connect timeout are set, and before
+/// settings a request type depends on, such as the redirect policy for indexes.
+#[derive(Clone, Default)]
+pub struct HttpClientHook(
+    Option<Arc<dyn Fn(reqwest::ClientBuilder) -> reqwest::ClientBuilder + Send + Sync>>,
+);
+
+impl HttpClientHook {
+    pub fn new<F>(f: F) -> HttpClientHook
+    where
+        F: Fn(reqwest::ClientBuilder) -> reqwest::ClientBuilder + Send + Sync + 'static,
+    {
+        HttpClientHook(Some(Arc::new(f)))
+    }
+
+    /// Identifies the hook's closure, so clients built with different hooks can be
+    /// told apart. Clones share it.
+    pub(crate) fn id(&self) -> usize {
+        self.0
+            .as_ref()
+            .map(|f| Arc::as_ptr(f) as *const () as usize)
+            .unwrap_or(0)
+    }
+
+    fn apply(&self, builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+        match self.0.as_ref() {
+            Some(f) => f(builder),
+            None => builder,
+        }
+    }
+}
+
+impl std::fmt::Debug for HttpClientHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("HttpClientHook")
+            .field(&self.0.as_ref().map(|_| "<fn>"))
+            .finish()
+    }
+}
+
+/// A client builder that sends requests through the proxies in `proxy`, then
+/// customized by `http`. Invalid proxy URLs are logged and skipped.
+///
+/// Only the connect timeout is set here; read timeouts are applied per request
+/// with [`with_timeout`].
+pub(crate) fn client_builder(
+    proxy: &ProxySettings,
+    timeouts: &TimeoutSettings,
+    http: &HttpClientHook,
+) -> reqwest::ClientBuilder {
+    let mut builder = reqwest::Client::builder();
+    if let Some(timeout) = timeouts.connect() {
+        builder = builder.connect_timeout(timeout);
+    }
+    let no_proxy = reqwest::NoProxy::from_string(&proxy.no_proxy.join(","));
+
+    let proxies = vec![
+        proxy
+            .http
+            .as_ref()
+            .map(|url| reqwest::Proxy::http(url.as_str())),
+        proxy
+            .https
+            .as_ref()
+            .map(|url| reqwest::Proxy::https(url.as_str())),
+    ];
+
+    let builder = proxies
+        .into_iter()
+        .flatten()
+        .fold(builder, |builder, result| match result {
+            Ok(p) => builder.proxy(p.no_proxy(no_proxy.clone())),
+            Err(e) => {
+                log::warn!("Ignoring invalid proxy: {:?}", e);
+                builder
+            }
+        });
+
+    http.apply(builder)
+}
+
+/// Waits for `future`, or fails if it takes longer than `timeout`.
+pub(crate) async fn with_timeout<F: Future>(
+    timeout: Option<Duration>,
+    future: F,
+) -> Result<F::Output, tokio::time::error::Elapsed> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future).await,
+        None => Ok(future.await),
+    }
+}
+
+/// Adds the credentials from `auth` to `req`. Unavailable secrets are logged and
+/// the request is sent without them, so the server's 401 reaches the caller.
+pub(crate) fn authorize(
+    req: reqwest::RequestBuilder,
+    auth: Option<&RepoAuth>,
+) -> reqwest::RequestBuilder {
+    match auth {
+        Some(RepoAuth::Bearer { token }) => match token.resolve() {
+            Some(token) => req.bearer_auth(token),
+            None => {
+                log::warn!("Bearer token unavailable; sending request without it");
+                req
+            }
+        },
+        Some(RepoAuth::Basic { username, password }) => match password.resolve() {
+            Some(password) => req.basic_auth(username, Some(password)),
+            None => {
+                log::warn!("Password unavailable; sending request without it");
+                req
+            }
+        },
+        None => req,
+    }
+}
+
+pub(crate) fn is_transient_io(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+
+    match e.kind() {
+        ErrorKind::Interrupted
+        | ErrorKind::TimedOut
+        | ErrorKind::WouldBlock
+        | ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionAborted
+        | ErrorKind::ConnectionRefused
+        | ErrorKind::BrokenPipe
+        | ErrorKind::UnexpectedEof => true,
+        _ => false,
+    }
 }
@@ -108,6 +108,12 @@ pub extern "C" fn pahkat_prefix_package_store_download(
             DownloadEvent::Progress((current, total)) => {
                 progress(package_key_str.as_ptr(), current, total);
             }
+            DownloadEvent::Fallback(index) => {
+                log::debug!("falling back to mirror {}", index);
+            }
+            DownloadEvent::Delta(from) => {
+                log::debug!("applied delta from {}", from);
+            }
             DownloadEvent::Complete(path_buf) => {
                 path = Some(path_buf);
             }
@@ -131,7 +137,8 @@ pub extern "C" fn pahkat_prefix_package_store_download_url(
 
     let repos = handle.repos();
     let repos = repos.read().unwrap();
-    let query = crate::repo::ReleaseQuery::new(&package_key, &*repos);
+    let query =
+        crate::repo::ReleaseQuery::new(&package_key, &*repos, &handle.config().read().unwrap());
 
     let (target, _, _) = match resolve_payload(&package_key, &query, &repos) {
         Ok(v) => v,
@@ -179,6 +186,19 @@ pub extern "C" fn pahkat_prefix_package_store_force_refresh_repos(
         .box_err()
 }
 
+#[cffi::marshal(return_marshaler = "JsonMarshaler")]
+pub extern "C" fn pahkat_prefix_package_store_repo_errors(
+    #[marshal(cffi::ArcRefMarshaler::<PrefixPackageStore>)] handle: Arc<PrefixPackageStore>,
+) -> BTreeMap<String, String> {
+    handle
+        .errors()
+        .read()
+        .unwrap()
+        .iter()
+        .map(|e| (e.url.to_string(), e.to_string()))
+        .collect()
+}
+
 // #[cffi::marshal(return_marshaler = "cffi::StringMarshaler")]
 // pub extern "C" fn pahkat_prefix_package_store_repo_indexes(
 //     #[marshal(cffi::ArcRefMarshaler::<PrefixPackageStore>)] handle: Arc<PrefixPackageStore>,
@@ -224,32 +244,39 @@ pub extern "C" fn pahkat_prefix_transaction_process(
     let (canceler, mut stream) = handle.process();
 
     while let Some(event) = block_on(stream.next()) {
-        use crate::transaction::TransactionEvent;
+        use crate::transaction::{ffi_event::FfiEvent, TransactionEvent};
 
         match event {
-            TransactionEvent::Installing(key) => {
+            TransactionEvent::Installing(key, _, _) => {
                 let k = PackageKeyMarshaler::to_foreign(&key).unwrap();
-                if progress_callback(tag, k, 1) == 0 {
+                if progress_callback(tag, k, FfiEvent::INSTALLING) == 0 {
                     drop(canceler);
                     break;
                 }
             }
-            TransactionEvent::Uninstalling(key) => {
+            TransactionEvent::Uninstalling(key, _, _) => {
                 let k = PackageKeyMarshaler::to_foreign(&key).unwrap();
-                if progress_callback(tag, k, 2) == 0 {
+                if progress_callback(tag, k, FfiEvent::UNINSTALLING) == 0 {
                     drop(canceler);
                     break;
                 }
             }
-            TransactionEvent::Complete => {
-                if progress_callback(tag, Default::default(), 3) == 0 {
+            TransactionEvent::Complete(_) => {
+                if progress_callback(tag, Default::default(), FfiEvent::COMPLETE) == 0 {
                     drop(canceler);
                     break;
                 }
             }
             TransactionEvent::Error(key, _) => {
                 let k = PackageKeyMarshaler::to_foreign(&key).unwrap();
-                if progress_callback(tag, k, 4) == 0 {
+                if progress_callback(tag, k, FfiEvent::ERROR) == 0 {
+                    drop(canceler);
+                    break;
+                }
+            }
+            TransactionEvent::RebootRequired(key) => {
+                let k = PackageKeyMarshaler::to_foreign(&key).unwrap();
+                if progress_callback(tag, k, FfiEvent::REBOOT_REQUIRED) == 0 {
                     drop(canceler);
                     break;
                 }
@@ -271,3 +298,25 @@ pub extern "C" fn pahkat_prefix_transaction_process(
     //     .unwrap()
     //     .box_err()
 }
+
+/// Like [`pahkat_prefix_transaction_process`], but passes every event to
+/// `event_callback` as an [`FfiEvent`](crate::FfiEvent) in JSON. Returning 0 from
+/// the callback cancels the transaction.
+#[cffi::marshal(return_marshaler = "cffi::UnitMarshaler")]
+pub extern "C" fn pahkat_prefix_transaction_process_events(
+    #[marshal(cffi::BoxRefMarshaler::<PackageTransaction>)] handle: &PackageTransaction,
+    tag: u32,
+    event_callback: extern "C" fn(u32, cffi::Slice<u8>) -> u8,
+) -> Result<(), Box<dyn Error>> {
+    let (canceler, mut stream) = handle.process();
+
+    while let Some(event) = block_on(stream.next()) {
+        let event = JsonMarshaler::to_foreign(event.to_ffi())?;
+        if event_callback(tag, event) == 0 {
+            drop(canceler);
+            break;
+        }
+    }
+
+    Ok(())
+}
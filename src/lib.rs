@@ -14,15 +14,27 @@ pub mod repo;
 pub mod transaction;
 
 mod cmp;
+mod delta;
 mod download;
 mod ext;
 mod fbs;
+mod signature;
 
 pub use self::config::{Config, Permission};
-pub use self::download::Download;
-pub use self::package_store::{DownloadEvent, InstallTarget, PackageStore};
-pub use self::repo::{LoadedRepository, PackageKey};
-pub use self::transaction::{PackageAction, PackageActionType, PackageStatus, PackageTransaction};
+pub use self::download::{Download, HttpClientHook};
+pub use self::package_store::{DownloadEvent, InstallTarget, InvalidInstallTarget, PackageStore};
+pub use self::repo::{
+    ChannelQuery, LoadedRepository, PackageKey, PackageKeyBuilder, PackageKeyExt, ALL_CHANNELS,
+};
+pub use self::transaction::ffi_event::FfiEvent;
+pub use self::transaction::history::TransactionRecord;
+pub use self::transaction::hook::{HookError, HookPoint};
+pub use self::transaction::progress::{AggregateProgress, TransactionProgress};
+pub use self::transaction::{
+    ActionOrigin, CancelReason, Canceller, DependencyLimit, DependencyLimits, InvalidActionType,
+    PackageAction, PackageActionType, PackageStatus, PackageTransaction, ProcessOptions,
+    ProgressDetail, ProgressThrottle, RetryPolicy, TransactionOptions, TransactionSummary,
+};
 
 #[cfg(all(target_os = "macos", feature = "macos"))]
 pub use package_store::macos::MacOSPackageStore;
@@ -16,13 +16,13 @@ use url::Url;
 
 use super::{PackageStore, SharedRepoErrors, SharedRepos, SharedStoreConfig};
 use crate::package_store::{ImportError, InstallTarget, LocalizedStrings};
-use crate::repo::{PackageCandidateError, PackageQuery, RepoDownloadError};
+use crate::repo::{PackageQuery, RepoLoadError};
 use crate::transaction::{install::InstallError, install::ProcessError, uninstall::UninstallError};
 use crate::transaction::{
     PackageDependencyStatusError, PackageStatus, PackageStatusError, ResolvedDescriptor,
     ResolvedPackageQuery,
 };
-use crate::{cmp, Config, PackageActionType, PackageKey};
+use crate::{cmp, Config, LoadedRepository, PackageActionType, PackageKey};
 
 #[cfg(target_os = "macos")]
 #[inline(always)]
@@ -77,10 +77,20 @@ impl PackageStore for MacOSPackageStore {
         install_target: InstallTarget,
     ) -> Result<PackageStatus, InstallError> {
         let repos = self.repos.read().unwrap();
-        let query = crate::repo::ReleaseQuery::new(key, &*repos);
+        let install_target = crate::repo::resolve_install_target(
+            key,
+            install_target,
+            &*repos,
+            &self.config.read().unwrap(),
+        );
+        let query = crate::repo::ReleaseQuery::new(key, &*repos, &self.config.read().unwrap());
 
         let (target, release, descriptor) =
             crate::repo::resolve_payload(key, &query, &*repos).map_err(InstallError::Payload)?;
+        if super::meta::is_meta_package(key, &*repos) {
+            super::meta::install(&self.config, key, install_target, &release.version)?;
+            return Ok(PackageStatus::UpToDate);
+        }
         let installer = match target.payload {
             pahkat_types::payload::Payload::MacOSPackage(v) => v,
             _ => return Err(InstallError::WrongPayloadType),
@@ -91,7 +101,7 @@ impl PackageStore for MacOSPackageStore {
 
         if !pkg_path.exists() {
             log::error!("Package path doesn't exist: {:?}", &pkg_path);
-            return Err(InstallError::PackageNotInCache);
+            return Err(InstallError::PackageNotInCache(pkg_path));
         }
 
         install_macos_package(&pkg_path, install_target).map_err(InstallError::InstallerFailure)?;
@@ -107,7 +117,19 @@ impl PackageStore for MacOSPackageStore {
         install_target: InstallTarget,
     ) -> Result<PackageStatus, UninstallError> {
         let repos = self.repos.read().unwrap();
-        let query = crate::repo::ReleaseQuery::new(key, &*repos);
+        let install_target = crate::repo::resolve_installed_target(
+            key,
+            install_target,
+            &*repos,
+            &self.config,
+            |target| is_installed(self.status_with_repos(key, target, &*repos)),
+        );
+
+        if super::meta::uninstall(&self.config, key, install_target)? {
+            return Ok(PackageStatus::NotInstalled);
+        }
+
+        let query = crate::repo::ReleaseQuery::new(key, &*repos, &self.config.read().unwrap());
 
         let (target, release, descriptor) =
             crate::repo::resolve_payload(key, &query, &*repos).map_err(UninstallError::Payload)?;
@@ -126,7 +148,7 @@ impl PackageStore for MacOSPackageStore {
 
     fn import(&self, key: &PackageKey, installer_path: &Path) -> Result<PathBuf, ImportError> {
         let repos = self.repos.read().unwrap();
-        let query = crate::repo::ReleaseQuery::new(key, &*repos);
+        let query = crate::repo::ReleaseQuery::new(key, &*repos, &self.config.read().unwrap());
         crate::repo::import(&self.config, key, &query, &*repos, installer_path)
     }
 
@@ -141,9 +163,10 @@ impl PackageStore for MacOSPackageStore {
                 + 'static,
         >,
     > {
+        let installed = crate::repo::delta_base_version(self, key);
         let repos = self.repos.read().unwrap();
-        let query = crate::repo::ReleaseQuery::new(key, &*repos);
-        crate::repo::download(&self.config, key, &query, &*repos)
+        let query = crate::repo::ReleaseQuery::new(key, &*repos, &self.config.read().unwrap());
+        crate::repo::download(&self.config, key, &query, &*repos, installed.as_ref())
     }
 
     fn status(
@@ -152,16 +175,17 @@ impl PackageStore for MacOSPackageStore {
         install_target: InstallTarget,
     ) -> Result<PackageStatus, PackageStatusError> {
         let repos = self.repos.read().unwrap();
-        let query = crate::repo::ReleaseQuery::new(key, &*repos);
-
-        let (target, release, descriptor) = crate::repo::resolve_payload(key, &query, &*repos)
-            .map_err(PackageStatusError::Payload)?;
-        let installer = match target.payload {
-            pahkat_types::payload::Payload::MacOSPackage(v) => v,
-            _ => return Err(PackageStatusError::WrongPayloadType),
-        };
+        self.status_with_repos(key, install_target, &*repos)
+    }
 
-        self.status_impl(&descriptor, &release, install_target)
+    fn statuses(
+        &self,
+        keys: &[(PackageKey, InstallTarget)],
+    ) -> Vec<Result<PackageStatus, PackageStatusError>> {
+        let repos = self.repos.read().unwrap();
+        keys.iter()
+            .map(|(key, target)| self.status_with_repos(key, *target, &*repos))
+            .collect()
     }
 
     fn dependency_status(
@@ -173,29 +197,17 @@ impl PackageStore for MacOSPackageStore {
             self,
             &[(PackageActionType::Install, key.clone())],
             &[target],
+            &[],
+            &[],
+            true,
+            &Default::default(),
         )
         .map(|dep| {
             dep.into_iter()
                 .map(|dep| (dep.package_key, dep.status))
                 .collect()
         })
-        .map_err(|err| match err {
-            PackageCandidateError::Status(p, PackageStatusError::Payload(e)) => {
-                PackageDependencyStatusError::Payload(p, e)
-            }
-            PackageCandidateError::Status(p, PackageStatusError::WrongPayloadType) => {
-                PackageDependencyStatusError::WrongPayloadType(p)
-            }
-            PackageCandidateError::Status(p, PackageStatusError::ParsingVersion) => {
-                PackageDependencyStatusError::ParsingVersion(p)
-            }
-
-            PackageCandidateError::Payload(p, e) => PackageDependencyStatusError::Payload(p, e),
-            PackageCandidateError::UnresolvedId(id) => {
-                PackageDependencyStatusError::PackageNotFound(id)
-            }
-            PackageCandidateError::UninstallConflict(_) => unreachable!(),
-        })
+        .map_err(PackageDependencyStatusError::from)
     }
 
     fn all_statuses(
@@ -206,6 +218,35 @@ impl PackageStore for MacOSPackageStore {
         crate::repo::all_statuses(self, repo_url, target)
     }
 
+    fn installed_version(
+        &self,
+        key: &PackageKey,
+        target: InstallTarget,
+    ) -> Option<pahkat_types::package::Version> {
+        let repos = self.repos.read().unwrap();
+        if super::meta::is_meta_package(key, &*repos) {
+            return None;
+        }
+        let descriptor = crate::repo::resolve_package(key, &*repos).ok()?;
+        let info = |target: InstallTarget| {
+            descriptor
+                .release
+                .iter()
+                .flat_map(|release| release.target.iter())
+                .filter_map(|x| match &x.payload {
+                    pahkat_types::payload::Payload::MacOSPackage(v) => Some(&*v.pkg_id),
+                    _ => None,
+                })
+                .find_map(|pkg_id| get_package_info(pkg_id, target).ok())
+        };
+        let target =
+            crate::repo::resolve_installed_target(key, target, &*repos, &self.config, |t| {
+                info(t).is_some()
+            });
+
+        info(target).and_then(|info| pahkat_types::package::Version::new(&info.pkg_version).ok())
+    }
+
     fn find_package_by_key(&self, key: &PackageKey) -> Option<Package> {
         let repos = self.repos.read().unwrap();
         crate::repo::find_package_by_key(key, &*repos)
@@ -216,20 +257,9 @@ impl PackageStore for MacOSPackageStore {
         crate::repo::find_package_by_id(self, package_id, &*repos)
     }
 
-    fn refresh_repos(
-        &self,
-    ) -> crate::package_store::Future<Result<(), HashMap<RepoUrl, RepoDownloadError>>> {
+    fn refresh_repos(&self) -> crate::package_store::Future<Result<(), Vec<RepoLoadError>>> {
         let config = self.config().read().unwrap().clone();
-        let repos = self.repos();
-        Box::pin(async move {
-            let (result, errors) = crate::repo::refresh_repos(config).await;
-            *repos.write().unwrap() = result;
-            if errors.is_empty() {
-                Ok(())
-            } else {
-                Err(errors)
-            }
-        })
+        Box::pin(super::refresh_repos(config, self.repos(), self.errors()))
     }
 
     fn clear_cache(&self) {
@@ -243,7 +273,16 @@ impl PackageStore for MacOSPackageStore {
         let repos = self.repos.read().unwrap();
         let urls = repos.keys().cloned().collect::<Vec<_>>();
 
-        Box::pin(crate::repo::strings(urls, language))
+        let (proxy, timeouts, http) = {
+            let config = self.config.read().unwrap();
+            (
+                config.settings().proxy(),
+                config.settings().timeouts(),
+                config.http_client_hook().clone(),
+            )
+        };
+
+        Box::pin(crate::repo::strings(urls, language, proxy, timeouts, http))
     }
 
     fn resolve_package_query(
@@ -261,16 +300,50 @@ impl MacOSPackageStore {
     pub async fn new(config: Config) -> MacOSPackageStore {
         let store = MacOSPackageStore {
             repos: Arc::new(RwLock::new(HashMap::new())),
-            errors: Arc::new(RwLock::new(HashMap::new())),
+            errors: Default::default(),
             config: Arc::new(RwLock::new(config)),
         };
 
-        // We ignore errors here.
-        let _ = store.refresh_repos().await;
+        super::load_repos(&store.repos, &store.errors, &store.config).await;
 
         store
     }
 
+    fn status_with_repos(
+        &self,
+        key: &PackageKey,
+        install_target: InstallTarget,
+        repos: &HashMap<RepoUrl, LoadedRepository>,
+    ) -> Result<PackageStatus, PackageStatusError> {
+        let install_target = crate::repo::resolve_installed_target(
+            key,
+            install_target,
+            repos,
+            &self.config,
+            |target| is_installed(self.status_with_repos(key, target, repos)),
+        );
+        crate::repo::check_repo_loaded(&self.config, key, repos)?;
+
+        let query = crate::repo::ReleaseQuery::new(key, repos, &self.config.read().unwrap());
+
+        let (target, release, descriptor) = crate::repo::resolve_payload(key, &query, repos)
+            .map_err(PackageStatusError::Payload)?;
+        if super::meta::is_meta_package(key, repos) {
+            return Ok(super::meta::status(
+                &self.config,
+                key,
+                install_target,
+                &release.version,
+            ));
+        }
+        let installer = match target.payload {
+            pahkat_types::payload::Payload::MacOSPackage(v) => v,
+            _ => return Err(PackageStatusError::WrongPayloadType),
+        };
+
+        self.status_impl(&descriptor, &release, install_target)
+    }
+
     fn status_impl(
         &self,
         descriptor: &pahkat_types::package::Descriptor,
@@ -396,10 +469,15 @@ fn get_package_info(
     return Ok(plist);
 }
 
+fn is_installed(status: Result<PackageStatus, PackageStatusError>) -> bool {
+    matches!(status, Ok(status) if status != PackageStatus::NotInstalled)
+}
+
 fn install_macos_package(pkg_path: &Path, target: InstallTarget) -> Result<(), ProcessError> {
     let target_str = match target {
         InstallTarget::User => "CurrentUserHomeDirectory",
         InstallTarget::System => "LocalSystem",
+        InstallTarget::Auto => unreachable!("install target must be resolved before installing"),
     };
 
     let args = &["-pkg", &pkg_path.to_str().unwrap(), "-target", target_str];
@@ -425,6 +503,9 @@ fn run_script(name: &str, bundle_id: &str, target: InstallTarget) -> Result<(),
     let path = match target {
         InstallTarget::User => crate::defaults::uninstall_path().unwrap(),
         InstallTarget::System => global_uninstall_path(),
+        InstallTarget::Auto => {
+            unreachable!("install target must be resolved before running scripts")
+        }
     };
     let script_path = path.join(bundle_id).join(name);
 
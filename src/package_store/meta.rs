@@ -0,0 +1,166 @@
+//! Meta-packages bundle other packages without installing anything themselves.
+//!
+//! A meta-package is a package with `meta_package = true` in its `[packages.<id>]`
+//! table in `index.toml`. Its members are the dependencies of its target, so
+//! installing it installs them like any other dependencies, and uninstalling it with
+//! [`TransactionOptions::remove_orphans`](crate::transaction::TransactionOptions::remove_orphans)
+//! removes the members nothing else needs. The index format requires a payload,
+//! but a meta-package's is never downloaded or run.
+//!
+//! No installer leaves a trace of a meta-package on the system, so installed ones
+//! are recorded in `meta-packages.json` in the config directory instead. Nothing
+//! is recorded under [`Permission::Memory`], and installs fail under
+//! [`Permission::ReadOnly`].
+
+use std::path::{Path, PathBuf};
+
+use hashbrown::HashMap;
+use pahkat_types::package::Version;
+use pahkat_types::repo::RepoUrl;
+use serde::{Deserialize, Serialize};
+
+use super::{InstallTarget, SharedStoreConfig};
+use crate::config::{FileError, Permission};
+use crate::repo::LoadedRepository;
+use crate::transaction::{install::InstallError, uninstall::UninstallError, PackageStatus};
+use crate::PackageKey;
+
+const RECORDS_FILE: &str = "meta-packages.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Record {
+    key: PackageKey,
+    target: InstallTarget,
+    version: Version,
+}
+
+pub(crate) fn is_meta_package(
+    key: &PackageKey,
+    repos: &HashMap<RepoUrl, LoadedRepository>,
+) -> bool {
+    repos
+        .get(&key.repository_url)
+        .and_then(|repo| repo.package_metadata(&key.id))
+        .map(|metadata| metadata.meta_package)
+        .unwrap_or(false)
+}
+
+fn records_path(config: &SharedStoreConfig) -> PathBuf {
+    config
+        .read()
+        .unwrap()
+        .settings()
+        .config_dir()
+        .join(RECORDS_FILE)
+}
+
+fn permission(config: &SharedStoreConfig) -> Permission {
+    config.read().unwrap().settings().permission()
+}
+
+fn load(path: &Path) -> Vec<Record> {
+    let file = match std::fs::read(path) {
+        Ok(v) => v,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return vec![],
+        Err(e) => {
+            log::warn!("Could not read {:?}: {:?}", path, e);
+            return vec![];
+        }
+    };
+
+    serde_json::from_slice(&file).unwrap_or_else(|e| {
+        log::warn!("Ignoring invalid {:?}: {:?}", path, e);
+        vec![]
+    })
+}
+
+fn save(permission: Permission, path: &Path, records: &[Record]) -> std::io::Result<()> {
+    match permission {
+        Permission::ReadWrite => {}
+        Permission::Memory => {
+            log::debug!("Not recording meta-packages in memory-only config");
+            return Ok(());
+        }
+        Permission::ReadOnly => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "the config is read-only",
+            ))
+        }
+    }
+
+    let bytes = serde_json::to_vec_pretty(records)?;
+    crate::config::write_atomic(path, &bytes).map_err(|e| match e {
+        FileError::Write(e, _) => e,
+        e => std::io::Error::new(std::io::ErrorKind::Other, e.to_string()),
+    })
+}
+
+fn position(records: &[Record], key: &PackageKey, target: InstallTarget) -> Option<usize> {
+    records
+        .iter()
+        .position(|x| x.target == target && crate::repo::is_same_package(&x.key, key))
+}
+
+pub(crate) fn install(
+    config: &SharedStoreConfig,
+    key: &PackageKey,
+    target: InstallTarget,
+    version: &Version,
+) -> Result<(), InstallError> {
+    let path = records_path(config);
+    let mut records = load(&path);
+    let record = Record {
+        key: PackageKey::new_unchecked(key.repository_url.clone(), key.id.clone(), None),
+        target,
+        version: version.clone(),
+    };
+
+    match position(&records, key, target) {
+        Some(i) => records[i] = record,
+        None => records.push(record),
+    }
+
+    save(permission(config), &path, &records).map_err(|e| InstallError::Io(e, path))
+}
+
+/// Removes the record of `key`, returning `false` if it isn't an installed meta-package.
+pub(crate) fn uninstall(
+    config: &SharedStoreConfig,
+    key: &PackageKey,
+    target: InstallTarget,
+) -> Result<bool, UninstallError> {
+    let path = records_path(config);
+    let mut records = load(&path);
+
+    match position(&records, key, target) {
+        Some(i) => {
+            records.remove(i);
+            save(permission(config), &path, &records).map_err(|e| UninstallError::Io(e, path))?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+pub(crate) fn status(
+    config: &SharedStoreConfig,
+    key: &PackageKey,
+    target: InstallTarget,
+    candidate: &Version,
+) -> PackageStatus {
+    let records = load(&records_path(config));
+
+    match position(&records, key, target) {
+        None => PackageStatus::NotInstalled,
+        Some(i) if candidate > &records[i].version => PackageStatus::RequiresUpdate,
+        Some(_) => PackageStatus::UpToDate,
+    }
+}
+
+pub(crate) fn installed(config: &SharedStoreConfig) -> Vec<(PackageKey, InstallTarget, Version)> {
+    load(&records_path(config))
+        .into_iter()
+        .map(|x| (x.key, x.target, x.version))
+        .collect()
+}
@@ -1,33 +1,39 @@
 #[cfg(all(target_os = "macos", feature = "macos"))]
 pub mod macos;
+pub(crate) mod meta;
 #[cfg(feature = "prefix")]
 pub mod prefix;
 #[cfg(all(windows, feature = "windows"))]
 pub mod windows;
 
 use std::collections::BTreeMap;
+use std::convert::TryInto;
 use std::fmt::Debug;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
 
 use hashbrown::HashMap;
-use pahkat_types::package::Package;
+use pahkat_types::package::{Descriptor, Package, Version};
 use serde::{Deserialize, Serialize};
 use url::Url;
 
 use crate::config::Config;
-use crate::repo::{PackageQuery, RepoDownloadError};
+use crate::repo::{PackageQuery, RepoDownloadError, RepoLoadError, ResolveError};
+use crate::transaction::history::TransactionRecord;
 use crate::transaction::{install::InstallError, uninstall::UninstallError};
 use crate::transaction::{
-    PackageDependencyStatusError, PackageStatus, PackageStatusError, ResolvedPackageQuery,
+    PackageDependencyStatusError, PackageStatus, PackageStatusError, ProgressDetail,
+    ResolvedPackageQuery, ResolvedRelease,
 };
 use crate::types::repo::RepoUrl;
 use crate::{LoadedRepository, PackageKey};
 
 pub type SharedStoreConfig = Arc<RwLock<Config>>;
 pub type SharedRepos = Arc<RwLock<HashMap<RepoUrl, LoadedRepository>>>;
-pub type SharedRepoErrors = Arc<RwLock<HashMap<RepoUrl, RepoDownloadError>>>;
+/// Repositories that failed to load, in config order, as of the last load or refresh.
+pub type SharedRepoErrors = Arc<RwLock<Vec<RepoLoadError>>>;
 
 #[derive(Debug, thiserror::Error)]
 pub enum ImportError {
@@ -41,28 +47,166 @@ pub enum ImportError {
     InvalidPayloadType,
 }
 
+/// The outcome of checking a package's installed files against the store's records.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct VerifyReport {
+    pub ok: Vec<PathBuf>,
+    pub missing: Vec<PathBuf>,
+    pub modified: Vec<PathBuf>,
+    /// Files the package marks as expected to change, such as configuration.
+    pub ignored: Vec<PathBuf>,
+}
+
+impl VerifyReport {
+    pub fn is_intact(&self) -> bool {
+        self.missing.is_empty() && self.modified.is_empty()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum VerifyError {
+    #[error("Package is not installed: `{0}`")]
+    NotInstalled(PackageKey),
+
+    #[error("This package store does not record installed files")]
+    Unsupported,
+
+    #[error("IO error")]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum InstalledSizeError {
+    #[error("Package is not installed: `{0}`")]
+    NotInstalled(PackageKey),
+
+    #[error("This package store does not record installed sizes")]
+    Unsupported,
+
+    #[error("IO error")]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CacheError {
+    #[error("Could not check for a running transaction")]
+    Lock(#[from] crate::transaction::lock::LockError),
+
+    #[error("IO error")]
+    Io(#[from] std::io::Error),
+}
+
+/// What [`PackageStore::clear_payload_cache`] removed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CacheClearReport {
+    pub files_removed: usize,
+    pub bytes_freed: u64,
+    /// Downloaded payloads left in place because a transaction is running.
+    pub payloads_kept: usize,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BundleError {
+    #[error("Not a bundle directory: {0:?}")]
+    NotADirectory(PathBuf),
+
+    #[error("IO error")]
+    Io(#[from] std::io::Error),
+}
+
+/// Why part of a bundle was not imported.
+#[derive(Debug, thiserror::Error)]
+pub enum BundleEntryError {
+    #[error("Invalid repository index")]
+    InvalidIndex(#[from] crate::repo::IndexError),
+
+    #[error("Could not cache the repository index")]
+    Index(#[source] RepoDownloadError),
+
+    #[error("The repository is not configured: {0}")]
+    NotConfigured(RepoUrl),
+
+    #[error("The bundled index is for another repository: {0}")]
+    UrlMismatch(RepoUrl),
+
+    #[error("Signature could not be verified")]
+    SignatureInvalid,
+
+    #[error("Payload is {actual} bytes, but the index says {expected}")]
+    SizeMismatch { expected: u64, actual: u64 },
+
+    #[error("IO error")]
+    Io(#[from] std::io::Error),
+}
+
+/// What [`PackageStore::import_bundle`] copied into the cache.
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    /// Repositories whose index was imported.
+    pub repos: Vec<RepoUrl>,
+    /// Packages with at least one payload imported.
+    pub packages: Vec<PackageKey>,
+    /// Repository directories of the bundle whose index was not imported.
+    pub failed_repos: Vec<(PathBuf, BundleEntryError)>,
+    /// Payloads that were not imported, with their package.
+    pub failed_packages: Vec<(PackageKey, BundleEntryError)>,
+}
+
 #[derive(Debug)]
 pub enum ProgressEvent<P: Debug, C: Debug, E: Debug> {
     Progress(P),
+    /// The previous source failed; continuing from this one: 0 for the payload's own
+    /// URL, otherwise the mirror's position in the repository's `mirrors`, from 1.
+    Fallback(usize),
+    /// The payload was rebuilt from the cached payload of this installed version
+    /// and a delta, rather than downloaded in full. Sent just before `Complete`.
+    Delta(String),
     Complete(C),
     Error(E),
 }
 
 pub type DownloadEvent = ProgressEvent<(u64, u64), PathBuf, crate::download::DownloadError>;
 
+/// Whether a package is installed for every user or only the current one.
+///
+/// To install into an arbitrary directory, open a
+/// [`PrefixPackageStore`](prefix::PrefixPackageStore) there instead; each prefix
+/// keeps its own config, receipts and statuses.
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[serde(rename_all = "lowercase")]
 #[non_exhaustive]
 pub enum InstallTarget {
     System,
     User,
+    /// [`System`](InstallTarget::System) if the process is privileged and the payload
+    /// allows it, otherwise [`User`](InstallTarget::User). Status, uninstall and
+    /// other queries of an installed package use the other target instead if the
+    /// package is only installed for that one. Stores resolve this before acting,
+    /// so it is never recorded.
+    Auto,
 }
 
+/// A byte that is not the code of any [`InstallTarget`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("Invalid install target: {0}")]
+pub struct InvalidInstallTarget(pub u8);
+
 impl InstallTarget {
+    /// Unlike `From<u8>`, which reads unknown values as `System`, rejects them.
+    pub fn try_from_u8(value: u8) -> Result<InstallTarget, InvalidInstallTarget> {
+        match value {
+            0 => Ok(InstallTarget::System),
+            1 => Ok(InstallTarget::User),
+            2 => Ok(InstallTarget::Auto),
+            _ => Err(InvalidInstallTarget(value)),
+        }
+    }
+
     pub fn to_u8(&self) -> u8 {
         match self {
             InstallTarget::System => 0,
             InstallTarget::User => 1,
+            InstallTarget::Auto => 2,
         }
     }
 }
@@ -71,6 +215,7 @@ impl From<u8> for InstallTarget {
     fn from(value: u8) -> InstallTarget {
         match value {
             1 => InstallTarget::User,
+            2 => InstallTarget::Auto,
             _ => InstallTarget::System,
         }
     }
@@ -90,11 +235,143 @@ impl Default for InstallTarget {
     }
 }
 
+/// Filters for [`PackageStore::search`].
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct SearchOptions {
+    /// Only match packages with at least one of these tags.
+    pub tags: Vec<String>,
+    /// Only match packages that are (or are not) installed for `target`.
+    pub installed: Option<bool>,
+    pub target: InstallTarget,
+    /// Locale to pick [`SearchHit::name`] in. Defaults to the system locale.
+    pub language: Option<String>,
+    pub limit: Option<usize>,
+}
+
+impl SearchOptions {
+    pub fn tag<S: Into<String>>(mut self, tag: S) -> Self {
+        self.tags.push(tag.into());
+        self
+    }
+
+    pub fn installed(mut self, installed: bool) -> Self {
+        self.installed = Some(installed);
+        self
+    }
+
+    pub fn target(mut self, target: InstallTarget) -> Self {
+        self.target = target;
+        self
+    }
+
+    pub fn language<S: Into<String>>(mut self, language: S) -> Self {
+        self.language = Some(language.into());
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct SearchHit {
+    pub key: PackageKey,
+    pub name: String,
+    /// The newest release available on the repository's channel, if any.
+    pub version: Option<Version>,
+    pub repo: RepoUrl,
+    /// Higher is more relevant. Only meaningful relative to other hits.
+    pub score: u32,
+}
+
+/// Where a package id resolved to, from [`PackageStore::resolve`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct ResolvedPackage {
+    pub key: PackageKey,
+    pub repository_url: RepoUrl,
+    /// The repository's priority; see [`crate::config::RepoRecord::priority`].
+    pub priority: i32,
+    /// The release and payload that installing the package would use.
+    pub release: ResolvedRelease,
+}
+
+/// A loaded repository's freshness, for [`PackageStore::repo_status`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct RepoStatus {
+    /// When the index was last downloaded or revalidated.
+    pub fetched_at: Option<SystemTime>,
+    /// When the server says the index was last changed.
+    pub published_at: Option<SystemTime>,
+    pub package_count: usize,
+    /// Whether the cache is older than the configured repository cache max age.
+    pub is_stale: bool,
+    /// Whether the repository has a partial index, so [`PackageStore::fetch_package`]
+    /// downloads only the requested package's metadata.
+    pub partial_index: bool,
+    /// The client version the repository requires, if this one is too old. Such a
+    /// repository isn't loaded, so the other fields are empty.
+    pub required_client_version: Option<String>,
+    /// Why the last load or refresh of the repository failed. If it isn't loaded,
+    /// the other fields are empty.
+    pub load_error: Option<String>,
+    /// The configured channel, if the index doesn't declare it. Such a repository
+    /// is still loaded.
+    pub unknown_channel: Option<String>,
+}
+
 pub type Stream<T> = Pin<Box<dyn futures::stream::Stream<Item = T> + Send + Sync + 'static>>;
 pub type Future<T> = Pin<Box<dyn std::future::Future<Output = T> + Send + Sync + 'static>>;
 
+/// Populates a newly created store's repositories, reusing cached indexes within
+/// the configured max age. Repositories that fail to load are recorded in
+/// `errors`, as with a refresh.
+pub(crate) async fn load_repos(
+    repos: &SharedRepos,
+    errors: &SharedRepoErrors,
+    config: &SharedStoreConfig,
+) {
+    let config = config.read().unwrap().clone();
+    let max_age = config.settings().repo_cache_max_age();
+    let (result, load_errors) = crate::repo::load_repos(config, max_age).await;
+    log::trace!("Finished loading repos: {:?}", &load_errors);
+    *repos.write().unwrap() = result;
+    *errors.write().unwrap() = load_errors;
+}
+
+/// Revalidates every repository for [`PackageStore::refresh_repos`], keeping the
+/// ones that loaded even if others failed.
+pub(crate) async fn refresh_repos(
+    config: Config,
+    repos: SharedRepos,
+    errors: SharedRepoErrors,
+) -> Result<(), Vec<RepoLoadError>> {
+    let (result, load_errors) = crate::repo::refresh_repos(config).await;
+    log::trace!("Finished refresh repos: {:?}", &load_errors);
+    *repos.write().unwrap() = result;
+    *errors.write().unwrap() = load_errors.clone();
+    if load_errors.is_empty() {
+        Ok(())
+    } else {
+        Err(load_errors)
+    }
+}
+
+fn locale_or_system(locale: Option<&str>) -> String {
+    locale
+        .map(str::to_string)
+        .or_else(crate::defaults::system_locale)
+        .unwrap_or_else(|| crate::defaults::DEFAULT_LANGUAGE.to_string())
+}
+
 pub trait PackageStore: Send + Sync {
     fn repos(&self) -> SharedRepos;
+    /// Repositories that failed to load. The rest are usable regardless.
     fn errors(&self) -> SharedRepoErrors;
     fn config(&self) -> SharedStoreConfig;
 
@@ -108,6 +385,20 @@ pub trait PackageStore: Send + Sync {
         target: InstallTarget,
     ) -> Result<PackageStatus, InstallError>;
 
+    /// Like [`install`](Self::install), reporting the phases after the download,
+    /// such as extraction, to `progress`. The default only reports
+    /// [`ProgressDetail::RunningInstaller`], for stores that hand the payload to
+    /// an installer they can't observe.
+    fn install_with_progress(
+        &self,
+        key: &PackageKey,
+        target: InstallTarget,
+        progress: &dyn Fn(ProgressDetail),
+    ) -> Result<PackageStatus, InstallError> {
+        progress(ProgressDetail::RunningInstaller);
+        self.install(key, target)
+    }
+
     fn uninstall(
         &self,
         key: &PackageKey,
@@ -120,12 +411,103 @@ pub trait PackageStore: Send + Sync {
         target: InstallTarget,
     ) -> Result<PackageStatus, PackageStatusError>;
 
+    /// Resolves the status of many packages at once, in the order given.
+    ///
+    /// Stores should override this to avoid reloading the repository index per key.
+    fn statuses(
+        &self,
+        keys: &[(PackageKey, InstallTarget)],
+    ) -> Vec<Result<PackageStatus, PackageStatusError>> {
+        keys.iter()
+            .map(|(key, target)| self.status(key, *target))
+            .collect()
+    }
+
+    /// The status of `key` for each target the store installs to. Targets whose
+    /// status can't be resolved, such as one the payload doesn't support, are left out.
+    fn status_all_targets(&self, key: &PackageKey) -> BTreeMap<InstallTarget, PackageStatus> {
+        let targets = [InstallTarget::System, InstallTarget::User];
+        let keys = targets
+            .iter()
+            .map(|target| (key.clone(), *target))
+            .collect::<Vec<_>>();
+
+        targets
+            .iter()
+            .zip(self.statuses(&keys))
+            .filter_map(|(target, status)| match status {
+                Ok(status) => Some((*target, status)),
+                Err(e) => {
+                    log::debug!("No {:?} status for {}: {:?}", target, key, e);
+                    None
+                }
+            })
+            .collect()
+    }
+
     fn dependency_status(
         &self,
         key: &PackageKey,
         target: InstallTarget,
     ) -> Result<Vec<(PackageKey, PackageStatus)>, PackageDependencyStatusError>;
 
+    /// The version of `key` installed for `target`, if it is installed there.
+    ///
+    /// Stores that can't tell return `None`.
+    fn installed_version(&self, _key: &PackageKey, _target: InstallTarget) -> Option<Version> {
+        None
+    }
+
+    /// Every package this store has a record of being installed, with its installed version.
+    ///
+    /// By default, [`installed_version`](PackageStore::installed_version) is asked about
+    /// every package in the loaded repositories, for each target.
+    fn installed(&self) -> Vec<(PackageKey, InstallTarget, Version)> {
+        crate::repo::installed(self)
+    }
+
+    /// Installed packages with a newer release available, as `(key, installed, available)`.
+    ///
+    /// Respects version pins and channel selection; disabled repositories are skipped.
+    fn available_updates(&self) -> Vec<(PackageKey, Version, Version)> {
+        crate::repo::available_updates(self)
+    }
+
+    /// Checks that the files `key` installed still exist and are unmodified.
+    fn verify(
+        &self,
+        _key: &PackageKey,
+        _target: InstallTarget,
+    ) -> Result<VerifyReport, VerifyError> {
+        Err(VerifyError::Unsupported)
+    }
+
+    /// The installed package whose receipt recorded `path`.
+    ///
+    /// Stores that keep no file lists return `None`.
+    fn owner_of(&self, _path: &Path) -> Option<PackageKey> {
+        None
+    }
+
+    /// Bytes used on disk by the files `key` installed, which is roughly what
+    /// uninstalling it would free.
+    fn installed_size(
+        &self,
+        _key: &PackageKey,
+        _target: InstallTarget,
+    ) -> Result<u64, InstalledSizeError> {
+        Err(InstalledSizeError::Unsupported)
+    }
+
+    /// Whether `key` was installed at the user's request rather than to satisfy a dependency.
+    ///
+    /// Stores that keep no install receipts treat every package as requested.
+    fn is_requested_by_user(&self, _key: &PackageKey) -> bool {
+        true
+    }
+
+    fn set_requested_by_user(&self, _key: &PackageKey, _requested: bool) {}
+
     fn all_statuses(
         &self,
         repo_url: &RepoUrl,
@@ -136,17 +518,210 @@ pub trait PackageStore: Send + Sync {
 
     fn find_package_by_key(&self, key: &PackageKey) -> Option<Package>;
 
+    /// As [`find_package_by_key`](PackageStore::find_package_by_key), saying why
+    /// the package wasn't found. Also fails if no release can be installed here.
+    fn try_find_package_by_key(&self, key: &PackageKey) -> Result<Package, ResolveError> {
+        let repos = self.repos();
+        let repos = repos.read().unwrap();
+        crate::repo::try_find_package_by_key(&self.config().read().unwrap(), key, &*repos)
+    }
+
+    /// Resolves a package id, or a full package key, to the repository and release
+    /// that would be used to install it. Bare ids are looked up in priority order.
+    fn resolve(&self, package_id: &str) -> Option<ResolvedPackage> {
+        let (key, _) = self.find_package_by_id(package_id)?;
+        let repos = self.repos();
+        let repos = repos.read().unwrap();
+
+        let query = crate::repo::ReleaseQuery::new(&key, &*repos, &self.config().read().unwrap());
+        let (target, release, _) = crate::repo::resolve_payload(&key, &query, &*repos).ok()?;
+        let priority = repos
+            .get(&key.repository_url)
+            .map(|repo| repo.meta().priority)
+            .unwrap_or_default();
+
+        Some(ResolvedPackage {
+            repository_url: key.repository_url.clone(),
+            key,
+            priority,
+            release: ResolvedRelease::new(release, target),
+        })
+    }
+
+    /// The channels the repository's index declares, or none if it isn't loaded.
+    fn channels(&self, repo_url: &RepoUrl) -> Vec<String> {
+        crate::repo::channels(repo_url, &self.repos().read().unwrap())
+    }
+
+    /// Deletes downloaded payloads and, if `indexes` is set, cached indexes of
+    /// repositories that are no longer configured. Unlike
+    /// [`clear_cache`](PackageStore::clear_cache), indexes still in use are kept.
+    /// Payloads are kept while a transaction is running.
+    fn clear_payload_cache(&self, indexes: bool) -> Result<CacheClearReport, CacheError> {
+        crate::repo::clear_payload_cache(&self.config().read().unwrap(), indexes)
+    }
+
+    /// Bytes used by downloaded payloads and cached indexes.
+    fn cache_size(&self) -> u64 {
+        crate::repo::cache_size(&self.config().read().unwrap())
+    }
+
+    /// Freshness of the repository's loaded index, or `None` if it isn't loaded
+    /// for any reason other than needing a newer client or failing to load.
+    fn repo_status(&self, repo_url: &RepoUrl) -> Option<RepoStatus> {
+        let max_age = self
+            .config()
+            .read()
+            .unwrap()
+            .settings()
+            .repo_cache_max_age();
+        let load_error = self
+            .errors()
+            .read()
+            .unwrap()
+            .iter()
+            .find(|e| &e.url == repo_url)
+            .map(|e| e.to_string());
+        let repos = self.repos();
+        let repos = repos.read().unwrap();
+        let repo = match repos.get(repo_url) {
+            Some(v) => v,
+            None => {
+                let cache_dir = self.config().read().unwrap().settings().repo_cache_dir();
+                let required_client_version =
+                    crate::repo::required_client_version(repo_url, &cache_dir);
+                if required_client_version.is_none() && load_error.is_none() {
+                    return None;
+                }
+                return Some(RepoStatus {
+                    fetched_at: None,
+                    published_at: None,
+                    package_count: 0,
+                    is_stale: true,
+                    partial_index: false,
+                    required_client_version,
+                    load_error,
+                    unknown_channel: None,
+                });
+            }
+        };
+        let meta = repo.meta();
+
+        let package_count = repo
+            .packages()
+            .packages()
+            .map(|x| x.keys().count())
+            .unwrap_or(0);
+        let is_stale = meta
+            .fetched_at
+            .and_then(|x| x.elapsed().ok())
+            .map(|age| age >= max_age)
+            .unwrap_or(true);
+
+        Some(RepoStatus {
+            fetched_at: meta.fetched_at,
+            published_at: meta.published_at,
+            package_count,
+            is_stale,
+            partial_index: meta.partial_index,
+            required_client_version: None,
+            load_error,
+            unknown_channel: crate::repo::unknown_channel(
+                meta.channel.as_ref(),
+                &repo.info().repository.channels,
+            )
+            .cloned(),
+        })
+    }
+
+    /// The package's name in `locale`, or the system locale if `None`. Falls back to
+    /// the locale's language, the repository's default language, English, any name,
+    /// and finally the package id.
+    fn localized_name(&self, key: &PackageKey, locale: Option<&str>) -> Option<String> {
+        let descriptor: Descriptor = self.find_package_by_key(key)?.try_into().ok()?;
+        let locale = locale_or_system(locale);
+        let repos = self.repos();
+        let repos = repos.read().unwrap();
+        let default_language = crate::repo::default_language(key, &*repos);
+        Some(
+            crate::repo::localized(&descriptor.name, &locale, default_language)
+                .cloned()
+                .unwrap_or_else(|| key.id.clone()),
+        )
+    }
+
+    /// As [`localized_name`](PackageStore::localized_name), without the id fallback.
+    fn localized_description(&self, key: &PackageKey, locale: Option<&str>) -> Option<String> {
+        let descriptor: Descriptor = self.find_package_by_key(key)?.try_into().ok()?;
+        let locale = locale_or_system(locale);
+        let repos = self.repos();
+        let repos = repos.read().unwrap();
+        let default_language = crate::repo::default_language(key, &*repos);
+        crate::repo::localized(&descriptor.description, &locale, default_language).cloned()
+    }
+
+    /// Packages in enabled repositories whose id, name or description contain every
+    /// word of `query`, most relevant first. An empty query matches everything.
+    fn search(&self, query: &str, options: SearchOptions) -> Vec<SearchHit> {
+        let repos = self.repos();
+        let repos = repos.read().unwrap();
+        crate::repo::search(self, query, &options, &*repos)
+    }
+
+    /// Fails with the repositories that couldn't be refreshed, which are also kept
+    /// in [`PackageStore::errors`]. The others are refreshed regardless.
     #[must_use]
-    fn refresh_repos(&self) -> Future<Result<(), HashMap<RepoUrl, RepoDownloadError>>>;
+    fn refresh_repos(&self) -> Future<Result<(), Vec<RepoLoadError>>>;
 
     #[must_use]
-    fn force_refresh_repos(&self) -> Future<Result<(), HashMap<RepoUrl, RepoDownloadError>>> {
+    fn force_refresh_repos(&self) -> Future<Result<(), Vec<RepoLoadError>>> {
         self.clear_cache();
         self.refresh_repos()
     }
 
     fn clear_cache(&self);
 
+    /// The `limit` most recently completed transactions, newest first.
+    fn history(&self, limit: usize) -> Vec<TransactionRecord> {
+        let config = self.config();
+        let config = config.read().unwrap();
+        crate::transaction::history::read(config.settings().config_dir(), limit)
+    }
+
+    /// Copies the indexes and payloads of the bundle directory at `path` into the
+    /// cache and loads the imported indexes, so their packages can be installed
+    /// without a network.
+    ///
+    /// The bundle holds a directory for each repository, at the path named for its
+    /// URL as in the repository cache. It holds `index.toml` and
+    /// `packages/index.bin`, which must be the index of that repository, and the
+    /// payloads in `payloads/` at the path named for their URL as in the download
+    /// cache. Signatures sit next to each file with a `.minisig` suffix.
+    /// Everything is checked against the repository's config as a download would
+    /// be, and only configured repositories are imported.
+    fn import_bundle(&self, path: &Path) -> Result<ImportReport, BundleError> {
+        crate::repo::import_bundle(self, path)
+    }
+
+    /// Resolves `key` from the store's repositories, or else straight from its
+    /// repository without loading the others. Repositories with a partial index
+    /// only send that package's metadata; others are loaded, and cached, in full.
+    ///
+    /// A configured repository the store hadn't loaded is added to it, so `key` can
+    /// then be installed as usual.
+    #[must_use]
+    fn fetch_package(
+        &self,
+        key: &PackageKey,
+    ) -> Future<Result<Option<Descriptor>, RepoDownloadError>> {
+        let config = self.config().read().unwrap().clone();
+        Box::pin(crate::repo::fetch_package(
+            config,
+            self.repos(),
+            key.clone(),
+        ))
+    }
+
     fn strings(&self, language: String) -> Future<HashMap<RepoUrl, LocalizedStrings>>;
 
     // #[export::experimental]
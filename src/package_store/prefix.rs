@@ -1,18 +1,22 @@
 #![cfg(feature = "prefix")]
 
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::fs::{create_dir_all, read_dir, remove_dir, remove_file, File};
+use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::sync::{Arc, RwLock};
-use std::{collections::BTreeMap, unreachable};
 
 use hashbrown::HashMap;
-use pahkat_types::package::{Descriptor, Package};
+use pahkat_types::package::{Descriptor, Package, Version};
 use pahkat_types::repo::RepoUrl;
 use r2d2_sqlite::SqliteConnectionManager;
 use xz2::bufread::XzDecoder;
 
 use super::InstallTarget;
-use crate::repo::RepoDownloadError;
+use crate::repo::RepoLoadError;
 use crate::transaction::{
     install::InstallError, uninstall::UninstallError, PackageDependencyError,
     PackageDependencyStatusError, ResolvedPackageQuery,
@@ -22,21 +26,35 @@ use crate::{
     download::Download,
     download::DownloadManager,
     ext::DependencyKeyExt,
-    package_store::ImportError,
+    package_store::{ImportError, InstalledSizeError, VerifyError, VerifyReport},
     repo::{LoadedRepository, PackageQuery},
     transaction::PackageStatusError,
-    transaction::{PackageStatus, ResolvedDescriptor},
+    transaction::{PackageStatus, ProgressDetail, ResolvedDescriptor},
     Config, PackageKey, PackageStore,
 };
 use crate::{
     package_store::{SharedRepoErrors, SharedRepos, SharedStoreConfig},
-    repo::PackageCandidateError,
     PackageActionType,
 };
 
 // type Result<T> = std::result::Result<T, Error>;
 
+/// Counts the bytes read from an archive, for extraction progress.
+struct CountingReader<R> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+}
+
 const SQL_INIT: &str = include_str!("prefix/prefix_init.sql");
+const SQL_MIGRATE_2: &str = include_str!("prefix/prefix_migrate_2.sql");
 
 pub struct PrefixPackageStore {
     pool: r2d2::Pool<SqliteConnectionManager>,
@@ -95,6 +113,7 @@ impl PrefixPackageStore {
         let pool = Self::make_pool(manager)?;
         let conn = pool.get()?;
         conn.execute_batch(SQL_INIT)?;
+        Self::migrate(&conn)?;
 
         let store = PrefixPackageStore {
             pool,
@@ -104,8 +123,7 @@ impl PrefixPackageStore {
             config: Arc::new(RwLock::new(config)),
         };
 
-        // We ignore failures here.
-        let _ = store.refresh_repos().await;
+        super::load_repos(&store.repos, &store.errors, &store.config).await;
 
         Ok(store)
     }
@@ -122,6 +140,7 @@ impl PrefixPackageStore {
         log::debug!("{:?}", &db_file_path);
         let manager = SqliteConnectionManager::file(&db_file_path);
         let pool = Self::make_pool(manager)?;
+        Self::migrate(&*pool.get()?)?;
 
         let store = PrefixPackageStore {
             pool,
@@ -131,8 +150,7 @@ impl PrefixPackageStore {
             config: Arc::new(RwLock::new(config)),
         };
 
-        // We ignore failures here.
-        let _ = store.refresh_repos().await;
+        super::load_repos(&store.repos, &store.errors, &store.config).await;
 
         Ok(store)
     }
@@ -148,6 +166,35 @@ impl PrefixPackageStore {
             .build(manager)
     }
 
+    fn migrate(conn: &rusqlite::Connection) -> Result<(), rusqlite::Error> {
+        let has_meta: bool = conn.query_row(
+            "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'meta'",
+            [],
+            |row| row.get(0),
+        )?;
+
+        if !has_meta {
+            return Ok(());
+        }
+
+        let version: i64 =
+            conn.query_row("SELECT schema_version FROM meta LIMIT 1", [], |row| {
+                row.get(0)
+            })?;
+
+        if version < 2 {
+            log::debug!("Migrating package database to schema version 2");
+            conn.execute_batch(SQL_MIGRATE_2)?;
+        }
+
+        Ok(())
+    }
+
+    /// The directory this store installs into, canonicalized.
+    pub fn prefix_path(&self) -> &Path {
+        &self.prefix
+    }
+
     fn package_db_path(config: &Config) -> PathBuf {
         config.settings().config_dir().join("packages.sqlite")
     }
@@ -155,6 +202,79 @@ impl PrefixPackageStore {
     fn package_dir(&self, package_id: &str) -> PathBuf {
         self.prefix.join("pkg").join(package_id)
     }
+
+    fn status_impl(
+        &self,
+        conn: &mut rusqlite::Connection,
+        key: &PackageKey,
+        install_target: InstallTarget,
+        repos: &HashMap<RepoUrl, LoadedRepository>,
+    ) -> Result<PackageStatus, PackageStatusError> {
+        if install_target == InstallTarget::Auto {
+            let install_target = crate::repo::resolve_installed_target(
+                key,
+                install_target,
+                repos,
+                &self.config,
+                |target| {
+                    matches!(
+                        self.status_impl(conn, key, target, repos),
+                        Ok(status) if status != PackageStatus::NotInstalled
+                    )
+                },
+            );
+            return self.status_impl(conn, key, install_target, repos);
+        }
+        if super::meta::is_meta_package(key, repos) {
+            let query = crate::repo::ReleaseQuery::new(key, repos, &self.config.read().unwrap());
+            let (_, release, _) = crate::repo::resolve_payload(key, &query, repos)
+                .map_err(PackageStatusError::Payload)?;
+            return Ok(super::meta::status(
+                &self.config,
+                key,
+                install_target,
+                &release.version,
+            ));
+        }
+
+        let record = match PackageDbRecord::find_by_id(conn, &key) {
+            None => return Ok(PackageStatus::NotInstalled),
+            Some(v) => v,
+        };
+
+        crate::repo::check_repo_loaded(&self.config, key, repos)?;
+
+        let query = crate::repo::ReleaseQuery::new(key, repos, &self.config.read().unwrap())
+            .and_payloads(vec!["TarballPackage"]);
+        log::debug!("query: {:?}", &query);
+
+        let (target, release, _package) = crate::repo::resolve_payload(key, &query, repos)
+            .map_err(PackageStatusError::Payload)?;
+        let _installer = match target.payload {
+            pahkat_types::payload::Payload::TarballPackage(v) => v,
+            _ => return Err(PackageStatusError::WrongPayloadType),
+        };
+
+        let status = match self::cmp::cmp(&record.version, &release.version) {
+            // Only existence is checked here; hashing every file is left to `verify`.
+            Ok(PackageStatus::UpToDate) => {
+                let pkg_path = self.package_dir(&key.id);
+                if record
+                    .files
+                    .iter()
+                    .any(|file| !pkg_path.join(file).exists())
+                {
+                    Ok(PackageStatus::RequiresReinstall)
+                } else {
+                    Ok(PackageStatus::UpToDate)
+                }
+            }
+            status => status,
+        };
+
+        log::debug!("Status: {:?}", &status);
+        status
+    }
 }
 
 /// <script>
@@ -192,7 +312,7 @@ impl PackageStore for PrefixPackageStore {
     fn import(&self, key: &PackageKey, installer_path: &Path) -> Result<PathBuf, ImportError> {
         log::debug!("IMPORTING");
         let repos = self.repos.read().unwrap();
-        let query = crate::repo::ReleaseQuery::new(key, &*repos);
+        let query = crate::repo::ReleaseQuery::new(key, &*repos, &self.config.read().unwrap());
         crate::repo::import(&self.config, key, &query, &*repos, installer_path)
     }
 
@@ -207,24 +327,44 @@ impl PackageStore for PrefixPackageStore {
                 + 'static,
         >,
     > {
+        let installed = crate::repo::delta_base_version(self, key);
         let repos = self.repos.read().unwrap();
-        let query = crate::repo::ReleaseQuery::new(key, &*repos);
-        crate::repo::download(&self.config, key, &query, &*repos)
+        let query = crate::repo::ReleaseQuery::new(key, &*repos, &self.config.read().unwrap());
+        crate::repo::download(&self.config, key, &query, &*repos, installed.as_ref())
     }
 
     fn install(
         &self,
         key: &PackageKey,
-        target: InstallTarget,
+        install_target: InstallTarget,
+    ) -> Result<PackageStatus, InstallError> {
+        self.install_with_progress(key, install_target, &|_| {})
+    }
+
+    fn install_with_progress(
+        &self,
+        key: &PackageKey,
+        install_target: InstallTarget,
+        progress: &dyn Fn(ProgressDetail),
     ) -> Result<PackageStatus, InstallError> {
         log::trace!("In prefix install");
 
         let repos = self.repos.read().unwrap();
-        let query = crate::repo::ReleaseQuery::new(key, &*repos);
+        let install_target = crate::repo::resolve_install_target(
+            key,
+            install_target,
+            &*repos,
+            &self.config.read().unwrap(),
+        );
+        let query = crate::repo::ReleaseQuery::new(key, &*repos, &self.config.read().unwrap());
 
         log::trace!("Query: {:?}", &query);
-        let (target, release, package) =
+        let (target, release, _) =
             crate::repo::resolve_payload(key, &query, &*repos).map_err(InstallError::Payload)?;
+        if super::meta::is_meta_package(key, &*repos) {
+            super::meta::install(&self.config, key, install_target, &release.version)?;
+            return Ok(PackageStatus::UpToDate);
+        }
         let installer = match target.payload {
             pahkat_types::payload::Payload::TarballPackage(v) => v,
             _ => return Err(InstallError::WrongPayloadType),
@@ -235,37 +375,72 @@ impl PackageStore for PrefixPackageStore {
 
         if !pkg_path.exists() {
             log::error!("Package path doesn't exist: {:?}", &pkg_path);
-            return Err(InstallError::PackageNotInCache);
+            return Err(InstallError::PackageNotInCache(pkg_path));
         }
 
-        let file = File::open(&pkg_path).unwrap();
+        let file = File::open(&pkg_path).map_err(|e| InstallError::Io(e, pkg_path.clone()))?;
+        let total = file
+            .metadata()
+            .map_err(|e| InstallError::Io(e, pkg_path.clone()))?
+            .len();
+        let done = Rc::new(Cell::new(0));
+        let file = CountingReader {
+            inner: file,
+            count: Rc::clone(&done),
+        };
         let reader = XzDecoder::new(std::io::BufReader::new(file));
 
         let mut tar_file = tar::Archive::new(reader);
         let mut files = vec![];
 
-        let pkg_path = self.package_dir(&package.package.id);
-        create_dir_all(&pkg_path).unwrap(); // map_err(InstallError::CreateDirFailed)?;
+        let archive_path = pkg_path;
+        let pkg_path = self.package_dir(&key.id);
+        create_dir_all(&pkg_path).map_err(|e| InstallError::Io(e, pkg_path.clone()))?;
 
         log::debug!("Prefix: {:?}", &self.prefix);
 
-        for entry in tar_file.entries().unwrap() {
-            let mut entry = entry.unwrap();
-            let unpack_res;
-            {
-                unpack_res = entry.unpack_in(&pkg_path).unwrap(); //.context(UnpackFailed)?;
-            }
+        let entries = tar_file
+            .entries()
+            .map_err(|e| InstallError::Io(e, archive_path.clone()))?;
+        progress(ProgressDetail::Extracting { done: 0, total });
+        for entry in entries {
+            let mut entry = entry.map_err(|e| InstallError::Io(e, archive_path.clone()))?;
+            let unpack_res = entry.unpack_in(&pkg_path).map_err(|e| {
+                let path = entry
+                    .path()
+                    .map(|x| pkg_path.join(x))
+                    .unwrap_or_else(|_| pkg_path.clone());
+                InstallError::Io(e, path)
+            })?;
 
             if unpack_res {
-                let entry_path = entry.header().path().unwrap();
+                let entry_path = entry
+                    .header()
+                    .path()
+                    .map_err(|e| InstallError::Io(e, archive_path.clone()))?;
                 log::debug!("entry path: {:?}", &entry_path);
                 let entry_path = entry_path.to_str().unwrap().to_string();
                 files.push(entry_path);
-            } else {
-                continue;
             }
+
+            progress(ProgressDetail::Extracting {
+                done: done.get().min(total),
+                total,
+            });
         }
 
+        let file_hashes = files
+            .iter()
+            .filter(|file| pkg_path.join(file).is_file())
+            .filter_map(|file| match file_sha256(&pkg_path.join(file)) {
+                Ok(hash) => Some((file.clone(), hash)),
+                Err(e) => {
+                    log::warn!("Could not hash installed file {:?}: {:?}", file, e);
+                    None
+                }
+            })
+            .collect();
+
         let deps = &target.dependencies;
         let dependencies: Vec<String> = deps
             .keys()
@@ -278,6 +453,7 @@ impl PackageStore for PrefixPackageStore {
                 url: key.clone().without_query_params().to_string(),
                 version: release.version.to_string(),
                 files,
+                file_hashes,
                 dependencies,
             };
 
@@ -291,8 +467,22 @@ impl PackageStore for PrefixPackageStore {
     fn uninstall(
         &self,
         key: &PackageKey,
-        _target: InstallTarget,
+        target: InstallTarget,
     ) -> Result<PackageStatus, UninstallError> {
+        let target = {
+            let repos = self.repos.read().unwrap();
+            let mut conn = self.pool.get().unwrap();
+            crate::repo::resolve_installed_target(key, target, &*repos, &self.config, |target| {
+                matches!(
+                    self.status_impl(&mut conn, key, target, &*repos),
+                    Ok(status) if status != PackageStatus::NotInstalled
+                )
+            })
+        };
+        if super::meta::uninstall(&self.config, key, target)? {
+            return Ok(PackageStatus::NotInstalled);
+        }
+
         let mut conn = self.pool.get().unwrap();
         let record = match PackageDbRecord::find_by_id(&mut conn, &key) {
             None => return Err(UninstallError::NotInstalled),
@@ -339,31 +529,33 @@ impl PackageStore for PrefixPackageStore {
     fn status(
         &self,
         key: &PackageKey,
-        _target: InstallTarget,
+        target: InstallTarget,
     ) -> Result<PackageStatus, PackageStatusError> {
         let mut conn = self.pool.get().unwrap();
-        let record = match PackageDbRecord::find_by_id(&mut conn, &key) {
-            None => return Ok(PackageStatus::NotInstalled),
-            Some(v) => v,
-        };
-
         let repos = self.repos.read().unwrap();
-        let query =
-            crate::repo::ReleaseQuery::new(key, &*repos).and_payloads(vec!["TarballPackage"]);
-        log::debug!("query: {:?}", &query);
-
-        let (target, release, package) = crate::repo::resolve_payload(key, &query, &*repos)
-            .map_err(PackageStatusError::Payload)?;
-        let _installer = match target.payload {
-            pahkat_types::payload::Payload::TarballPackage(v) => v,
-            _ => return Err(PackageStatusError::WrongPayloadType),
-        };
+        self.status_impl(&mut conn, key, target, &*repos)
+    }
 
-        let config = self.config.read().unwrap();
-        let status = self::cmp::cmp(&record.version, &release.version);
+    /// A prefix has no user scope, so only [`InstallTarget::System`] is reported.
+    fn status_all_targets(&self, key: &PackageKey) -> BTreeMap<InstallTarget, PackageStatus> {
+        match self.status(key, InstallTarget::System) {
+            Ok(status) => std::iter::once((InstallTarget::System, status)).collect(),
+            Err(e) => {
+                log::debug!("No status for {}: {:?}", key, e);
+                BTreeMap::new()
+            }
+        }
+    }
 
-        log::debug!("Status: {:?}", &status);
-        status
+    fn statuses(
+        &self,
+        keys: &[(PackageKey, InstallTarget)],
+    ) -> Vec<Result<PackageStatus, PackageStatusError>> {
+        let mut conn = self.pool.get().unwrap();
+        let repos = self.repos.read().unwrap();
+        keys.iter()
+            .map(|(key, target)| self.status_impl(&mut conn, key, *target, &*repos))
+            .collect()
     }
 
     fn dependency_status(
@@ -375,29 +567,155 @@ impl PackageStore for PrefixPackageStore {
             self,
             &[(PackageActionType::Install, key.clone())],
             &[target],
+            &[],
+            &[],
+            true,
+            &Default::default(),
         )
         .map(|dep| {
             dep.into_iter()
                 .map(|dep| (dep.package_key, dep.status))
                 .collect()
         })
-        .map_err(|err| match err {
-            PackageCandidateError::Status(p, PackageStatusError::Payload(e)) => {
-                PackageDependencyStatusError::Payload(p, e)
-            }
-            PackageCandidateError::Status(p, PackageStatusError::WrongPayloadType) => {
-                PackageDependencyStatusError::WrongPayloadType(p)
+        .map_err(PackageDependencyStatusError::from)
+    }
+
+    /// Read from the prefix's database, so packages from repositories that are no
+    /// longer configured are listed too. As in
+    /// [`status_all_targets`](PackageStore::status_all_targets), everything in a
+    /// prefix is installed for [`InstallTarget::System`].
+    fn installed(&self) -> Vec<(PackageKey, InstallTarget, Version)> {
+        let mut conn = self.pool.get().unwrap();
+
+        PackageDbConnection(&mut conn)
+            .installed()
+            .into_iter()
+            .filter_map(|(url, version)| {
+                let key = match PackageKey::try_from(&*url) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        log::warn!("Invalid package key in database: {}: {:?}", &url, e);
+                        return None;
+                    }
+                };
+                let version = match Version::new(&version) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        log::warn!("Invalid version in database: {}: {:?}", &version, e);
+                        return None;
+                    }
+                };
+
+                Some((key, InstallTarget::System, version))
+            })
+            .chain(super::meta::installed(&self.config))
+            .collect()
+    }
+
+    fn verify(
+        &self,
+        key: &PackageKey,
+        _target: InstallTarget,
+    ) -> Result<VerifyReport, VerifyError> {
+        let mut conn = self.pool.get().unwrap();
+        let record = PackageDbRecord::find_by_id(&mut conn, &key)
+            .ok_or_else(|| VerifyError::NotInstalled(key.clone()))?;
+
+        let ignored = crate::repo::verify_ignored_paths(key, &*self.repos.read().unwrap());
+
+        let pkg_path = self.package_dir(&key.id);
+        let mut report = VerifyReport::default();
+
+        for file in &record.files {
+            let path = pkg_path.join(file);
+
+            if path.is_dir() {
+                continue;
             }
-            PackageCandidateError::Status(p, PackageStatusError::ParsingVersion) => {
-                PackageDependencyStatusError::ParsingVersion(p)
+
+            if ignored.contains(file) {
+                report.ignored.push(path);
+            } else if !path.exists() {
+                report.missing.push(path);
+            } else {
+                // Files recorded before hashes were kept can only be checked for existence.
+                match record.file_hashes.get(file) {
+                    Some(hash) if &file_sha256(&path)? != hash => report.modified.push(path),
+                    _ => report.ok.push(path),
+                }
             }
+        }
 
-            PackageCandidateError::Payload(p, e) => PackageDependencyStatusError::Payload(p, e),
-            PackageCandidateError::UnresolvedId(id) => {
-                PackageDependencyStatusError::PackageNotFound(id)
+        Ok(report)
+    }
+
+    fn owner_of(&self, path: &Path) -> Option<PackageKey> {
+        // Only the parent is canonicalized, so a symlink is matched as itself.
+        let path = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => parent.canonicalize().ok()?.join(name),
+            _ => return None,
+        };
+        let package_id = path
+            .strip_prefix(self.prefix.join("pkg"))
+            .ok()?
+            .components()
+            .next()?
+            .as_os_str()
+            .to_str()?;
+
+        let mut conn = self.pool.get().unwrap();
+        let pkg_path = self.package_dir(package_id);
+        let (url, key) = PackageDbConnection(&mut conn)
+            .installed()
+            .into_iter()
+            .filter_map(|(url, _)| PackageKey::try_from(&*url).ok().map(|key| (url, key)))
+            .find(|(_, key)| key.id == package_id)?;
+
+        let files = PackageDbConnection(&mut conn).files(&url);
+        if files.iter().any(|file| pkg_path.join(file) == path) {
+            Some(key)
+        } else {
+            None
+        }
+    }
+
+    fn installed_size(
+        &self,
+        key: &PackageKey,
+        _target: InstallTarget,
+    ) -> Result<u64, InstalledSizeError> {
+        let mut conn = self.pool.get().unwrap();
+        let record = PackageDbRecord::find_by_id(&mut conn, &key)
+            .ok_or_else(|| InstalledSizeError::NotInstalled(key.clone()))?;
+
+        let pkg_path = self.package_dir(&key.id);
+        let mut size = 0;
+
+        for file in &record.files {
+            // Files removed since install no longer take up space.
+            match std::fs::symlink_metadata(pkg_path.join(file)) {
+                Ok(meta) if meta.is_file() => size += meta.len(),
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
             }
-            PackageCandidateError::UninstallConflict(_) => unreachable!(),
-        })
+        }
+
+        Ok(size)
+    }
+
+    fn is_requested_by_user(&self, key: &PackageKey) -> bool {
+        let mut conn = self.pool.get().unwrap();
+        let url = key.clone().without_query_params().to_string();
+        !PackageDbConnection(&mut conn).is_dependent(&url)
+    }
+
+    fn set_requested_by_user(&self, key: &PackageKey, requested: bool) {
+        let mut conn = self.pool.get().unwrap();
+        let url = key.clone().without_query_params().to_string();
+        if let Err(e) = PackageDbConnection(&mut conn).set_dependent(&url, !requested) {
+            log::error!("Could not update install reason for {}: {:?}", &url, e);
+        }
     }
 
     fn all_statuses(
@@ -418,22 +736,9 @@ impl PackageStore for PrefixPackageStore {
         crate::repo::find_package_by_id(self, package_id, &*repos)
     }
 
-    fn refresh_repos(
-        &self,
-    ) -> crate::package_store::Future<Result<(), HashMap<RepoUrl, RepoDownloadError>>> {
+    fn refresh_repos(&self) -> crate::package_store::Future<Result<(), Vec<RepoLoadError>>> {
         let config = self.config().read().unwrap().clone();
-        let repos = self.repos();
-        Box::pin(async move {
-            log::trace!("Calling into refresh repos");
-            let (result, errors) = crate::repo::refresh_repos(config).await;
-            log::trace!("Finished refresh repos: {:?}", &errors);
-            *repos.write().unwrap() = result;
-            if errors.is_empty() {
-                Ok(())
-            } else {
-                Err(errors)
-            }
-        })
+        Box::pin(super::refresh_repos(config, self.repos(), self.errors()))
     }
 
     fn clear_cache(&self) {
@@ -448,7 +753,16 @@ impl PackageStore for PrefixPackageStore {
         let repos = self.repos.read().unwrap();
         let urls = repos.keys().cloned().collect::<Vec<_>>();
 
-        Box::pin(crate::repo::strings(urls, language))
+        let (proxy, timeouts, http) = {
+            let config = self.config.read().unwrap();
+            (
+                config.settings().proxy(),
+                config.settings().timeouts(),
+                config.http_client_hook().clone(),
+            )
+        };
+
+        Box::pin(crate::repo::strings(urls, language, proxy, timeouts, http))
     }
 
     fn resolve_package_query(
@@ -468,9 +782,19 @@ struct PackageDbRecord {
     url: String,
     version: String,
     files: Vec<String>,
+    file_hashes: BTreeMap<String, String>,
     dependencies: Vec<String>,
 }
 
+fn file_sha256(path: &Path) -> std::io::Result<String> {
+    use sha2::{Digest, Sha256};
+
+    let mut file = File::open(path)?;
+    let mut sha = Sha256::new();
+    std::io::copy(&mut file, &mut sha)?;
+    Ok(format!("{:x}", sha.finalize()))
+}
+
 struct PackageDbConnection<'a>(&'a mut rusqlite::Connection);
 
 impl<'a> PackageDbConnection<'a> {
@@ -504,6 +828,36 @@ impl<'a> PackageDbConnection<'a> {
         res
     }
 
+    fn file_hashes(&self, url: &str) -> BTreeMap<String, String> {
+        let mut stmt = self
+            .0
+            .prepare("SELECT file_path, file_hash FROM packages_files WHERE file_hash IS NOT NULL AND package_id = (SELECT id FROM packages WHERE url = ?)")
+            .expect("prepared statement");
+
+        let res = stmt
+            .query_map(&[&url], |row| Ok((row.get(0)?, row.get(1)?)))
+            .expect("query_map succeeds")
+            .map(|x: Result<(String, String), _>| x.unwrap())
+            .collect();
+
+        res
+    }
+
+    fn installed(&self) -> Vec<(String, String)> {
+        let mut stmt = self
+            .0
+            .prepare("SELECT url, version FROM packages")
+            .expect("prepared statement");
+
+        let res = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .expect("query_map succeeds")
+            .map(|x: Result<(String, String), _>| x.unwrap())
+            .collect();
+
+        res
+    }
+
     fn version(&self, url: &str) -> Option<String> {
         match self.0.query_row(
             "SELECT version FROM packages WHERE url = ? LIMIT 1",
@@ -515,6 +869,24 @@ impl<'a> PackageDbConnection<'a> {
         }
     }
 
+    fn is_dependent(&self, url: &str) -> bool {
+        self.0
+            .query_row(
+                "SELECT is_dependent FROM packages WHERE url = ? LIMIT 1",
+                &[&url],
+                |row| row.get(0),
+            )
+            .unwrap_or(false)
+    }
+
+    fn set_dependent(&mut self, url: &str, is_dependent: bool) -> rusqlite::Result<()> {
+        self.0.execute(
+            "UPDATE packages SET is_dependent = ? WHERE url = ?",
+            rusqlite::params![is_dependent, url],
+        )?;
+        Ok(())
+    }
+
     fn replace_pkg(&mut self, pkg: &PackageDbRecord) -> rusqlite::Result<()> {
         use chrono::prelude::*;
         let utc: DateTime<Utc> = Utc::now();
@@ -565,12 +937,17 @@ impl<'a> PackageDbConnection<'a> {
                 dep_stmt.execute_named(&[(":id", &id), (":dep_url", &*dep_url)])?;
             }
 
-            let mut file_stmt = tx
-                .prepare("INSERT INTO packages_files(package_id, file_path) VALUES (:id, :path)")?;
+            let mut file_stmt = tx.prepare(
+                "INSERT INTO packages_files(package_id, file_path, file_hash) VALUES (:id, :path, :hash)",
+            )?;
 
             for file_path in &pkg.files {
                 file_stmt
-                    .execute_named(&[(":id", &id), (":path", &file_path.as_str())])
+                    .execute_named(&[
+                        (":id", &id),
+                        (":path", &file_path.as_str()),
+                        (":hash", &pkg.file_hashes.get(file_path)),
+                    ])
                     .unwrap();
             }
         }
@@ -609,6 +986,7 @@ impl PackageDbRecord {
         };
 
         let files = conn.files(&url);
+        let file_hashes = conn.file_hashes(&url);
         let dependencies = conn.dependencies(&url);
 
         Some(PackageDbRecord {
@@ -616,6 +994,7 @@ impl PackageDbRecord {
             url,
             version,
             files,
+            file_hashes,
             dependencies,
         })
     }
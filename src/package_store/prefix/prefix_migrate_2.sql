@@ -0,0 +1,7 @@
+BEGIN;
+
+ALTER TABLE packages_files ADD COLUMN file_hash TEXT;
+
+UPDATE meta SET schema_version = 2;
+
+COMMIT;
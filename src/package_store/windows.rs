@@ -11,8 +11,8 @@ use hashbrown::HashMap;
 use registry::{Data, Hive, RegKey, Security};
 use url::Url;
 
-use crate::package_store::{ImportError, InstallTarget};
-use crate::repo::{PackageCandidateError, PackageQuery, RepoDownloadError};
+use crate::package_store::{ImportError, InstallTarget, InstalledSizeError};
+use crate::repo::{PackageQuery, RepoLoadError};
 use crate::transaction::{
     install::InstallError, install::ProcessError, uninstall::UninstallError,
     PackageDependencyStatusError, PackageStatus, PackageStatusError, ResolvedDescriptor,
@@ -29,6 +29,7 @@ use pahkat_types::{
 const UNINSTALL_PATH: &'static str = r"Software\Microsoft\Windows\CurrentVersion\Uninstall";
 const DISPLAY_VERSION: &'static str = "DisplayVersion";
 const QUIET_UNINSTALL_STRING: &'static str = "QuietUninstallString";
+const ESTIMATED_SIZE: &'static str = "EstimatedSize";
 
 use super::LocalizedStrings;
 use super::{SharedRepoErrors, SharedRepos, SharedStoreConfig};
@@ -64,9 +65,10 @@ impl PackageStore for WindowsPackageStore {
                 + 'static,
         >,
     > {
+        let installed = crate::repo::delta_base_version(self, key);
         let repos = self.repos.read().unwrap();
-        let query = crate::repo::ReleaseQuery::new(key, &*repos);
-        crate::repo::download(&self.config, key, &query, &*repos)
+        let query = crate::repo::ReleaseQuery::new(key, &*repos, &self.config.read().unwrap());
+        crate::repo::download(&self.config, key, &query, &*repos, installed.as_ref())
     }
 
     fn install(
@@ -75,10 +77,20 @@ impl PackageStore for WindowsPackageStore {
         install_target: InstallTarget,
     ) -> Result<PackageStatus, InstallError> {
         let repos = self.repos.read().unwrap();
-        let query = crate::repo::ReleaseQuery::new(key, &*repos);
+        let install_target = crate::repo::resolve_install_target(
+            key,
+            install_target,
+            &*repos,
+            &self.config.read().unwrap(),
+        );
+        let query = crate::repo::ReleaseQuery::new(key, &*repos, &self.config.read().unwrap());
 
         let (target, release, descriptor) =
             crate::repo::resolve_payload(key, &query, &*repos).map_err(InstallError::Payload)?;
+        if super::meta::is_meta_package(key, &*repos) {
+            super::meta::install(&self.config, key, install_target, &release.version)?;
+            return Ok(PackageStatus::UpToDate);
+        }
         let installer = match target.payload {
             pahkat_types::payload::Payload::WindowsExecutable(v) => v,
             _ => return Err(InstallError::WrongPayloadType),
@@ -89,7 +101,7 @@ impl PackageStore for WindowsPackageStore {
 
         if !pkg_path.exists() {
             log::error!("Package path doesn't exist: {:?}", &pkg_path);
-            return Err(InstallError::PackageNotInCache);
+            return Err(InstallError::PackageNotInCache(pkg_path));
         }
 
         let mut args: Vec<OsString> = match (&installer.kind, &installer.args) {
@@ -149,7 +161,7 @@ impl PackageStore for WindowsPackageStore {
         }
 
         Ok(self
-            .status_impl(key, &descriptor, &release.version, install_target)
+            .status_impl(key, &descriptor, &release.version, install_target, &*repos)
             .unwrap())
     }
 
@@ -159,7 +171,19 @@ impl PackageStore for WindowsPackageStore {
         install_target: InstallTarget,
     ) -> Result<PackageStatus, UninstallError> {
         let repos = self.repos.read().unwrap();
-        let query = crate::repo::ReleaseQuery::new(key, &*repos);
+        let install_target = crate::repo::resolve_installed_target(
+            key,
+            install_target,
+            &*repos,
+            &self.config,
+            |target| is_installed(self.status_with_repos(key, target, &*repos)),
+        );
+
+        if super::meta::uninstall(&self.config, key, install_target)? {
+            return Ok(PackageStatus::NotInstalled);
+        }
+
+        let query = crate::repo::ReleaseQuery::new(key, &*repos, &self.config.read().unwrap());
 
         let (target, release, descriptor) =
             crate::repo::resolve_payload(key, &query, &*repos).map_err(UninstallError::Payload)?;
@@ -168,7 +192,7 @@ impl PackageStore for WindowsPackageStore {
             _ => return Err(UninstallError::WrongPayloadType),
         };
 
-        let regkey = match uninstall_regkey(&installer) {
+        let regkey = match uninstall_regkey(&installer, install_target) {
             Some(v) => v,
             None => return Err(UninstallError::NotInstalled),
         };
@@ -234,7 +258,7 @@ impl PackageStore for WindowsPackageStore {
         }
 
         Ok(self
-            .status_impl(key, &descriptor, &release.version, install_target)
+            .status_impl(key, &descriptor, &release.version, install_target, &*repos)
             .unwrap())
     }
 
@@ -243,19 +267,18 @@ impl PackageStore for WindowsPackageStore {
         key: &PackageKey,
         install_target: InstallTarget,
     ) -> Result<PackageStatus, PackageStatusError> {
-        log::debug!("status: {}, target: {:?}", &key.to_string(), install_target);
-
         let repos = self.repos.read().unwrap();
-        let query = crate::repo::ReleaseQuery::new(key, &*repos);
-
-        let (target, release, descriptor) = crate::repo::resolve_payload(key, &query, &*repos)
-            .map_err(PackageStatusError::Payload)?;
-        let installer = match target.payload {
-            pahkat_types::payload::Payload::WindowsExecutable(v) => v,
-            _ => return Err(PackageStatusError::WrongPayloadType),
-        };
+        self.status_with_repos(key, install_target, &*repos)
+    }
 
-        self.status_impl(key, &descriptor, &release.version, install_target)
+    fn statuses(
+        &self,
+        keys: &[(PackageKey, InstallTarget)],
+    ) -> Vec<Result<PackageStatus, PackageStatusError>> {
+        let repos = self.repos.read().unwrap();
+        keys.iter()
+            .map(|(key, target)| self.status_with_repos(key, *target, &*repos))
+            .collect()
     }
 
     fn dependency_status(
@@ -267,29 +290,68 @@ impl PackageStore for WindowsPackageStore {
             self,
             &[(PackageActionType::Install, key.clone())],
             &[target],
+            &[],
+            &[],
+            true,
+            &Default::default(),
         )
         .map(|dep| {
             dep.into_iter()
                 .map(|dep| (dep.package_key, dep.status))
                 .collect()
         })
-        .map_err(|err| match err {
-            PackageCandidateError::Status(p, PackageStatusError::Payload(e)) => {
-                PackageDependencyStatusError::Payload(p, e)
-            }
-            PackageCandidateError::Status(p, PackageStatusError::WrongPayloadType) => {
-                PackageDependencyStatusError::WrongPayloadType(p)
-            }
-            PackageCandidateError::Status(p, PackageStatusError::ParsingVersion) => {
-                PackageDependencyStatusError::ParsingVersion(p)
-            }
+        .map_err(PackageDependencyStatusError::from)
+    }
 
-            PackageCandidateError::Payload(p, e) => PackageDependencyStatusError::Payload(p, e),
-            PackageCandidateError::UnresolvedId(id) => {
-                PackageDependencyStatusError::PackageNotFound(id)
-            }
-            PackageCandidateError::UninstallConflict(_) => unreachable!(),
-        })
+    /// Uses the size the installer reported to Windows, which is an estimate.
+    fn installed_size(
+        &self,
+        key: &PackageKey,
+        target: InstallTarget,
+    ) -> Result<u64, InstalledSizeError> {
+        let repos = self.repos.read().unwrap();
+        let inst_key = crate::repo::resolve_package(key, &*repos)
+            .ok()
+            .and_then(|descriptor| {
+                let target = crate::repo::resolve_installed_target(
+                    key,
+                    target,
+                    &*repos,
+                    &self.config,
+                    |t| installed_regkey(&descriptor, t).is_some(),
+                );
+                installed_regkey(&descriptor, target)
+            })
+            .ok_or_else(|| InstalledSizeError::NotInstalled(key.clone()))?;
+
+        match inst_key.value(ESTIMATED_SIZE) {
+            // Recorded in kilobytes.
+            Ok(Data::U32(kb)) => Ok(kb as u64 * 1024),
+            _ => Err(InstalledSizeError::Unsupported),
+        }
+    }
+
+    fn installed_version(
+        &self,
+        key: &PackageKey,
+        target: InstallTarget,
+    ) -> Option<pahkat_types::package::Version> {
+        let repos = self.repos.read().unwrap();
+        if super::meta::is_meta_package(key, &*repos) {
+            return None;
+        }
+        let descriptor = crate::repo::resolve_package(key, &*repos).ok()?;
+        let target =
+            crate::repo::resolve_installed_target(key, target, &*repos, &self.config, |t| {
+                installed_regkey(&descriptor, t).is_some()
+            });
+        let inst_key = installed_regkey(&descriptor, target)?;
+
+        let disp_version = match inst_key.value(DISPLAY_VERSION) {
+            Ok(Data::String(v)) => v.to_string_lossy(),
+            _ => return None,
+        };
+        pahkat_types::package::Version::new(&disp_version).ok()
     }
 
     fn find_package_by_key(&self, key: &PackageKey) -> Option<Package> {
@@ -302,20 +364,9 @@ impl PackageStore for WindowsPackageStore {
         crate::repo::find_package_by_id(self, package_id, &*repos)
     }
 
-    fn refresh_repos(
-        &self,
-    ) -> crate::package_store::Future<Result<(), HashMap<RepoUrl, RepoDownloadError>>> {
+    fn refresh_repos(&self) -> crate::package_store::Future<Result<(), Vec<RepoLoadError>>> {
         let config = self.config().read().unwrap().clone();
-        let repos = self.repos();
-        Box::pin(async move {
-            let (result, errors) = crate::repo::refresh_repos(config).await;
-            *repos.write().unwrap() = result;
-            if errors.is_empty() {
-                Ok(())
-            } else {
-                Err(errors)
-            }
-        })
+        Box::pin(super::refresh_repos(config, self.repos(), self.errors()))
     }
 
     fn clear_cache(&self) {
@@ -324,7 +375,7 @@ impl PackageStore for WindowsPackageStore {
 
     fn import(&self, key: &PackageKey, installer_path: &Path) -> Result<PathBuf, ImportError> {
         let repos = self.repos.read().unwrap();
-        let query = crate::repo::ReleaseQuery::new(key, &*repos);
+        let query = crate::repo::ReleaseQuery::new(key, &*repos, &self.config.read().unwrap());
         crate::repo::import(&self.config, key, &query, &*repos, installer_path)
     }
 
@@ -343,7 +394,16 @@ impl PackageStore for WindowsPackageStore {
         let repos = self.repos.read().unwrap();
         let urls = repos.keys().cloned().collect::<Vec<_>>();
 
-        Box::pin(crate::repo::strings(urls, language))
+        let (proxy, timeouts, http) = {
+            let config = self.config.read().unwrap();
+            (
+                config.settings().proxy(),
+                config.settings().timeouts(),
+                config.http_client_hook().clone(),
+            )
+        };
+
+        Box::pin(crate::repo::strings(urls, language, proxy, timeouts, http))
     }
 
     fn resolve_package_query(
@@ -365,8 +425,7 @@ impl WindowsPackageStore {
             config: Arc::new(RwLock::new(config)),
         };
 
-        // We ignore errors here.
-        let _ = store.refresh_repos().await;
+        super::load_repos(&store.repos, &store.errors, &store.config).await;
 
         store
     }
@@ -375,15 +434,52 @@ impl WindowsPackageStore {
         Arc::clone(&self.config)
     }
 
+    fn status_with_repos(
+        &self,
+        key: &PackageKey,
+        install_target: InstallTarget,
+        repos: &HashMap<RepoUrl, LoadedRepository>,
+    ) -> Result<PackageStatus, PackageStatusError> {
+        let install_target = crate::repo::resolve_installed_target(
+            key,
+            install_target,
+            repos,
+            &self.config,
+            |target| is_installed(self.status_with_repos(key, target, repos)),
+        );
+        log::debug!("status: {}, target: {:?}", &key.to_string(), install_target);
+
+        crate::repo::check_repo_loaded(&self.config, key, repos)?;
+
+        let query = crate::repo::ReleaseQuery::new(key, repos, &self.config.read().unwrap());
+
+        let (target, release, descriptor) = crate::repo::resolve_payload(key, &query, repos)
+            .map_err(PackageStatusError::Payload)?;
+        if super::meta::is_meta_package(key, repos) {
+            return Ok(super::meta::status(
+                &self.config,
+                key,
+                install_target,
+                &release.version,
+            ));
+        }
+        let installer = match target.payload {
+            pahkat_types::payload::Payload::WindowsExecutable(v) => v,
+            _ => return Err(PackageStatusError::WrongPayloadType),
+        };
+
+        self.status_impl(key, &descriptor, &release.version, install_target, repos)
+    }
+
     fn status_impl(
         &self,
         key: &PackageKey,
         package: &Descriptor,
         version: &pahkat_types::package::Version,
-        _target: InstallTarget,
+        target: InstallTarget,
+        repos: &HashMap<RepoUrl, LoadedRepository>,
     ) -> Result<PackageStatus, PackageStatusError> {
-        let repos = self.repos.read().unwrap();
-        let mut query = crate::repo::ReleaseQuery::new(key, &*repos);
+        let mut query = crate::repo::ReleaseQuery::new(key, repos, &self.config.read().unwrap());
 
         let (response, inst_key) = match query
             .iter(package)
@@ -391,7 +487,7 @@ impl WindowsPackageStore {
                 pahkat_types::payload::Payload::WindowsExecutable(ref v) => Some((x, v)),
                 _ => None,
             })
-            .find_map(|(x, v)| uninstall_regkey(&v).map(|i| (x, i)))
+            .find_map(|(x, v)| uninstall_regkey(&v, target).map(|i| (x, i)))
         {
             Some(v) => v,
             None => return Ok(PackageStatus::NotInstalled),
@@ -411,18 +507,40 @@ impl WindowsPackageStore {
     }
 }
 
+/// The uninstall key of whichever of the descriptor's installers is present for `target`.
+fn installed_regkey(descriptor: &Descriptor, target: InstallTarget) -> Option<RegKey> {
+    descriptor
+        .release
+        .iter()
+        .flat_map(|release| release.target.iter())
+        .filter_map(|target| match target.payload {
+            pahkat_types::payload::Payload::WindowsExecutable(ref v) => Some(v),
+            _ => None,
+        })
+        .find_map(|v| uninstall_regkey(v, target))
+}
+
+fn is_installed(status: Result<PackageStatus, PackageStatusError>) -> bool {
+    matches!(status, Ok(status) if status != PackageStatus::NotInstalled)
+}
+
+/// Per-user installers register under the current user's hive, others under the machine's.
 #[inline(always)]
-fn uninstall_regkey(installer: &windows::Executable) -> Option<RegKey> {
-    Hive::LocalMachine
-        .open(
+fn uninstall_regkey(installer: &windows::Executable, target: InstallTarget) -> Option<RegKey> {
+    let hive = match target {
+        InstallTarget::User => Hive::CurrentUser,
+        InstallTarget::System | InstallTarget::Auto => Hive::LocalMachine,
+    };
+
+    hive.open(
+        vec![UNINSTALL_PATH, &*installer.product_code].join(r"\"),
+        Security::Read | Security::Wow6464Key,
+    )
+    .or_else(|_| {
+        hive.open(
             vec![UNINSTALL_PATH, &*installer.product_code].join(r"\"),
-            Security::Read | Security::Wow6464Key,
+            Security::Read | Security::Wow6432Key,
         )
-        .or_else(|_| {
-            Hive::LocalMachine.open(
-                vec![UNINSTALL_PATH, &*installer.product_code].join(r"\"),
-                Security::Read | Security::Wow6432Key,
-            )
-        })
-        .ok()
+    })
+    .ok()
 }
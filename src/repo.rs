@@ -1,8 +1,16 @@
+mod bundle;
+mod index;
+mod key;
 mod repository;
 
 use futures::Future;
+
+pub(crate) use bundle::import_bundle;
+pub use index::{parse_index, IndexError, PackageMetadata, RepoIndex};
+pub use key::{ChannelQuery, PackageKeyBuilder, PackageKeyError, PackageKeyExt, ALL_CHANNELS};
 pub use pahkat_types::PackageKey;
-pub use repository::{LoadedRepository, RepoDownloadError};
+pub(crate) use repository::{required_client_version, LoadOptions};
+pub use repository::{LoadedRepository, RepoDownloadError, RepoLoadError};
 
 use std::collections::BTreeMap;
 use std::convert::{TryFrom, TryInto};
@@ -10,6 +18,7 @@ use std::hash::Hash;
 use std::path::Path;
 use std::pin::Pin;
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use crossbeam_queue::SegQueue;
 use futures::future::FutureExt;
@@ -25,7 +34,9 @@ use crate::defaults;
 use crate::fbs::PackagesExt;
 use crate::package_store::DownloadEvent;
 use crate::package_store::PackageStore;
+use crate::package_store::SharedRepos;
 use crate::transaction::{
+    DependencyLimit, DependencyLimits, PackageDependencyError, PackageDependencyStatusError,
     PackageStatus, PackageStatusError, ResolvedDescriptor, ResolvedPackageQuery,
 };
 use pahkat_types::package::{Descriptor, Package, Release, Version};
@@ -42,6 +53,30 @@ pub enum PayloadError {
     NoPayloadFound,
     #[error("Some criteria is not met for the current payload")]
     CriteriaUnmet(String),
+    #[error("Payload signature could not be verified: {0}")]
+    SignatureInvalid(String),
+}
+
+/// Why [`PackageStore::try_find_package_by_key`] found no package.
+#[derive(Debug, Clone, Error)]
+pub enum ResolveError {
+    #[error("The repository is not configured: {0}")]
+    RepoNotConfigured(RepoUrl),
+
+    #[error("The repository index could not be loaded: {0}")]
+    RepoUnavailable(RepoUrl),
+
+    #[error("The repository {url} requires client version {required} or newer")]
+    RepoUnsupported { url: RepoUrl, required: String },
+
+    #[error("The repository has no package `{}`", .0.id)]
+    NotInRepo(PackageKey),
+
+    #[error("The repository has an invalid entry for `{}`", .0.id)]
+    InvalidPackage(PackageKey),
+
+    #[error("No release of `{}` matches the channel, version and platform", .0.id)]
+    NoMatchingRelease(PackageKey),
 }
 
 #[derive(Debug, Clone)]
@@ -49,15 +84,44 @@ pub struct ReleaseQuery<'a> {
     pub platform: &'a str,
     pub arch: Option<&'a str>,
     pub channels: Vec<&'a str>,
+    /// Accept releases of any channel, newest first, ignoring `channels`.
+    pub(crate) all_channels: bool,
     pub versions: Vec<VersionQuery<'a>>,
     pub payloads: Vec<&'a str>,
 }
 
 impl<'a> ReleaseQuery<'a> {
+    /// Accepts releases of any channel, newest first, ignoring `channels`.
+    pub fn all_channels(mut self) -> Self {
+        self.all_channels = true;
+        self
+    }
+
+    pub fn is_all_channels(&self) -> bool {
+        self.all_channels
+    }
+
     pub(crate) fn and_payloads(mut self, payloads: Vec<&'a str>) -> Self {
         self.payloads = payloads;
         self
     }
+
+    /// What a release must match, for error messages.
+    pub(crate) fn criteria(&self) -> String {
+        let mut criteria = format!("platform `{}`", self.platform);
+        if let Some(arch) = self.arch {
+            criteria.push_str(&format!(", arch `{}`", arch));
+        }
+        if self.all_channels {
+            criteria.push_str(", any channel");
+        } else if !self.channels.is_empty() {
+            criteria.push_str(&format!(", channel `{}`", self.channels.join("`, `")));
+        }
+        if !self.versions.is_empty() {
+            criteria.push_str(&format!(", version {:?}", self.versions));
+        }
+        criteria
+    }
 }
 
 impl<'a> Default for ReleaseQuery<'a> {
@@ -66,6 +130,7 @@ impl<'a> Default for ReleaseQuery<'a> {
             platform: defaults::platform(),
             arch: defaults::arch(),
             channels: vec![],
+            all_channels: false,
             versions: vec![],
             payloads: defaults::payloads().to_vec(),
         }
@@ -77,6 +142,7 @@ impl<'a> Default for ReleaseQuery<'a> {
 pub enum VersionQuery<'a> {
     Match(&'a str),
     Semantic(semver::VersionReq),
+    AtMost(Version),
 }
 
 #[inline(always)]
@@ -87,6 +153,8 @@ fn empty_payloads() -> &'static [&'static str] {
 pub(crate) struct ReleaseQueryIter<'a> {
     query: &'a ReleaseQuery<'a>,
     descriptor: &'a pahkat_types::package::Descriptor,
+    /// Indexes of the releases to try, in order.
+    releases: Vec<usize>,
     next_release: usize,
 }
 
@@ -119,6 +187,12 @@ impl<'a> ReleaseQueryIter<'a> {
                         is_match = false;
                     }
                 }
+                (VersionQuery::AtMost(x), v) => {
+                    if v > x {
+                        log::trace!("Skipping (release version is newer than pin)");
+                        is_match = false;
+                    }
+                }
                 _ => {
                     log::trace!("Skipping (unhandled values)");
                     is_match = false;
@@ -133,7 +207,11 @@ impl<'a> ReleaseQueryIter<'a> {
     fn next_release(&mut self) -> Option<ReleaseQueryResponse<'a>> {
         log::trace!("Beginning release query iter: {:?}", &self.query);
 
-        while let Some(release) = self.descriptor.release.get(self.next_release) {
+        while let Some(release) = self
+            .releases
+            .get(self.next_release)
+            .and_then(|i| self.descriptor.release.get(*i))
+        {
             log::trace!(
                 "Candidate release: version:{:?}, channel:{:?}",
                 &release.version.to_string(),
@@ -141,7 +219,9 @@ impl<'a> ReleaseQueryIter<'a> {
             );
 
             // If query is empty, it means search only for the main empty channel
-            if let Some(channel) = release.channel.as_ref().map(|x| x.as_str()) {
+            if self.query.all_channels {
+                // Any channel is accepted.
+            } else if let Some(channel) = release.channel.as_ref().map(|x| x.as_str()) {
                 if !self.query.channels.contains(&channel) {
                     log::trace!("Skipping (not accepted channel)");
                     self.next_release += 1;
@@ -217,33 +297,49 @@ impl<'a> ReleaseQuery<'a> {
         &'a self,
         descriptor: &'a pahkat_types::package::Descriptor,
     ) -> ReleaseQueryIter<'a> {
+        let mut releases = (0..descriptor.release.len()).collect::<Vec<_>>();
+        // Each channel lists its releases newest first, but the channels can be
+        // interleaved in any order, so compare versions instead.
+        if self.all_channels {
+            releases.sort_by(|a, b| {
+                let a = &descriptor.release[*a].version;
+                let b = &descriptor.release[*b].version;
+                b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal)
+            });
+        }
+
         ReleaseQueryIter {
             query: self,
             descriptor,
+            releases,
             next_release: 0,
         }
     }
 
-    pub fn new(key: &'a PackageKey, repos: &'a HashMap<RepoUrl, LoadedRepository>) -> Self {
-        let channels = key
-            .query
-            .channel
-            .as_ref()
-            .map(|x| vec![&**x])
-            .unwrap_or_else(|| {
-                repos
-                    .iter()
-                    .find_map(|(url, repo)| {
-                        if &key.repository_url == url {
-                            let channel = repo.meta().channel.as_ref().map(|x| &**x);
-                            channel
-                        } else {
-                            None
-                        }
-                    })
-                    .map(|x| vec![x])
-                    .unwrap_or_else(|| vec![])
-            });
+    /// The releases `key` resolves to, narrowed to at most its pinned version in
+    /// `config`'s settings unless the key names a version.
+    pub fn new(
+        key: &'a PackageKey,
+        repos: &'a HashMap<RepoUrl, LoadedRepository>,
+        config: &Config,
+    ) -> Self {
+        let channel_query = key.channel_query();
+        let channels = match channel_query {
+            ChannelQuery::Channel(channel) => vec![channel],
+            ChannelQuery::All => vec![],
+            ChannelQuery::Repository => repos
+                .iter()
+                .find_map(|(url, repo)| {
+                    if &key.repository_url == url {
+                        let channel = repo.meta().channel.as_ref().map(|x| &**x);
+                        channel
+                    } else {
+                        None
+                    }
+                })
+                .map(|x| vec![x])
+                .unwrap_or_else(|| vec![]),
+        };
 
         ReleaseQuery {
             platform: key
@@ -259,17 +355,23 @@ impl<'a> ReleaseQuery<'a> {
                 .map(|x| &**x)
                 .or_else(|| defaults::arch()),
             channels,
+            all_channels: channel_query == ChannelQuery::All,
             versions: key
                 .query
                 .version
                 .as_ref()
                 .map(|v| vec![VersionQuery::Match(&*v)])
+                .or_else(|| pinned_version(key, config).map(|v| vec![VersionQuery::AtMost(v)]))
                 .unwrap_or_else(|| vec![]),
             payloads: defaults::payloads().to_vec(),
         }
     }
 }
 
+pub(crate) fn pinned_version(key: &PackageKey, config: &Config) -> Option<Version> {
+    config.settings().pinned_version(key).cloned()
+}
+
 pub(crate) fn resolve_package<'a>(
     package_key: &PackageKey,
     repos: &'a HashMap<RepoUrl, LoadedRepository>,
@@ -305,29 +407,42 @@ pub(crate) fn resolve_package_query<'a>(
         log::debug!("In tags");
         let descriptors: Vec<ResolvedDescriptor> = repos
             .values()
+            .filter(|repo| repo.meta.enabled)
             .flat_map(|repo| {
                 let repo_url = repo.info().repository.url.clone();
 
                 log::debug!("Repo: {:?}", repo_url);
 
+                let packages = repo.packages();
+                let packages = match packages.packages() {
+                    Some(v) => v,
+                    None => {
+                        log::error!("No packages map in fbs for {:?}!", &repo_url);
+                        return vec![];
+                    }
+                };
+
                 // Collect all matching descriptors into one list
-                repo.packages()
-                    .packages()
-                    .unwrap()
+                packages
                     .iter()
                     .map(|(_, pkg)| pkg)
                     .filter(|pkg| {
-                        let pkg_tags = pkg.tags().unwrap().unwrap();
+                        let pkg_tags = match pkg.tags() {
+                            Ok(Some(v)) => v,
+                            _ => return false,
+                        };
                         pkg_tags.iter().any(|x| {
-                            let t = x.unwrap().to_string();
-                            log::debug!("Tag: {:?}", t);
-                            tags.contains(&t)
+                            x.map(|t| {
+                                log::debug!("Tag: {:?}", t);
+                                tags.iter().any(|tag| tag == t)
+                            })
+                            .unwrap_or(false)
                         })
                     })
                     .filter_map(move |pkg| {
                         let key = PackageKey::new_unchecked(
                             repo_url.clone(),
-                            pkg.id().unwrap().to_string(),
+                            pkg.id().ok()?.to_string(),
                             None,
                         );
                         let status = install_target.iter().fold(None, |acc, cur| match acc {
@@ -337,7 +452,7 @@ pub(crate) fn resolve_package_query<'a>(
 
                         let descriptor = Descriptor::try_from(&pkg).ok()?;
 
-                        ReleaseQuery::new(&key, repos)
+                        ReleaseQuery::new(&key, repos, &store.config().read().unwrap())
                             .iter(&descriptor)
                             .next()
                             .map(|x| ResolvedDescriptor {
@@ -361,6 +476,9 @@ pub(crate) fn resolve_package_query<'a>(
                 match (acc, cur.status) {
                     // If currently requires update, nothing trumps this state
                     (PackageStatus::RequiresUpdate, _) => acc,
+                    // Only requires update trumps a broken install
+                    (PackageStatus::RequiresReinstall, PackageStatus::RequiresUpdate) => cur.status,
+                    (PackageStatus::RequiresReinstall, _) => acc,
                     // Only requires update trumps NotInstalled
                     (PackageStatus::NotInstalled, PackageStatus::RequiresUpdate) => cur.status,
                     (PackageStatus::NotInstalled, PackageStatus::UpToDate) => {
@@ -417,7 +535,13 @@ pub(crate) fn resolve_payload<'a>(
         .iter(&descriptor)
         .next()
         .map(|x| (x.target.clone(), x.release.clone(), descriptor.clone()))
-        .ok_or(PayloadError::NoPayloadFound);
+        .ok_or_else(|| {
+            if descriptor.release.iter().all(|x| x.target.is_empty()) {
+                PayloadError::NoPayloadFound
+            } else {
+                PayloadError::CriteriaUnmet(query.criteria())
+            }
+        });
     result
 }
 
@@ -441,12 +565,87 @@ pub(crate) fn import<'a>(
     std::fs::copy(installer_path, &output_path)?;
     Ok(output_path)
 }
+/// Every package of the loaded repositories that `store` reports as installed,
+/// per target, followed by the installed meta-packages.
+pub(crate) fn installed<S: PackageStore + ?Sized>(
+    store: &S,
+) -> Vec<(PackageKey, InstallTarget, Version)> {
+    let keys = index_keys(&*store.repos().read().unwrap());
+    let mut installed = vec![];
+
+    for key in keys {
+        for target in [InstallTarget::System, InstallTarget::User].iter() {
+            if let Some(version) = store.installed_version(&key, *target) {
+                installed.push((key.clone(), *target, version));
+            }
+        }
+    }
+
+    installed.extend(crate::package_store::meta::installed(&store.config()));
+    installed
+}
+
+/// The installed version of the package, for any target, if a delta may be
+/// applied from it: its installed files must verify clean. Stores that can't
+/// verify their files never use deltas.
+pub(crate) fn delta_base_version<S: PackageStore + ?Sized>(
+    store: &S,
+    package_key: &PackageKey,
+) -> Option<Version> {
+    store
+        .installed()
+        .into_iter()
+        .find(|(key, _, _)| is_same_package(key, package_key))
+        .filter(|(key, target, _)| match store.verify(key, *target) {
+            Ok(report) => report.is_intact(),
+            Err(e) => {
+                log::debug!("Not using a delta for {}: {}", key, e);
+                false
+            }
+        })
+        .map(|(_, _, version)| version)
+}
+
+/// The cached payload of `installed`, from which a delta to `release` can be
+/// applied, if `release` is newer.
+fn delta_source(
+    config: &Config,
+    descriptor: &Descriptor,
+    query: &ReleaseQuery<'_>,
+    release: &Release,
+    installed: Option<&Version>,
+) -> Option<(Version, std::path::PathBuf)> {
+    use pahkat_types::AsDownloadUrl;
+
+    let installed = installed.filter(|v| *v < &release.version)?;
+
+    let mut query = query.clone();
+    query.versions = vec![];
+    let old = query
+        .iter(descriptor)
+        .find(|x| &x.release.version == installed)?;
+
+    let path = download_file_path(config, old.target.payload.as_download_url());
+    if path.is_file() {
+        Some((installed.clone(), path))
+    } else {
+        None
+    }
+}
+
+/// Downloads the payload of the release `query` resolves to, into the cache.
+///
+/// If the release is newer than `installed`, which the caller gets from
+/// [`delta_base_version`], and the payload of the installed release is still
+/// cached, a delta from it is tried first. The rebuilt payload is checked like a
+/// download, and the full payload downloaded if anything fails.
 #[must_use]
 pub(crate) fn download<'a>(
     config: &Arc<RwLock<Config>>,
     package_key: &PackageKey,
     query: &ReleaseQuery<'a>,
     repos: &HashMap<RepoUrl, LoadedRepository>,
+    installed: Option<&Version>,
 ) -> std::pin::Pin<
     Box<
         dyn futures::stream::Stream<Item = crate::package_store::DownloadEvent>
@@ -458,7 +657,7 @@ pub(crate) fn download<'a>(
     log::trace!("Downloading {} {:?}", package_key, &query);
     use pahkat_types::AsDownloadUrl;
 
-    let (target, _, _) = match resolve_payload(package_key, &query, repos) {
+    let (target, release, descriptor) = match resolve_payload(package_key, &query, repos) {
         Ok(v) => v,
         Err(e) => {
             log::error!("Failed to resolve: {} {:?}", &package_key, &query);
@@ -471,28 +670,345 @@ pub(crate) fn download<'a>(
     let url = target.payload.as_download_url().to_owned();
 
     let config = config.read().unwrap();
+    if config.settings().offline() {
+        let path = download_file_path(&*config, &url);
+        let signatures = crate::signature::SignaturePolicy::from_record(
+            config.repos().get(&package_key.repository_url),
+        );
+        log::debug!("Offline, using downloaded payload {:?}", &path);
+        return Box::pin(async_stream::stream! {
+            if !path.is_file() {
+                yield DownloadEvent::Error(crate::download::DownloadError::Offline(url.to_string()));
+            } else if let Err(e) = verify_kept_signature(&url, &path, &signatures) {
+                yield DownloadEvent::Error(e);
+            } else {
+                yield DownloadEvent::Complete(path);
+            }
+        });
+    }
+
+    let record = config.repos().get(&package_key.repository_url);
+    let candidates = mirror_candidates(&url, record.map(|r| &*r.mirrors).unwrap_or_default());
     let settings = config.settings();
     let dm = crate::download::DownloadManager::new(
         settings.download_cache_dir().to_path_buf(),
         settings.max_concurrent_downloads(),
+        &settings.proxy(),
+        &settings.timeouts(),
+        config.http_client_hook(),
     );
 
+    let repo_record = record.cloned();
+    let repo_url = package_key.repository_url.clone();
+    let auth_for = move |url: &url::Url| payload_auth(repo_record.as_ref(), &repo_url, url);
+
+    let signatures = crate::signature::SignaturePolicy::from_record(record);
+
     let output_path = crate::repo::download_dir(&*config, &url);
+    let output_file = download_file_path(&*config, &url);
+    let size = target.payload.size();
+    let delta = delta_source(&*config, &descriptor, query, &release, installed).and_then(
+        |(from, old_path)| {
+            let delta_url = crate::delta::delta_url(&url, &from.to_string())?;
+            let delta_dir = download_dir(&*config, &delta_url);
+            Some((from, old_path, delta_url, delta_dir))
+        },
+    );
+
     let stream = async_stream::stream! {
-        match dm.download(&url, output_path).await {
-            Ok(mut v) => {
-                while let Some(value) = v.next().await {
-                    yield value;
+        if let Some((from, old_path, delta_url, delta_dir)) = delta {
+            log::debug!("Trying delta {} from {}", &delta_url, &from);
+            let auth = auth_for(&delta_url);
+            let mut delta_path = None;
+            match dm.download(&delta_url, delta_dir, auth.as_ref()).await {
+                Ok(mut v) => {
+                    while let Some(value) = v.next().await {
+                        match value {
+                            DownloadEvent::Complete(path) => {
+                                delta_path = Some(path);
+                                break;
+                            }
+                            DownloadEvent::Error(e) => {
+                                log::debug!("No delta for {}: {:?}", &url, e);
+                                break;
+                            }
+                            DownloadEvent::Progress(progress) => yield DownloadEvent::Progress(progress),
+                            _ => {}
+                        }
+                    }
                 }
+                Err(e) => log::debug!("No delta for {}: {:?}", &url, e),
             }
-            Err(e) => {
-                yield DownloadEvent::Error(e);
+
+            if let Some(delta_path) = delta_path {
+                let result = crate::delta::patch(&old_path, &delta_path, &output_file, size);
+                let _ = std::fs::remove_file(&delta_path);
+
+                let verified = match result {
+                    Ok(()) if signatures.is_enabled() => {
+                        verify_payload(&dm, &url, &output_file, auth_for(&url).as_ref(), &signatures).await.is_ok()
+                    }
+                    Ok(()) => true,
+                    Err(e) => {
+                        log::warn!("Could not apply delta to {:?}: {}", &old_path, e);
+                        false
+                    }
+                };
+
+                if verified {
+                    yield DownloadEvent::Delta(from.to_string());
+                    yield DownloadEvent::Complete(output_file);
+                    return;
+                }
+                let _ = std::fs::remove_file(&output_file);
+                log::warn!("Delta for {} did not verify, downloading in full", &url);
+            }
+        }
+
+        let total = candidates.len();
+
+        for (attempt, (mirror, candidate)) in candidates.into_iter().enumerate() {
+            if attempt > 0 {
+                yield DownloadEvent::Fallback(mirror);
+            }
+
+            let auth = auth_for(&candidate);
+            let error = match dm.download(&candidate, output_path.clone(), auth.as_ref()).await {
+                Ok(mut v) => {
+                    let mut error = None;
+                    while let Some(value) = v.next().await {
+                        match value {
+                            DownloadEvent::Complete(path) => {
+                                // Verify before reporting completion, so an unverified payload
+                                // never reaches the install step.
+                                if signatures.is_enabled() {
+                                    if let Err(e) = verify_payload(&dm, &candidate, &path, auth.as_ref(), &signatures).await {
+                                        let _ = std::fs::remove_file(&path);
+                                        error = Some(e);
+                                        break;
+                                    }
+                                }
+                                yield DownloadEvent::Complete(path);
+                                return;
+                            }
+                            DownloadEvent::Error(e) => {
+                                error = Some(e);
+                                break;
+                            }
+                            value => yield value,
+                        }
+                    }
+                    match error {
+                        Some(e) => e,
+                        None => return,
+                    }
+                }
+                Err(e) => e,
+            };
+
+            if matches!(error, crate::download::DownloadError::UserCancelled) {
+                yield DownloadEvent::Error(error);
+                return;
+            }
+
+            record_mirror_failure(&candidate);
+            if attempt + 1 < total {
+                log::warn!("Download from {} failed: {:?}", &candidate, &error);
+                continue;
             }
+
+            yield DownloadEvent::Error(error);
         }
     };
     Box::pin(stream)
 }
 
+/// The credentials of the repository at `repo_url` to send when downloading `url`.
+/// A repository's credentials are never sent to a payload hosted elsewhere.
+fn payload_auth(
+    record: Option<&crate::config::RepoRecord>,
+    repo_url: &RepoUrl,
+    url: &url::Url,
+) -> Option<crate::config::RepoAuth> {
+    let repo_origin = url::Url::parse(&repo_url.to_string()).ok()?.origin();
+    record
+        .and_then(|record| record.auth.clone())
+        .filter(|_| repo_origin == url.origin())
+}
+
+/// Checks the cached payload `url` of `package_key` against its repository's
+/// signature policy, as a fresh download would be. A payload that fails is
+/// removed so the next attempt downloads it again.
+pub(crate) async fn verify_cached_payload(
+    config: &Arc<RwLock<Config>>,
+    package_key: &PackageKey,
+    url: &url::Url,
+) -> Result<(), crate::download::DownloadError> {
+    let (dm, path, auth, signatures) = {
+        let config = config.read().unwrap();
+        let record = config.repos().get(&package_key.repository_url);
+        let signatures = crate::signature::SignaturePolicy::from_record(record);
+        if !signatures.is_enabled() {
+            return Ok(());
+        }
+
+        let settings = config.settings();
+        let dm = crate::download::DownloadManager::new(
+            settings.download_cache_dir().to_path_buf(),
+            settings.max_concurrent_downloads(),
+            &settings.proxy(),
+            &settings.timeouts(),
+            config.http_client_hook(),
+        );
+        let auth = payload_auth(record, &package_key.repository_url, url);
+        (dm, download_file_path(&*config, url), auth, signatures)
+    };
+
+    let result = verify_payload(&dm, url, &path, auth.as_ref(), &signatures).await;
+    if let Err(crate::download::DownloadError::Payload(PayloadError::SignatureInvalid(_))) = &result
+    {
+        let _ = std::fs::remove_file(&path);
+    }
+    result
+}
+
+/// Failed downloads per origin in this process, used to try healthy mirrors first.
+static MIRROR_FAILURES: once_cell::sync::Lazy<std::sync::Mutex<HashMap<String, usize>>> =
+    once_cell::sync::Lazy::new(Default::default);
+
+fn mirror_failures(url: &url::Url) -> usize {
+    MIRROR_FAILURES
+        .lock()
+        .unwrap()
+        .get(&url.origin().ascii_serialization())
+        .copied()
+        .unwrap_or(0)
+}
+
+fn record_mirror_failure(url: &url::Url) {
+    *MIRROR_FAILURES
+        .lock()
+        .unwrap()
+        .entry(url.origin().ascii_serialization())
+        .or_insert(0) += 1;
+}
+
+/// The payload URL followed by its equivalent on each mirror, with the ones that
+/// have failed least often this session first. Each comes with its number in the
+/// config: 0 for the payload URL, then the mirrors from 1.
+fn mirror_candidates(url: &url::Url, mirrors: &[url::Url]) -> Vec<(usize, url::Url)> {
+    let mut candidates = vec![(0, url.clone())];
+
+    for (index, mirror) in mirrors.iter().enumerate() {
+        let mut candidate = mirror.clone();
+        candidate.set_path(&format!(
+            "{}{}",
+            mirror.path().trim_end_matches('/'),
+            url.path()
+        ));
+        candidate.set_query(url.query());
+        candidates.push((index + 1, candidate));
+    }
+
+    // Stable, so the configured order breaks ties.
+    candidates.sort_by_key(|(_, candidate)| mirror_failures(candidate));
+    candidates
+}
+
+/// Removes what was partially downloaded of `url` from any of its mirrors, so it
+/// won't be resumed. Returns how many files were removed.
+pub(crate) fn remove_partial_download(
+    config: &Config,
+    package_key: &PackageKey,
+    url: &url::Url,
+) -> usize {
+    use crate::ext::PathExt;
+
+    let record = config.repos().get(&package_key.repository_url);
+    let download_cache_dir = config.settings().download_cache_dir();
+    let mut removed = 0;
+
+    for (_, candidate) in mirror_candidates(url, record.map(|r| &*r.mirrors).unwrap_or_default()) {
+        let filename = match candidate.path_segments().and_then(|x| x.last()) {
+            Some(v) => v.to_string(),
+            None => continue,
+        };
+        let cache_dir = download_cache_dir.join_sha256(candidate.as_str().as_bytes());
+
+        for path in [
+            cache_dir.join(format!("{}.part", filename)),
+            cache_dir.join(format!("{}.part.etag", filename)),
+        ] {
+            match std::fs::remove_file(&path) {
+                Ok(()) => {
+                    log::debug!("Removed partial download {:?}", &path);
+                    removed += 1;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => log::warn!("Could not remove {:?}: {:?}", &path, e),
+            }
+        }
+
+        // Only succeeds if nothing else was downloaded there.
+        let _ = std::fs::remove_dir(&cache_dir);
+    }
+
+    removed
+}
+
+async fn verify_payload(
+    dm: &crate::download::DownloadManager,
+    url: &url::Url,
+    path: &Path,
+    auth: Option<&crate::config::RepoAuth>,
+    signatures: &crate::signature::SignaturePolicy,
+) -> Result<(), crate::download::DownloadError> {
+    use crate::download::DownloadError;
+
+    let signature = dm.download_signature(url, auth).await?;
+    let data = std::fs::read(path).map_err(|e| DownloadError::ReadFailed(e, path.to_path_buf()))?;
+
+    if signatures.accepts(&data, signature.as_deref(), url.as_str()) {
+        // Kept so the payload can be checked again when it is used offline.
+        if let Some(signature) = signature {
+            let signature_path = crate::signature::signature_path(path);
+            if let Err(e) = std::fs::write(&signature_path, signature) {
+                log::warn!("Could not keep signature {:?}: {:?}", &signature_path, e);
+            }
+        }
+        Ok(())
+    } else {
+        Err(DownloadError::Payload(PayloadError::SignatureInvalid(
+            url.to_string(),
+        )))
+    }
+}
+
+/// Checks the payload `url` at `path` against the signature kept next to it by
+/// [`verify_payload`] or a bundle import, for when there is no server to ask.
+fn verify_kept_signature(
+    url: &url::Url,
+    path: &Path,
+    signatures: &crate::signature::SignaturePolicy,
+) -> Result<(), crate::download::DownloadError> {
+    use crate::download::DownloadError;
+
+    if !signatures.is_enabled() {
+        return Ok(());
+    }
+
+    let signature = crate::signature::read_signature(path);
+    let data = std::fs::read(path).map_err(|e| DownloadError::ReadFailed(e, path.to_path_buf()))?;
+
+    if signatures.accepts(&data, signature.as_deref(), url.as_str()) {
+        Ok(())
+    } else {
+        Err(DownloadError::Payload(PayloadError::SignatureInvalid(
+            url.to_string(),
+        )))
+    }
+}
+
 pub(crate) fn download_dir(config: &Config, url: &url::Url) -> std::path::PathBuf {
     let mut sha = Sha256::new();
     sha.update(url.as_str().as_bytes());
@@ -509,6 +1025,111 @@ pub(crate) fn download_dir(config: &Config, url: &url::Url) -> std::path::PathBu
         .join(part3)
 }
 
+/// Every file below `dir` with its size, and the directories deepest first.
+fn cache_entries(
+    dir: &Path,
+    files: &mut Vec<(std::path::PathBuf, u64)>,
+    dirs: &mut Vec<std::path::PathBuf>,
+) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        match entry.metadata() {
+            Ok(meta) if meta.is_dir() => cache_entries(&path, files, dirs),
+            Ok(meta) => files.push((path, meta.len())),
+            Err(_) => {}
+        }
+    }
+
+    dirs.push(dir.to_path_buf());
+}
+
+pub(crate) fn cache_size(config: &Config) -> u64 {
+    let settings = config.settings();
+    let mut files = vec![];
+    for dir in [
+        settings.download_cache_dir(),
+        settings.package_cache_dir(),
+        settings.repo_cache_dir(),
+    ]
+    .iter()
+    {
+        cache_entries(dir, &mut files, &mut vec![]);
+    }
+    files.iter().map(|x| x.1).sum()
+}
+
+pub(crate) fn clear_payload_cache(
+    config: &Config,
+    indexes: bool,
+) -> Result<crate::package_store::CacheClearReport, crate::package_store::CacheError> {
+    use crate::ext::PathExt;
+    use crate::transaction::lock::{LockError, TransactionLock};
+
+    let settings = config.settings();
+
+    // Holding the lock keeps a transaction from starting while we clear. If one
+    // is already running, the payloads it may be using are left alone.
+    let lock_path = crate::transaction::lock::lock_path(settings.config_dir());
+    let lock = match TransactionLock::acquire(&lock_path) {
+        Ok(v) => Some(v),
+        Err(LockError::Locked { .. }) => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut report = crate::package_store::CacheClearReport::default();
+    let mut files = vec![];
+    let mut dirs = vec![];
+    if lock.is_some() {
+        cache_entries(&settings.download_cache_dir(), &mut files, &mut dirs);
+        cache_entries(&settings.package_cache_dir(), &mut files, &mut dirs);
+    } else {
+        let mut payloads = vec![];
+        cache_entries(&settings.download_cache_dir(), &mut payloads, &mut vec![]);
+        cache_entries(&settings.package_cache_dir(), &mut payloads, &mut vec![]);
+        report.payloads_kept = payloads.len();
+    }
+
+    if indexes {
+        let repo_cache_dir = settings.repo_cache_dir();
+        let configured = config
+            .repos()
+            .keys()
+            .map(|url| repo_cache_dir.join_sha256(url.to_string().as_bytes()))
+            .collect::<Vec<_>>();
+
+        let mut index_files = vec![];
+        cache_entries(&repo_cache_dir, &mut index_files, &mut dirs);
+        files.extend(
+            index_files
+                .into_iter()
+                .filter(|(path, _)| !configured.iter().any(|dir| path.starts_with(dir))),
+        );
+    }
+
+    for (path, size) in files {
+        std::fs::remove_file(&path)?;
+        report.files_removed += 1;
+        report.bytes_freed += size;
+    }
+
+    // Only succeeds for directories left empty.
+    let roots = [
+        settings.download_cache_dir(),
+        settings.package_cache_dir(),
+        settings.repo_cache_dir(),
+    ];
+    for dir in dirs.into_iter().filter(|x| !roots.contains(x)) {
+        let _ = std::fs::remove_dir(dir);
+    }
+
+    Ok(report)
+}
+
 pub(crate) fn download_file_path(config: &Config, url: &url::Url) -> std::path::PathBuf {
     download_dir(config, url).join(
         url.path_segments()
@@ -543,12 +1164,22 @@ pub(crate) fn all_statuses<'a>(
             }
         };
 
-        for id in packages.keys() {
-            let key =
-                PackageKey::new_unchecked(repo.info().repository.url.clone(), id.to_string(), None);
-            let status = store.status(&key, target);
-            log::trace!("Package: {:?}, status: {:?}", &id, &status);
-            map.insert(id.to_string(), status);
+        let keys = packages
+            .keys()
+            .map(|id| {
+                let key = PackageKey::new_unchecked(
+                    repo.info().repository.url.clone(),
+                    id.to_string(),
+                    None,
+                );
+                (key, target)
+            })
+            .collect::<Vec<_>>();
+        let statuses = store.statuses(&keys);
+
+        for ((key, _), status) in keys.into_iter().zip(statuses.into_iter()) {
+            log::trace!("Package: {:?}, status: {:?}", &key.id, &status);
+            map.insert(key.id, status);
         }
     } else {
         log::warn!("Did not find repo {:?} in available repos", &repo_url);
@@ -561,7 +1192,18 @@ pub(crate) fn all_statuses<'a>(
 pub(crate) async fn strings<'p>(
     repo_urls: Vec<RepoUrl>,
     language: String,
+    proxy: crate::config::ProxySettings,
+    timeouts: crate::config::TimeoutSettings,
+    http: crate::download::HttpClientHook,
 ) -> HashMap<RepoUrl, crate::package_store::LocalizedStrings> {
+    let client = match crate::download::client_builder(&proxy, &timeouts, &http).build() {
+        Ok(v) => v,
+        Err(e) => {
+            log::error!("Could not create HTTP client: {:?}", e);
+            return HashMap::new();
+        }
+    };
+
     let futures = repo_urls
         .into_iter()
         .map(|url| {
@@ -572,24 +1214,34 @@ pub(crate) async fn strings<'p>(
                 .unwrap();
             (url, strings_url)
         })
-        .map(|(url, strings_url)| async move {
-            let (tx, rx) = tokio::sync::oneshot::channel();
-            tokio::spawn(async move {
-                let response = match reqwest::get(strings_url).await {
-                    Ok(v) => match v.text().await {
-                        Ok(v) => match toml::from_str(&v) {
-                            Ok(v) => Some(v),
+        .map(|(url, strings_url)| {
+            let client = client.clone();
+            let read_timeout = timeouts.read();
+            async move {
+                let (tx, rx) = tokio::sync::oneshot::channel();
+                tokio::spawn(async move {
+                    let fetch = async move {
+                        match client.get(strings_url).send().await {
+                            Ok(v) => match v.text().await {
+                                Ok(v) => match toml::from_str(&v) {
+                                    Ok(v) => Some(v),
+                                    Err(_) => None,
+                                },
+                                Err(_) => None,
+                            },
                             Err(_) => None,
-                        },
-                        Err(_) => None,
-                    },
-                    Err(_) => None,
-                };
-                tx.send(response).unwrap();
-            });
-            let result = rx.await.unwrap();
-
-            (url, result)
+                        }
+                    };
+                    let response = crate::download::with_timeout(read_timeout, fetch)
+                        .await
+                        .ok()
+                        .flatten();
+                    tx.send(response).unwrap();
+                });
+                let result = rx.await.unwrap();
+
+                (url, result)
+            }
         })
         .collect::<Vec<_>>();
     let results = futures::future::join_all(futures).await;
@@ -600,6 +1252,28 @@ pub(crate) async fn strings<'p>(
         .collect::<HashMap<_, _>>()
 }
 
+/// Distinguishes a configured repository whose index failed to load, or that
+/// needs a newer client, from a package that simply isn't in any loaded index.
+pub(crate) fn check_repo_loaded(
+    config: &Arc<RwLock<Config>>,
+    package_key: &PackageKey,
+    repos: &HashMap<RepoUrl, LoadedRepository>,
+) -> Result<(), PackageStatusError> {
+    let url = &package_key.repository_url;
+    let config = config.read().unwrap();
+    if !repos.contains_key(url) && config.repos().get(url).is_some() {
+        let cache_dir = config.settings().repo_cache_dir();
+        if let Some(required) = repository::required_client_version(url, &cache_dir) {
+            return Err(PackageStatusError::RepoUnsupported {
+                required,
+                current: repository::CLIENT_VERSION.to_string(),
+            });
+        }
+        return Err(PackageStatusError::RepoUnavailable(url.to_string()));
+    }
+    Ok(())
+}
+
 pub(crate) fn find_package_by_key<'p>(
     package_key: &PackageKey,
     repos: &'p HashMap<RepoUrl, LoadedRepository>,
@@ -612,7 +1286,6 @@ pub(crate) fn find_package_by_key<'p>(
 
     repos.get(&package_key.repository_url).and_then(|r| {
         log::trace!("Got repo: {}", &r.info.repository.url);
-        // TODO: need to check that any release supports the requested channel
         let packages = r.packages();
         let packages = match packages.packages() {
             Some(v) => v,
@@ -631,10 +1304,95 @@ pub(crate) fn find_package_by_key<'p>(
         };
         log::trace!("Found pkg: {}", &package_key);
 
-        (&pkg).try_into().map(Package::Concrete).ok()
+        let descriptor: Descriptor = (&pkg).try_into().ok()?;
+
+        // A channel override only finds packages with a release in that channel,
+        // or in the main channel, which every query accepts.
+        if let ChannelQuery::Channel(channel) = package_key.channel_query() {
+            let has_channel = descriptor
+                .release
+                .iter()
+                .any(|x| x.channel.as_deref().map_or(true, |c| c == channel));
+            if !has_channel {
+                log::trace!("No release of {} in channel {:?}", &package_key, channel);
+                return None;
+            }
+        }
+
+        Some(Package::Concrete(descriptor))
     })
 }
 
+/// As [`find_package_by_key`], saying why the package wasn't found. A package is
+/// only found if one of its releases has a payload for this platform in the
+/// key's channel.
+pub(crate) fn try_find_package_by_key(
+    config: &Config,
+    package_key: &PackageKey,
+    repos: &HashMap<RepoUrl, LoadedRepository>,
+) -> Result<Package, ResolveError> {
+    let url = &package_key.repository_url;
+    let repo = match repos.get(url) {
+        Some(v) => v,
+        None if config.repos().get(url).is_none() => {
+            return Err(ResolveError::RepoNotConfigured(url.clone()))
+        }
+        None => {
+            let cache_dir = config.settings().repo_cache_dir();
+            return Err(match repository::required_client_version(url, &cache_dir) {
+                Some(required) => ResolveError::RepoUnsupported {
+                    url: url.clone(),
+                    required,
+                },
+                None => ResolveError::RepoUnavailable(url.clone()),
+            });
+        }
+    };
+
+    let packages = repo.packages();
+    let pkg = packages
+        .packages()
+        .and_then(|x| x.get(&package_key.id))
+        .ok_or_else(|| ResolveError::NotInRepo(package_key.clone()))?;
+    let descriptor: Descriptor = (&pkg)
+        .try_into()
+        .map_err(|_| ResolveError::InvalidPackage(package_key.clone()))?;
+
+    let query = ReleaseQuery::new(package_key, repos, config);
+    if query.iter(&descriptor).next().is_none() {
+        return Err(ResolveError::NoMatchingRelease(package_key.clone()));
+    }
+
+    Ok(Package::Concrete(descriptor))
+}
+
+/// Orders enabled repositories by descending priority, then by their position in
+/// the repository config. Linked repositories not in the config come last.
+pub(crate) fn repos_by_priority(
+    repos: &HashMap<RepoUrl, LoadedRepository>,
+) -> Vec<&LoadedRepository> {
+    let mut repos = repos
+        .values()
+        .filter(|repo| repo.meta.enabled)
+        .collect::<Vec<_>>();
+    repos.sort_by(|a, b| {
+        b.meta.priority.cmp(&a.meta.priority).then_with(|| {
+            match (a.meta.config_order, b.meta.config_order) {
+                (Some(a), Some(b)) => a.cmp(&b),
+                (Some(_), None) => std::cmp::Ordering::Less,
+                (None, Some(_)) => std::cmp::Ordering::Greater,
+                (None, None) => a
+                    .info
+                    .repository
+                    .url
+                    .to_string()
+                    .cmp(&b.info.repository.url.to_string()),
+            }
+        })
+    });
+    repos
+}
+
 pub(crate) fn find_package_by_id(
     store: &dyn PackageStore,
     package_id: &str,
@@ -645,12 +1403,15 @@ pub(crate) fn find_package_by_id(
         Err(_) => {}
     };
 
-    repos.iter().find_map(|(key, repo)| {
+    repos_by_priority(repos).into_iter().find_map(|repo| {
         let packages = repo.packages();
         let packages = match packages.packages() {
             Some(v) => v,
             None => {
-                log::error!("No packages map in fbs for {:?}!", &key);
+                log::error!(
+                    "No packages map in fbs for {:?}!",
+                    &repo.info().repository.url
+                );
                 return None;
             }
         };
@@ -667,36 +1428,223 @@ pub(crate) fn find_package_by_id(
     })
 }
 
-pub async fn work<T, W, F, C>(
-    context: C,
-    starting_items: SegQueue<W>,
-    worker: F,
-) -> Result<HashMap<W, T>, JoinError>
-where
-    W: std::fmt::Debug + Clone + Eq + Hash + Send + Sync + 'static,
-    F: Fn(W, Arc<SegQueue<W>>, Arc<C>) -> Pin<Box<dyn Future<Output = T> + Send + Sync + 'static>>
-        + Send
-        + Sync
-        + 'static,
-    T: Send + Sync + 'static,
-{
-    let context = Arc::new(context);
-    let work_queue = Arc::new(starting_items);
-    let fut_queue = Arc::new(SegQueue::<(W, JoinHandle<T>)>::new());
+fn normalize_lang_tag(tag: &str) -> String {
+    tag.replace('_', "-").to_lowercase()
+}
 
-    let mut is_processed = HashSet::<W>::new();
-    let mut completed = HashMap::<W, T>::new();
+/// The channels the index of the repository at `url` declares, or none if it
+/// isn't loaded.
+pub(crate) fn channels(url: &RepoUrl, repos: &HashMap<RepoUrl, LoadedRepository>) -> Vec<String> {
+    repos
+        .get(url)
+        .map(|repo| repo.info().repository.channels.clone())
+        .unwrap_or_default()
+}
 
-    loop {
-        if fut_queue.is_empty() && work_queue.is_empty() {
-            log::trace!("All queues empty, breaking.");
-            break;
+/// The default language the index of `key`'s repository declares, if any.
+pub(crate) fn default_language<'a>(
+    key: &PackageKey,
+    repos: &'a HashMap<RepoUrl, LoadedRepository>,
+) -> Option<&'a str> {
+    repos
+        .get(&key.repository_url)?
+        .meta
+        .default_language
+        .as_deref()
+}
+
+/// Picks the entry of `map` for `locale`: the exact tag, then its language alone,
+/// then the repository's `default_language`, then [`defaults::DEFAULT_LANGUAGE`],
+/// then whichever entry comes first.
+pub(crate) fn localized<'m>(
+    map: &'m pahkat_types::LangTagMap<String>,
+    locale: &str,
+    default_language: Option<&str>,
+) -> Option<&'m String> {
+    let locale = normalize_lang_tag(locale);
+    let language = locale.split('-').next().unwrap_or("");
+
+    let find = |tag: &str| {
+        map.iter()
+            .find(|(k, _)| normalize_lang_tag(k) == tag)
+            .map(|(_, v)| v)
+    };
+
+    find(&locale)
+        .or_else(|| find(language))
+        .or_else(|| default_language.and_then(|x| find(&normalize_lang_tag(x))))
+        .or_else(|| find(defaults::DEFAULT_LANGUAGE))
+        .or_else(|| map.values().next())
+}
+
+/// Relevance of `descriptor` to `terms`, or `None` if any term is missing.
+/// Terms are expected to be lowercase.
+fn search_score(descriptor: &Descriptor, terms: &[String]) -> Option<u32> {
+    let id = descriptor.package.id.to_lowercase();
+    let names = descriptor
+        .name
+        .values()
+        .map(|x| x.to_lowercase())
+        .collect::<Vec<_>>();
+    let descriptions = descriptor
+        .description
+        .values()
+        .map(|x| x.to_lowercase())
+        .collect::<Vec<_>>();
+
+    terms.iter().try_fold(0, |score, term| {
+        let term = term.as_str();
+        let term_score = if id == term {
+            100
+        } else if names.iter().any(|x| x == term) {
+            80
+        } else if id.starts_with(term) {
+            50
+        } else if names.iter().any(|x| x.contains(term)) {
+            30
+        } else if id.contains(term) {
+            20
+        } else if descriptions.iter().any(|x| x.contains(term)) {
+            5
+        } else {
+            return None;
+        };
+        Some(score + term_score)
+    })
+}
+
+pub(crate) fn search<S: PackageStore + ?Sized>(
+    store: &S,
+    query: &str,
+    options: &crate::package_store::SearchOptions,
+    repos: &HashMap<RepoUrl, LoadedRepository>,
+) -> Vec<crate::package_store::SearchHit> {
+    use crate::fbs::DescriptorExt;
+    use crate::package_store::SearchHit;
+
+    let language = options
+        .language
+        .clone()
+        .or_else(defaults::system_locale)
+        .unwrap_or_else(|| defaults::DEFAULT_LANGUAGE.to_string());
+
+    let terms = query
+        .split_whitespace()
+        .map(|x| x.to_lowercase())
+        .collect::<Vec<_>>();
+
+    let mut hits = repos
+        .values()
+        .filter(|repo| repo.meta.enabled)
+        .flat_map(|repo| {
+            let repo_url = repo.info().repository.url.clone();
+            let packages = repo.packages();
+            let packages = match packages.packages() {
+                Some(v) => v,
+                None => {
+                    log::error!("No packages map in fbs for {:?}!", &repo_url);
+                    return vec![];
+                }
+            };
+
+            packages
+                .iter()
+                .filter_map(|(_, pkg)| {
+                    if !options.tags.is_empty() {
+                        let pkg_tags = pkg.tags().ok()??;
+                        if !pkg_tags.iter().any(|x| {
+                            x.map(|t| options.tags.iter().any(|tag| tag == t))
+                                .unwrap_or(false)
+                        }) {
+                            return None;
+                        }
+                    }
+
+                    let descriptor = Descriptor::try_from(&pkg).ok()?;
+                    let score = search_score(&descriptor, &terms)?;
+                    let key = PackageKey::new_unchecked(
+                        repo_url.clone(),
+                        descriptor.package.id.clone(),
+                        None,
+                    );
+
+                    if let Some(installed) = options.installed {
+                        let is_installed = match store.status(&key, options.target) {
+                            Ok(PackageStatus::NotInstalled) | Err(_) => false,
+                            Ok(_) => true,
+                        };
+                        if is_installed != installed {
+                            return None;
+                        }
+                    }
+
+                    let name =
+                        localized(&descriptor.name, &language, default_language(&key, repos))
+                            .cloned()
+                            .unwrap_or_else(|| descriptor.package.id.clone());
+
+                    let version = ReleaseQuery::new(&key, repos, &store.config().read().unwrap())
+                        .iter(&descriptor)
+                        .next()
+                        .map(|x| x.release.version.clone());
+
+                    Some(SearchHit {
+                        key,
+                        name,
+                        version,
+                        repo: repo_url.clone(),
+                        score,
+                    })
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    hits.sort_by(|a, b| {
+        b.score
+            .cmp(&a.score)
+            .then_with(|| a.name.to_lowercase().cmp(&b.name.to_lowercase()))
+            .then_with(|| a.key.to_string().cmp(&b.key.to_string()))
+    });
+
+    if let Some(limit) = options.limit {
+        hits.truncate(limit);
+    }
+
+    hits
+}
+
+pub async fn work<T, W, F, C>(
+    context: C,
+    starting_items: SegQueue<W>,
+    worker: F,
+) -> HashMap<W, Result<T, JoinError>>
+where
+    W: std::fmt::Debug + Clone + Eq + Hash + Send + Sync + 'static,
+    F: Fn(W, Arc<SegQueue<W>>, Arc<C>) -> Pin<Box<dyn Future<Output = T> + Send + Sync + 'static>>
+        + Send
+        + Sync
+        + 'static,
+    T: Send + Sync + 'static,
+{
+    let context = Arc::new(context);
+    let work_queue = Arc::new(starting_items);
+    let fut_queue = Arc::new(SegQueue::<(W, JoinHandle<T>)>::new());
+
+    let mut is_processed = HashSet::<W>::new();
+    let mut completed = HashMap::<W, Result<T, JoinError>>::new();
+
+    loop {
+        if fut_queue.is_empty() && work_queue.is_empty() {
+            log::trace!("All queues empty, breaking.");
+            break;
         }
 
         if let Ok(item) = fut_queue.pop() {
             let (work, future) = item;
             log::trace!("Got item from future queue for {:?}.", &work);
-            completed.insert(work, future.await?);
+            // A worker that panicked only loses its own item.
+            completed.insert(work, future.await);
         }
 
         while let Ok(work) = work_queue.pop() {
@@ -715,15 +1663,90 @@ where
         }
     }
 
-    Ok(completed)
+    completed
 }
 
+/// Revalidates every repository's cached index with the server.
 pub(crate) async fn refresh_repos(
     config: Config,
-) -> (
-    HashMap<RepoUrl, LoadedRepository>,
-    HashMap<RepoUrl, RepoDownloadError>,
-) {
+) -> (HashMap<RepoUrl, LoadedRepository>, Vec<RepoLoadError>) {
+    load_repos(config, Duration::from_secs(0)).await
+}
+
+/// Copies the repository's config record and pins onto a freshly loaded index.
+fn apply_repo_config(repo: &mut LoadedRepository, config: &Config, url: &RepoUrl) {
+    let record = config.repos().get_full(url);
+    repo.meta.priority = record.map(|x| x.2.priority).unwrap_or_default();
+    repo.meta.config_order = record.map(|x| x.0);
+    repo.meta.enabled = record.map(|x| x.2.enabled).unwrap_or(true);
+    if repo.meta.channel.is_none() {
+        repo.meta.channel = repo.info().repository.default_channel.clone();
+    }
+}
+
+/// The configured channel, if the index declares channels and it isn't one of them.
+pub(crate) fn unknown_channel<'a>(
+    channel: Option<&'a String>,
+    channels: &[String],
+) -> Option<&'a String> {
+    channel.filter(|channel| !channels.is_empty() && !channels.contains(channel))
+}
+
+/// Resolves `key` from `repos`, or else from its repository alone, downloading only
+/// that package's index file if the repository has a partial index. `Ok(None)` if
+/// it isn't there.
+///
+/// A configured repository missing from `repos` is added as fetched, so the key
+/// then resolves from the store as any other. One `repos` holds only partially is
+/// left as is.
+pub(crate) async fn fetch_package(
+    config: Config,
+    repos: SharedRepos,
+    key: PackageKey,
+) -> Result<Option<Descriptor>, RepoDownloadError> {
+    let url = key.repository_url.clone();
+    {
+        let repos = repos.read().unwrap();
+        match repos.get(&url) {
+            Some(repo) if !repo.is_partial() => return Ok(resolve_package(&key, &repos).ok()),
+            Some(_) => {
+                if let Ok(descriptor) = resolve_package(&key, &repos) {
+                    return Ok(Some(descriptor));
+                }
+            }
+            None => {}
+        }
+    }
+
+    let is_configured = config.repos().get(&url).is_some();
+    let options = LoadOptions::from_config(&config, &url, config.settings().repo_cache_max_age());
+
+    let mut repo = if config.settings().offline() {
+        LoadedRepository::from_cache(url.clone(), options.channel, options.cache_dir)?
+    } else {
+        LoadedRepository::from_url_partial(url.clone(), &key.id, options).await?
+    };
+    apply_repo_config(&mut repo, &config, &url);
+
+    let mut fetched = HashMap::new();
+    fetched.insert(url.clone(), repo);
+    let descriptor = resolve_package(&key, &fetched).ok();
+    if let Some(repo) = fetched.remove(&url).filter(|_| is_configured) {
+        repos.write().unwrap().entry(url).or_insert(repo);
+    }
+    Ok(descriptor)
+}
+
+/// Loads every repository, only contacting servers for indexes cached longer
+/// ago than `max_age`.
+///
+/// A repository that fails to load is left out and reported, in config order,
+/// without affecting the others.
+pub(crate) async fn load_repos(
+    config: Config,
+    max_age: Duration,
+) -> (HashMap<RepoUrl, LoadedRepository>, Vec<RepoLoadError>) {
+    let order = config.repos().keys().cloned().collect::<Vec<_>>();
     let config = Arc::new(config);
 
     log::debug!("Refreshing repos...");
@@ -744,11 +1767,22 @@ pub(crate) async fn refresh_repos(
                 Box::pin(async move {
                     log::trace!("Downloading repo at {:?}…", &url);
 
-                    let cache_dir = config.settings().repo_cache_dir();
-                    let channel = config.repos().get(&url).and_then(|r| r.channel.clone());
+                    let options = LoadOptions::from_config(&config, &url, max_age);
+
+                    let result = if config.settings().offline() {
+                        LoadedRepository::from_cache(
+                            url.clone(),
+                            options.channel,
+                            options.cache_dir,
+                        )
+                    } else {
+                        LoadedRepository::from_cache_or_url(url.clone(), options).await
+                    };
+
+                    match result {
+                        Ok(mut repo) => {
+                            apply_repo_config(&mut repo, &config, &url);
 
-                    match LoadedRepository::from_cache_or_url(url, channel, cache_dir).await {
-                        Ok(repo) => {
                             for url in repo.info().repository.linked_repositories.iter() {
                                 log::trace!("Queuing linked repo: {:?}", &url);
                                 queue.push(url.clone());
@@ -765,33 +1799,73 @@ pub(crate) async fn refresh_repos(
                 })
             })
             .await
-            .unwrap()
         }
     };
 
+    let (res_map, errors) = collect_loads(&order, repo_data);
+
+    // A configured channel the index doesn't list is only a warning, shown by
+    // `repo_status`, as the repository stays loaded so installed packages keep
+    // their status.
+    for (url, repo) in res_map.iter() {
+        let channels = &repo.info().repository.channels;
+        if let Some(channel) = unknown_channel(repo.meta.channel.as_ref(), channels) {
+            log::warn!("Repository {} has no channel named {:?}", url, channel);
+        }
+    }
+
+    log::trace!("Finished refreshing");
+
+    (res_map, errors)
+}
+
+/// Splits finished loads into the repositories that loaded and the errors of the
+/// ones that didn't, in config order.
+fn collect_loads<R>(
+    order: &[RepoUrl],
+    repo_data: HashMap<RepoUrl, Result<Result<R, RepoDownloadError>, JoinError>>,
+) -> (HashMap<RepoUrl, R>, Vec<RepoLoadError>) {
     let mut res_map = HashMap::new();
-    let mut err_map = HashMap::new();
+    let mut errors = vec![];
 
     for (key, value) in repo_data.into_iter() {
         match value {
-            Ok(v) => {
+            Ok(Ok(v)) => {
                 log::debug!("Resolved repository: {}", &key);
                 res_map.insert(key, v);
             }
-            Err(e) => {
+            Ok(Err(e)) => {
                 log::debug!("Repository resolution failed: {:?} {:?}", &key, &e);
-                err_map.insert(key, e);
+                errors.push(RepoLoadError::new(key, e));
+            }
+            Err(e) => {
+                log::error!("Loading repository {} stopped: {:?}", &key, &e);
+                errors.push(RepoLoadError::new(
+                    key.clone(),
+                    RepoDownloadError::Aborted(key),
+                ));
             }
         }
     }
 
-    log::trace!("Finished refreshing");
+    // Linked repositories not in the config come last.
+    errors.sort_by_key(|e| {
+        order
+            .iter()
+            .position(|x| x == &e.url)
+            .unwrap_or(order.len())
+    });
 
-    (res_map, err_map)
+    (res_map, errors)
 }
 
 pub(crate) fn clear_cache(config: &Arc<RwLock<Config>>) {
-    // todo!()
+    let cache_dir = config.read().unwrap().settings().repo_cache_dir();
+    match std::fs::remove_dir_all(&cache_dir) {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => log::warn!("Could not clear repo cache {:?}: {:?}", &cache_dir, e),
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -806,218 +1880,1019 @@ pub struct PackageCandidate {
 }
 
 impl PackageCandidate {
-    fn dependencies_in_set(&self, set: &[PackageCandidate]) -> usize {
-        self.target
-            .dependencies
-            .keys()
-            .filter(|x| {
-                let v = x.to_package_key(&self.package_key.repository_url);
-                let key = match v {
-                    Ok(v) => v,
-                    Err(_) => return false,
-                };
-
-                set.iter().find(|x| x.package_key == key).is_some()
-            })
-            .count()
+    fn depends_on(&self, other: &PackageCandidate) -> bool {
+        target_depends_on(&self.target, &other.package_key)
     }
 }
 
-#[derive(Debug, thiserror::Error)]
-pub enum PackageCandidateError {
-    #[error("Could not resolve package status for package key: `{0}`")]
-    Status(PackageKey, #[source] PackageStatusError),
+pub(crate) fn target_depends_on(target: &Target, package_key: &PackageKey) -> bool {
+    target.dependencies.keys().any(|key| match key {
+        DependencyKey::Remote(url) => PackageKey::try_from(url)
+            .map(|key| is_same_package(&key, package_key))
+            .unwrap_or(false),
+        DependencyKey::Local(id) => id == &package_key.id,
+    })
+}
 
-    #[error("Could not resolve payload for package key: `{0}`")]
-    Payload(PackageKey, #[source] PayloadError),
+/// The install actions among `targets` that depend on each package, as
+/// [`target_depends_on`] matches them: by key without its query, or by bare id.
+#[derive(Default)]
+pub(crate) struct DependentsIndex {
+    by_key: HashMap<PackageKey, Vec<usize>>,
+    by_id: HashMap<String, Vec<usize>>,
+}
 
-    #[error("Could not resolve identifier to package key: `{0}`")]
-    UnresolvedId(String),
+impl DependentsIndex {
+    pub(crate) fn new<'a>(targets: impl Iterator<Item = &'a Target>) -> DependentsIndex {
+        let mut index = DependentsIndex::default();
+        for (i, target) in targets.enumerate() {
+            for key in target.dependencies.keys() {
+                match key {
+                    DependencyKey::Remote(url) => {
+                        if let Ok(key) = PackageKey::try_from(url) {
+                            index
+                                .by_key
+                                .entry(package_identity(&key))
+                                .or_default()
+                                .push(i);
+                        }
+                    }
+                    DependencyKey::Local(id) => index.by_id.entry(id.clone()).or_default().push(i),
+                }
+            }
+        }
+        index
+    }
 
-    #[error("Attempting to uninstall package required by installation set: `{0}`")]
-    UninstallConflict(PackageKey),
+    /// Positions of the targets depending on `key`, in order.
+    pub(crate) fn dependents(&self, key: &PackageKey) -> Vec<usize> {
+        let mut dependents = self
+            .by_key
+            .get(&package_identity(key))
+            .into_iter()
+            .chain(self.by_id.get(&key.id))
+            .flatten()
+            .copied()
+            .collect::<Vec<_>>();
+        dependents.sort_unstable();
+        dependents.dedup();
+        dependents
+    }
 }
 
-use crate::{ext::DependencyKeyExt, package_store::InstallTarget, PackageActionType};
-use types::DependencyKey;
+pub(crate) fn available_updates<S: PackageStore + ?Sized>(
+    store: &S,
+) -> Vec<(PackageKey, Version, Version)> {
+    let repos = store.repos();
+    let repos = repos.read().unwrap();
+    let mut updates: Vec<(PackageKey, Version, Version)> = vec![];
+    let mut updated = HashSet::new();
 
-fn resolve_package_candidate(
-    store: &dyn PackageStore,
-    candidate: &(PackageActionType, PackageKey),
-    install_target: &[InstallTarget],
-    repos: &HashMap<RepoUrl, LoadedRepository>,
-) -> Result<PackageCandidate, PackageCandidateError> {
-    let package_key = &candidate.1;
-    let query = crate::repo::ReleaseQuery::new(package_key, &repos);
+    for (key, _target, installed) in store.installed() {
+        if updated.contains(&package_identity(&key)) {
+            continue;
+        }
 
-    match candidate.0 {
-        PackageActionType::Install => {
-            let status = install_target
-                .iter()
-                .fold(None, |acc, cur| match acc {
-                    Some(Ok(v)) if v != PackageStatus::NotInstalled => Some(Ok(v)),
-                    _ => Some(
-                        store
-                            .status(&package_key, *cur)
-                            .map_err(|e| PackageCandidateError::Status(package_key.to_owned(), e)),
-                    ),
-                })
-                .unwrap_or_else(|| {
-                    Err(PackageCandidateError::UnresolvedId(package_key.to_string()))
-                })?;
+        let is_enabled = repos
+            .get(&key.repository_url)
+            .map(|repo| repo.meta.enabled)
+            .unwrap_or(false);
+        if !is_enabled {
+            continue;
+        }
 
-            let (target, release, descriptor) = resolve_payload(package_key, &query, &*repos)
-                .map_err(|e| PackageCandidateError::Payload(package_key.to_owned(), e))?;
+        let query = ReleaseQuery::new(&key, &*repos, &store.config().read().unwrap());
+        let release = match resolve_payload(&key, &query, &*repos) {
+            Ok((_, release, _)) => release,
+            Err(e) => {
+                log::debug!("No update candidate for {}: {:?}", &key, e);
+                continue;
+            }
+        };
 
-            use pahkat_types::payload::Payload;
+        if release.version > installed {
+            updated.insert(package_identity(&key));
+            updates.push((key, installed, release.version));
+        }
+    }
 
-            let is_reboot_required = match &target.payload {
-                Payload::TarballPackage(_) => false,
-                Payload::MacOSPackage(pkg) => {
-                    use pahkat_types::payload::macos::RebootSpec;
-                    match status {
-                        PackageStatus::NotInstalled => {
-                            pkg.requires_reboot.contains(&RebootSpec::Install)
-                        }
-                        PackageStatus::RequiresUpdate => {
-                            pkg.requires_reboot.contains(&RebootSpec::Update)
-                        }
-                        _ => false,
-                    }
-                }
-                Payload::WindowsExecutable(pkg) => {
-                    use pahkat_types::payload::windows::RebootSpec;
-                    match status {
-                        PackageStatus::NotInstalled => {
-                            pkg.requires_reboot.contains(&RebootSpec::Install)
-                        }
-                        PackageStatus::RequiresUpdate => {
-                            pkg.requires_reboot.contains(&RebootSpec::Update)
-                        }
-                        _ => false,
-                    }
-                }
-                _ => false,
-            };
+    updates
+}
 
-            Ok(PackageCandidate {
-                package_key: package_key.to_owned(),
-                action: candidate.0,
-                descriptor,
-                release,
-                target,
-                status,
-                is_reboot_required,
-            })
+/// Whether the payload may be installed for `target`. Only macOS packages declare
+/// their supported targets; an empty set allows any.
+pub(crate) fn payload_allows_target(
+    payload: &pahkat_types::payload::Payload,
+    target: InstallTarget,
+) -> bool {
+    use pahkat_types::payload::macos::InstallTarget as MacOSInstallTarget;
+    use pahkat_types::payload::Payload;
+
+    match payload {
+        Payload::MacOSPackage(pkg) => {
+            let target = match target {
+                InstallTarget::System => MacOSInstallTarget::System,
+                InstallTarget::User => MacOSInstallTarget::User,
+                InstallTarget::Auto => return true,
+            };
+            pkg.targets.is_empty() || pkg.targets.contains(&target)
         }
-        PackageActionType::Uninstall => {
-            let status = install_target
-                .iter()
-                .fold(None, |acc, cur| match acc {
-                    Some(Ok(v)) if v != PackageStatus::NotInstalled => Some(Ok(v)),
-                    _ => Some(
-                        store
-                            .status(&package_key, *cur)
-                            .map_err(|e| PackageCandidateError::Status(package_key.to_owned(), e)),
-                    ),
-                })
-                .unwrap_or_else(|| {
-                    Err(PackageCandidateError::UnresolvedId(package_key.to_string()))
-                })?;
+        _ => true,
+    }
+}
 
-            let (target, release, descriptor) = resolve_payload(package_key, &query, &*repos)
-                .map_err(|e| PackageCandidateError::Payload(package_key.to_owned(), e))?;
+/// The target to install dependency `key` for: `inherited` from what pulled it in,
+/// if `allows` it. Otherwise a dependency of a user install that `allows` only
+/// system-wide installs goes system-wide when `system_fallback` is set.
+pub(crate) fn dependency_target(
+    key: &PackageKey,
+    inherited: InstallTarget,
+    allows: impl Fn(InstallTarget) -> bool,
+    system_fallback: bool,
+) -> Result<InstallTarget, PackageDependencyError> {
+    if allows(inherited) {
+        return Ok(inherited);
+    }
+
+    // A system-wide package can't rely on something only one user has installed.
+    match inherited {
+        InstallTarget::User if system_fallback && allows(InstallTarget::System) => {
+            Ok(InstallTarget::System)
+        }
+        _ => Err(PackageDependencyError::TargetNotAllowed(
+            key.clone(),
+            inherited,
+        )),
+    }
+}
 
-            use pahkat_types::payload::Payload;
+/// Resolves [`InstallTarget::Auto`] for `key`; any other target is returned as is.
+///
+/// A package that can't be resolved is treated as allowing either target.
+pub(crate) fn resolve_install_target(
+    key: &PackageKey,
+    target: InstallTarget,
+    repos: &HashMap<RepoUrl, LoadedRepository>,
+    config: &Config,
+) -> InstallTarget {
+    if target != InstallTarget::Auto {
+        return target;
+    }
 
-            let is_reboot_required = match &target.payload {
-                Payload::TarballPackage(_) => false,
-                Payload::MacOSPackage(pkg) => {
-                    use pahkat_types::payload::macos::RebootSpec;
-                    match status {
-                        PackageStatus::NotInstalled => {
-                            pkg.requires_reboot.contains(&RebootSpec::Uninstall)
-                        }
-                        _ => false,
-                    }
-                }
-                Payload::WindowsExecutable(pkg) => {
-                    use pahkat_types::payload::windows::RebootSpec;
-                    match status {
-                        PackageStatus::NotInstalled => {
-                            pkg.requires_reboot.contains(&RebootSpec::Uninstall)
-                        }
-                        _ => false,
-                    }
-                }
-                _ => false,
-            };
+    let query = ReleaseQuery::new(key, repos, config);
+    let payload = resolve_payload(key, &query, repos)
+        .ok()
+        .map(|x| x.0.payload);
+    let allows = |target| {
+        payload
+            .as_ref()
+            .map(|payload| payload_allows_target(payload, target))
+            .unwrap_or(true)
+    };
 
-            Ok(PackageCandidate {
-                package_key: package_key.to_owned(),
-                action: candidate.0,
-                descriptor,
-                release,
-                target,
-                status,
-                is_reboot_required,
-            })
-        }
+    if (crate::defaults::is_elevated() && allows(InstallTarget::System))
+        || !allows(InstallTarget::User)
+    {
+        InstallTarget::System
+    } else {
+        InstallTarget::User
     }
 }
 
-fn recurse_package_set(
-    store: &dyn PackageStore,
-    package_candidate: &PackageCandidate,
-    install_target: &[InstallTarget],
+/// Resolves [`InstallTarget::Auto`] for a package that may already be installed:
+/// the target `is_installed` finds it for, trying the one [`resolve_install_target`]
+/// picks first. Any other target is returned as is.
+pub(crate) fn resolve_installed_target(
+    key: &PackageKey,
+    target: InstallTarget,
     repos: &HashMap<RepoUrl, LoadedRepository>,
-    set: &mut HashMap<PackageKey, PackageCandidate>,
-) -> Result<(), PackageCandidateError> {
-    package_candidate
-        .target
-        .dependencies
-        .keys()
-        .try_fold((), |_, key| {
-            let key = match key {
-                DependencyKey::Remote(key) => PackageKey::try_from(key)
-                    .map_err(|_| PackageCandidateError::UnresolvedId(key.to_string()))?,
-                DependencyKey::Local(key) => store
-                    .find_package_by_id(key)
-                    .map(|x| x.0)
-                    .ok_or_else(|| PackageCandidateError::UnresolvedId(key.to_string()))?,
-            };
+    config: &Arc<RwLock<Config>>,
+    mut is_installed: impl FnMut(InstallTarget) -> bool,
+) -> InstallTarget {
+    if target != InstallTarget::Auto {
+        return target;
+    }
 
-            // FIXME: this uninstall thing here is a workaround to make uninstall work at all.
-            // No dependency cleanup will occur.
-            if set.contains_key(&key) || package_candidate.action == PackageActionType::Uninstall {
-                return Ok(());
+    // `is_installed` may need the config itself.
+    let preferred = resolve_install_target(key, target, repos, &config.read().unwrap());
+    let other = match preferred {
+        InstallTarget::User => InstallTarget::System,
+        _ => InstallTarget::User,
+    };
+    if !is_installed(preferred) && is_installed(other) {
+        other
+    } else {
+        preferred
+    }
+}
+
+pub(crate) fn index_keys(repos: &HashMap<RepoUrl, LoadedRepository>) -> Vec<PackageKey> {
+    let mut keys = vec![];
+
+    for (repo_url, repo) in repos.iter() {
+        let packages = repo.packages();
+        let packages = match packages.packages() {
+            Some(v) => v,
+            None => {
+                log::error!("No packages map in fbs for {:?}!", &repo_url);
+                continue;
             }
+        };
 
-            let candidate = resolve_package_candidate(
-                store,
-                &(PackageActionType::Install, key.to_owned()),
-                install_target,
-                repos,
-            )?;
-            set.insert(key, candidate);
-            Ok(())
-        })
+        keys.extend(packages.keys().map(|id| {
+            PackageKey::new_unchecked(repo.info().repository.url.clone(), id.to_string(), None)
+        }));
+    }
+
+    keys
 }
 
-pub(crate) fn resolve_package_set(
+/// Keeps the keys installed for any of `install_target`.
+fn retain_installed(
     store: &dyn PackageStore,
-    candidates: &[(PackageActionType, PackageKey)],
+    keys: Vec<PackageKey>,
     install_target: &[InstallTarget],
-) -> Result<Vec<PackageCandidate>, PackageCandidateError> {
-    let repos = store.repos();
-    let repos = repos.read().unwrap();
-
-    // Resolve initial package set
-    let mut candidate_set = candidates
-        .iter()
-        .map(|key| {
-            resolve_package_candidate(store, &key, install_target, &*repos)
-                .map(|v| (key.1.to_owned(), v))
+) -> Vec<PackageKey> {
+    let statuses = candidate_statuses(store, &keys.iter().collect::<Vec<_>>(), install_target);
+
+    keys.into_iter()
+        .zip(statuses.into_iter())
+        .filter(|(_, status)| match status {
+            Ok(status) => *status != PackageStatus::NotInstalled,
+            Err(_) => false,
+        })
+        .map(|(key, _)| key)
+        .collect()
+}
+
+/// Finds installed packages that were only pulled in as dependencies and that no
+/// package remaining after this transaction depends on, in the order they can be
+/// uninstalled.
+pub(crate) fn find_orphans(
+    store: &dyn PackageStore,
+    uninstalling: &[PackageKey],
+    installing: &[PackageKey],
+    repos: &HashMap<RepoUrl, LoadedRepository>,
+) -> Vec<(PackageKey, InstallTarget)> {
+    let installed = store.installed();
+    let mut removed = uninstalling.to_vec();
+    let mut orphans = vec![];
+
+    let targets = installed
+        .iter()
+        .map(|(key, _, _)| key)
+        .chain(installing.iter())
+        .filter_map(|key| {
+            let query = ReleaseQuery::new(key, repos, &store.config().read().unwrap());
+            resolve_payload(key, &query, repos)
+                .ok()
+                .map(|(target, _, _)| (key.clone(), target))
+        })
+        .collect::<Vec<_>>();
+
+    loop {
+        let is_removed = |key: &PackageKey| removed.iter().any(|x| is_same_package(x, key));
+
+        let found = installed
+            .iter()
+            .filter(|(key, _, _)| !is_removed(key))
+            .filter(|(key, _, _)| !installing.iter().any(|x| is_same_package(x, key)))
+            .filter(|(key, _, _)| !store.is_requested_by_user(key))
+            .filter(|(key, _, _)| {
+                !targets.iter().any(|(dependent, target)| {
+                    !is_same_package(dependent, key)
+                        && !is_removed(dependent)
+                        && target_depends_on(target, key)
+                })
+            })
+            .map(|(key, target, _)| (key.clone(), *target))
+            .collect::<Vec<_>>();
+
+        if found.is_empty() {
+            break;
+        }
+
+        for (key, target) in found {
+            if !removed.iter().any(|x| is_same_package(x, &key)) {
+                removed.push(key.clone());
+                orphans.push((key, target));
+            }
+        }
+    }
+
+    orphans
+}
+
+/// Finds the packages installed for any of `install_target` whose payload
+/// depends on `package_key`.
+pub(crate) fn installed_dependents(
+    store: &dyn PackageStore,
+    package_key: &PackageKey,
+    install_target: &[InstallTarget],
+    repos: &HashMap<RepoUrl, LoadedRepository>,
+) -> Vec<PackageKey> {
+    let dependents = index_keys(repos)
+        .into_iter()
+        .filter(|key| !is_same_package(key, package_key))
+        .filter(|key| {
+            let query = ReleaseQuery::new(key, repos, &store.config().read().unwrap());
+            match resolve_payload(key, &query, repos) {
+                Ok((target, _, _)) => target_depends_on(&target, package_key),
+                Err(_) => false,
+            }
+        })
+        .collect();
+
+    retain_installed(store, dependents, install_target)
+}
+
+/// What the index of `key`'s repository says about it, or the defaults if the
+/// repository isn't loaded or says nothing.
+pub(crate) fn package_metadata(
+    key: &PackageKey,
+    repos: &HashMap<RepoUrl, LoadedRepository>,
+) -> PackageMetadata {
+    repos
+        .get(&key.repository_url)
+        .and_then(|repo| repo.package_metadata(&key.id))
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Packages that `package_key` is declared mutually exclusive with in its
+/// repository's index. Bare identifiers refer to the package's own repository.
+pub(crate) fn declared_conflicts(
+    package_key: &PackageKey,
+    repos: &HashMap<RepoUrl, LoadedRepository>,
+) -> Vec<PackageKey> {
+    package_metadata(package_key, repos)
+        .conflicts
+        .iter()
+        .map(|value| {
+            PackageKey::try_from(value).unwrap_or_else(|_| {
+                PackageKey::new_unchecked(
+                    package_key.repository_url.clone(),
+                    value.to_string(),
+                    None,
+                )
+            })
+        })
+        .collect()
+}
+
+/// Installed file paths that `package_key` is expected to change after install.
+pub(crate) fn verify_ignored_paths(
+    package_key: &PackageKey,
+    repos: &HashMap<RepoUrl, LoadedRepository>,
+) -> Vec<String> {
+    package_metadata(package_key, repos).verify_ignore
+}
+
+pub(crate) fn is_conflicting(
+    a: &PackageKey,
+    b: &PackageKey,
+    repos: &HashMap<RepoUrl, LoadedRepository>,
+) -> bool {
+    declared_conflicts(a, repos)
+        .iter()
+        .any(|x| is_same_package(x, b))
+        || declared_conflicts(b, repos)
+            .iter()
+            .any(|x| is_same_package(x, a))
+}
+
+/// Finds the packages installed for any of `install_target` that conflict with
+/// `package_key`, whichever side declares the conflict.
+pub(crate) fn installed_conflicts(
+    store: &dyn PackageStore,
+    package_key: &PackageKey,
+    install_target: &[InstallTarget],
+    repos: &HashMap<RepoUrl, LoadedRepository>,
+) -> Vec<PackageKey> {
+    let conflicts = index_keys(repos)
+        .into_iter()
+        .filter(|key| !is_same_package(key, package_key))
+        .filter(|key| is_conflicting(package_key, key, repos))
+        .collect();
+
+    retain_installed(store, conflicts, install_target)
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum PackageCandidateError {
+    #[error("Could not resolve package status for package key: `{0}`")]
+    Status(PackageKey, #[source] PackageStatusError),
+
+    #[error("Could not resolve payload for package key: `{0}`")]
+    Payload(PackageKey, #[source] PayloadError),
+
+    #[error("Could not resolve identifier to package key: `{0}`")]
+    UnresolvedId(String),
+
+    #[error("Attempting to uninstall package required by installation set: `{0}`")]
+    UninstallConflict(PackageKey),
+
+    #[error("Dependency cycle detected: {}", format_keys(.0))]
+    DependencyCycle(Vec<PackageKey>),
+
+    #[error("Could not satisfy dependency: `{0}`")]
+    Deps(PackageKey, #[source] PackageDependencyError),
+
+    #[error("Package belongs to a disabled repository: `{0}`")]
+    RepoDisabled(PackageKey),
+
+    #[error("Resolving `{package}` exceeded the limit of {limit}")]
+    DependencyLimitExceeded {
+        package: PackageKey,
+        limit: DependencyLimit,
+    },
+}
+
+pub(crate) fn format_keys(keys: &[PackageKey]) -> String {
+    keys.iter()
+        .map(|x| format!("`{}`", x))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+use crate::{ext::DependencyKeyExt, package_store::InstallTarget, PackageActionType};
+use types::DependencyKey;
+
+/// Upper bound on the threads used to resolve statuses for one transaction.
+const STATUS_WORKERS: usize = 4;
+
+/// Below this many keys per worker, splitting the batch costs more than it saves.
+const STATUS_CHUNK_MIN: usize = 16;
+
+/// Splits `pairs` across up to [`STATUS_WORKERS`] threads, each making one
+/// [`PackageStore::statuses`] call. Results keep the order of `pairs`.
+fn concurrent_statuses(
+    store: &dyn PackageStore,
+    pairs: &[(PackageKey, InstallTarget)],
+) -> Vec<Result<PackageStatus, PackageStatusError>> {
+    let chunk_size = std::cmp::max(
+        (pairs.len() + STATUS_WORKERS - 1) / STATUS_WORKERS,
+        STATUS_CHUNK_MIN,
+    );
+    if pairs.len() <= chunk_size {
+        return store.statuses(pairs);
+    }
+
+    std::thread::scope(|scope| {
+        let handles = pairs
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || store.statuses(chunk)))
+            .collect::<Vec<_>>();
+
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    })
+}
+
+/// Resolves the status of each key across `install_target`, querying the store
+/// concurrently in batches.
+///
+/// A package counts as installed if it is installed for any of the targets.
+fn candidate_statuses(
+    store: &dyn PackageStore,
+    keys: &[&PackageKey],
+    install_target: &[InstallTarget],
+) -> Vec<Result<PackageStatus, PackageCandidateError>> {
+    let pairs = keys
+        .iter()
+        .flat_map(|key| install_target.iter().map(move |t| ((*key).clone(), *t)))
+        .collect::<Vec<_>>();
+    let mut results = concurrent_statuses(store, &pairs).into_iter();
+
+    keys.iter()
+        .map(|key| {
+            results
+                .by_ref()
+                .take(install_target.len())
+                .fold(None, |acc, cur| match acc {
+                    Some(Ok(v)) if v != PackageStatus::NotInstalled => Some(Ok(v)),
+                    _ => Some(cur.map_err(|e| PackageCandidateError::Status((*key).to_owned(), e))),
+                })
+                .unwrap_or_else(|| Err(PackageCandidateError::UnresolvedId(key.to_string())))
+        })
+        .collect()
+}
+
+fn resolve_package_candidate(
+    candidate: &(PackageActionType, PackageKey),
+    status: PackageStatus,
+    repos: &HashMap<RepoUrl, LoadedRepository>,
+    config: &Config,
+) -> Result<PackageCandidate, PackageCandidateError> {
+    let package_key = &candidate.1;
+    let query = crate::repo::ReleaseQuery::new(package_key, &repos, config);
+
+    match candidate.0 {
+        PackageActionType::Install => {
+            let is_enabled = repos
+                .get(&package_key.repository_url)
+                .map(|repo| repo.meta.enabled)
+                .unwrap_or(true);
+            if !is_enabled {
+                return Err(PackageCandidateError::RepoDisabled(package_key.to_owned()));
+            }
+
+            let (target, release, descriptor) = resolve_payload(package_key, &query, &*repos)
+                .map_err(|e| PackageCandidateError::Payload(package_key.to_owned(), e))?;
+
+            let is_reboot_required = is_reboot_required(&target.payload, candidate.0, status);
+
+            Ok(PackageCandidate {
+                package_key: package_key.to_owned(),
+                action: candidate.0,
+                descriptor,
+                release,
+                target,
+                status,
+                is_reboot_required,
+            })
+        }
+        PackageActionType::Uninstall => {
+            let (target, release, descriptor) = resolve_payload(package_key, &query, &*repos)
+                .map_err(|e| PackageCandidateError::Payload(package_key.to_owned(), e))?;
+
+            let is_reboot_required = is_reboot_required(&target.payload, candidate.0, status);
+
+            Ok(PackageCandidate {
+                package_key: package_key.to_owned(),
+                action: candidate.0,
+                descriptor,
+                release,
+                target,
+                status,
+                is_reboot_required,
+            })
+        }
+    }
+}
+
+/// Whether the payload declares that `action` on a package with `status` needs a
+/// reboot to finish. Reinstalls count as updates.
+pub(crate) fn is_reboot_required(
+    payload: &pahkat_types::payload::Payload,
+    action: PackageActionType,
+    status: PackageStatus,
+) -> bool {
+    use pahkat_types::payload::Payload;
+
+    match payload {
+        Payload::MacOSPackage(pkg) => {
+            use pahkat_types::payload::macos::RebootSpec;
+            let spec = match (action, status) {
+                (PackageActionType::Install, PackageStatus::NotInstalled) => RebootSpec::Install,
+                (PackageActionType::Install, _) => RebootSpec::Update,
+                (PackageActionType::Uninstall, PackageStatus::NotInstalled) => return false,
+                (PackageActionType::Uninstall, _) => RebootSpec::Uninstall,
+            };
+            pkg.requires_reboot.contains(&spec)
+        }
+        Payload::WindowsExecutable(pkg) => {
+            use pahkat_types::payload::windows::RebootSpec;
+            let spec = match (action, status) {
+                (PackageActionType::Install, PackageStatus::NotInstalled) => RebootSpec::Install,
+                (PackageActionType::Install, _) => RebootSpec::Update,
+                (PackageActionType::Uninstall, PackageStatus::NotInstalled) => return false,
+                (PackageActionType::Uninstall, _) => RebootSpec::Uninstall,
+            };
+            pkg.requires_reboot.contains(&spec)
+        }
+        _ => false,
+    }
+}
+
+pub(crate) fn is_same_package(a: &PackageKey, b: &PackageKey) -> bool {
+    a.repository_url == b.repository_url && a.id == b.id
+}
+
+/// `key` without its query, so keys that are the same package compare equal.
+pub(crate) fn package_identity(key: &PackageKey) -> PackageKey {
+    PackageKey::new_unchecked(key.repository_url.clone(), key.id.clone(), None)
+}
+
+/// Dependencies are resolved for the platform and architecture of `parent`,
+/// unless their key names its own.
+fn resolve_dependency_key(
+    store: &dyn PackageStore,
+    parent: &PackageKey,
+    key: &DependencyKey,
+) -> Result<PackageKey, PackageCandidateError> {
+    let mut key = match key {
+        DependencyKey::Remote(key) => PackageKey::try_from(key)
+            .map_err(|_| PackageCandidateError::UnresolvedId(key.to_string()))?,
+        DependencyKey::Local(key) => store
+            .find_package_by_id(key)
+            .map(|x| x.0)
+            .ok_or_else(|| PackageCandidateError::UnresolvedId(key.to_string()))?,
+    };
+
+    if key.query.platform.is_none() {
+        key.query.platform = parent.query.platform.clone();
+    }
+    if key.query.arch.is_none() {
+        key.query.arch = parent.query.arch.clone();
+    }
+    Ok(key)
+}
+
+fn check_dependency_cycle(
+    stack: &[PackageKey],
+    key: &PackageKey,
+) -> Result<(), PackageCandidateError> {
+    match stack.iter().position(|x| is_same_package(x, key)) {
+        Some(pos) => {
+            let mut cycle = stack[pos..].to_vec();
+            cycle.push(key.clone());
+            Err(PackageCandidateError::DependencyCycle(cycle))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Resolves `key`, required by `parent` at `version_req`, reusing the candidate in
+/// `set` if there is one, and checks the requirement against any version pin.
+fn dependency_candidate(
+    store: &dyn PackageStore,
+    parent: &PackageCandidate,
+    key: &PackageKey,
+    version_req: &str,
+    install_target: &[InstallTarget],
+    repos: &HashMap<RepoUrl, LoadedRepository>,
+    set: &mut HashMap<PackageKey, PackageCandidate>,
+) -> Result<PackageCandidate, PackageCandidateError> {
+    let pin = pinned_version(key, &store.config().read().unwrap());
+
+    let candidate = match set.get(key) {
+        Some(candidate) => candidate.clone(),
+        None => {
+            let status = candidate_statuses(store, &[key], install_target)
+                .pop()
+                .unwrap()?;
+            let candidate = resolve_package_candidate(
+                &(PackageActionType::Install, key.to_owned()),
+                status,
+                repos,
+                &store.config().read().unwrap(),
+            )
+            .map_err(|e| match (e, pin.as_ref()) {
+                (
+                    PackageCandidateError::Payload(
+                        key,
+                        PayloadError::NoPayloadFound | PayloadError::CriteriaUnmet(_),
+                    ),
+                    Some(pin),
+                ) => {
+                    let message = format!("no release of `{}` at or below pin {}", &key, pin);
+                    PackageCandidateError::Deps(
+                        key,
+                        PackageDependencyError::VersionNotFound(message),
+                    )
+                }
+                (e, _) => e,
+            })?;
+            set.insert(key.clone(), candidate.clone());
+            candidate
+        }
+    };
+
+    if let Some(pin) = pin {
+        let is_satisfied = match (
+            semver::VersionReq::parse(version_req),
+            &candidate.release.version,
+        ) {
+            (Ok(req), Version::Semantic(v)) => req.matches(&*v),
+            _ => true,
+        };
+
+        if !is_satisfied {
+            let message = format!(
+                "`{}` requires `{}` {}, but it is pinned to {}",
+                &parent.package_key, key, version_req, pin
+            );
+            return Err(PackageCandidateError::Deps(
+                key.clone(),
+                PackageDependencyError::VersionNotFound(message),
+            ));
+        }
+    }
+
+    Ok(candidate)
+}
+
+/// Where [`recurse_package_set`] is in its walk of the dependency graph.
+#[derive(Default)]
+struct DependencyWalk {
+    /// The keys currently being resolved, so revisiting one of them means the
+    /// repository describes a dependency cycle.
+    stack: Vec<PackageKey>,
+    /// The packages whose dependencies are all resolved, as [`package_identity`].
+    visited: HashSet<PackageKey>,
+}
+
+/// Resolves the dependencies of `package_candidate` into `set`, depth first.
+///
+/// Both the walk's stack and its visited packages compare keys by package,
+/// ignoring their query. Here `limits.max_dependencies` bounds the size of the
+/// whole of `set`.
+fn recurse_package_set(
+    store: &dyn PackageStore,
+    package_candidate: &PackageCandidate,
+    install_target: &[InstallTarget],
+    repos: &HashMap<RepoUrl, LoadedRepository>,
+    limits: &DependencyLimits,
+    set: &mut HashMap<PackageKey, PackageCandidate>,
+    walk: &mut DependencyWalk,
+) -> Result<(), PackageCandidateError> {
+    // FIXME: this uninstall thing here is a workaround to make uninstall work at all.
+    // No dependency cleanup will occur.
+    if package_candidate.action == PackageActionType::Uninstall {
+        return Ok(());
+    }
+
+    walk.stack.push(package_candidate.package_key.clone());
+
+    for (key, version_req) in package_candidate.target.dependencies.iter() {
+        let key = resolve_dependency_key(store, &package_candidate.package_key, key)?;
+        check_dependency_cycle(&walk.stack, &key)?;
+
+        if walk.visited.contains(&package_identity(&key)) {
+            continue;
+        }
+
+        // `key` is `walk.stack.len()` levels below the requested package.
+        if walk.stack.len() > limits.max_depth {
+            return Err(PackageCandidateError::DependencyLimitExceeded {
+                package: key,
+                limit: DependencyLimit::Depth(limits.max_depth),
+            });
+        }
+
+        let candidate = dependency_candidate(
+            store,
+            package_candidate,
+            &key,
+            version_req,
+            install_target,
+            repos,
+            set,
+        )?;
+
+        if set.len() > limits.max_dependencies {
+            return Err(PackageCandidateError::DependencyLimitExceeded {
+                package: key,
+                limit: DependencyLimit::Dependencies(limits.max_dependencies),
+            });
+        }
+
+        recurse_package_set(store, &candidate, install_target, repos, limits, set, walk)?;
+    }
+
+    walk.stack.pop();
+    walk.visited
+        .insert(package_identity(&package_candidate.package_key));
+    Ok(())
+}
+
+/// A package and, recursively, everything it would pull in when installed.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct DependencyTree {
+    pub key: PackageKey,
+    pub version: Version,
+    /// `NotInstalled` for packages installing this one would add.
+    pub status: PackageStatus,
+    /// The version requirement the parent node declared, or `None` for the root.
+    pub requirement: Option<String>,
+    /// Repeated for every package that depends on it, so shared dependencies
+    /// appear more than once.
+    pub dependencies: Vec<DependencyTree>,
+}
+
+impl DependencyTree {
+    pub fn is_installed(&self) -> bool {
+        self.status != PackageStatus::NotInstalled
+    }
+}
+
+fn build_dependency_tree(
+    store: &dyn PackageStore,
+    candidate: &PackageCandidate,
+    requirement: Option<String>,
+    install_target: &[InstallTarget],
+    repos: &HashMap<RepoUrl, LoadedRepository>,
+    set: &mut HashMap<PackageKey, PackageCandidate>,
+    stack: &mut Vec<PackageKey>,
+) -> Result<DependencyTree, PackageCandidateError> {
+    stack.push(candidate.package_key.clone());
+
+    let mut dependencies = vec![];
+    for (key, version_req) in candidate.target.dependencies.iter() {
+        let key = resolve_dependency_key(store, &candidate.package_key, key)?;
+        check_dependency_cycle(stack, &key)?;
+
+        let dependency = dependency_candidate(
+            store,
+            candidate,
+            &key,
+            version_req,
+            install_target,
+            repos,
+            set,
+        )?;
+        dependencies.push(build_dependency_tree(
+            store,
+            &dependency,
+            Some(version_req.to_string()),
+            install_target,
+            repos,
+            set,
+            stack,
+        )?);
+    }
+
+    stack.pop();
+
+    Ok(DependencyTree {
+        key: candidate.package_key.clone(),
+        version: candidate.release.version.clone(),
+        status: candidate.status,
+        requirement,
+        dependencies,
+    })
+}
+
+/// Resolves the dependencies installing `key` for `target` would pull in, without
+/// building a transaction.
+pub fn resolve_dependencies(
+    store: &dyn PackageStore,
+    key: &PackageKey,
+    target: InstallTarget,
+) -> Result<DependencyTree, PackageDependencyStatusError> {
+    let repos = store.repos();
+    let repos = repos.read().unwrap();
+
+    let status = candidate_statuses(store, &[key], &[target])
+        .pop()
+        .unwrap()?;
+    let candidate = resolve_package_candidate(
+        &(PackageActionType::Install, key.clone()),
+        status,
+        &*repos,
+        &store.config().read().unwrap(),
+    )?;
+
+    let tree = build_dependency_tree(
+        store,
+        &candidate,
+        None,
+        &[target],
+        &*repos,
+        &mut HashMap::new(),
+        &mut vec![],
+    )?;
+    Ok(tree)
+}
+
+/// Orders candidates so that dependencies are installed before their dependents,
+/// and uninstalled after them.
+fn sort_by_dependencies(
+    candidates: Vec<PackageCandidate>,
+) -> Result<Vec<PackageCandidate>, PackageCandidateError> {
+    dependency_order(
+        candidates,
+        |x| &x.package_key,
+        |candidate, other| {
+            candidate.action == other.action
+                && match candidate.action {
+                    PackageActionType::Install => candidate.depends_on(other),
+                    PackageActionType::Uninstall => other.depends_on(candidate),
+                }
+        },
+    )
+    .map_err(PackageCandidateError::DependencyCycle)
+}
+
+/// Orders `items` so that each comes after the ones it `waits_for`, or returns the
+/// keys around a cycle of items waiting on each other.
+fn dependency_order<T>(
+    mut items: Vec<T>,
+    key: impl Fn(&T) -> &PackageKey,
+    waits_for: impl Fn(&T, &T) -> bool,
+) -> Result<Vec<T>, Vec<PackageKey>> {
+    // The candidates come out of a hash map, so independent ones are put in key
+    // order to make the result the same on every run.
+    items.sort_by_cached_key(|x| key(x).to_string());
+    let len = items.len();
+
+    // For each item, the indices of the items that must be processed first.
+    let before: Vec<Vec<usize>> = items
+        .iter()
+        .enumerate()
+        .map(|(i, item)| {
+            items
+                .iter()
+                .enumerate()
+                .filter(|(j, other)| i != *j && waits_for(item, other))
+                .map(|(j, _)| j)
+                .collect()
+        })
+        .collect();
+
+    let mut is_sorted = vec![false; len];
+    let mut order = Vec::with_capacity(len);
+
+    while order.len() < len {
+        let ready = (0..len)
+            .filter(|&i| !is_sorted[i] && before[i].iter().all(|&j| is_sorted[j]))
+            .collect::<Vec<_>>();
+
+        if ready.is_empty() {
+            // Every remaining item waits on another remaining item, so walking
+            // those edges must eventually revisit a node.
+            let mut path = vec![(0..len).find(|&i| !is_sorted[i]).unwrap()];
+            loop {
+                let current = *path.last().unwrap();
+                let next = before[current]
+                    .iter()
+                    .copied()
+                    .find(|&j| !is_sorted[j])
+                    .unwrap();
+
+                if let Some(pos) = path.iter().position(|&x| x == next) {
+                    return Err(path[pos..]
+                        .iter()
+                        .chain(std::iter::once(&next))
+                        .map(|&i| key(&items[i]).clone())
+                        .collect());
+                }
+
+                path.push(next);
+            }
+        }
+
+        for i in ready {
+            is_sorted[i] = true;
+            order.push(i);
+        }
+    }
+
+    let mut items = items.into_iter().map(Some).collect::<Vec<_>>();
+    Ok(order
+        .into_iter()
+        .map(|i| items[i].take().unwrap())
+        .collect())
+}
+
+/// The packages, as [`package_identity`], whose up-to-date installs are kept:
+/// `forced`, and every candidate the packages in `forced_dependencies` depend on
+/// directly or through each other.
+fn forced_installs(
+    forced: &[PackageKey],
+    forced_dependencies: &[PackageKey],
+    candidates: &[&PackageKey],
+    depends_on: impl Fn(&PackageKey, &PackageKey) -> bool,
+) -> HashSet<PackageKey> {
+    let mut forced = forced.iter().map(package_identity).collect::<HashSet<_>>();
+    let mut pending = forced_dependencies.to_vec();
+    while let Some(parent) = pending.pop() {
+        for key in candidates {
+            if depends_on(&parent, key) && forced.insert(package_identity(key)) {
+                pending.push((*key).clone());
+            }
+        }
+    }
+    forced
+}
+
+pub(crate) fn resolve_package_set(
+    store: &dyn PackageStore,
+    candidates: &[(PackageActionType, PackageKey)],
+    install_target: &[InstallTarget],
+    forced: &[PackageKey],
+    forced_dependencies: &[PackageKey],
+    resolve_dependencies: bool,
+    limits: &DependencyLimits,
+) -> Result<Vec<PackageCandidate>, PackageCandidateError> {
+    let repos = store.repos();
+    let repos = repos.read().unwrap();
+
+    // Resolve initial package set
+    let statuses = candidate_statuses(
+        store,
+        &candidates.iter().map(|x| &x.1).collect::<Vec<_>>(),
+        install_target,
+    );
+    let mut candidate_set = candidates
+        .iter()
+        .zip(statuses.into_iter())
+        .map(|(key, status)| {
+            resolve_package_candidate(&key, status?, &*repos, &store.config().read().unwrap())
+                .map(|v| (key.1.to_owned(), v))
         })
         .collect::<Result<HashMap<_, _>, _>>()?;
 
@@ -1025,24 +2900,44 @@ pub(crate) fn resolve_package_set(
     let values = candidate_set.values().cloned().collect::<Vec<_>>();
     log::trace!("Package candidates: {:?}", &values);
 
-    values.iter().try_fold((), |_, candidate| {
-        log::trace!("Recursing packages for candidate: {:?}", candidate);
+    if resolve_dependencies {
+        // Only dependencies count towards the limit.
+        let limits = DependencyLimits {
+            max_dependencies: limits.max_dependencies.saturating_add(candidate_set.len()),
+            ..*limits
+        };
+        let mut walk = DependencyWalk::default();
+        values.iter().try_fold((), |_, candidate| {
+            log::trace!("Recursing packages for candidate: {:?}", candidate);
 
-        recurse_package_set(
-            store,
-            candidate,
-            install_target,
-            &*repos,
-            &mut candidate_set,
-        )
-    })?;
+            recurse_package_set(
+                store,
+                candidate,
+                install_target,
+                &*repos,
+                &limits,
+                &mut candidate_set,
+                &mut walk,
+            )
+        })?;
+    }
+
+    let keys = candidate_set.keys().collect::<Vec<_>>();
+    let forced = forced_installs(forced, forced_dependencies, &keys, |parent, key| {
+        candidate_set
+            .get(parent)
+            .map(|parent| target_depends_on(&parent.target, key))
+            .unwrap_or(false)
+    });
 
     // Take our candidate set and resolve it down to a mutation set
     let mutation_set: Vec<PackageCandidate> = candidate_set
         .into_iter()
         .filter_map(|(key, candidate)| {
+            // A newer installed version reports the older release as up to date.
             if candidate.action == PackageActionType::Install
                 && candidate.status == PackageStatus::UpToDate
+                && !forced.contains(&package_identity(&key))
             {
                 None
             } else if candidate.action == PackageActionType::Uninstall
@@ -1055,14 +2950,7 @@ pub(crate) fn resolve_package_set(
         })
         .collect();
 
-    let mut output_mutation_set = mutation_set.clone();
-
-    // WORKAROUND: re-order these dependencies so that they are in dependency order
-    output_mutation_set.sort_by(|a, b| {
-        let a_deps = a.dependencies_in_set(&mutation_set);
-        let b_deps = b.dependencies_in_set(&mutation_set);
-        a_deps.cmp(&b_deps)
-    });
+    let output_mutation_set = sort_by_dependencies(mutation_set)?;
 
     log::trace!(
         "Output mutation set: {:?}",
@@ -1074,3 +2962,417 @@ pub(crate) fn resolve_package_set(
 
     Ok(output_mutation_set)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(input: &str) -> PackageKey {
+        PackageKey::try_from(input).unwrap()
+    }
+
+    /// A package with a tarball for this platform in each of `releases`, given as
+    /// version and channel.
+    fn descriptor(id: &str, releases: &[(&str, Option<&str>)]) -> Descriptor {
+        let release = releases
+            .iter()
+            .map(|(version, channel)| {
+                let payload = pahkat_types::payload::tarball::Package::builder()
+                    .url(
+                        format!("https://example.com/{}-{}.txz", id, version)
+                            .parse()
+                            .unwrap(),
+                    )
+                    .size(1)
+                    .installed_size(1)
+                    .build();
+                let target = Target::builder()
+                    .platform(defaults::platform().to_string())
+                    .arch(defaults::arch().map(str::to_string))
+                    .dependencies(Default::default())
+                    .payload(pahkat_types::payload::Payload::TarballPackage(payload))
+                    .build();
+                Release::builder()
+                    .version(Version::new(version).unwrap())
+                    .channel(channel.map(str::to_string))
+                    .target(vec![target])
+                    .build()
+            })
+            .collect();
+
+        Descriptor::builder()
+            .package(
+                pahkat_types::package::DescriptorData::builder()
+                    .id(id.to_string())
+                    .tags(vec![])
+                    .build(),
+            )
+            .name(Default::default())
+            .description(Default::default())
+            .release(release)
+            .build()
+    }
+
+    /// An install of version 1.0.0 of `id` whose target depends on `needs`.
+    fn candidate(id: &str, needs: &[&str]) -> PackageCandidate {
+        let descriptor = descriptor(id, &[("1.0.0", None)]);
+        let release = descriptor.release[0].clone();
+        let mut target = release.target[0].clone();
+        target.dependencies = needs
+            .iter()
+            .map(|x| (DependencyKey::Local(x.to_string()), "*".to_string()))
+            .collect();
+
+        PackageCandidate {
+            package_key: key(&format!("https://example.com/repo/packages/{}", id)),
+            action: PackageActionType::Install,
+            descriptor,
+            release,
+            target,
+            status: PackageStatus::NotInstalled,
+            is_reboot_required: false,
+        }
+    }
+
+    fn cycle_ids(candidates: Vec<PackageCandidate>) -> Vec<String> {
+        match sort_by_dependencies(candidates) {
+            Err(PackageCandidateError::DependencyCycle(cycle)) => {
+                cycle.into_iter().map(|x| x.id).collect()
+            }
+            other => panic!("expected a cycle, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn dependency_cycle_is_found_through_keys_with_a_query() {
+        let a = key("https://example.com/repo/packages/a");
+        let b = key("https://example.com/repo/packages/b?platform=windows");
+        let a_again = key("https://example.com/repo/packages/a?platform=windows");
+        let stack = vec![a.clone(), b.clone()];
+
+        match check_dependency_cycle(&stack, &a_again) {
+            Err(PackageCandidateError::DependencyCycle(cycle)) => {
+                assert_eq!(cycle, vec![a, b, a_again])
+            }
+            other => panic!("expected a cycle, got {:?}", other),
+        }
+
+        let c = key("https://example.com/repo/packages/c");
+        assert!(check_dependency_cycle(&stack, &c).is_ok());
+    }
+
+    #[test]
+    fn two_package_cycle_is_reported_with_its_chain() {
+        let candidates = vec![candidate("a", &["b"]), candidate("b", &["a"])];
+        assert_eq!(cycle_ids(candidates), ["a", "b", "a"]);
+    }
+
+    #[test]
+    fn three_package_cycle_is_reported_with_its_chain() {
+        let candidates = vec![
+            candidate("c", &["a"]),
+            candidate("d", &[]),
+            candidate("b", &["c"]),
+            candidate("a", &["b"]),
+        ];
+        assert_eq!(cycle_ids(candidates), ["a", "b", "c", "a"]);
+    }
+
+    #[test]
+    fn mirrors_keep_their_number_when_reordered() {
+        let url: url::Url = "https://primary.invalid/repo/a.txz".parse().unwrap();
+        let mirrors: Vec<url::Url> = vec![
+            "https://one.invalid/m/".parse().unwrap(),
+            "https://two.invalid/".parse().unwrap(),
+        ];
+        record_mirror_failure(&url);
+
+        let candidates = mirror_candidates(&url, &mirrors);
+        assert_eq!(
+            candidates.iter().map(|x| x.0).collect::<Vec<_>>(),
+            [1, 2, 0]
+        );
+        assert_eq!(candidates[0].1.as_str(), "https://one.invalid/m/repo/a.txz");
+        assert_eq!(candidates[1].1.as_str(), "https://two.invalid/repo/a.txz");
+        assert_eq!(candidates[2].1, url);
+    }
+
+    #[test]
+    fn visited_packages_ignore_the_query() {
+        let mut visited = HashSet::new();
+        visited.insert(package_identity(&key(
+            "https://example.com/repo/packages/a?platform=windows",
+        )));
+
+        assert!(visited.contains(&package_identity(&key(
+            "https://example.com/repo/packages/a"
+        ))));
+        assert!(!visited.contains(&package_identity(&key(
+            "https://example.com/other/packages/a"
+        ))));
+    }
+
+    #[test]
+    fn user_install_rejects_a_system_only_dependency() {
+        let runtime = key("https://example.com/repo/packages/runtime");
+        let system_only = |target| target == InstallTarget::System;
+
+        match dependency_target(&runtime, InstallTarget::User, system_only, false) {
+            Err(PackageDependencyError::TargetNotAllowed(k, InstallTarget::User)) => {
+                assert_eq!(k, runtime)
+            }
+            other => panic!("expected TargetNotAllowed, got {:?}", other),
+        }
+        assert_eq!(
+            dependency_target(&runtime, InstallTarget::User, system_only, true).ok(),
+            Some(InstallTarget::System)
+        );
+        assert_eq!(
+            dependency_target(&runtime, InstallTarget::System, system_only, false).ok(),
+            Some(InstallTarget::System)
+        );
+    }
+
+    #[test]
+    fn system_install_rejects_a_user_only_dependency() {
+        let plugin = key("https://example.com/repo/packages/plugin");
+        let user_only = |target| target == InstallTarget::User;
+
+        assert!(matches!(
+            dependency_target(&plugin, InstallTarget::System, user_only, true),
+            Err(PackageDependencyError::TargetNotAllowed(
+                _,
+                InstallTarget::System
+            ))
+        ));
+    }
+
+    #[test]
+    fn pins_apply_without_reloading_the_repository() {
+        let mut config = Config::read_only();
+        let repos = HashMap::new();
+        let key = key("https://example.com/repo/packages/speller");
+        let descriptor = descriptor("speller", &[("2.0.0", None), ("1.0.0", None)]);
+        let newest = |config: &Config| {
+            ReleaseQuery::new(&key, &repos, config)
+                .iter(&descriptor)
+                .next()
+                .map(|x| x.release.version.to_string())
+        };
+
+        assert_eq!(newest(&config).as_deref(), Some("2.0.0"));
+        config
+            .settings_mut()
+            .pin_version(&key, Version::new("1.0.0").unwrap())
+            .unwrap();
+        assert_eq!(newest(&config).as_deref(), Some("1.0.0"));
+        config.settings_mut().unpin_version(&key).unwrap();
+        assert_eq!(newest(&config).as_deref(), Some("2.0.0"));
+    }
+
+    #[test]
+    fn auto_target_follows_the_installed_package() {
+        let repos = HashMap::new();
+        let config = Arc::new(RwLock::new(Config::read_only()));
+        let key = key("https://example.com/repo/packages/a");
+
+        for installed in [InstallTarget::User, InstallTarget::System].iter() {
+            let target =
+                resolve_installed_target(&key, InstallTarget::Auto, &repos, &config, |t| {
+                    t == *installed
+                });
+            assert_eq!(target, *installed);
+        }
+
+        let preferred =
+            resolve_install_target(&key, InstallTarget::Auto, &repos, &config.read().unwrap());
+        let target =
+            resolve_installed_target(&key, InstallTarget::Auto, &repos, &config, |_| false);
+        assert_eq!(target, preferred);
+
+        let target = resolve_installed_target(&key, InstallTarget::User, &repos, &config, |t| {
+            t == InstallTarget::System
+        });
+        assert_eq!(target, InstallTarget::User);
+    }
+
+    #[test]
+    fn reinstalled_dependencies_are_forced_transitively() {
+        let app = key("https://example.com/repo/packages/app");
+        let lib = key("https://example.com/repo/packages/lib?platform=windows");
+        let runtime = key("https://example.com/repo/packages/runtime");
+        let other = key("https://example.com/repo/packages/other");
+        let candidates = [&app, &lib, &runtime, &other];
+        // lib and runtime depend on each other, which must not loop.
+        let depends_on = |parent: &PackageKey, key: &PackageKey| {
+            (parent.id == "app" && key.id == "lib")
+                || (parent.id == "lib" && key.id == "runtime")
+                || (parent.id == "runtime" && key.id == "lib")
+        };
+
+        let forced = forced_installs(&[app.clone()], &[app.clone()], &candidates, depends_on);
+        for key in [&app, &lib, &runtime].iter() {
+            assert!(
+                forced.contains(&package_identity(key)),
+                "{} not forced",
+                key
+            );
+        }
+        assert!(!forced.contains(&package_identity(&other)));
+
+        let forced = forced_installs(&[app.clone()], &[], &candidates, depends_on);
+        assert_eq!(forced.len(), 1);
+    }
+
+    #[test]
+    fn dependency_order_is_the_same_on_every_build() {
+        let deps: &[(&str, &[&str])] = &[
+            ("app", &["lib", "runtime"]),
+            ("lib", &["runtime"]),
+            ("runtime", &[]),
+            ("fonts", &[]),
+            ("docs", &[]),
+            ("speller", &["runtime"]),
+        ];
+        let order = |rotation: usize| {
+            let mut input = HashMap::new();
+            for (id, needs) in deps.iter().cycle().skip(rotation).take(deps.len()) {
+                input.insert(
+                    key(&format!("https://example.com/repo/packages/{}", id)),
+                    *needs,
+                );
+            }
+            let items = input.into_iter().collect::<Vec<_>>();
+            dependency_order(
+                items,
+                |x| &x.0,
+                |item, other| item.1.contains(&&*other.0.id),
+            )
+            .unwrap()
+            .into_iter()
+            .map(|x| x.0.id)
+            .collect::<Vec<_>>()
+        };
+
+        let first = order(0);
+        assert_eq!(
+            first,
+            vec!["docs", "fonts", "runtime", "lib", "speller", "app"]
+        );
+        for rotation in 1..20 {
+            assert_eq!(order(rotation), first);
+        }
+    }
+
+    #[test]
+    fn configured_channel_selects_releases_and_defaults_to_the_index() {
+        let url = RepoUrl::new("https://example.com/repo/".parse().unwrap()).unwrap();
+        let index: pahkat_types::repo::Index = toml::from_str(
+            r#"
+            [repository]
+            url = "https://example.com/repo/"
+            channels = ["stable", "beta"]
+            default_channel = "stable"
+            "#,
+        )
+        .unwrap();
+        let config = Config::read_only();
+        let key = key("https://example.com/repo/packages/speller");
+        let descriptor = descriptor(
+            "speller",
+            &[("2.0.0-beta.1", Some("beta")), ("1.0.0", Some("stable"))],
+        );
+
+        let resolved = |channel: Option<&str>| {
+            let mut repo = LoadedRepository::from_index(index.clone(), channel.map(str::to_string));
+            apply_repo_config(&mut repo, &config, &url);
+            let mut repos = HashMap::new();
+            repos.insert(url.clone(), repo);
+
+            assert_eq!(channels(&url, &repos), ["stable", "beta"]);
+            let repo_channel = repos[&url].meta.channel.clone();
+            let version = ReleaseQuery::new(&key, &repos, &config)
+                .iter(&descriptor)
+                .next()
+                .map(|x| x.release.version.to_string());
+            (repo_channel, version)
+        };
+
+        assert_eq!(
+            resolved(Some("beta")),
+            (Some("beta".to_string()), Some("2.0.0-beta.1".to_string()))
+        );
+        assert_eq!(
+            resolved(None),
+            (Some("stable".to_string()), Some("1.0.0".to_string()))
+        );
+    }
+
+    #[test]
+    fn only_channels_missing_from_the_index_are_unknown() {
+        let channels = vec!["stable".to_string(), "beta".to_string()];
+        let channel = |name: &str| Some(name.to_string());
+
+        assert_eq!(unknown_channel(channel("stable").as_ref(), &channels), None);
+        assert_eq!(unknown_channel(channel("beta").as_ref(), &channels), None);
+        assert_eq!(
+            unknown_channel(channel("nightly").as_ref(), &channels),
+            channel("nightly").as_ref()
+        );
+        assert_eq!(unknown_channel(None, &channels), None);
+        // An index without channels accepts whatever is configured.
+        assert_eq!(unknown_channel(channel("nightly").as_ref(), &[]), None);
+    }
+
+    #[test]
+    fn failed_repository_is_reported_and_the_others_load() {
+        let urls = [
+            "https://a.example/",
+            "https://b.example/",
+            "https://c.example/",
+        ]
+        .iter()
+        .map(|x| RepoUrl::new(url::Url::parse(x).unwrap()).unwrap())
+        .collect::<Vec<_>>();
+        let queue = urls.iter().cloned().fold(SegQueue::new(), |acc, cur| {
+            acc.push(cur);
+            acc
+        });
+        let failing = urls[1].clone();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let results = runtime.block_on(work(failing, queue, |url, _, failing| {
+            Box::pin(async move {
+                if url == *failing {
+                    Err(RepoDownloadError::Offline(url))
+                } else {
+                    Ok(url.to_string())
+                }
+            })
+        }));
+        let (loaded, errors) = collect_loads(&urls, results);
+
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[&urls[0]], urls[0].to_string());
+        assert_eq!(loaded[&urls[2]], urls[2].to_string());
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].url, urls[1]);
+        assert!(matches!(*errors[0].error, RepoDownloadError::Offline(_)));
+    }
+
+    #[test]
+    fn localized_falls_back_to_the_repository_default_language() {
+        let mut names = pahkat_types::LangTagMap::new();
+        names.insert("se".to_string(), "Sámegiella".to_string());
+        names.insert("en".to_string(), "Sami".to_string());
+        names.insert("nb".to_string(), "Samisk".to_string());
+
+        let name = |locale, default| localized(&names, locale, default).map(|x| &**x);
+        assert_eq!(name("nb-NO", Some("se")), Some("Samisk"));
+        assert_eq!(name("fi", Some("se")), Some("Sámegiella"));
+        assert_eq!(name("fi", None), Some("Sami"));
+        assert_eq!(name("fi", Some("sv")), Some("Sami"));
+    }
+}
@@ -0,0 +1,203 @@
+//! Importing repository indexes and payloads from a local bundle, for machines
+//! that can't reach the repositories.
+//!
+//! A bundle is laid out as the caches are: each repository in the directory
+//! named for its URL as in the repository cache, and each of its payloads in
+//! `payloads/` under the path named for the payload's URL as in the download
+//! cache.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use pahkat_types::{repo::RepoUrl, AsDownloadUrl};
+
+use super::index::{parse_index, RepoIndex};
+use super::repository::LoadedRepository;
+use super::{apply_repo_config, download_file_path};
+use crate::config::Config;
+use crate::ext::PathExt;
+use crate::package_store::{BundleEntryError, BundleError, ImportReport, PackageStore};
+use crate::signature::{read_signature, signature_path, SignaturePolicy};
+use crate::PackageKey;
+
+const PAYLOADS_DIR: &str = "payloads";
+
+/// The directory of each configured repository that the bundle at `path` holds,
+/// in config order.
+fn repo_dirs(config: &Config, path: &Path) -> Result<Vec<(RepoUrl, PathBuf)>, BundleError> {
+    if !path.is_dir() {
+        return Err(BundleError::NotADirectory(path.to_path_buf()));
+    }
+
+    Ok(config
+        .repos()
+        .keys()
+        .map(|url| (url.clone(), path.join_sha256(url.to_string().as_bytes())))
+        .filter(|(_, dir)| dir.join("index.toml").is_file())
+        .collect())
+}
+
+/// Imports the index in `dir` as that of the configured repository `url`, which
+/// it must say it is.
+fn import_index(
+    config: &Config,
+    url: &RepoUrl,
+    dir: &Path,
+) -> Result<(LoadedRepository, RepoIndex), BundleEntryError> {
+    let info_path = dir.join("index.toml");
+    let packages_path = dir.join("packages").join("index.bin");
+    let info = fs::read(&info_path)?;
+    let packages = fs::read(&packages_path)?;
+
+    let index = parse_index(&info, packages.clone())?;
+    if index.url() != url {
+        return Err(BundleEntryError::UrlMismatch(index.url().clone()));
+    }
+
+    let record = config
+        .repos()
+        .get(url)
+        .ok_or_else(|| BundleEntryError::NotConfigured(url.clone()))?;
+    let signatures = SignaturePolicy::from_record(Some(record));
+    for (path, data) in [(&info_path, &info), (&packages_path, &packages)] {
+        let name = format!("{}/{}", url, path.strip_prefix(dir).unwrap().display());
+        if !signatures.accepts(data, read_signature(path).as_deref(), &name) {
+            return Err(BundleEntryError::SignatureInvalid);
+        }
+    }
+
+    let mut repo = LoadedRepository::import(
+        url.clone(),
+        record.channel.clone(),
+        config.settings().repo_cache_dir(),
+        info,
+        packages,
+    )
+    .map_err(BundleEntryError::Index)?;
+    apply_repo_config(&mut repo, config, &url);
+
+    Ok((repo, index))
+}
+
+/// Copies the payload at `path` to where a download of `url` would have put it.
+///
+/// It is copied next to its destination first and checked there, so what is
+/// checked is what gets used, and renamed into place once it passes.
+fn import_payload(
+    config: &Config,
+    path: &Path,
+    url: &url::Url,
+    size: u64,
+    signatures: &SignaturePolicy,
+) -> Result<(), BundleEntryError> {
+    let output_path = download_file_path(config, url);
+    fs::create_dir_all(output_path.parent().unwrap())?;
+    let mut file_name = output_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".import");
+    let tmp_path = output_path.with_file_name(file_name);
+
+    let result = copy_payload(path, &tmp_path, url, size, signatures).and_then(|signature| {
+        crate::config::replace_file(&tmp_path, &output_path)?;
+        // Kept so the payload can be checked again when it is used offline.
+        if let Some(signature) = signature {
+            fs::write(signature_path(&output_path), signature)?;
+        }
+        Ok(())
+    });
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Copies the payload at `path` to `tmp_path` and checks the copy, returning the
+/// signature it was checked against.
+fn copy_payload(
+    path: &Path,
+    tmp_path: &Path,
+    url: &url::Url,
+    size: u64,
+    signatures: &SignaturePolicy,
+) -> Result<Option<String>, BundleEntryError> {
+    let actual = fs::copy(path, tmp_path)?;
+    if size != 0 && actual != size {
+        return Err(BundleEntryError::SizeMismatch {
+            expected: size,
+            actual,
+        });
+    }
+
+    let signature = read_signature(path);
+    if signatures.is_enabled() {
+        // Signatures can only be checked over the whole payload at once.
+        let data = fs::read(tmp_path)?;
+        if !signatures.accepts(&data, signature.as_deref(), url.as_str()) {
+            return Err(BundleEntryError::SignatureInvalid);
+        }
+    }
+    Ok(signature)
+}
+
+pub(crate) fn import_bundle<S: PackageStore + ?Sized>(
+    store: &S,
+    path: &Path,
+) -> Result<ImportReport, BundleError> {
+    let config = store.config().read().unwrap().clone();
+    let mut report = ImportReport::default();
+
+    for (url, dir) in repo_dirs(&config, path)? {
+        let (repo, index) = match import_index(&config, &url, &dir) {
+            Ok(v) => v,
+            Err(e) => {
+                log::warn!("Not importing bundled index {:?}: {}", &dir, e);
+                report.failed_repos.push((dir, e));
+                continue;
+            }
+        };
+        let signatures = SignaturePolicy::from_record(config.repos().get(&url));
+
+        for id in index.package_ids() {
+            let key = PackageKey::new_unchecked(url.clone(), id.clone(), None);
+            let descriptor = match index.package(&id) {
+                Some(Ok(v)) => v,
+                Some(Err(e)) => {
+                    log::warn!("Skipping invalid bundled package {}: {}", &key, e);
+                    continue;
+                }
+                None => continue,
+            };
+
+            let targets = descriptor.release.iter().flat_map(|x| x.target.iter());
+            for target in targets {
+                let payload_url = target.payload.as_download_url();
+                let payload_path = dir
+                    .join(PAYLOADS_DIR)
+                    .join_sha256(payload_url.as_str().as_bytes());
+                if !payload_path.is_file() {
+                    continue;
+                }
+
+                let size = target.payload.size();
+                match import_payload(&config, &payload_path, payload_url, size, &signatures) {
+                    Ok(()) => {
+                        log::debug!("Imported {:?} for {}", &payload_path, &key);
+                        if !report.packages.contains(&key) {
+                            report.packages.push(key.clone());
+                        }
+                    }
+                    Err(e) => {
+                        log::warn!("Not importing {:?} for {}: {}", &payload_path, &key, e);
+                        report.failed_packages.push((key.clone(), e));
+                    }
+                }
+            }
+        }
+
+        store.errors().write().unwrap().retain(|e| e.url != url);
+        store.repos().write().unwrap().insert(url.clone(), repo);
+        report.repos.push(url);
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,222 @@
+//! Parsing of repository indexes, independent of any package store.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use pahkat_types::package::Descriptor;
+use pahkat_types::repo::{Index, RepoUrl};
+use serde::{Deserialize, Serialize};
+
+use super::repository::CLIENT_VERSION;
+use crate::fbs::PackagesExt;
+use crate::generated::pahkat as pahkat_fbs;
+
+#[derive(Debug, thiserror::Error)]
+pub enum IndexError {
+    #[error("Error parsing TOML index")]
+    Toml(#[from] toml::de::Error),
+
+    #[error("Invalid package index: {0:?}")]
+    Packages(fbs::Error),
+
+    #[error("The index requires client version {required} or newer, but this is {current}")]
+    Unsupported { required: String, current: String },
+}
+
+/// What `index.toml` says about a package beyond its descriptor, from the
+/// package's `[packages.<id>]` table.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct PackageMetadata {
+    /// Packages that can't be installed alongside this one. Bare identifiers
+    /// refer to packages in the same repository.
+    pub conflicts: Vec<String>,
+    /// Installed files, relative to the package's directory, that are expected to
+    /// change after install and are skipped by verification.
+    pub verify_ignore: Vec<String>,
+    /// The package installs nothing itself and only bundles its dependencies.
+    pub meta_package: bool,
+}
+
+/// A parsed repository index: the `index.toml` metadata and the packages in
+/// `packages/index.bin`.
+#[derive(Debug)]
+pub struct RepoIndex {
+    metadata: Index,
+    packages: Box<[u8]>,
+    package_metadata: BTreeMap<String, PackageMetadata>,
+    min_client_version: Option<String>,
+    partial_index: bool,
+    default_language: Option<String>,
+}
+
+/// Parses an index from the contents of its `index.toml` (`info`) and
+/// `packages/index.bin` (`packages`).
+///
+/// A `min_client_version` newer than this client is refused before anything
+/// else in `info` is parsed. Descriptors are only decoded when read, so use
+/// [`RepoIndex::descriptors`] to check every package in the index.
+pub fn parse_index(info: &[u8], packages: Vec<u8>) -> Result<RepoIndex, IndexError> {
+    let raw: toml::Value = toml::from_str(&String::from_utf8_lossy(info))?;
+    let repository = raw.get("repository");
+
+    let min_client_version = repository
+        .and_then(|x| x.get("min_client_version")?.as_str())
+        .map(str::to_string);
+    let partial_index = repository
+        .and_then(|x| x.get("partial_index")?.as_bool())
+        .unwrap_or(false);
+    let default_language = repository
+        .and_then(|x| x.get("default_language")?.as_str())
+        .map(str::to_string);
+
+    if let Some(required) = min_client_version.as_ref() {
+        let current =
+            semver::Version::parse(CLIENT_VERSION).expect("crate version is valid semver");
+        match semver::Version::parse(required) {
+            Ok(v) if v > current => {
+                return Err(IndexError::Unsupported {
+                    required: required.clone(),
+                    current: CLIENT_VERSION.to_string(),
+                })
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!("Ignoring invalid min_client_version: {:?}", e),
+        }
+    }
+
+    let package_metadata = parse_package_metadata(&raw)?;
+    let metadata: Index = raw.try_into()?;
+    pahkat_fbs::Packages::get_root(&*packages).map_err(IndexError::Packages)?;
+
+    Ok(RepoIndex {
+        metadata,
+        packages: packages.into_boxed_slice(),
+        package_metadata,
+        min_client_version,
+        partial_index,
+        default_language,
+    })
+}
+
+fn parse_package_metadata(
+    raw: &toml::Value,
+) -> Result<BTreeMap<String, PackageMetadata>, toml::de::Error> {
+    match raw.get("packages") {
+        Some(packages) => packages.clone().try_into(),
+        None => Ok(BTreeMap::new()),
+    }
+}
+
+impl RepoIndex {
+    /// The repository metadata from `index.toml`.
+    pub fn metadata(&self) -> &Index {
+        &self.metadata
+    }
+
+    pub fn url(&self) -> &RepoUrl {
+        &self.metadata.repository.url
+    }
+
+    pub fn channels(&self) -> &[String] {
+        &self.metadata.repository.channels
+    }
+
+    pub fn min_client_version(&self) -> Option<&str> {
+        self.min_client_version.as_deref()
+    }
+
+    /// Whether the repository publishes a separate index file per package.
+    pub fn partial_index(&self) -> bool {
+        self.partial_index
+    }
+
+    /// The language to show the repository's packages in when they have no
+    /// name or description in the requested one.
+    pub fn default_language(&self) -> Option<&str> {
+        self.default_language.as_deref()
+    }
+
+    /// The identifiers of every package in the index.
+    pub fn package_ids(&self) -> Vec<String> {
+        match self.fbs_packages().packages() {
+            Some(packages) => packages.iter().map(|(id, _)| id.to_string()).collect(),
+            None => vec![],
+        }
+    }
+
+    /// What `index.toml` says about package `id`, if it has a table for it.
+    pub fn package_metadata(&self, id: &str) -> Option<&PackageMetadata> {
+        self.package_metadata.get(id)
+    }
+
+    /// Decodes the descriptor of package `id`, or `None` if the index doesn't have it.
+    pub fn package(&self, id: &str) -> Option<Result<Descriptor, IndexError>> {
+        let packages = self.fbs_packages();
+        let pkg = packages.packages()?.get(id)?;
+        Some(Descriptor::try_from(&pkg).map_err(IndexError::Packages))
+    }
+
+    /// Decodes every descriptor in the index, failing on the first invalid one.
+    pub fn descriptors(&self) -> Result<Vec<Descriptor>, IndexError> {
+        let packages = self.fbs_packages();
+        let packages = match packages.packages() {
+            Some(v) => v,
+            None => return Ok(vec![]),
+        };
+
+        packages
+            .iter()
+            .map(|(_, pkg)| Descriptor::try_from(&pkg).map_err(IndexError::Packages))
+            .collect()
+    }
+
+    fn fbs_packages(&self) -> pahkat_fbs::Packages<&[u8]> {
+        pahkat_fbs::Packages::get_root(&*self.packages).expect("validated when parsed")
+    }
+
+    pub(crate) fn into_parts(self) -> (Index, Box<[u8]>, BTreeMap<String, PackageMetadata>) {
+        (self.metadata, self.packages, self.package_metadata)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn package_metadata_is_read_from_package_tables() {
+        let raw: toml::Value = toml::from_str(
+            r#"
+            [repository]
+            url = "https://example.com/repo/"
+
+            [packages.a]
+            conflicts = ["b", "https://example.com/other/packages/c"]
+            verify_ignore = ["settings.ini"]
+            meta_package = true
+
+            [packages.b]
+            "#,
+        )
+        .unwrap();
+
+        let metadata = parse_package_metadata(&raw).unwrap();
+        assert_eq!(
+            metadata["a"].conflicts,
+            vec!["b", "https://example.com/other/packages/c"]
+        );
+        assert_eq!(metadata["a"].verify_ignore, vec!["settings.ini"]);
+        assert!(metadata["a"].meta_package);
+        assert_eq!(metadata["b"], PackageMetadata::default());
+        assert!(!metadata.contains_key("c"));
+    }
+
+    #[test]
+    fn package_metadata_is_optional() {
+        let raw: toml::Value =
+            toml::from_str("[repository]\nurl = \"https://example.com/repo/\"\n").unwrap();
+        assert!(parse_package_metadata(&raw).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,169 @@
+use std::convert::TryFrom;
+
+use pahkat_types::repo::RepoUrl;
+use pahkat_types::PackageKey;
+use thiserror::Error;
+
+/// Query parameters a [`PackageKey`] can carry.
+const PARAMS: &[&str] = &["channel", "platform", "arch", "version"];
+
+/// The `channel` of a key resolving to the newest release in any channel.
+pub const ALL_CHANNELS: &str = "*";
+
+/// Which channels a key's releases are resolved from, from its `channel` parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelQuery<'a> {
+    /// No `channel`: the channel configured for the repository.
+    Repository,
+    /// `channel=<name>`: this channel, regardless of the repository's.
+    Channel(&'a str),
+    /// `channel=*`: every channel of the repository.
+    All,
+}
+
+#[derive(Debug, Clone, Error)]
+pub enum PackageKeyError {
+    #[error("Invalid URL")]
+    Url(#[from] url::ParseError),
+
+    #[error("Not a package key: `{0}`")]
+    Malformed(String),
+
+    #[error("Unknown package key parameter: `{0}`")]
+    UnknownParameter(String),
+
+    #[error("Package id is missing")]
+    MissingId,
+
+    #[error("Invalid package id: `{0}`")]
+    InvalidId(String),
+
+    #[error("Package key parameter `{0}` is empty")]
+    EmptyParameter(String),
+}
+
+/// Checked construction of [`PackageKey`], which lives in `pahkat-types`.
+pub trait PackageKeyExt: Sized {
+    /// Parses a key such as `https://example.com/repo/packages/foo?channel=beta`.
+    ///
+    /// Unlike `TryFrom<&str>`, unknown query parameters are rejected, so that the
+    /// parsed key always converts back to the same string.
+    fn from_url(input: &str) -> Result<Self, PackageKeyError>;
+
+    fn try_from_str(input: &str) -> Result<Self, PackageKeyError> {
+        Self::from_url(input)
+    }
+
+    fn builder(repository_url: RepoUrl) -> PackageKeyBuilder {
+        PackageKeyBuilder::new(repository_url)
+    }
+
+    /// Keys without a `channel` parameter resolve from the repository's channel.
+    fn channel_query(&self) -> ChannelQuery<'_> {
+        ChannelQuery::Repository
+    }
+}
+
+impl PackageKeyExt for PackageKey {
+    fn from_url(input: &str) -> Result<PackageKey, PackageKeyError> {
+        let url = url::Url::parse(input)?;
+
+        if let Some((name, _)) = url
+            .query_pairs()
+            .find(|(name, _)| !PARAMS.contains(&&**name))
+        {
+            return Err(PackageKeyError::UnknownParameter(name.into_owned()));
+        }
+
+        if let Some((name, _)) = url.query_pairs().find(|(_, value)| value.is_empty()) {
+            return Err(PackageKeyError::EmptyParameter(name.into_owned()));
+        }
+
+        let key = PackageKey::try_from(input)
+            .map_err(|_| PackageKeyError::Malformed(input.to_string()))?;
+
+        match PackageKey::try_from(&*key.to_string()) {
+            Ok(ref round_trip) if round_trip == &key => Ok(key),
+            _ => Err(PackageKeyError::Malformed(input.to_string())),
+        }
+    }
+
+    fn channel_query(&self) -> ChannelQuery<'_> {
+        match self.query.channel.as_deref() {
+            None => ChannelQuery::Repository,
+            Some(ALL_CHANNELS) => ChannelQuery::All,
+            Some(channel) => ChannelQuery::Channel(channel),
+        }
+    }
+}
+
+/// Builds a [`PackageKey`] from its parts, validating the package id.
+#[derive(Debug, Clone)]
+pub struct PackageKeyBuilder {
+    repository_url: RepoUrl,
+    id: Option<String>,
+    channel: Option<String>,
+    platform: Option<String>,
+    arch: Option<String>,
+    version: Option<String>,
+}
+
+impl PackageKeyBuilder {
+    pub fn new(repository_url: RepoUrl) -> PackageKeyBuilder {
+        PackageKeyBuilder {
+            repository_url,
+            id: None,
+            channel: None,
+            platform: None,
+            arch: None,
+            version: None,
+        }
+    }
+
+    pub fn id<S: Into<String>>(mut self, id: S) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    pub fn channel<S: Into<String>>(mut self, channel: S) -> Self {
+        self.channel = Some(channel.into());
+        self
+    }
+
+    /// Resolves to the newest release in any channel of the repository.
+    pub fn all_channels(mut self) -> Self {
+        self.channel = Some(ALL_CHANNELS.to_string());
+        self
+    }
+
+    pub fn platform<S: Into<String>>(mut self, platform: S) -> Self {
+        self.platform = Some(platform.into());
+        self
+    }
+
+    pub fn arch<S: Into<String>>(mut self, arch: S) -> Self {
+        self.arch = Some(arch.into());
+        self
+    }
+
+    pub fn version<S: Into<String>>(mut self, version: S) -> Self {
+        self.version = Some(version.into());
+        self
+    }
+
+    pub fn build(self) -> Result<PackageKey, PackageKeyError> {
+        let id = self.id.ok_or(PackageKeyError::MissingId)?;
+
+        // The id becomes a single path segment of the key's URL.
+        if id.is_empty() || id.contains(|c| matches!(c, '/' | '?' | '#' | '%')) {
+            return Err(PackageKeyError::InvalidId(id));
+        }
+
+        let mut key = PackageKey::new_unchecked(self.repository_url, id, None);
+        key.query.channel = self.channel;
+        key.query.platform = self.platform;
+        key.query.arch = self.arch;
+        key.query.version = self.version;
+        Ok(key)
+    }
+}
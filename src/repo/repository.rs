@@ -1,10 +1,25 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::future::Future;
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use futures::future::{FutureExt, Shared};
+use hashbrown::HashMap;
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 
+use crate::config::{Config, ProxySettings, RepoAuth, TimeoutSettings};
+use crate::download::{with_timeout, HttpClientHook};
+use crate::ext::PathExt;
 use crate::generated::pahkat as pahkat_fbs;
+use crate::signature::SignaturePolicy;
 use pahkat_types::{repo::RepoUrl, PackageKey};
 
+use super::index::{parse_index, IndexError, PackageMetadata, RepoIndex};
+
 #[derive(Debug, thiserror::Error)]
 pub enum RepoDownloadError {
     #[error("Error while processing HTTP request")]
@@ -15,13 +30,328 @@ pub enum RepoDownloadError {
 
     #[error("I/O error")]
     IoError(#[from] std::io::Error),
+
+    #[error("The repository stopped responding: {0}")]
+    TimedOut(RepoUrl),
+
+    #[error("The repository requires valid credentials: {0}")]
+    Unauthorized(RepoUrl),
+
+    #[error("The repository index signature could not be verified: {0}")]
+    SignatureInvalid(RepoUrl),
+
+    #[error("Offline, and the repository has no cached index: {0}")]
+    Offline(RepoUrl),
+
+    #[error("The repository index of {0} is invalid")]
+    InvalidIndex(RepoUrl, #[source] IndexError),
+
+    #[error(
+        "The repository {url} requires client version {required} or newer, but this is {current}"
+    )]
+    Unsupported {
+        url: RepoUrl,
+        required: String,
+        current: String,
+    },
+
+    #[error("Loading the repository {0} stopped unexpectedly")]
+    Aborted(RepoUrl),
+
+    #[error("`{0}` is not a valid package ID")]
+    InvalidPackageId(String),
+
+    /// The error from a load of the same repository that was already in flight.
+    #[error(transparent)]
+    Coalesced(Arc<RepoDownloadError>),
+}
+
+impl RepoDownloadError {
+    /// Whether the server couldn't be reached or stopped responding, so that a
+    /// later attempt, or a cached copy, might be used instead.
+    pub fn is_unreachable(&self) -> bool {
+        match self {
+            RepoDownloadError::ReqwestError(e) => e.is_connect() || e.is_timeout(),
+            RepoDownloadError::TimedOut(_) => true,
+            RepoDownloadError::Coalesced(e) => e.is_unreachable(),
+            _ => false,
+        }
+    }
+}
+
+/// A repository that could not be loaded. The others are loaded regardless, so
+/// this is reported alongside them rather than failing the load.
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("{error}")]
+pub struct RepoLoadError {
+    pub url: RepoUrl,
+    pub error: Arc<RepoDownloadError>,
+}
+
+impl RepoLoadError {
+    pub(crate) fn new(url: RepoUrl, error: RepoDownloadError) -> RepoLoadError {
+        RepoLoadError {
+            url,
+            error: Arc::new(error),
+        }
+    }
+}
+
+type LoadResult = Result<LoadedRepository, Arc<RepoDownloadError>>;
+type SharedLoad = Shared<Pin<Box<dyn Future<Output = LoadResult> + Send>>>;
+
+/// What a load's result depends on besides the cache's age. Only loads that agree
+/// on all of it can share a request.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct LoadKey {
+    url: String,
+    cache_dir: PathBuf,
+    signatures: SignaturePolicy,
+    auth: Option<RepoAuth>,
+    http: usize,
+}
+
+/// How to load a repository: where its cache is, how to reach its server, and what
+/// its files must be signed with.
+#[derive(Clone)]
+pub(crate) struct LoadOptions {
+    pub(crate) channel: Option<String>,
+    pub(crate) cache_dir: PathBuf,
+    pub(crate) auth: Option<RepoAuth>,
+    pub(crate) signatures: SignaturePolicy,
+    pub(crate) proxy: ProxySettings,
+    pub(crate) timeouts: TimeoutSettings,
+    pub(crate) http: HttpClientHook,
+    /// How old a cached index may be before it is revalidated.
+    pub(crate) max_age: Duration,
+}
+
+impl LoadOptions {
+    /// The options `config` sets for the repository at `url`.
+    pub(crate) fn from_config(config: &Config, url: &RepoUrl, max_age: Duration) -> LoadOptions {
+        let record = config.repos().get(url);
+        LoadOptions {
+            channel: record.and_then(|r| r.channel.clone()),
+            cache_dir: config.settings().repo_cache_dir(),
+            auth: record.and_then(|r| r.auth.clone()),
+            signatures: SignaturePolicy::from_record(record),
+            proxy: config.settings().proxy(),
+            timeouts: config.settings().timeouts(),
+            http: config.http_client_hook().clone(),
+            max_age,
+        }
+    }
+}
+
+/// Loads in progress, so concurrent refreshes of the same repository with the
+/// same options share one request.
+static IN_FLIGHT: Lazy<Mutex<HashMap<LoadKey, SharedLoad>>> = Lazy::new(Default::default);
+
+/// The version repositories' `min_client_version` is compared against.
+pub(crate) const CLIENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Repositories whose last load was refused for needing a newer client, keyed by
+/// their cache directory, with the version they require.
+static UNSUPPORTED: Lazy<Mutex<HashMap<PathBuf, String>>> = Lazy::new(Default::default);
+
+/// The client version the repository at `url` required when it was last refused
+/// by a load using `cache_dir`.
+pub(crate) fn required_client_version(url: &RepoUrl, cache_dir: &Path) -> Option<String> {
+    let cache_dir = cache_dir.join_sha256(url.to_string().as_bytes());
+    UNSUPPORTED.lock().unwrap().get(&cache_dir).cloned()
+}
+
+/// Parses the index of the repository at `url`, cached in `cache_dir`,
+/// remembering whether it was refused for needing a newer client.
+fn parse_repo_index(
+    url: &RepoUrl,
+    cache_dir: &Path,
+    info: &[u8],
+    packages: Vec<u8>,
+) -> Result<RepoIndex, RepoDownloadError> {
+    let mut unsupported = UNSUPPORTED.lock().unwrap();
+    match parse_index(info, packages) {
+        Ok(index) => {
+            unsupported.remove(cache_dir);
+            Ok(index)
+        }
+        Err(IndexError::Unsupported { required, current }) => {
+            unsupported.insert(cache_dir.to_path_buf(), required.clone());
+            Err(RepoDownloadError::Unsupported {
+                url: url.clone(),
+                required,
+                current,
+            })
+        }
+        Err(IndexError::Toml(e)) => Err(RepoDownloadError::TomlError(e)),
+        Err(e) => Err(RepoDownloadError::InvalidIndex(url.clone(), e)),
+    }
+}
+
+#[inline(always)]
+fn enabled_default() -> bool {
+    true
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct LoadedRepositoryMeta {
     pub channel: Option<String>,
+    /// Copied from the repository's config record; see [`crate::config::RepoRecord::priority`].
+    #[serde(default)]
+    pub priority: i32,
+    /// Position in the repository config, or `None` for linked repositories.
+    #[serde(default)]
+    pub config_order: Option<usize>,
+    /// Copied from the repository's config record; see [`crate::config::RepoRecord::enabled`].
+    #[serde(default = "enabled_default")]
+    pub enabled: bool,
+    /// When the index was last downloaded or confirmed unchanged by the server.
+    #[serde(default)]
+    pub fetched_at: Option<SystemTime>,
+    /// The `Last-Modified` time the server reported for the index.
+    #[serde(default)]
+    pub published_at: Option<SystemTime>,
+    /// Whether the repository publishes an index file per package, so a single
+    /// package can be resolved without the full index.
+    #[serde(default)]
+    pub partial_index: bool,
+    /// Copied from the index; see [`RepoIndex::default_language`].
+    #[serde(default)]
+    pub default_language: Option<String>,
     // pub hash_id: String,
-    // TODO: last update
+}
+
+const CACHE_META_FILE: &str = "cache.json";
+const CACHE_INDEX_FILE: &str = "index.toml";
+const CACHE_PACKAGES_FILE: &str = "index.bin";
+
+/// Where repositories with `partial_index = true` in the `[repository]` table of
+/// their `index.toml` publish `<package id>.bin`, each holding only that package.
+const PARTIAL_INDEX_DIR: &str = "packages/index";
+
+/// The partial index file of `package_id`, which must be a single path segment so
+/// it can't name a file outside [`PARTIAL_INDEX_DIR`].
+fn partial_index_path(package_id: &str) -> Result<String, RepoDownloadError> {
+    let is_segment = !matches!(package_id, "" | "." | "..")
+        && !package_id.contains(|c: char| matches!(c, '/' | '\\' | '?' | '#' | '%'));
+    if is_segment {
+        Ok(format!("{}/{}.bin", PARTIAL_INDEX_DIR, package_id))
+    } else {
+        Err(RepoDownloadError::InvalidPackageId(package_id.to_string()))
+    }
+}
+
+fn advertises_partial_index(info: &[u8]) -> bool {
+    toml::from_str::<toml::Value>(&String::from_utf8_lossy(info))
+        .ok()
+        .and_then(|x| x.get("repository")?.get("partial_index")?.as_bool())
+        .unwrap_or(false)
+}
+
+/// Response headers sent back on revalidation to make the request conditional.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl Validators {
+    fn from_response(res: &reqwest::Response) -> Validators {
+        let header = |name| {
+            res.headers()
+                .get(name)
+                .and_then(|x| x.to_str().ok())
+                .map(str::to_string)
+        };
+
+        Validators {
+            etag: header(reqwest::header::ETAG),
+            last_modified: header(reqwest::header::LAST_MODIFIED),
+        }
+    }
+
+    fn apply(&self, mut req: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        if let Some(etag) = self.etag.as_ref() {
+            req = req.header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = self.last_modified.as_ref() {
+            req = req.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+        }
+        req
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct CacheMeta {
+    /// Seconds since the Unix epoch at which the cache was last known to be current.
+    validated_at: u64,
+    index: Validators,
+    packages: Validators,
+}
+
+enum Fetched {
+    NotModified,
+    /// The new body, its validators, and its detached signature if one was requested.
+    Modified(Vec<u8>, Validators, Option<String>),
+}
+
+/// A repository's index files as last downloaded.
+#[derive(Debug, Default)]
+struct CachedIndex {
+    meta: CacheMeta,
+    info: Vec<u8>,
+    packages: Vec<u8>,
+}
+
+impl CachedIndex {
+    /// Returns `None` unless every file of the cache is present and readable.
+    fn load(dir: &Path) -> Option<CachedIndex> {
+        let meta = fs::read(dir.join(CACHE_META_FILE)).ok()?;
+        let meta = serde_json::from_slice(&meta).ok()?;
+        let info = fs::read(dir.join(CACHE_INDEX_FILE)).ok()?;
+        let packages = fs::read(dir.join(CACHE_PACKAGES_FILE)).ok()?;
+
+        Some(CachedIndex {
+            meta,
+            info,
+            packages,
+        })
+    }
+
+    /// The metadata is written last, so an interrupted save is never mistaken
+    /// for a current cache.
+    fn save(&self, dir: &Path) -> std::io::Result<()> {
+        fs::create_dir_all(dir)?;
+        let _ = fs::remove_file(dir.join(CACHE_META_FILE));
+        fs::write(dir.join(CACHE_INDEX_FILE), &self.info)?;
+        fs::write(dir.join(CACHE_PACKAGES_FILE), &self.packages)?;
+        let meta = serde_json::to_vec(&self.meta)?;
+        fs::write(dir.join(CACHE_META_FILE), meta)
+    }
+
+    fn age(&self) -> Duration {
+        Duration::from_secs(unix_now().saturating_sub(self.meta.validated_at))
+    }
+}
+
+impl CacheMeta {
+    fn fetched_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.validated_at)
+    }
+
+    fn published_at(&self) -> Option<SystemTime> {
+        let value = self.index.last_modified.as_ref()?;
+        chrono::DateTime::parse_from_rfc2822(value)
+            .ok()
+            .map(SystemTime::from)
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0)
 }
 
 #[derive(Debug, Clone)]
@@ -29,21 +359,401 @@ pub struct LoadedRepository {
     pub info: pahkat_types::repo::Index,
     pub packages: Box<[u8]>,
     pub meta: LoadedRepositoryMeta,
+    package_metadata: BTreeMap<String, PackageMetadata>,
+    is_partial: bool,
 }
 
 impl LoadedRepository {
-    pub async fn from_cache_or_url(
+    /// Loads the repository at `url` from the on-disk cache in `cache_dir` alone,
+    /// however old it is.
+    pub(crate) fn from_cache(
         url: RepoUrl,
         channel: Option<String>,
         cache_dir: PathBuf,
     ) -> Result<LoadedRepository, RepoDownloadError> {
-        Self::from_url(url, channel).await
+        let cache_dir = cache_dir.join_sha256(url.to_string().as_bytes());
+        let cached =
+            CachedIndex::load(&cache_dir).ok_or_else(|| RepoDownloadError::Offline(url.clone()))?;
+        log::trace!("Using cached repo while offline: {}", &url);
+        Self::from_parts(
+            &url,
+            &cache_dir,
+            &cached.info,
+            cached.packages,
+            channel,
+            &cached.meta,
+        )
     }
 
-    async fn from_url(
+    /// Caches index files obtained other than from the server as the index of the
+    /// repository at `url`, then loads it as [`from_cache`](Self::from_cache) does.
+    ///
+    /// The cache is recorded as never validated, so the next online load fetches
+    /// the repository's current index.
+    pub(crate) fn import(
         url: RepoUrl,
         channel: Option<String>,
+        cache_dir: PathBuf,
+        info: Vec<u8>,
+        packages: Vec<u8>,
+    ) -> Result<LoadedRepository, RepoDownloadError> {
+        let cached = CachedIndex {
+            meta: CacheMeta::default(),
+            info,
+            packages,
+        };
+        cached.save(&cache_dir.join_sha256(url.to_string().as_bytes()))?;
+        Self::from_cache(url, channel, cache_dir)
+    }
+
+    /// Loads the repository at `url`, reusing the on-disk cache in `cache_dir`.
+    ///
+    /// A cache validated less than `max_age` ago is used as is. Otherwise the
+    /// index is revalidated with a conditional request and only downloaded again
+    /// if it changed. If the server can't be reached, a stale cache is used.
+    ///
+    /// Downloaded files are checked against the options' signature policy before
+    /// being cached.
+    ///
+    /// Calls made while another load of `url` with the same cache, signature policy,
+    /// credentials and HTTP hook is in flight wait for that load and share its result.
+    pub(crate) async fn from_cache_or_url(
+        url: RepoUrl,
+        options: LoadOptions,
+    ) -> Result<LoadedRepository, RepoDownloadError> {
+        let key = LoadKey {
+            url: url.to_string(),
+            cache_dir: options.cache_dir.clone(),
+            signatures: options.signatures.clone(),
+            auth: options.auth.clone(),
+            http: options.http.id(),
+        };
+        let channel = options.channel.clone();
+
+        let load = {
+            let mut in_flight = IN_FLIGHT.lock().unwrap();
+            match in_flight.get(&key) {
+                Some(load) => {
+                    log::trace!("Sharing in-flight load of repo: {}", &url);
+                    load.clone()
+                }
+                None => {
+                    // Removes itself when done, whichever caller ends up polling it.
+                    let done_key = key.clone();
+                    let load: Pin<Box<dyn Future<Output = LoadResult> + Send>> =
+                        Box::pin(Self::load(url, options).map(move |result| {
+                            IN_FLIGHT.lock().unwrap().remove(&done_key);
+                            result.map_err(Arc::new)
+                        }));
+                    let load = load.shared();
+                    in_flight.insert(key, load.clone());
+                    load
+                }
+            }
+        };
+
+        match load.await {
+            Ok(mut repo) => {
+                repo.meta.channel = channel;
+                Ok(repo)
+            }
+            Err(e) => Err(Arc::try_unwrap(e).unwrap_or_else(RepoDownloadError::Coalesced)),
+        }
+    }
+
+    /// Loads just enough of the repository at `url` to resolve `package_id`.
+    ///
+    /// A cache validated less than `max_age` ago is used as is. Otherwise, if the
+    /// repository has a partial index, only `index.toml` and the package's own
+    /// index file are downloaded, and the result holds at most that package and
+    /// isn't cached. Repositories without one are loaded in full with
+    /// [`from_cache_or_url`](Self::from_cache_or_url).
+    pub(crate) async fn from_url_partial(
+        url: RepoUrl,
+        package_id: &str,
+        options: LoadOptions,
+    ) -> Result<LoadedRepository, RepoDownloadError> {
+        let path = partial_index_path(package_id)?;
+        let cache_path = options.cache_dir.join_sha256(url.to_string().as_bytes());
+        if let Some(cached) = CachedIndex::load(&cache_path) {
+            if cached.age() < options.max_age {
+                log::trace!("Using cached repo: {}", &url);
+                return Self::from_parts(
+                    &url,
+                    &cache_path,
+                    &cached.info,
+                    cached.packages,
+                    options.channel,
+                    &cached.meta,
+                );
+            }
+        }
+
+        let signatures = &options.signatures;
+        let fetch = |path: String| {
+            Self::fetch_files(
+                url.clone(),
+                options.auth.clone(),
+                options.proxy.clone(),
+                options.timeouts,
+                options.http.clone(),
+                vec![(path, None)],
+                signatures.is_enabled(),
+            )
+        };
+
+        // An unreachable server is left to the full load, which can fall back to a stale cache.
+        let mut meta = CacheMeta::default();
+        let info = match fetch("index.toml".into()).await {
+            Ok(mut fetched) => fetched.pop(),
+            Err(e) if e.is_unreachable() => None,
+            Err(e) => return Err(e),
+        };
+        let info = match info {
+            Some(Fetched::Modified(body, validators, signature)) => {
+                let name = format!("{}/index.toml", url);
+                if !signatures.accepts(&body, signature.as_deref(), &name) {
+                    return Err(RepoDownloadError::SignatureInvalid(url));
+                }
+                meta.index = validators;
+                Some(body)
+            }
+            _ => None,
+        };
+
+        let packages = match info.as_deref() {
+            Some(info) if advertises_partial_index(info) => match fetch(path.clone()).await {
+                Ok(mut fetched) => match fetched.pop() {
+                    Some(Fetched::Modified(body, _, signature)) => Some((body, signature)),
+                    _ => None,
+                },
+                Err(RepoDownloadError::ReqwestError(e))
+                    if e.status() == Some(reqwest::StatusCode::NOT_FOUND) =>
+                {
+                    log::debug!("No partial index for {} in {}", package_id, &url);
+                    None
+                }
+                Err(e) => return Err(e),
+            },
+            _ => None,
+        };
+
+        let (info, packages, signature) = match (info, packages) {
+            (Some(info), Some((packages, signature))) => (info, packages, signature),
+            _ => return Self::from_cache_or_url(url, options).await,
+        };
+
+        let name = format!("{}/{}", url, path);
+        if !signatures.accepts(&packages, signature.as_deref(), &name) {
+            return Err(RepoDownloadError::SignatureInvalid(url));
+        }
+
+        meta.validated_at = unix_now();
+        let channel = options.channel.clone();
+        let mut repo = Self::from_parts(&url, &cache_path, &info, packages, channel, &meta)?;
+        repo.is_partial = true;
+        Ok(repo)
+    }
+
+    async fn load(
+        url: RepoUrl,
+        options: LoadOptions,
     ) -> Result<LoadedRepository, RepoDownloadError> {
+        let LoadOptions {
+            channel,
+            cache_dir,
+            auth,
+            signatures,
+            proxy,
+            timeouts,
+            http,
+            max_age,
+        } = options;
+        let cache_dir = cache_dir.join_sha256(url.to_string().as_bytes());
+        let cached = CachedIndex::load(&cache_dir);
+
+        if let Some(cached) = cached.as_ref() {
+            if cached.age() < max_age {
+                log::trace!("Using cached repo: {}", &url);
+                return Self::from_parts(
+                    &url,
+                    &cache_dir,
+                    &cached.info,
+                    cached.packages.clone(),
+                    channel,
+                    &cached.meta,
+                );
+            }
+        }
+
+        let validators = cached.as_ref().map(|x| x.meta.clone());
+        let check_signatures = signatures.is_enabled();
+        let fetched = Self::fetch(
+            url.clone(),
+            auth,
+            proxy,
+            timeouts,
+            http,
+            validators,
+            check_signatures,
+        )
+        .await;
+        let (info, packages) = match fetched {
+            Ok(v) => v,
+            Err(e) if e.is_unreachable() => match cached {
+                Some(cached) => {
+                    log::warn!("Could not reach {}, using cached index: {:?}", &url, e);
+                    return Self::from_parts(
+                        &url,
+                        &cache_dir,
+                        &cached.info,
+                        cached.packages,
+                        channel,
+                        &cached.meta,
+                    );
+                }
+                None => {
+                    return Err(match e {
+                        RepoDownloadError::ReqwestError(e) if e.is_timeout() => {
+                            RepoDownloadError::TimedOut(url)
+                        }
+                        e => e,
+                    })
+                }
+            },
+            Err(e) => return Err(e),
+        };
+
+        let mut cached = cached.unwrap_or_default();
+
+        if let Fetched::Modified(body, validators, signature) = info {
+            let name = format!("{}/index.toml", url);
+            if !signatures.accepts(&body, signature.as_deref(), &name) {
+                return Err(RepoDownloadError::SignatureInvalid(url));
+            }
+            cached.info = body;
+            cached.meta.index = validators;
+        }
+
+        if let Fetched::Modified(body, validators, signature) = packages {
+            let name = format!("{}/packages/index.bin", url);
+            if !signatures.accepts(&body, signature.as_deref(), &name) {
+                return Err(RepoDownloadError::SignatureInvalid(url));
+            }
+            cached.packages = body;
+            cached.meta.packages = validators;
+        }
+
+        cached.meta.validated_at = unix_now();
+        let repo = Self::from_parts(
+            &url,
+            &cache_dir,
+            &cached.info,
+            cached.packages.clone(),
+            channel,
+            &cached.meta,
+        )?;
+
+        if let Err(e) = cached.save(&cache_dir) {
+            log::warn!("Could not cache repo {}: {:?}", &url, e);
+        }
+
+        Ok(repo)
+    }
+
+    fn from_parts(
+        url: &RepoUrl,
+        cache_dir: &Path,
+        info: &[u8],
+        packages: Vec<u8>,
+        channel: Option<String>,
+        cache: &CacheMeta,
+    ) -> Result<LoadedRepository, RepoDownloadError> {
+        let index = parse_repo_index(url, cache_dir, info, packages)?;
+        let partial_index = index.partial_index();
+        let default_language = index.default_language().map(str::to_string);
+        let (info, packages, package_metadata) = index.into_parts();
+
+        Ok(LoadedRepository {
+            info,
+            packages,
+            package_metadata,
+            is_partial: false,
+            meta: LoadedRepositoryMeta {
+                channel,
+                priority: 0,
+                config_order: None,
+                enabled: true,
+                fetched_at: Some(cache.fetched_at()),
+                published_at: cache.published_at(),
+                partial_index,
+                default_language,
+                // hash_id: "".into(),
+            },
+        })
+    }
+
+    /// A repository with the metadata of `info` and no packages.
+    #[cfg(test)]
+    pub(crate) fn from_index(
+        info: pahkat_types::repo::Index,
+        channel: Option<String>,
+    ) -> LoadedRepository {
+        LoadedRepository {
+            info,
+            packages: Box::new([]),
+            package_metadata: BTreeMap::new(),
+            is_partial: false,
+            meta: LoadedRepositoryMeta {
+                channel,
+                priority: 0,
+                config_order: None,
+                enabled: true,
+                fetched_at: None,
+                published_at: None,
+                partial_index: false,
+                default_language: None,
+            },
+        }
+    }
+
+    async fn fetch(
+        url: RepoUrl,
+        auth: Option<RepoAuth>,
+        proxy: ProxySettings,
+        timeouts: TimeoutSettings,
+        http: HttpClientHook,
+        validators: Option<CacheMeta>,
+        check_signatures: bool,
+    ) -> Result<(Fetched, Fetched), RepoDownloadError> {
+        let files = vec![
+            (
+                "index.toml".to_string(),
+                validators.as_ref().map(|x| x.index.clone()),
+            ),
+            (
+                "packages/index.bin".to_string(),
+                validators.map(|x| x.packages),
+            ),
+        ];
+        let mut fetched =
+            Self::fetch_files(url, auth, proxy, timeouts, http, files, check_signatures).await?;
+        let packages = fetched.pop().unwrap();
+        let info = fetched.pop().unwrap();
+        Ok((info, packages))
+    }
+
+    /// Downloads each of `files`, relative to `url`, in order. A file with
+    /// validators is requested conditionally.
+    async fn fetch_files(
+        url: RepoUrl,
+        auth: Option<RepoAuth>,
+        proxy: ProxySettings,
+        timeouts: TimeoutSettings,
+        http: HttpClientHook,
+        files: Vec<(String, Option<Validators>)>,
+        check_signatures: bool,
+    ) -> Result<Vec<Fetched>, RepoDownloadError> {
         const USER_AGENT: &str = concat!(
             "pahkat-client/",
             env!("GIT_VERSION"),
@@ -55,42 +765,89 @@ impl LoadedRepository {
 
         tokio::spawn(async move {
             let result = async move {
-                let client = reqwest::Client::builder()
+                let client = crate::download::client_builder(&proxy, &timeouts, &http)
                     .user_agent(USER_AGENT)
                     .referer(false)
                     .redirect(reqwest::redirect::Policy::none())
                     .build()?;
 
-                log::trace!("Loading repo: {} channel:{:?}", &url, &channel);
-
-                let info = client
-                    .get(&format!("{}/index.toml", url))
-                    .send()
-                    .await?
-                    .text()
-                    .await?;
-                let info: pahkat_types::repo::Index = toml::from_str(&info)?;
-
-                let packages = client
-                    .get(&format!("{}/packages/index.bin", url))
-                    .send()
-                    .await?
-                    .bytes()
-                    .await?
-                    .to_vec()
-                    .into_boxed_slice();
-
-                let repo = LoadedRepository {
-                    info,
-                    packages,
-                    meta: LoadedRepositoryMeta {
-                        channel,
-                        // hash_id: "".into(),
-                    },
+                log::trace!("Loading repo: {}", &url);
+
+                let get = |path: &str, validators: Option<&Validators>| {
+                    let req = client.get(&format!("{}/{}", url, path));
+                    let req = crate::download::authorize(req, auth.as_ref());
+                    let req = match validators {
+                        Some(v) => v.apply(req),
+                        None => req,
+                    };
+                    let url = url.clone();
+                    let read_timeout = timeouts.read();
+                    async move {
+                        let res = with_timeout(read_timeout, req.send())
+                            .await
+                            .map_err(|_| RepoDownloadError::TimedOut(url.clone()))??;
+                        match res.status() {
+                            reqwest::StatusCode::UNAUTHORIZED => {
+                                Err(RepoDownloadError::Unauthorized(url))
+                            }
+                            reqwest::StatusCode::NOT_MODIFIED => Ok(Fetched::NotModified),
+                            _ => {
+                                let mut res = res.error_for_status()?;
+                                let validators = Validators::from_response(&res);
+                                let mut body = vec![];
+                                while let Some(chunk) = with_timeout(read_timeout, res.chunk())
+                                    .await
+                                    .map_err(|_| RepoDownloadError::TimedOut(url.clone()))??
+                                {
+                                    body.extend_from_slice(&chunk);
+                                }
+                                Ok(Fetched::Modified(body, validators, None))
+                            }
+                        }
+                    }
+                };
+
+                let get_signature = |path: &str| {
+                    let req = client.get(&format!(
+                        "{}/{}{}",
+                        url,
+                        path,
+                        crate::signature::SIGNATURE_SUFFIX
+                    ));
+                    let req = crate::download::authorize(req, auth.as_ref());
+                    let url = url.clone();
+                    let read_timeout = timeouts.read();
+                    let fetch = async move {
+                        let res = req.send().await?;
+                        match res.status() {
+                            reqwest::StatusCode::NOT_FOUND => Ok(None),
+                            _ => res.error_for_status()?.text().await.map(Some),
+                        }
+                    };
+                    async move {
+                        with_timeout(read_timeout, fetch)
+                            .await
+                            .map_err(|_| RepoDownloadError::TimedOut(url))?
+                            .map_err(RepoDownloadError::from)
+                    }
                 };
 
+                let mut fetched = vec![];
+                for (path, validators) in files.iter() {
+                    fetched.push(get(path, validators.as_ref()).await?);
+                }
+
+                // Signatures only need fetching for files that changed.
+                if check_signatures {
+                    for ((path, _), fetched) in files.iter().zip(fetched.iter_mut()) {
+                        if let Fetched::Modified(_, _, signature) = fetched {
+                            *signature = get_signature(path).await?;
+                        }
+                    }
+                }
+
                 log::trace!("Loaded.");
-                Ok(repo)
+                Ok(fetched)
             }
             .await;
 
@@ -112,6 +869,17 @@ impl LoadedRepository {
         &self.meta
     }
 
+    /// Whether this holds just the packages fetched from the repository's partial
+    /// index, rather than its full index.
+    pub(crate) fn is_partial(&self) -> bool {
+        self.is_partial
+    }
+
+    /// What `index.toml` says about package `id`, if it has a table for it.
+    pub fn package_metadata(&self, id: &str) -> Option<&PackageMetadata> {
+        self.package_metadata.get(id)
+    }
+
     pub fn package_key(&self, descriptor: &pahkat_types::package::Descriptor) -> PackageKey {
         PackageKey::new_unchecked(
             self.info.repository.url.to_owned(),
@@ -120,3 +888,28 @@ impl LoadedRepository {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_index_path_stays_in_the_index_dir() {
+        assert_eq!(
+            partial_index_path("speller-sme").unwrap(),
+            "packages/index/speller-sme.bin"
+        );
+        for id in [
+            "", ".", "..", "../index", "a/b", "a\\b", "a?b", "a#b", "%2e%2e",
+        ] {
+            assert!(
+                matches!(
+                    partial_index_path(id),
+                    Err(RepoDownloadError::InvalidPackageId(_))
+                ),
+                "{:?} was accepted",
+                id
+            );
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use std::path::{Path, PathBuf};
+
+use minisign_verify::{PublicKey, Signature};
+
+use crate::config::{RepoRecord, SignatureMode};
+
+/// Appended to a file's URL to find its detached minisign signature.
+pub(crate) const SIGNATURE_SUFFIX: &str = ".minisig";
+
+/// Where the detached signature of the file at `path` is kept.
+pub(crate) fn signature_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(SIGNATURE_SUFFIX);
+    name.into()
+}
+
+/// Reads the detached signature kept next to `path`, if there is one.
+pub(crate) fn read_signature(path: &Path) -> Option<String> {
+    std::fs::read_to_string(signature_path(path)).ok()
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum SignatureError {
+    #[error("No signature was published")]
+    Missing,
+
+    #[error("Malformed signature")]
+    Malformed(#[source] minisign_verify::Error),
+
+    #[error("Not signed by a trusted key")]
+    Untrusted,
+}
+
+/// The keys a repository's index and payloads must be signed with.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub(crate) struct SignaturePolicy {
+    keys: Vec<String>,
+    mode: SignatureMode,
+}
+
+impl SignaturePolicy {
+    pub(crate) fn from_record(record: Option<&RepoRecord>) -> SignaturePolicy {
+        match record {
+            Some(record) => SignaturePolicy {
+                keys: record.trusted_keys.clone(),
+                mode: record.signature_mode,
+            },
+            None => SignaturePolicy::default(),
+        }
+    }
+
+    /// Repositories without trusted keys are not verified.
+    pub(crate) fn is_enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// Whether `data` may be used, given the detached `signature` published for it.
+    pub(crate) fn accepts(&self, data: &[u8], signature: Option<&str>, name: &str) -> bool {
+        if !self.is_enabled() {
+            return true;
+        }
+
+        match verify(&self.keys, data, signature) {
+            Ok(()) => true,
+            Err(e) if self.mode == SignatureMode::Permissive => {
+                log::warn!("Accepting {} despite failed verification: {}", name, e);
+                true
+            }
+            Err(e) => {
+                log::error!("Signature verification failed for {}: {}", name, e);
+                false
+            }
+        }
+    }
+}
+
+fn verify(keys: &[String], data: &[u8], signature: Option<&str>) -> Result<(), SignatureError> {
+    let signature = signature.ok_or(SignatureError::Missing)?;
+    let signature = Signature::decode(signature).map_err(SignatureError::Malformed)?;
+
+    let is_trusted = keys
+        .iter()
+        .filter_map(|key| match PublicKey::from_base64(key) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                log::warn!("Ignoring invalid trusted key {:?}: {}", key, e);
+                None
+            }
+        })
+        .any(|key| key.verify(data, &signature, false).is_ok());
+
+    if is_trusted {
+        Ok(())
+    } else {
+        Err(SignatureError::Untrusted)
+    }
+}
@@ -1,14 +1,27 @@
 use std::collections::HashSet;
 use std::fmt;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+use futures::future::FutureExt;
+use futures::stream::StreamExt;
+use pahkat_types::payload::AsDownloadUrl;
 use serde::{Deserialize, Serialize};
+use stream_cancel::StreamExt as _;
 use url::Url;
 
-use crate::package_store::PackageStore;
+use crate::package_store::{DownloadEvent, PackageStore};
 use pahkat_types::PackageKey;
 
+pub mod ffi_event;
+pub mod history;
+pub mod hook;
 pub mod install;
+pub mod journal;
+pub mod lock;
+pub mod progress;
+mod trace;
 pub mod uninstall;
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
@@ -16,6 +29,8 @@ pub enum PackageStatus {
     NotInstalled,
     UpToDate,
     RequiresUpdate,
+    /// Installed at the current version, but its files are missing or modified.
+    RequiresReinstall,
 }
 
 use crate::repo::PayloadError;
@@ -26,15 +41,19 @@ pub fn status_to_i8(result: Result<PackageStatus, PackageStatusError>) -> i8 {
             PackageStatus::NotInstalled => 0,
             PackageStatus::UpToDate => 1,
             PackageStatus::RequiresUpdate => 2,
+            PackageStatus::RequiresReinstall => 3,
         },
         Err(error) => match error {
             PackageStatusError::Payload(e) => match e {
                 PayloadError::NoPackage | PayloadError::NoConcretePackage => -1,
                 PayloadError::NoPayloadFound => -2,
                 PayloadError::CriteriaUnmet(_) => -5,
+                PayloadError::SignatureInvalid(_) => -6,
             },
             PackageStatusError::WrongPayloadType => -3,
             PackageStatusError::ParsingVersion => -4,
+            PackageStatusError::RepoUnavailable(_) => -7,
+            PackageStatusError::RepoUnsupported { .. } => -8,
         },
     }
 }
@@ -48,6 +67,7 @@ impl fmt::Display for PackageStatus {
                 PackageStatus::NotInstalled => "Not installed",
                 PackageStatus::UpToDate => "Up to date",
                 PackageStatus::RequiresUpdate => "Requires update",
+                PackageStatus::RequiresReinstall => "Requires reinstall",
             }
         )
     }
@@ -62,6 +82,15 @@ pub struct PackageAction {
     pub action: PackageActionType,
     #[serde(default)]
     pub target: InstallTarget,
+    /// Install this exact release instead of the newest one. Installing a release
+    /// older than the installed one requires [`TransactionOptions::allow_downgrade`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<pahkat_types::package::Version>,
+    #[serde(default, skip_serializing_if = "ActionOrigin::is_requested")]
+    pub origin: ActionOrigin,
+    /// Install even if the package is up to date, removing the installed copy first.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub reinstall: bool,
 }
 
 impl fmt::Display for PackageAction {
@@ -70,16 +99,61 @@ impl fmt::Display for PackageAction {
             .field("id", &self.id.to_string())
             .field("action", &self.action)
             .field("target", &self.target)
+            .field("version", &self.version.as_ref().map(|x| x.to_string()))
+            .field("origin", &self.origin.to_string())
+            .field("reinstall", &self.reinstall)
             .finish()
     }
 }
 
+/// Why an action is part of a transaction.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum ActionOrigin {
+    /// Passed to the transaction by the caller.
+    Requested,
+    /// Added because this package depends on it.
+    Dependency(PackageKey),
+    /// Added by [`PackageTransaction::update_all`].
+    Update,
+    /// Added by [`TransactionOptions::remove_orphans`].
+    Orphan,
+}
+
+impl Default for ActionOrigin {
+    fn default() -> Self {
+        ActionOrigin::Requested
+    }
+}
+
+impl ActionOrigin {
+    #[inline]
+    pub fn is_requested(&self) -> bool {
+        *self == ActionOrigin::Requested
+    }
+}
+
+impl fmt::Display for ActionOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ActionOrigin::Requested => write!(f, "requested"),
+            ActionOrigin::Dependency(key) => write!(f, "required by {}", key.id),
+            ActionOrigin::Update => write!(f, "update"),
+            ActionOrigin::Orphan => write!(f, "no longer required"),
+        }
+    }
+}
+
 impl PackageAction {
     pub fn install(id: PackageKey, target: InstallTarget) -> PackageAction {
         PackageAction {
             id,
             action: PackageActionType::Install,
             target,
+            version: None,
+            origin: ActionOrigin::Requested,
+            reinstall: false,
         }
     }
 
@@ -88,9 +162,45 @@ impl PackageAction {
             id,
             action: PackageActionType::Uninstall,
             target,
+            version: None,
+            origin: ActionOrigin::Requested,
+            reinstall: false,
+        }
+    }
+
+    /// Installs the package again even if it is up to date, for example to repair it.
+    /// It is installed over the installed copy, which isn't removed first, so a
+    /// failed reinstall leaves the package installed. Its dependencies are left
+    /// alone unless [`TransactionOptions::reinstall_dependencies`] is set.
+    pub fn reinstall(id: PackageKey, target: InstallTarget) -> PackageAction {
+        PackageAction {
+            reinstall: true,
+            ..PackageAction::install(id, target)
         }
     }
 
+    /// Installs for the target in
+    /// [`Settings::default_install_target`](crate::config::Settings::default_install_target).
+    pub fn install_default(id: PackageKey, settings: &crate::config::Settings) -> PackageAction {
+        PackageAction::install(id, settings.default_install_target())
+    }
+
+    /// Uninstalls from the target in
+    /// [`Settings::default_install_target`](crate::config::Settings::default_install_target).
+    pub fn uninstall_default(id: PackageKey, settings: &crate::config::Settings) -> PackageAction {
+        PackageAction::uninstall(id, settings.default_install_target())
+    }
+
+    pub fn with_version(mut self, version: pahkat_types::package::Version) -> PackageAction {
+        self.version = Some(version);
+        self
+    }
+
+    pub fn with_origin(mut self, origin: ActionOrigin) -> PackageAction {
+        self.origin = origin;
+        self
+    }
+
     #[inline]
     pub fn is_install(&self) -> bool {
         self.action == PackageActionType::Install
@@ -112,6 +222,12 @@ pub enum PackageStatusError {
 
     #[error("Error parsing version")]
     ParsingVersion,
+
+    #[error("Repository index is unavailable: {0}")]
+    RepoUnavailable(String),
+
+    #[error("Repository requires client version {required} or newer, but this is {current}")]
+    RepoUnsupported { required: String, current: String },
 }
 
 #[derive(Debug, thiserror::Error, Clone)]
@@ -125,8 +241,33 @@ pub enum PackageDependencyStatusError {
     #[error("Error parsing version")]
     ParsingVersion(PackageKey),
 
+    #[error("Repository index is unavailable: {1}")]
+    RepoUnavailable(PackageKey, String),
+
+    #[error("Repository requires client version {required} or newer, but this is {current}")]
+    RepoUnsupported {
+        package: PackageKey,
+        required: String,
+        current: String,
+    },
+
     #[error("Package not found: {0}")]
     PackageNotFound(String),
+
+    #[error("Dependency cycle detected: {}", crate::repo::format_keys(.0))]
+    DependencyCycle(Vec<PackageKey>),
+
+    #[error("Could not satisfy dependency")]
+    Deps(PackageKey, #[source] PackageDependencyError),
+
+    #[error("Package belongs to a disabled repository")]
+    RepoDisabled(PackageKey),
+
+    #[error("Dependency resolution exceeded the limit of {limit}")]
+    DependencyLimitExceeded {
+        package: PackageKey,
+        limit: DependencyLimit,
+    },
 }
 
 impl PackageDependencyStatusError {
@@ -135,7 +276,56 @@ impl PackageDependencyStatusError {
             PackageDependencyStatusError::Payload(p, _) => p.to_string(),
             PackageDependencyStatusError::WrongPayloadType(p) => p.to_string(),
             PackageDependencyStatusError::ParsingVersion(p) => p.to_string(),
+            PackageDependencyStatusError::RepoUnavailable(p, _) => p.to_string(),
+            PackageDependencyStatusError::RepoUnsupported { package, .. } => package.to_string(),
             PackageDependencyStatusError::PackageNotFound(p) => p.clone(),
+            PackageDependencyStatusError::DependencyCycle(p) => crate::repo::format_keys(p),
+            PackageDependencyStatusError::Deps(p, _) => p.to_string(),
+            PackageDependencyStatusError::RepoDisabled(p) => p.to_string(),
+            PackageDependencyStatusError::DependencyLimitExceeded { package, .. } => {
+                package.to_string()
+            }
+        }
+    }
+}
+
+impl From<PackageCandidateError> for PackageDependencyStatusError {
+    fn from(err: PackageCandidateError) -> Self {
+        match err {
+            PackageCandidateError::Status(p, PackageStatusError::Payload(e)) => {
+                PackageDependencyStatusError::Payload(p, e)
+            }
+            PackageCandidateError::Status(p, PackageStatusError::WrongPayloadType) => {
+                PackageDependencyStatusError::WrongPayloadType(p)
+            }
+            PackageCandidateError::Status(p, PackageStatusError::ParsingVersion) => {
+                PackageDependencyStatusError::ParsingVersion(p)
+            }
+            PackageCandidateError::Status(p, PackageStatusError::RepoUnavailable(url)) => {
+                PackageDependencyStatusError::RepoUnavailable(p, url)
+            }
+            PackageCandidateError::Status(
+                package,
+                PackageStatusError::RepoUnsupported { required, current },
+            ) => PackageDependencyStatusError::RepoUnsupported {
+                package,
+                required,
+                current,
+            },
+
+            PackageCandidateError::Payload(p, e) => PackageDependencyStatusError::Payload(p, e),
+            PackageCandidateError::UnresolvedId(id) => {
+                PackageDependencyStatusError::PackageNotFound(id)
+            }
+            PackageCandidateError::DependencyCycle(keys) => {
+                PackageDependencyStatusError::DependencyCycle(keys)
+            }
+            PackageCandidateError::Deps(p, e) => PackageDependencyStatusError::Deps(p, e),
+            PackageCandidateError::RepoDisabled(p) => PackageDependencyStatusError::RepoDisabled(p),
+            PackageCandidateError::DependencyLimitExceeded { package, limit } => {
+                PackageDependencyStatusError::DependencyLimitExceeded { package, limit }
+            }
+            PackageCandidateError::UninstallConflict(_) => unreachable!(),
         }
     }
 }
@@ -150,6 +340,12 @@ pub enum PackageDependencyError {
 
     #[error("A package status was invalid: {0}")]
     PackageStatusError(String, #[source] PackageStatusError),
+
+    #[error("Package `{0}` is still required by: {}", crate::repo::format_keys(.1))]
+    StillRequiredBy(PackageKey, Vec<PackageKey>),
+
+    #[error("Dependency `{0}` can't be installed for target: {1:?}")]
+    TargetNotAllowed(PackageKey, InstallTarget),
 }
 
 #[derive(Debug, Clone, thiserror::Error)]
@@ -157,17 +353,96 @@ pub enum PackageTransactionError {
     #[error("No package found with identifier: {0}")]
     NoPackage(String),
 
+    #[error("Package `{0}` could not be resolved")]
+    Unresolved(PackageKey, #[source] crate::repo::ResolveError),
+
     #[error("A dependency resolution error occurred")]
-    Deps(#[from] PackageDependencyError),
+    Deps(#[source] PackageDependencyError),
 
     #[error("Some transaction actions contradict: {0}")]
     ActionContradiction(String),
 
     #[error("Invalid package status detected")]
-    InvalidStatus(#[from] crate::transaction::PackageStatusError),
+    InvalidStatus(#[source] crate::transaction::PackageStatusError),
 
     #[error("A payload could not be resolved")]
     InvalidPayload(#[from] crate::repo::PayloadError),
+
+    #[error("Dependency cycle detected: {}", crate::repo::format_keys(.0))]
+    DependencyCycle(Vec<PackageKey>),
+
+    #[error("A package candidate could not be resolved")]
+    Candidate(#[source] PackageCandidateError),
+
+    #[error("Package `{0}` conflicts with `{1}`")]
+    Conflict(PackageKey, PackageKey),
+
+    #[error("Transaction resolution was cancelled")]
+    Cancelled,
+
+    #[error("Installing `{0}` {2} would downgrade it from {1}")]
+    Downgrade(
+        PackageKey,
+        pahkat_types::package::Version,
+        pahkat_types::package::Version,
+    ),
+
+    /// A configured repository's index could not be loaded, so retrying once the
+    /// network is back may succeed.
+    #[error("Repository index could not be loaded: {0}")]
+    RepoUnavailable(String),
+
+    /// Resolving dependencies went past [`TransactionOptions::dependency_limits`].
+    #[error("Dependency resolution exceeded the limit of {limit}")]
+    DependencyLimitExceeded { limit: DependencyLimit },
+}
+
+impl PackageTransactionError {
+    /// Whether the failure is a connectivity problem rather than a bad request.
+    pub fn is_repo_unavailable(&self) -> bool {
+        matches!(self, PackageTransactionError::RepoUnavailable(_))
+    }
+}
+
+impl From<PackageStatusError> for PackageTransactionError {
+    fn from(err: PackageStatusError) -> Self {
+        match err {
+            PackageStatusError::RepoUnavailable(url) => {
+                PackageTransactionError::RepoUnavailable(url)
+            }
+            err => PackageTransactionError::InvalidStatus(err),
+        }
+    }
+}
+
+impl From<PackageDependencyError> for PackageTransactionError {
+    fn from(err: PackageDependencyError) -> Self {
+        match err {
+            PackageDependencyError::PackageStatusError(
+                _,
+                PackageStatusError::RepoUnavailable(url),
+            ) => PackageTransactionError::RepoUnavailable(url),
+            err => PackageTransactionError::Deps(err),
+        }
+    }
+}
+
+impl From<PackageCandidateError> for PackageTransactionError {
+    fn from(err: PackageCandidateError) -> Self {
+        match err {
+            PackageCandidateError::DependencyCycle(keys) => {
+                PackageTransactionError::DependencyCycle(keys)
+            }
+            PackageCandidateError::Status(_, PackageStatusError::RepoUnavailable(url)) => {
+                PackageTransactionError::RepoUnavailable(url)
+            }
+            PackageCandidateError::Deps(_, e) => e.into(),
+            PackageCandidateError::DependencyLimitExceeded { limit, .. } => {
+                PackageTransactionError::DependencyLimitExceeded { limit }
+            }
+            err => PackageTransactionError::Candidate(err),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -177,12 +452,25 @@ pub enum PackageActionType {
     Uninstall,
 }
 
+/// A byte that is not the code of any [`PackageActionType`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("Invalid package action type: {0}")]
+pub struct InvalidActionType(pub u8);
+
 impl PackageActionType {
+    #[deprecated(note = "panics on invalid input; use `try_from_u8`")]
     pub fn from_u8(x: u8) -> PackageActionType {
+        match Self::try_from_u8(x) {
+            Ok(v) => v,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    pub fn try_from_u8(x: u8) -> Result<PackageActionType, InvalidActionType> {
         match x {
-            0 => PackageActionType::Install,
-            1 => PackageActionType::Uninstall,
-            _ => panic!("Invalid package action type: {}", x),
+            0 => Ok(PackageActionType::Install),
+            1 => Ok(PackageActionType::Uninstall),
+            _ => Err(InvalidActionType(x)),
         }
     }
 
@@ -194,15 +482,67 @@ impl PackageActionType {
     }
 }
 
+use self::hook::{run_hooks, Hook, HookError, HookPoint};
 use self::install::InstallError;
+use self::journal::{Journal, JournalError, ResumeError};
+use self::lock::{LockError, TransactionLock};
 use self::uninstall::UninstallError;
+use crate::download::DownloadError;
+
+/// Why a transaction no longer matches the system, from [`PackageTransaction::validate`].
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum ValidationError {
+    #[error("Package is both installed and uninstalled: `{0}`")]
+    Contradiction(PackageKey),
+
+    #[error("Package can no longer be resolved: `{0}`")]
+    Unresolvable(PackageKey, #[source] PayloadError),
+
+    #[error("Release of `{0}` changed from {1} to {2}")]
+    ReleaseChanged(
+        PackageKey,
+        pahkat_types::package::Version,
+        pahkat_types::package::Version,
+    ),
+
+    #[error("Status of `{0}` changed from {1} to {2}")]
+    StatusChanged(PackageKey, PackageStatus, PackageStatus),
+
+    #[error("Could not determine status of package: `{0}`")]
+    Status(PackageKey, #[source] PackageStatusError),
+}
+
+impl ValidationError {
+    pub fn package(&self) -> &PackageKey {
+        match self {
+            ValidationError::Contradiction(p)
+            | ValidationError::Unresolvable(p, _)
+            | ValidationError::ReleaseChanged(p, _, _)
+            | ValidationError::StatusChanged(p, _, _)
+            | ValidationError::Status(p, _) => p,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum TransactionError {
-    ValidationFailed,
+    ValidationFailed(ValidationError),
     UserCancelled,
+    Download(DownloadError),
     Uninstall(UninstallError),
     Install(InstallError),
+    Locked { held_by_pid: Option<u32> },
+    Lock(std::io::Error),
+    Hook(HookError),
+}
+
+impl From<LockError> for TransactionError {
+    fn from(err: LockError) -> Self {
+        match err {
+            LockError::Locked { held_by_pid } => TransactionError::Locked { held_by_pid },
+            LockError::Io(e) => TransactionError::Lock(e),
+        }
+    }
 }
 
 impl std::error::Error for TransactionError {}
@@ -212,21 +552,316 @@ impl std::fmt::Display for TransactionError {
         use TransactionError::*;
 
         match self {
-            ValidationFailed => write!(f, "Validation failed"),
+            ValidationFailed(e) => write!(f, "Validation failed: {}", e),
             UserCancelled => write!(f, "User cancelled"),
-            Uninstall(e) => write!(f, "{:?}", e),
-            Install(e) => write!(f, "{:?}", e),
+            Download(e) => write!(f, "{}", e),
+            Uninstall(e) => write!(f, "{}", e),
+            Install(e) => write!(f, "{}", e),
+            Locked {
+                held_by_pid: Some(pid),
+            } => write!(f, "Another transaction is in progress (pid {})", pid),
+            Locked { held_by_pid: None } => write!(f, "Another transaction is in progress"),
+            Lock(e) => write!(f, "Could not acquire transaction lock: {}", e),
+            Hook(e) => write!(f, "Hook failed: {}", e),
         }
     }
 }
 
 #[derive(Debug)]
 pub enum TransactionEvent {
-    Installing(PackageKey),
-    Uninstalling(PackageKey),
-    Progress(PackageKey, String),
+    Downloading(PackageKey, u64, u64),
+    /// The package, its 1-based position in the transaction, and the total number of actions.
+    Installing(PackageKey, usize, usize),
+    /// Same fields as `Installing`.
+    Uninstalling(PackageKey, usize, usize),
+    Progress(PackageKey, ProgressDetail),
     Error(PackageKey, TransactionError),
-    Complete,
+    RollingBack(PackageKey),
+    RolledBack,
+    RollbackFailed(PackageKey, TransactionError),
+    /// A transient failure occurred; the package and the attempt about to be made.
+    Retrying(PackageKey, u32),
+    /// A dependency failed, so the action was not attempted.
+    Skipped(PackageKey, String),
+    /// The action on the package succeeded, but a reboot is needed to finish it.
+    RebootRequired(PackageKey),
+    Cancelled(CancelReason),
+    Complete(TransactionSummary),
+}
+
+/// Why a transaction was cancelled, sent with [`TransactionEvent::Cancelled`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CancelReason {
+    User,
+    Timeout,
+    Shutdown,
+}
+
+impl fmt::Display for CancelReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CancelReason::User => write!(f, "Cancelled by user"),
+            CancelReason::Timeout => write!(f, "Timed out"),
+            CancelReason::Shutdown => write!(f, "Shutting down"),
+        }
+    }
+}
+
+/// Cancels the transaction it was returned with. Dropping it cancels with
+/// [`CancelReason::User`].
+pub struct Canceller {
+    trigger: Option<stream_cancel::Trigger>,
+    reason: Arc<Mutex<Option<CancelReason>>>,
+}
+
+impl Canceller {
+    fn new() -> (
+        Canceller,
+        stream_cancel::Tripwire,
+        Arc<Mutex<Option<CancelReason>>>,
+    ) {
+        let (trigger, tripwire) = stream_cancel::Tripwire::new();
+        let reason = Arc::new(Mutex::new(None));
+        let canceller = Canceller {
+            trigger: Some(trigger),
+            reason: Arc::clone(&reason),
+        };
+        (canceller, tripwire, reason)
+    }
+
+    pub fn cancel(self, reason: CancelReason) {
+        *self.reason.lock().unwrap() = Some(reason);
+    }
+
+    /// Lets the transaction run to completion even after this is dropped.
+    pub fn disable(mut self) {
+        if let Some(trigger) = self.trigger.take() {
+            trigger.disable();
+        }
+    }
+}
+
+impl Drop for Canceller {
+    fn drop(&mut self) {
+        if let Some(trigger) = self.trigger.take() {
+            self.reason
+                .lock()
+                .unwrap()
+                .get_or_insert(CancelReason::User);
+            drop(trigger);
+        }
+    }
+}
+
+/// The outcome of every action, sent with [`TransactionEvent::Complete`].
+///
+/// `failed` and `skipped` can only be non-empty with
+/// [`ProcessOptions::continue_on_error`]; otherwise the stream ends at the first
+/// error instead of completing.
+#[derive(Debug, Clone, Default)]
+pub struct TransactionSummary {
+    pub succeeded: Vec<PackageKey>,
+    pub failed: Vec<(PackageKey, String)>,
+    pub skipped: Vec<(PackageKey, String)>,
+    /// Whether any succeeded action needs a reboot to finish.
+    pub reboot_required: bool,
+}
+
+impl TransactionSummary {
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty() && self.skipped.is_empty()
+    }
+
+    fn failed_dependency(&self, target: &Target) -> Option<&PackageKey> {
+        self.failed
+            .iter()
+            .chain(self.skipped.iter())
+            .map(|(key, _)| key)
+            .find(|key| crate::repo::target_depends_on(target, key))
+    }
+}
+
+/// What a package is doing, for [`TransactionEvent::Progress`]. Byte counts are
+/// `0` for `total` when it isn't known.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ProgressDetail {
+    Downloading {
+        done: u64,
+        total: u64,
+    },
+    /// The download failed and is continuing from another source, numbered as in
+    /// [`ProgressEvent::Fallback`](crate::package_store::ProgressEvent::Fallback).
+    FallingBack {
+        mirror: usize,
+    },
+    Extracting {
+        done: u64,
+        total: u64,
+    },
+    RunningInstaller,
+    Verifying,
+    /// The payload was rebuilt from the cached payload of the installed version
+    /// `from` and a delta, instead of downloaded in full.
+    Delta {
+        from: String,
+    },
+}
+
+impl fmt::Display for ProgressDetail {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProgressDetail::Downloading { done, total } => {
+                write!(f, "downloading {}/{} bytes", done, total)
+            }
+            ProgressDetail::FallingBack { mirror: 0 } => {
+                write!(f, "falling back to the payload URL")
+            }
+            ProgressDetail::FallingBack { mirror } => {
+                write!(f, "falling back to mirror {}", mirror)
+            }
+            ProgressDetail::Extracting { done, total } => {
+                write!(f, "extracting {}/{} bytes", done, total)
+            }
+            ProgressDetail::RunningInstaller => write!(f, "running installer"),
+            ProgressDetail::Verifying => write!(f, "verifying"),
+            ProgressDetail::Delta { from } => write!(f, "applied delta from {}", from),
+        }
+    }
+}
+
+/// Options controlling how [`PackageTransaction::process_with_options`] runs.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct ProcessOptions {
+    /// On the first failed action, reverse every action that already completed.
+    ///
+    /// Fresh installs are reversed by uninstalling, and updates by installing the
+    /// version that was installed before. Uninstalls are reversed by reinstalling.
+    /// Restoring a package requires its payload to still be in the cache.
+    pub rollback: bool,
+
+    /// Rewrite a journal at this path after every completed action, so an
+    /// interrupted run can be picked up by [`PackageTransaction::resume_from_journal`].
+    pub journal: Option<PathBuf>,
+
+    /// How often to retry downloads and actions that fail transiently.
+    pub retry: RetryPolicy,
+
+    /// Called before and after each install and uninstall, in registration order.
+    ///
+    /// An error from a pre-hook vetoes the action and fails the transaction; errors
+    /// from post-hooks are only logged. Hooks are not run while rolling back.
+    pub hooks: Vec<Hook>,
+
+    /// How often [`TransactionEvent::Downloading`], and extraction progress, is
+    /// emitted per package.
+    pub progress: ProgressThrottle,
+
+    /// After a failed download or action, carry on with the remaining actions and
+    /// report the failures in the summary. Installs depending on a failed package
+    /// are skipped with [`TransactionEvent::Skipped`]. Ignored when `rollback` is set.
+    pub continue_on_error: bool,
+
+    /// When cancelled while downloading, delete the partially downloaded payloads
+    /// instead of keeping them to resume on the next run.
+    pub discard_partial_downloads: bool,
+}
+
+impl ProcessOptions {
+    pub fn rollback(mut self, rollback: bool) -> Self {
+        self.rollback = rollback;
+        self
+    }
+
+    pub fn journal<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.journal = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn continue_on_error(mut self, continue_on_error: bool) -> Self {
+        self.continue_on_error = continue_on_error;
+        self
+    }
+
+    pub fn discard_partial_downloads(mut self, discard_partial_downloads: bool) -> Self {
+        self.discard_partial_downloads = discard_partial_downloads;
+        self
+    }
+
+    pub fn progress(mut self, progress: ProgressThrottle) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    pub fn hook<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&HookPoint) -> Result<(), HookError> + Send + Sync + 'static,
+    {
+        self.hooks.push(Hook::new(f));
+        self
+    }
+}
+
+/// Exponential backoff for transient failures. The default makes a single attempt.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: u32, base_delay: Duration) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_delay,
+        }
+    }
+
+    /// Time to wait after `attempt` (1-based) has failed.
+    fn delay(&self, attempt: u32) -> Duration {
+        self.base_delay * 2u32.saturating_pow(attempt.saturating_sub(1))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::new(1, Duration::from_secs(1))
+    }
+}
+
+/// Limits download progress events to one per `interval`, or sooner once `bytes`
+/// more have arrived. The event for the last chunk of a download is always sent.
+///
+/// Downloads report progress every 100ms, so a shorter `interval` sends no more
+/// events than that.
+#[derive(Debug, Clone, Copy)]
+pub struct ProgressThrottle {
+    pub interval: Duration,
+    pub bytes: Option<u64>,
+}
+
+impl ProgressThrottle {
+    pub fn new(interval: Duration, bytes: Option<u64>) -> ProgressThrottle {
+        ProgressThrottle { interval, bytes }
+    }
+
+    /// Emits every progress event.
+    pub fn none() -> ProgressThrottle {
+        ProgressThrottle::new(Duration::from_secs(0), None)
+    }
+}
+
+impl Default for ProgressThrottle {
+    fn default() -> Self {
+        ProgressThrottle::new(crate::download::PROGRESS_INTERVAL, None)
+    }
 }
 
 use pahkat_types::{
@@ -240,6 +875,10 @@ pub struct ResolvedAction {
     pub descriptor: Descriptor,
     pub release: Release,
     pub target: Target,
+    pub status: PackageStatus,
+    /// Whether the payload declares that a reboot is needed to finish the action.
+    #[serde(default)]
+    pub is_reboot_required: bool,
 }
 
 impl std::fmt::Display for ResolvedAction {
@@ -252,12 +891,37 @@ impl std::fmt::Display for ResolvedAction {
     }
 }
 
+const DEFAULT_CONCURRENT_DOWNLOADS: usize = 3;
+
 pub struct PackageTransaction {
     store: Arc<dyn PackageStore>,
+    requested: Vec<PackageAction>,
     actions: Arc<Vec<ResolvedAction>>,
     is_reboot_required: bool,
 }
 
+/// A preview of what [`PackageTransaction::process`] would do, in processing order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransactionPlan {
+    pub actions: Vec<PlannedAction>,
+    /// Requested actions that were dropped because there was nothing to do,
+    /// such as installing a package that is already up to date.
+    pub dropped: Vec<PackageAction>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlannedAction {
+    pub action: PackageAction,
+    /// The package's status before the transaction runs.
+    pub status: PackageStatus,
+    pub is_dependency: bool,
+    /// The release being installed, or for uninstalls, the one the index resolved.
+    pub version: pahkat_types::package::Version,
+    /// Payload size in bytes, as listed in the repository index.
+    pub size: u64,
+    pub installed_size: u64,
+}
+
 use crate::repo::PackageCandidateError;
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
@@ -312,34 +976,410 @@ pub struct ResolvedPackageQuery {
     pub status: PackageStatus,
 }
 
+/// Options controlling how a [`PackageTransaction`] is resolved.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct TransactionOptions {
+    /// Uninstall packages even if other installed packages still depend on them.
+    pub force: bool,
+
+    /// Also uninstall packages that were only installed as dependencies and are
+    /// no longer required by anything that remains installed.
+    pub remove_orphans: bool,
+
+    /// Allow install actions with a `version` older than the installed one.
+    pub allow_downgrade: bool,
+
+    /// Add the dependencies of installed packages. When disabled, only the
+    /// requested actions are planned and the caller is responsible for any
+    /// dependencies being present. Enabled by default.
+    pub resolve_dependencies: bool,
+
+    /// Also reinstall the up-to-date dependencies of [`PackageAction::reinstall`]
+    /// actions, not only the packages themselves.
+    pub reinstall_dependencies: bool,
+
+    /// Bounds on the dependencies resolved for the requested actions.
+    pub dependency_limits: DependencyLimits,
+
+    /// Resolve payloads for this platform instead of the host's, such as to plan
+    /// or download for another machine. Keys that name a platform keep theirs,
+    /// and dependencies follow the package that requires them.
+    pub platform: Option<String>,
+
+    /// As [`platform`](TransactionOptions::platform), for the architecture.
+    pub arch: Option<String>,
+
+    /// Install dependencies of user installs that only support system-wide
+    /// installs system-wide, instead of failing with
+    /// [`PackageDependencyError::TargetNotAllowed`].
+    pub allow_system_dependencies: bool,
+}
+
+impl Default for TransactionOptions {
+    fn default() -> Self {
+        TransactionOptions {
+            force: false,
+            remove_orphans: false,
+            allow_downgrade: false,
+            resolve_dependencies: true,
+            reinstall_dependencies: false,
+            dependency_limits: DependencyLimits::default(),
+            platform: None,
+            arch: None,
+            allow_system_dependencies: false,
+        }
+    }
+}
+
+impl TransactionOptions {
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    pub fn remove_orphans(mut self, remove_orphans: bool) -> Self {
+        self.remove_orphans = remove_orphans;
+        self
+    }
+
+    pub fn allow_downgrade(mut self, allow_downgrade: bool) -> Self {
+        self.allow_downgrade = allow_downgrade;
+        self
+    }
+
+    pub fn resolve_dependencies(mut self, resolve_dependencies: bool) -> Self {
+        self.resolve_dependencies = resolve_dependencies;
+        self
+    }
+
+    pub fn reinstall_dependencies(mut self, reinstall_dependencies: bool) -> Self {
+        self.reinstall_dependencies = reinstall_dependencies;
+        self
+    }
+
+    pub fn dependency_limits(mut self, dependency_limits: DependencyLimits) -> Self {
+        self.dependency_limits = dependency_limits;
+        self
+    }
+
+    pub fn platform<S: Into<String>>(mut self, platform: S) -> Self {
+        self.platform = Some(platform.into());
+        self
+    }
+
+    pub fn arch<S: Into<String>>(mut self, arch: S) -> Self {
+        self.arch = Some(arch.into());
+        self
+    }
+
+    pub fn allow_system_dependencies(mut self, allow_system_dependencies: bool) -> Self {
+        self.allow_system_dependencies = allow_system_dependencies;
+        self
+    }
+}
+
+/// Bounds on dependency resolution, so that a repository can't make it run away
+/// even without a cycle. The defaults are far beyond any real dependency graph.
+#[derive(Debug, Clone, Copy)]
+pub struct DependencyLimits {
+    /// Most packages added as dependencies of the requested ones.
+    pub max_dependencies: usize,
+    /// Most levels of dependencies below a requested package.
+    pub max_depth: usize,
+}
+
+impl DependencyLimits {
+    pub fn new(max_dependencies: usize, max_depth: usize) -> DependencyLimits {
+        DependencyLimits {
+            max_dependencies,
+            max_depth,
+        }
+    }
+}
+
+impl Default for DependencyLimits {
+    fn default() -> Self {
+        DependencyLimits::new(5000, 64)
+    }
+}
+
+/// Which of the [`DependencyLimits`] was exceeded, and its value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DependencyLimit {
+    Dependencies(usize),
+    Depth(usize),
+}
+
+impl fmt::Display for DependencyLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DependencyLimit::Dependencies(n) => write!(f, "{} dependencies", n),
+            DependencyLimit::Depth(n) => write!(f, "{} levels of dependencies", n),
+        }
+    }
+}
+
 impl PackageTransaction {
     pub fn new(
         store: Arc<dyn PackageStore>,
         actions: Vec<PackageAction>,
-    ) -> Result<PackageTransaction, PackageCandidateError> {
+    ) -> Result<PackageTransaction, PackageTransactionError> {
+        Self::new_with_options(store, actions, TransactionOptions::default())
+    }
+
+    /// Updates every package installed for `target` that has a newer release,
+    /// along with any new dependencies. Pinned packages and packages from disabled
+    /// repositories are left alone.
+    pub fn update_all(
+        store: Arc<dyn PackageStore>,
+        target: InstallTarget,
+    ) -> Result<PackageTransaction, PackageTransactionError> {
+        let keys = {
+            let available = store.available_updates();
+            let config = store.config();
+            let config = config.read().unwrap();
+            available
+                .into_iter()
+                .map(|(key, _, _)| key)
+                .filter(|key| crate::repo::pinned_version(key, &config).is_none())
+                .collect::<Vec<_>>()
+        };
+
+        let pairs = keys
+            .iter()
+            .map(|key| (key.clone(), target))
+            .collect::<Vec<_>>();
+        let actions = keys
+            .into_iter()
+            .zip(store.statuses(&pairs).into_iter())
+            .filter(|(_, status)| matches!(status, Ok(PackageStatus::RequiresUpdate)))
+            .map(|(key, _)| PackageAction::install(key, target).with_origin(ActionOrigin::Update))
+            .collect::<Vec<_>>();
+
+        Self::new(store, actions)
+    }
+
+    /// [`update_all`](Self::update_all) for the store's
+    /// [`default_install_target`](crate::config::Settings::default_install_target).
+    pub fn update_all_default(
+        store: Arc<dyn PackageStore>,
+    ) -> Result<PackageTransaction, PackageTransactionError> {
+        let target = default_install_target(&*store);
+        Self::update_all(store, target)
+    }
+
+    /// Uninstalls every package installed for `target` from the repository at `url`.
+    ///
+    /// Run this before removing the repository from the config, as uninstalling
+    /// needs its index. Packages from other repositories that still depend on one
+    /// of these make this fail with [`PackageDependencyError::StillRequiredBy`].
+    pub fn uninstall_repo(
+        store: Arc<dyn PackageStore>,
+        url: &pahkat_types::repo::RepoUrl,
+        target: InstallTarget,
+    ) -> Result<PackageTransaction, PackageTransactionError> {
+        let actions = {
+            let repos = store.repos();
+            let repos = repos.read().unwrap();
+            store
+                .installed()
+                .into_iter()
+                .filter(|(key, installed, _)| {
+                    key.repository_url == *url
+                        && crate::repo::resolve_install_target(
+                            key,
+                            target,
+                            &*repos,
+                            &store.config().read().unwrap(),
+                        ) == *installed
+                })
+                .map(|(key, installed, _)| PackageAction::uninstall(key, installed))
+                .collect::<Vec<_>>()
+        };
+
+        Self::new(store, actions)
+    }
+
+    /// [`uninstall_repo`](Self::uninstall_repo) for the store's
+    /// [`default_install_target`](crate::config::Settings::default_install_target).
+    pub fn uninstall_repo_default(
+        store: Arc<dyn PackageStore>,
+        url: &pahkat_types::repo::RepoUrl,
+    ) -> Result<PackageTransaction, PackageTransactionError> {
+        let target = default_install_target(&*store);
+        Self::uninstall_repo(store, url, target)
+    }
+
+    /// Resolves the transaction on the blocking pool, returning
+    /// [`PackageTransactionError::Cancelled`] as soon as `cancel` is triggered.
+    ///
+    /// Resolution itself can't be interrupted; a cancelled resolution finishes in
+    /// the background and its result is discarded.
+    pub async fn new_async(
+        store: Arc<dyn PackageStore>,
+        actions: Vec<PackageAction>,
+        options: TransactionOptions,
+        cancel: stream_cancel::Tripwire,
+    ) -> Result<PackageTransaction, PackageTransactionError> {
+        use futures::future::{select, Either};
+
+        let resolve =
+            tokio::task::spawn_blocking(move || Self::new_with_options(store, actions, options));
+        futures::pin_mut!(cancel);
+
+        let result = match select(resolve, cancel).await {
+            Either::Left((result, _)) => result,
+            Either::Right((true, _)) => return Err(PackageTransactionError::Cancelled),
+            // The trigger was disabled rather than fired, so wait it out.
+            Either::Right((false, resolve)) => resolve.await,
+        };
+
+        result.unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+    }
+
+    pub fn new_with_options(
+        store: Arc<dyn PackageStore>,
+        mut actions: Vec<PackageAction>,
+        options: TransactionOptions,
+    ) -> Result<PackageTransaction, PackageTransactionError> {
         log::debug!("New transaction with actions: {:#?}", &actions);
 
         let repos = store.repos();
         let repos = repos.read().unwrap();
 
+        // The store resolves the release to install from the key alone.
+        for action in actions.iter_mut() {
+            if let Some(version) = action.version.as_ref() {
+                action.id.query.version = Some(version.to_string());
+            }
+            if action.id.query.platform.is_none() {
+                action.id.query.platform = options.platform.clone();
+            }
+            if action.id.query.arch.is_none() {
+                action.id.query.arch = options.arch.clone();
+            }
+        }
+
+        // Say why a requested package can't be found before trying to plan for it.
+        {
+            let config = store.config();
+            let config = config.read().unwrap();
+            for action in actions.iter() {
+                use crate::repo::ResolveError;
+
+                match crate::repo::try_find_package_by_key(&config, &action.id, &*repos) {
+                    Ok(_) => {}
+                    Err(ResolveError::RepoUnavailable(url)) => {
+                        return Err(PackageTransactionError::RepoUnavailable(url.to_string()))
+                    }
+                    Err(e) => {
+                        return Err(PackageTransactionError::Unresolved(action.id.clone(), e))
+                    }
+                }
+            }
+        }
+
+        // Settle automatic targets up front so every action records a concrete one.
+        {
+            let config = store.config();
+            let config = config.read().unwrap();
+            for action in actions.iter_mut() {
+                action.target = crate::repo::resolve_install_target(
+                    &action.id,
+                    action.target,
+                    &*repos,
+                    &config,
+                );
+            }
+        }
+
+        // Only installs of a chosen version need what's installed, so most
+        // transactions skip listing every installed package.
+        let installed = if actions
+            .iter()
+            .any(|x| x.is_install() && x.version.is_some())
+        {
+            store
+                .installed()
+                .into_iter()
+                .map(|(key, target, version)| {
+                    ((crate::repo::package_identity(&key), target), version)
+                })
+                .collect::<std::collections::HashMap<_, _>>()
+        } else {
+            Default::default()
+        };
+        let reinstalls = actions
+            .iter()
+            .filter(|x| x.is_install() && x.reinstall)
+            .map(|x| x.id.clone())
+            .collect::<Vec<_>>();
+        let mut downgrades = vec![];
+        for action in actions.iter().filter(|x| x.is_install()) {
+            let version = match action.version.as_ref() {
+                Some(v) => v,
+                None => continue,
+            };
+            let current =
+                installed.get(&(crate::repo::package_identity(&action.id), action.target));
+
+            if let Some(current) = current {
+                if current > version {
+                    if !options.allow_downgrade {
+                        return Err(PackageTransactionError::Downgrade(
+                            action.id.clone(),
+                            current.clone(),
+                            version.clone(),
+                        ));
+                    }
+                    downgrades.push(action.id.clone());
+                }
+            }
+        }
+
         // // Get mutation set (for install and uninstall actions)
         let install_target = actions
             .iter()
             .map(|a| a.target)
-            .collect::<std::collections::HashSet<_>>()
+            .collect::<std::collections::BTreeSet<_>>()
             .into_iter()
             .collect::<Vec<_>>();
         let candidate_keys = actions
             .iter()
             .map(|a| (a.action, a.id.clone()))
             .collect::<Vec<_>>();
-        let mutation_set =
-            crate::repo::resolve_package_set(&*store, &*candidate_keys, &*install_target)?;
+        let forced = downgrades
+            .into_iter()
+            .chain(reinstalls.iter().cloned())
+            .collect::<Vec<_>>();
+        let forced_dependencies: &[PackageKey] = match options.reinstall_dependencies {
+            true => &reinstalls,
+            false => &[],
+        };
+        let mutation_set = crate::repo::resolve_package_set(
+            &*store,
+            &*candidate_keys,
+            &*install_target,
+            &forced,
+            forced_dependencies,
+            options.resolve_dependencies,
+            &options.dependency_limits,
+        )?;
+
+        let requested = actions
+            .iter()
+            .map(|x| (&x.id, x))
+            .collect::<std::collections::HashMap<_, _>>();
 
-        let is_reboot_required = mutation_set.iter().any(|x| x.is_reboot_required);
+        // Dependencies follow the requested actions when they all agree on a target.
+        let dependency_target = match &*install_target {
+            [target] => *target,
+            _ => InstallTarget::System,
+        };
 
         // Create a list of resolved actions to be processed.
-        let new_actions = mutation_set
+        let mut new_actions = mutation_set
             .into_iter()
             .map(|candidate| {
                 let key = candidate.package_key;
@@ -349,19 +1389,206 @@ impl PackageTransaction {
                     descriptor: candidate.descriptor,
                     release: candidate.release,
                     target: candidate.target,
-                    action: actions
-                        .iter()
-                        .find(|x| &x.id == &key)
-                        .cloned()
+                    status: candidate.status,
+                    is_reboot_required: candidate.is_reboot_required,
+                    action: requested
+                        .get(&key)
+                        .map(|x| (*x).clone())
                         .unwrap_or_else(|| PackageAction {
                             id: key,
                             action,
-                            target: InstallTarget::System,
+                            target: dependency_target,
+                            version: None,
+                            origin: ActionOrigin::Requested,
+                            // Up-to-date dependencies are only planned when reinstalling them.
+                            reinstall: candidate.status == PackageStatus::UpToDate,
                         }),
                 }
             })
             .collect::<Vec<_>>();
 
+        // Attribute each added dependency to a package that needs it, preferring
+        // the requested ones.
+        let installs = new_actions
+            .iter()
+            .filter(|x| x.action.is_install())
+            .collect::<Vec<_>>();
+        let index = crate::repo::DependentsIndex::new(installs.iter().map(|x| &x.target));
+        let dependents = new_actions
+            .iter()
+            .map(|record| {
+                if !record.action.is_install() || requested.contains_key(&record.action.id) {
+                    return None;
+                }
+                let dependents = index.dependents(&record.action.id);
+                let mut dependents = dependents.iter().map(|i| installs[*i]);
+                let first = dependents.clone().next();
+                dependents
+                    .find(|other| requested.contains_key(&other.action.id))
+                    .or(first)
+                    .map(|other| other.action.id.clone())
+            })
+            .collect::<Vec<_>>();
+        for (record, dependent) in new_actions.iter_mut().zip(dependents.into_iter()) {
+            if let Some(dependent) = dependent {
+                record.action.origin = ActionOrigin::Dependency(dependent);
+            }
+        }
+
+        let mut satisfied = std::collections::HashSet::new();
+        for record in new_actions
+            .iter_mut()
+            .filter(|x| x.action.is_install() && !requested.contains_key(&x.action.id))
+        {
+            let payload = &record.target.payload;
+            let target = crate::repo::dependency_target(
+                &record.action.id,
+                record.action.target,
+                |target| crate::repo::payload_allows_target(payload, target),
+                options.allow_system_dependencies,
+            )?;
+            if target == record.action.target {
+                continue;
+            }
+
+            // The status was resolved for the inherited target.
+            let status = store.status(&record.action.id, target).map_err(|e| {
+                PackageDependencyError::PackageStatusError(record.action.id.to_string(), e)
+            })?;
+            if status == PackageStatus::UpToDate && !record.action.reinstall {
+                satisfied.insert(record.action.id.clone());
+                continue;
+            }
+
+            record.action.target = target;
+            record.action.reinstall = status == PackageStatus::UpToDate;
+            record.status = status;
+            record.is_reboot_required = crate::repo::is_reboot_required(
+                &record.target.payload,
+                record.action.action,
+                status,
+            );
+        }
+        new_actions.retain(|x| !satisfied.contains(&x.action.id));
+
+        if options.remove_orphans {
+            let uninstalling = new_actions
+                .iter()
+                .filter(|x| x.action.is_uninstall())
+                .map(|x| x.action.id.clone())
+                .collect::<Vec<_>>();
+            let installing = new_actions
+                .iter()
+                .filter(|x| x.action.is_install())
+                .map(|x| x.action.id.clone())
+                .collect::<Vec<_>>();
+
+            for (key, target) in
+                crate::repo::find_orphans(&*store, &uninstalling, &installing, &*repos)
+            {
+                let query =
+                    crate::repo::ReleaseQuery::new(&key, &*repos, &store.config().read().unwrap());
+                let (payload_target, release, descriptor) =
+                    match crate::repo::resolve_payload(&key, &query, &*repos) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            log::warn!("Skipping orphan {}: {:?}", &key, e);
+                            continue;
+                        }
+                    };
+                let status = match store.status(&key, target) {
+                    Ok(PackageStatus::NotInstalled) => continue,
+                    Ok(v) => v,
+                    Err(e) => {
+                        log::warn!("Skipping orphan {}: {:?}", &key, e);
+                        continue;
+                    }
+                };
+
+                log::debug!("Removing orphaned dependency: {}", &key);
+                let is_reboot_required = crate::repo::is_reboot_required(
+                    &payload_target.payload,
+                    PackageActionType::Uninstall,
+                    status,
+                );
+                new_actions.push(ResolvedAction {
+                    action: PackageAction::uninstall(key, target).with_origin(ActionOrigin::Orphan),
+                    descriptor,
+                    release,
+                    target: payload_target,
+                    status,
+                    is_reboot_required,
+                });
+            }
+        }
+
+        // Refuse to uninstall packages that remaining installed packages depend on
+        if !options.force {
+            let uninstalling = new_actions
+                .iter()
+                .filter(|x| x.action.is_uninstall())
+                .map(|x| &x.action.id)
+                .collect::<Vec<_>>();
+
+            for key in uninstalling.iter() {
+                let mut dependents =
+                    crate::repo::installed_dependents(&*store, key, &*install_target, &*repos)
+                        .into_iter()
+                        .filter(|dep| {
+                            !uninstalling
+                                .iter()
+                                .any(|x| crate::repo::is_same_package(x, dep))
+                        })
+                        .collect::<Vec<_>>();
+                dependents.sort_by_cached_key(|x| x.to_string());
+
+                if !dependents.is_empty() {
+                    return Err(PackageDependencyError::StillRequiredBy(
+                        (*key).clone(),
+                        dependents,
+                    )
+                    .into());
+                }
+            }
+        }
+
+        // Refuse to leave two conflicting packages installed side by side
+        {
+            let installing = new_actions
+                .iter()
+                .filter(|x| x.action.is_install())
+                .collect::<Vec<_>>();
+            let uninstalling = new_actions
+                .iter()
+                .filter(|x| x.action.is_uninstall())
+                .map(|x| &x.action.id)
+                .collect::<Vec<_>>();
+
+            for (i, record) in installing.iter().enumerate() {
+                let key = &record.action.id;
+
+                for other in installing[i + 1..].iter() {
+                    if crate::repo::is_conflicting(key, &other.action.id, &*repos) {
+                        return Err(PackageTransactionError::Conflict(
+                            key.clone(),
+                            other.action.id.clone(),
+                        ));
+                    }
+                }
+
+                let installed =
+                    crate::repo::installed_conflicts(&*store, key, &*install_target, &*repos);
+
+                if let Some(other) = installed.into_iter().find(|other| {
+                    !uninstalling
+                        .iter()
+                        .any(|x| crate::repo::is_same_package(x, other))
+                }) {
+                    return Err(PackageTransactionError::Conflict(key.clone(), other));
+                }
+            }
+        }
+
         // Check for uninstall actions that contradict this set
         // for action in actions
         //     .iter()
@@ -380,8 +1607,12 @@ impl PackageTransaction {
                 .collect::<Vec<_>>()
         );
 
+        // Includes the orphans removed along the way.
+        let is_reboot_required = new_actions.iter().any(|x| x.is_reboot_required);
+
         Ok(PackageTransaction {
             store,
+            requested: actions,
             actions: Arc::new(new_actions),
             is_reboot_required,
         })
@@ -391,63 +1622,732 @@ impl PackageTransaction {
         Arc::clone(&self.actions)
     }
 
+    /// Install actions that weren't requested but were added to satisfy dependencies,
+    /// in processing order. Their [`origin`](PackageAction::origin) names a package
+    /// that needs them.
+    pub fn added_dependencies(&self) -> Vec<&ResolvedAction> {
+        self.actions
+            .iter()
+            .filter(|record| {
+                record.action.is_install()
+                    && !self.requested.iter().any(|x| x.id == record.action.id)
+            })
+            .collect()
+    }
+
+    /// Writes the full transaction to `path` as a journal with no actions completed.
+    pub fn save_journal<P: AsRef<Path>>(&self, path: P) -> Result<(), JournalError> {
+        self.journal(0).save(path.as_ref())
+    }
+
+    /// Reconstructs a transaction from a journal, skipping actions that have
+    /// already been applied.
+    ///
+    /// Actions the journal records as completed must still be reflected by the
+    /// store, otherwise [`ResumeError::StateChanged`] is returned.
+    pub fn resume_from_journal<P: AsRef<Path>>(
+        store: Arc<dyn PackageStore>,
+        path: P,
+    ) -> Result<PackageTransaction, ResumeError> {
+        let journal = Journal::load(path.as_ref())?;
+        log::debug!(
+            "Resuming transaction: {} of {} actions completed",
+            journal.completed,
+            journal.actions.len()
+        );
+
+        let mut remaining = vec![];
+        let statuses = store.statuses(
+            &journal
+                .actions
+                .iter()
+                .map(|x| (x.action.id.clone(), x.action.target))
+                .collect::<Vec<_>>(),
+        );
+
+        for (i, (record, status)) in journal
+            .actions
+            .into_iter()
+            .zip(statuses.into_iter())
+            .enumerate()
+        {
+            let action = &record.action;
+            let status = status.map_err(|e| ResumeError::Status(action.id.clone(), e))?;
+
+            let is_applied = match action.action {
+                PackageActionType::Install => status == PackageStatus::UpToDate,
+                PackageActionType::Uninstall => status == PackageStatus::NotInstalled,
+            };
+
+            if i < journal.completed {
+                if !is_applied {
+                    return Err(ResumeError::StateChanged(action.id.clone()));
+                }
+            } else if is_applied && !action.reinstall {
+                log::debug!("Skipping already applied action: {}", action);
+            } else {
+                remaining.push(record);
+            }
+        }
+
+        Ok(PackageTransaction {
+            store,
+            requested: journal.requested,
+            actions: Arc::new(remaining),
+            is_reboot_required: journal.is_reboot_required,
+        })
+    }
+
+    fn journal(&self, completed: usize) -> Journal {
+        Journal {
+            requested: self.requested.clone(),
+            actions: self.actions.to_vec(),
+            is_reboot_required: self.is_reboot_required,
+            completed,
+        }
+    }
+
+    /// Describes the resolved actions without invoking the store.
+    pub fn describe(&self) -> TransactionPlan {
+        let requested = self
+            .requested
+            .iter()
+            .map(|x| &x.id)
+            .collect::<std::collections::HashSet<_>>();
+        let resolved = self
+            .actions
+            .iter()
+            .map(|x| &x.action.id)
+            .collect::<std::collections::HashSet<_>>();
+
+        let actions = self
+            .actions
+            .iter()
+            .map(|record| PlannedAction {
+                action: record.action.clone(),
+                status: record.status,
+                is_dependency: !requested.contains(&record.action.id),
+                version: record.release.version.clone(),
+                size: record.target.payload.size(),
+                installed_size: record.target.payload.installed_size(),
+            })
+            .collect();
+
+        let dropped = self
+            .requested
+            .iter()
+            .filter(|x| !resolved.contains(&x.id))
+            .cloned()
+            .collect();
+
+        TransactionPlan { actions, dropped }
+    }
+
     pub fn is_reboot_required(&self) -> bool {
         self.is_reboot_required
     }
 
-    pub fn process(
+    /// Checks that the actions still make sense: none contradict, every package
+    /// still resolves to the planned release, and no status changed since the
+    /// transaction was created. Processing does this before starting.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        validate_actions(&*self.store, &self.actions)
+    }
+
+    /// Sums the payload sizes of every install action, as listed in the repository index.
+    pub fn download_size(&self) -> Result<u64, crate::repo::PayloadError> {
+        let repos = self.store.repos();
+        let repos = repos.read().unwrap();
+
+        self.actions
+            .iter()
+            .filter(|record| record.action.is_install())
+            .filter(|record| {
+                !crate::package_store::meta::is_meta_package(&record.action.id, &*repos)
+            })
+            .try_fold(0, |acc, record| {
+                let key = &record.action.id;
+                let query = crate::repo::ReleaseQuery::new(
+                    key,
+                    &*repos,
+                    &self.store.config().read().unwrap(),
+                );
+                let (target, _, _) = crate::repo::resolve_payload(key, &query, &*repos)?;
+                Ok(acc + target.payload.size())
+            })
+    }
+
+    pub fn process(&self) -> (Canceller, crate::package_store::Stream<TransactionEvent>) {
+        self.process_with_options(ProcessOptions::default())
+    }
+
+    /// Processes the transaction, yielding events as it goes.
+    ///
+    /// Dropping the returned canceller cancels the transaction. Downloads are
+    /// interrupted immediately; an install or uninstall already handed to the
+    /// store runs to completion, after which no further actions are started
+    /// and the stream ends with [`TransactionEvent::Cancelled`].
+    ///
+    /// Only one transaction may be processed per config directory at a time;
+    /// if another holds the lock, the stream yields [`TransactionError::Locked`].
+    pub fn process_with_options(
         &self,
-    ) -> (
-        stream_cancel::Trigger,
-        crate::package_store::Stream<TransactionEvent>,
-    ) {
-        log::debug!("beginning transaction process");
+        options: ProcessOptions,
+    ) -> (Canceller, crate::package_store::Stream<TransactionEvent>) {
+        log::debug!("beginning transaction process: {:?}", &options);
 
-        let (canceler, valve) = stream_cancel::Valve::new();
+        let (canceler, tripwire, cancel_reason) = Canceller::new();
+        let transaction_span = trace::transaction(self.actions.len());
+        let cancel_reason = move || cancel_reason.lock().unwrap().unwrap_or(CancelReason::User);
 
         let store = Arc::clone(&self.store);
         let actions: Arc<Vec<ResolvedAction>> = Arc::clone(&self.actions);
+        let requested = self.requested.clone();
+        let mut journal = options.journal.as_ref().map(|_| self.journal(0));
+
+        let concurrency = match store
+            .config()
+            .read()
+            .unwrap()
+            .settings()
+            .max_concurrent_downloads()
+        {
+            0 => DEFAULT_CONCURRENT_DOWNLOADS,
+            n => n as usize,
+        };
+
+        let (config_dir, permission) = {
+            let config = store.config();
+            let config = config.read().unwrap();
+            (
+                config.settings().config_dir().to_path_buf(),
+                config.settings().permission(),
+            )
+        };
+        let lock_path = lock::lock_path(&config_dir);
 
         let stream = async_stream::stream! {
-            for record in actions.iter() {
+            // Held until the stream finishes or is dropped.
+            let _lock = match TransactionLock::acquire(&lock_path) {
+                Ok(v) => v,
+                Err(e) => {
+                    if let Some(record) = actions.first() {
+                        yield TransactionEvent::Error(record.action.id.clone(), e.into());
+                    } else {
+                        yield TransactionEvent::Complete(TransactionSummary::default());
+                    }
+                    return;
+                }
+            };
+            let started_at = std::time::SystemTime::now();
+
+            // Time may have passed since the transaction was planned.
+            if let Err(e) = validate_actions(&*store, &actions) {
+                log::error!("Transaction is no longer valid: {}", &e);
+                yield TransactionEvent::Error(e.package().clone(), TransactionError::ValidationFailed(e));
+                return;
+            }
+
+            // Fetch every payload that isn't cached yet before touching the system.
+            let (cached, pending): (Vec<_>, Vec<_>) = {
+                let config = store.config();
+                let config = config.read().unwrap();
+                let repos = store.repos();
+                let repos = repos.read().unwrap();
+                actions
+                    .iter()
+                    .filter(|record| record.action.is_install())
+                    // A meta-package's payload is never used.
+                    .filter(|record| !crate::package_store::meta::is_meta_package(&record.action.id, &*repos))
+                    .map(|record| {
+                        let url = record.target.payload.as_download_url().clone();
+                        (record.action.id.clone(), url)
+                    })
+                    .partition(|(_, url)| crate::repo::download_file_path(&config, url).exists())
+            };
+
+            // Dropped with the stream, so this also covers the stream being dropped mid-download.
+            let mut partials = PartialDownloads {
+                config: store.config(),
+                pending: if options.discard_partial_downloads { pending.clone() } else { vec![] },
+            };
+            let pending = pending.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+
+            log::debug!("downloading {} payloads, {} at a time", pending.len(), concurrency);
+
+            let download_store = Arc::clone(&store);
+            let retry = options.retry;
+            let throttle = options.progress;
+            let mut downloads = futures::stream::iter(pending)
+                .map(move |key| {
+                    let span = trace::download(&key);
+                    trace::instrument(download_with_retry(Arc::clone(&download_store), key, retry, throttle), span)
+                })
+                .flatten_unordered(concurrency)
+                .take_until_if(tripwire.clone());
+
+            let keep_going = options.continue_on_error && !options.rollback;
+            let mut summary = TransactionSummary::default();
+
+            while let Some(event) = downloads.next().await {
+                let failed = match &event {
+                    TransactionEvent::Error(key, e) => Some((key.clone(), e.to_string())),
+                    _ => None,
+                };
+                yield event;
+                if let Some(failed) = failed {
+                    if !keep_going {
+                        // Kept so a retry can resume them.
+                        partials.disarm();
+                        return;
+                    }
+                    summary.failed.push(failed);
+                }
+            }
+
+            if is_cancelled(&tripwire) {
+                yield TransactionEvent::Cancelled(cancel_reason());
+                return;
+            }
+            partials.disarm();
+
+            // Cached payloads are held to the same signature policy as downloads.
+            for (key, url) in cached {
+                if let Err(e) = crate::repo::verify_cached_payload(&store.config(), &key, &url).await {
+                    log::error!("Cached payload of {} did not verify: {:?}", &key, &e);
+                    let message = e.to_string();
+                    yield TransactionEvent::Error(key.clone(), TransactionError::Download(e));
+                    if !keep_going {
+                        return;
+                    }
+                    summary.failed.push((key, message));
+                }
+            }
+
+            let mut completed: Vec<&PackageAction> = vec![];
+
+            // What was installed before, so rolling back an update restores it.
+            let previous = if options.rollback { store.installed() } else { vec![] };
+
+            if let (Some(journal), Some(path)) = (journal.as_ref(), options.journal.as_ref()) {
+                if let Err(e) = journal.save(path) {
+                    log::warn!("{:?}", e);
+                }
+            }
+
+            let total = actions.len();
+
+            for (index, record) in actions.iter().enumerate() {
+                if is_cancelled(&tripwire) {
+                    let reason = cancel_reason();
+                    log::debug!("transaction cancelled: {}", reason);
+                    yield TransactionEvent::Cancelled(reason);
+                    return;
+                }
+
                 let action = &record.action;
-                log::debug!("processing action: {}", &action);
 
-                match action.action {
-                    PackageActionType::Install => {
-                        log::debug!("Going to yield now.");
-                        yield TransactionEvent::Installing(action.id.clone());
+                if summary.failed.iter().any(|(key, _)| key == &action.id) {
+                    log::debug!("skipping action with failed download: {}", &action);
+                    continue;
+                }
+
+                if action.is_install() {
+                    if let Some(dependency) = summary.failed_dependency(&record.target) {
+                        let reason = format!("Dependency `{}` failed", dependency.id);
+                        log::debug!("skipping {}: {}", &action, &reason);
+                        summary.skipped.push((action.id.clone(), reason.clone()));
+                        yield TransactionEvent::Skipped(action.id.clone(), reason);
+                        continue;
+                    }
+                }
+
+                let span = trace::action(action);
+                span.in_scope(|| log::debug!("processing action: {}", &action));
+
+                let pre = match action.action {
+                    PackageActionType::Install => HookPoint::PreInstall(action.id.clone()),
+                    PackageActionType::Uninstall => HookPoint::PreUninstall(action.id.clone()),
+                };
 
-                        log::debug!("Going to install now.");
-                        match store.install(&action.id, action.target) {
-                            Ok(_) => {
-                                log::trace!("We came out the other side.");
+                let result = match span.in_scope(|| run_hooks(&options.hooks, &pre)) {
+                    Err(e) => {
+                        log::warn!("Hook vetoed {}", &action);
+                        Err(TransactionError::Hook(e))
+                    }
+                    Ok(()) => match action.action {
+                        PackageActionType::Install => {
+                            yield TransactionEvent::Installing(action.id.clone(), index + 1, total);
+
+                            // A reinstall installs over the installed copy, so a failed
+                            // install can't leave the package removed.
+                            log::debug!("Going to install now.");
+                            let mut attempt = 1;
+                            loop {
+                                // The install runs on a blocking thread so its progress
+                                // can be passed on while it runs.
+                                let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+                                let task = {
+                                    let store = Arc::clone(&store);
+                                    let key = action.id.clone();
+                                    let target = action.target;
+                                    let span = span.clone();
+                                    tokio::task::spawn_blocking(move || {
+                                        span.in_scope(|| {
+                                            store.install_with_progress(&key, target, &|detail| {
+                                                let _ = tx.send(detail);
+                                            })
+                                        })
+                                    })
+                                };
+
+                                let mut last_sent: Option<std::time::Instant> = None;
+                                while let Some(detail) = rx.recv().await {
+                                    let is_due = match (&detail, last_sent) {
+                                        (ProgressDetail::Extracting { done, total }, Some(at)) => {
+                                            done >= total || at.elapsed() >= options.progress.interval
+                                        }
+                                        _ => true,
+                                    };
+
+                                    if is_due {
+                                        last_sent = Some(std::time::Instant::now());
+                                        yield TransactionEvent::Progress(action.id.clone(), detail);
+                                    }
+                                }
+
+                                let result = task.await.unwrap_or_else(|e| Err(InstallError::Aborted(e)));
+                                match result {
+                                    Err(e) if e.is_transient() && attempt < options.retry.max_attempts => {
+                                        log::warn!("Install attempt {} failed: {:?}", attempt, &e);
+                                        yield TransactionEvent::Retrying(action.id.clone(), attempt + 1);
+                                        tokio::time::sleep(options.retry.delay(attempt)).await;
+                                        attempt += 1;
+                                    }
+                                    result => break result.map(|_| ()).map_err(TransactionError::Install),
+                                }
                             }
-                            Err(e) => {
-                                log::error!("{:?}", &e);
-                                yield TransactionEvent::Error(action.id.clone(), TransactionError::Install(e));
-                                return;
+                        }
+                        PackageActionType::Uninstall => {
+                            yield TransactionEvent::Uninstalling(action.id.clone(), index + 1, total);
+
+                            let mut attempt = 1;
+                            loop {
+                                match span.in_scope(|| store.uninstall(&action.id, action.target)) {
+                                    Err(e) if e.is_transient() && attempt < options.retry.max_attempts => {
+                                        log::warn!("Uninstall attempt {} failed: {:?}", attempt, &e);
+                                        yield TransactionEvent::Retrying(action.id.clone(), attempt + 1);
+                                        tokio::time::sleep(options.retry.delay(attempt)).await;
+                                        attempt += 1;
+                                    }
+                                    result => break result.map(|_| ()).map_err(TransactionError::Uninstall),
+                                }
                             }
-                        };
+                        }
+                    },
+                };
+
+                if let Err(e) = result {
+                    log::error!("{:?}", &e);
+                    let message = e.to_string();
+                    yield TransactionEvent::Error(action.id.clone(), e);
+
+                    if keep_going {
+                        summary.failed.push((action.id.clone(), message));
+                        continue;
+                    }
+
+                    if !options.rollback {
+                        return;
+                    }
+
+                    // Reverse everything that already succeeded, most recent first.
+                    for action in completed.into_iter().rev() {
+                        log::debug!("rolling back action: {}", &action);
+                        yield TransactionEvent::RollingBack(action.id.clone());
+
+                        let previous_version = previous
+                            .iter()
+                            .find(|(key, target, _)| {
+                                *target == action.target && crate::repo::is_same_package(key, &action.id)
+                            })
+                            .map(|(_, _, version)| version);
+
+                        let result = trace::action(action).in_scope(|| match action.action {
+                            PackageActionType::Install => match previous_version {
+                                Some(version) => {
+                                    let mut key = action.id.clone();
+                                    key.query.version = Some(version.to_string());
+                                    store
+                                        .install(&key, action.target)
+                                        .map(|_| ())
+                                        .map_err(TransactionError::Install)
+                                }
+                                None => store
+                                    .uninstall(&action.id, action.target)
+                                    .map(|_| ())
+                                    .map_err(TransactionError::Uninstall),
+                            },
+                            PackageActionType::Uninstall => store
+                                .install(&action.id, action.target)
+                                .map(|_| ())
+                                .map_err(TransactionError::Install),
+                        });
+
+                        if let Err(e) = result {
+                            log::error!("Rollback failed: {:?}", &e);
+                            yield TransactionEvent::RollbackFailed(action.id.clone(), e);
+                            return;
+                        }
+                    }
+
+                    if let Some(path) = options.journal.as_ref() {
+                        Journal::remove(path);
                     }
-                    PackageActionType::Uninstall => {
-                        yield TransactionEvent::Uninstalling(action.id.clone());
-
-                        match store.uninstall(&action.id, action.target) {
-                            Ok(_) => {}
-                            Err(e) => {
-                                log::error!("{:?}", &e);
-                                yield TransactionEvent::Error(action.id.clone(), TransactionError::Uninstall(e));
-                                return;
+
+                    yield TransactionEvent::RolledBack;
+                    return;
+                }
+
+                log::trace!("We came out the other side.");
+
+                let post = match action.action {
+                    PackageActionType::Install => HookPoint::PostInstall(action.id.clone()),
+                    PackageActionType::Uninstall => HookPoint::PostUninstall(action.id.clone()),
+                };
+
+                if let Err(e) = span.in_scope(|| run_hooks(&options.hooks, &post)) {
+                    log::warn!("Post-action hook failed for {}: {:?}", &action, &e);
+                }
+
+                if action.is_install() {
+                    let is_requested = requested
+                        .iter()
+                        .any(|x| x.is_install() && crate::repo::is_same_package(&x.id, &action.id));
+
+                    // Only fresh installs are recorded as dependencies, so updating a
+                    // package the user asked for doesn't demote it.
+                    if is_requested {
+                        store.set_requested_by_user(&action.id, true);
+                    } else if record.status == PackageStatus::NotInstalled {
+                        store.set_requested_by_user(&action.id, false);
+                    }
+                }
+
+                completed.push(action);
+                summary.succeeded.push(action.id.clone());
+
+                if record.is_reboot_required {
+                    summary.reboot_required = true;
+                    yield TransactionEvent::RebootRequired(action.id.clone());
+                }
+
+                // The journal records a prefix of completed actions, so it stops
+                // advancing at the first failure; resuming skips what was applied.
+                if !summary.is_success() {
+                    continue;
+                }
+
+                if let (Some(journal), Some(path)) = (journal.as_mut(), options.journal.as_ref()) {
+                    journal.completed = completed.len();
+                    if let Err(e) = journal.save(path) {
+                        log::warn!("{:?}", e);
+                    }
+                }
+            }
+
+            // Keep the journal when something failed, so the rest can be resumed.
+            if let Some(path) = options.journal.as_ref().filter(|_| summary.is_success()) {
+                Journal::remove(path);
+            }
+
+            if !actions.is_empty() {
+                let record = history::TransactionRecord::new(started_at, &actions, &summary);
+                if let Err(e) = history::append(permission, &config_dir, &record) {
+                    log::warn!("Could not record transaction history: {:?}", e);
+                }
+            }
+
+            yield TransactionEvent::Complete(summary);
+        };
+
+        (
+            canceler,
+            trace::instrument(Box::pin(stream), transaction_span),
+        )
+    }
+}
+
+/// Removes the partial downloads of `pending` when dropped, unless disarmed first.
+struct PartialDownloads {
+    config: crate::package_store::SharedStoreConfig,
+    pending: Vec<(PackageKey, Url)>,
+}
+
+impl PartialDownloads {
+    fn disarm(&mut self) {
+        self.pending.clear();
+    }
+}
+
+impl Drop for PartialDownloads {
+    fn drop(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+
+        let config = match self.config.read() {
+            Ok(v) => v,
+            Err(_) => return,
+        };
+        let removed = self
+            .pending
+            .iter()
+            .map(|(key, url)| crate::repo::remove_partial_download(&config, key, url))
+            .sum::<usize>();
+        log::debug!("Removed {} partial download files", removed);
+    }
+}
+
+fn default_install_target(store: &dyn PackageStore) -> InstallTarget {
+    store
+        .config()
+        .read()
+        .unwrap()
+        .settings()
+        .default_install_target()
+}
+
+/// Downloads the payload for `key`, starting over when a transient error occurs.
+fn download_with_retry(
+    store: Arc<dyn PackageStore>,
+    key: PackageKey,
+    retry: RetryPolicy,
+    throttle: ProgressThrottle,
+) -> crate::package_store::Stream<TransactionEvent> {
+    Box::pin(async_stream::stream! {
+        let mut attempt = 1;
+
+        'attempts: loop {
+            let mut download = store.download(&key);
+            let mut last_sent: Option<(std::time::Instant, u64)> = None;
+            let mut unsent = None;
+
+            while let Some(event) = download.next().await {
+                match event {
+                    DownloadEvent::Progress((current, total)) => {
+                        let is_due = match last_sent {
+                            None => true,
+                            Some((at, bytes)) => {
+                                (total > 0 && current >= total)
+                                    || at.elapsed() >= throttle.interval
+                                    || throttle
+                                        .bytes
+                                        .map(|b| current.saturating_sub(bytes) >= b)
+                                        .unwrap_or(false)
                             }
                         };
+
+                        if is_due {
+                            last_sent = Some((std::time::Instant::now(), current));
+                            unsent = None;
+                            yield TransactionEvent::Downloading(key.clone(), current, total);
+                        } else {
+                            unsent = Some((current, total));
+                        }
+                    }
+                    DownloadEvent::Fallback(index) => {
+                        yield TransactionEvent::Progress(key.clone(), ProgressDetail::FallingBack { mirror: index });
+                    }
+                    DownloadEvent::Delta(from) => {
+                        yield TransactionEvent::Progress(key.clone(), ProgressDetail::Delta { from });
+                    }
+                    DownloadEvent::Complete(path) => {
+                        if let Some((current, total)) = unsent {
+                            yield TransactionEvent::Downloading(key.clone(), current, total);
+                        }
+                        log::debug!("downloaded {} to {:?}", &key, &path);
+                        return;
+                    }
+                    DownloadEvent::Error(e) => {
+                        if e.is_transient() && attempt < retry.max_attempts {
+                            log::warn!("Download attempt {} failed: {:?}", attempt, &e);
+                            yield TransactionEvent::Retrying(key.clone(), attempt + 1);
+                            tokio::time::sleep(retry.delay(attempt)).await;
+                            attempt += 1;
+                            continue 'attempts;
+                        }
+
+                        log::error!("{:?}", &e);
+                        yield TransactionEvent::Error(key, TransactionError::Download(e));
+                        return;
                     }
                 }
             }
 
-            yield TransactionEvent::Complete;
-        };
+            return;
+        }
+    })
+}
 
-        (canceler, Box::pin(valve.wrap(stream)))
+fn validate_actions(
+    store: &dyn PackageStore,
+    actions: &[ResolvedAction],
+) -> Result<(), ValidationError> {
+    let mut seen = std::collections::HashMap::new();
+    for record in actions {
+        let action = &record.action;
+        match seen.insert(&action.id, action.action) {
+            Some(previous) if previous != action.action => {
+                return Err(ValidationError::Contradiction(action.id.clone()));
+            }
+            _ => {}
+        }
     }
+
+    {
+        let repos = store.repos();
+        let repos = repos.read().unwrap();
+
+        for record in actions.iter().filter(|x| x.action.is_install()) {
+            let key = &record.action.id;
+            let query =
+                crate::repo::ReleaseQuery::new(key, &*repos, &store.config().read().unwrap());
+            let (_, release, _) = crate::repo::resolve_payload(key, &query, &*repos)
+                .map_err(|e| ValidationError::Unresolvable(key.clone(), e))?;
+
+            if release.version != record.release.version {
+                return Err(ValidationError::ReleaseChanged(
+                    key.clone(),
+                    record.release.version.clone(),
+                    release.version,
+                ));
+            }
+        }
+    }
+
+    let keys = actions
+        .iter()
+        .map(|x| (x.action.id.clone(), x.action.target))
+        .collect::<Vec<_>>();
+
+    for (record, status) in actions.iter().zip(store.statuses(&keys).into_iter()) {
+        let key = &record.action.id;
+        let status = status.map_err(|e| ValidationError::Status(key.clone(), e))?;
+
+        if status != record.status {
+            return Err(ValidationError::StatusChanged(
+                key.clone(),
+                record.status,
+                status,
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[inline]
+fn is_cancelled(tripwire: &stream_cancel::Tripwire) -> bool {
+    tripwire.clone().now_or_never().unwrap_or(false)
 }
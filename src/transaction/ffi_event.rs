@@ -0,0 +1,204 @@
+//! A flat encoding of [`TransactionEvent`] for callers across the FFI boundary,
+//! who can't depend on the layout of Rust enums.
+//!
+//! Every event becomes an [`FfiEvent`]: a `tag` saying which event it is, a
+//! `detail` code refining it, and up to three numbers and two strings. The codes
+//! below are stable: existing ones never change meaning and new ones are only
+//! ever added.
+//!
+//! | `tag` | Event                                | `detail`           | `a`           | `b`         | `c`     | `message` |
+//! |-------|--------------------------------------|--------------------|---------------|-------------|---------|-----------|
+//! | 1     | [`Installing`][TE::Installing]       |                    | position      | total       |         |           |
+//! | 2     | [`Uninstalling`][TE::Uninstalling]   |                    | position      | total       |         |           |
+//! | 3     | [`Complete`][TE::Complete]           | 1 if reboot needed | succeeded     | failed      | skipped |           |
+//! | 4     | [`Error`][TE::Error]                 | error code         | lock holder   |             |         | error     |
+//! | 5     | [`Downloading`][TE::Downloading]     |                    | bytes done    | bytes total |         |           |
+//! | 6     | [`Progress`][TE::Progress]           | progress code      | done / mirror | total       |         | delta from |
+//! | 7     | [`RollingBack`][TE::RollingBack]     |                    |               |             |         |           |
+//! | 8     | [`RolledBack`][TE::RolledBack]       |                    |               |             |         |           |
+//! | 9     | [`RollbackFailed`][TE::RollbackFailed] | error code       | lock holder   |             |         | error     |
+//! | 10    | [`Retrying`][TE::Retrying]           |                    | attempt       |             |         |           |
+//! | 11    | [`Skipped`][TE::Skipped]             |                    |               |             |         | reason    |
+//! | 12    | [`Cancelled`][TE::Cancelled]         | cancel code        |               |             |         | reason    |
+//! | 13    | [`RebootRequired`][TE::RebootRequired] |                  |               |             |         |           |
+//!
+//! Error codes: 1 validation failed, 2 user cancelled, 3 download, 4 uninstall,
+//! 5 install, 6 locked (`a` is the holder's pid, or 0 if unknown), 7 lock I/O,
+//! 8 hook.
+//!
+//! Progress codes: 1 downloading, 2 falling back (`a` is the mirror, or 0 for the
+//! payload URL), 3 extracting, 4 running installer, 5 verifying, 6 applied a delta
+//! (`message` is the version it was applied to).
+//!
+//! Cancel codes: 1 user, 2 timeout, 3 shutdown.
+//!
+//! `package` is the event's package key as a string, if it has one. Fields an
+//! event doesn't use are zero or `None`.
+//!
+//! `pahkat_prefix_transaction_process_events` passes each event to its callback
+//! as this struct in JSON.
+//!
+//! [TE::Installing]: TransactionEvent::Installing
+//! [TE::Uninstalling]: TransactionEvent::Uninstalling
+//! [TE::Complete]: TransactionEvent::Complete
+//! [TE::Error]: TransactionEvent::Error
+//! [TE::Downloading]: TransactionEvent::Downloading
+//! [TE::Progress]: TransactionEvent::Progress
+//! [TE::RollingBack]: TransactionEvent::RollingBack
+//! [TE::RolledBack]: TransactionEvent::RolledBack
+//! [TE::RollbackFailed]: TransactionEvent::RollbackFailed
+//! [TE::Retrying]: TransactionEvent::Retrying
+//! [TE::Skipped]: TransactionEvent::Skipped
+//! [TE::Cancelled]: TransactionEvent::Cancelled
+//! [TE::RebootRequired]: TransactionEvent::RebootRequired
+
+use serde::{Deserialize, Serialize};
+
+use super::{CancelReason, ProgressDetail, TransactionError, TransactionEvent};
+
+/// A [`TransactionEvent`] as a tag code and payload fields; see the
+/// [module documentation](self) for what each field holds.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FfiEvent {
+    pub tag: u32,
+    pub detail: u32,
+    pub package: Option<String>,
+    pub a: u64,
+    pub b: u64,
+    pub c: u64,
+    pub message: Option<String>,
+}
+
+impl FfiEvent {
+    pub const INSTALLING: u32 = 1;
+    pub const UNINSTALLING: u32 = 2;
+    pub const COMPLETE: u32 = 3;
+    pub const ERROR: u32 = 4;
+    pub const DOWNLOADING: u32 = 5;
+    pub const PROGRESS: u32 = 6;
+    pub const ROLLING_BACK: u32 = 7;
+    pub const ROLLED_BACK: u32 = 8;
+    pub const ROLLBACK_FAILED: u32 = 9;
+    pub const RETRYING: u32 = 10;
+    pub const SKIPPED: u32 = 11;
+    pub const CANCELLED: u32 = 12;
+    pub const REBOOT_REQUIRED: u32 = 13;
+
+    fn new(tag: u32) -> FfiEvent {
+        FfiEvent {
+            tag,
+            ..Default::default()
+        }
+    }
+
+    fn error(tag: u32, package: String, error: &TransactionError) -> FfiEvent {
+        let (detail, a) = match error {
+            TransactionError::ValidationFailed(_) => (1, 0),
+            TransactionError::UserCancelled => (2, 0),
+            TransactionError::Download(_) => (3, 0),
+            TransactionError::Uninstall(_) => (4, 0),
+            TransactionError::Install(_) => (5, 0),
+            TransactionError::Locked { held_by_pid } => (6, held_by_pid.unwrap_or(0) as u64),
+            TransactionError::Lock(_) => (7, 0),
+            TransactionError::Hook(_) => (8, 0),
+        };
+
+        FfiEvent {
+            tag,
+            detail,
+            package: Some(package),
+            a,
+            message: Some(error.to_string()),
+            ..Default::default()
+        }
+    }
+}
+
+impl TransactionEvent {
+    /// Encodes the event for foreign callers.
+    pub fn to_ffi(&self) -> FfiEvent {
+        match self {
+            TransactionEvent::Installing(key, position, total) => FfiEvent {
+                package: Some(key.to_string()),
+                a: *position as u64,
+                b: *total as u64,
+                ..FfiEvent::new(FfiEvent::INSTALLING)
+            },
+            TransactionEvent::Uninstalling(key, position, total) => FfiEvent {
+                package: Some(key.to_string()),
+                a: *position as u64,
+                b: *total as u64,
+                ..FfiEvent::new(FfiEvent::UNINSTALLING)
+            },
+            TransactionEvent::Complete(summary) => FfiEvent {
+                detail: summary.reboot_required as u32,
+                a: summary.succeeded.len() as u64,
+                b: summary.failed.len() as u64,
+                c: summary.skipped.len() as u64,
+                ..FfiEvent::new(FfiEvent::COMPLETE)
+            },
+            TransactionEvent::Error(key, error) => {
+                FfiEvent::error(FfiEvent::ERROR, key.to_string(), error)
+            }
+            TransactionEvent::Downloading(key, done, total) => FfiEvent {
+                package: Some(key.to_string()),
+                a: *done,
+                b: *total,
+                ..FfiEvent::new(FfiEvent::DOWNLOADING)
+            },
+            TransactionEvent::Progress(key, detail) => {
+                let (code, a, b) = match detail {
+                    ProgressDetail::Downloading { done, total } => (1, *done, *total),
+                    ProgressDetail::FallingBack { mirror } => (2, *mirror as u64, 0),
+                    ProgressDetail::Extracting { done, total } => (3, *done, *total),
+                    ProgressDetail::RunningInstaller => (4, 0, 0),
+                    ProgressDetail::Verifying => (5, 0, 0),
+                    ProgressDetail::Delta { .. } => (6, 0, 0),
+                };
+                let message = match detail {
+                    ProgressDetail::Delta { from } => Some(from.clone()),
+                    _ => None,
+                };
+                FfiEvent {
+                    detail: code,
+                    package: Some(key.to_string()),
+                    a,
+                    b,
+                    message,
+                    ..FfiEvent::new(FfiEvent::PROGRESS)
+                }
+            }
+            TransactionEvent::RollingBack(key) => FfiEvent {
+                package: Some(key.to_string()),
+                ..FfiEvent::new(FfiEvent::ROLLING_BACK)
+            },
+            TransactionEvent::RolledBack => FfiEvent::new(FfiEvent::ROLLED_BACK),
+            TransactionEvent::RollbackFailed(key, error) => {
+                FfiEvent::error(FfiEvent::ROLLBACK_FAILED, key.to_string(), error)
+            }
+            TransactionEvent::Retrying(key, attempt) => FfiEvent {
+                package: Some(key.to_string()),
+                a: *attempt as u64,
+                ..FfiEvent::new(FfiEvent::RETRYING)
+            },
+            TransactionEvent::Skipped(key, reason) => FfiEvent {
+                package: Some(key.to_string()),
+                message: Some(reason.clone()),
+                ..FfiEvent::new(FfiEvent::SKIPPED)
+            },
+            TransactionEvent::Cancelled(reason) => FfiEvent {
+                detail: match reason {
+                    CancelReason::User => 1,
+                    CancelReason::Timeout => 2,
+                    CancelReason::Shutdown => 3,
+                },
+                message: Some(reason.to_string()),
+                ..FfiEvent::new(FfiEvent::CANCELLED)
+            },
+            TransactionEvent::RebootRequired(key) => FfiEvent {
+                package: Some(key.to_string()),
+                ..FfiEvent::new(FfiEvent::REBOOT_REQUIRED)
+            },
+        }
+    }
+}
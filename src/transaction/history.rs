@@ -0,0 +1,146 @@
+//! A log of completed transactions, one JSON record per line, kept in the
+//! config directory.
+//!
+//! Once the log grows past [`MAX_LOG_SIZE`] it is rotated: the current file
+//! replaces the single older one, and a new one is started.
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use super::{PackageAction, ResolvedAction, TransactionSummary};
+use crate::config::Permission;
+use crate::PackageKey;
+
+const LOG_FILE: &str = "history.jsonl";
+const ROTATED_LOG_FILE: &str = "history.1.jsonl";
+
+/// Size in bytes past which the log is rotated before the next record is written.
+pub const MAX_LOG_SIZE: u64 = 1024 * 1024;
+
+/// A transaction that ran to completion, as returned by
+/// [`PackageStore::history`](crate::PackageStore::history).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransactionRecord {
+    pub started_at: SystemTime,
+    pub finished_at: SystemTime,
+    /// Every action of the transaction in processing order. Installs have
+    /// `version` set to the release that was installed.
+    pub actions: Vec<PackageAction>,
+    pub succeeded: Vec<PackageKey>,
+    pub failed: Vec<(PackageKey, String)>,
+    pub skipped: Vec<(PackageKey, String)>,
+    #[serde(default)]
+    pub reboot_required: bool,
+}
+
+impl TransactionRecord {
+    pub(crate) fn new(
+        started_at: SystemTime,
+        actions: &[ResolvedAction],
+        summary: &TransactionSummary,
+    ) -> TransactionRecord {
+        let actions = actions
+            .iter()
+            .map(|record| {
+                let mut action = record.action.clone();
+                if action.is_install() {
+                    action.version = Some(record.release.version.clone());
+                }
+                action
+            })
+            .collect();
+
+        TransactionRecord {
+            started_at,
+            finished_at: SystemTime::now(),
+            actions,
+            succeeded: summary.succeeded.clone(),
+            failed: summary.failed.clone(),
+            skipped: summary.skipped.clone(),
+            reboot_required: summary.reboot_required,
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty() && self.skipped.is_empty()
+    }
+}
+
+/// Appends `record` to the log in `config_dir`, rotating it first if it is full.
+/// Nothing is written unless `permission` allows writing to the config.
+pub(crate) fn append(
+    permission: Permission,
+    config_dir: &Path,
+    record: &TransactionRecord,
+) -> std::io::Result<()> {
+    if permission != Permission::ReadWrite {
+        log::debug!(
+            "Not recording transaction history in {:?} config",
+            permission
+        );
+        return Ok(());
+    }
+
+    let path = config_dir.join(LOG_FILE);
+
+    let size = match fs::metadata(&path) {
+        Ok(meta) => meta.len(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e),
+    };
+    if size >= MAX_LOG_SIZE {
+        fs::rename(&path, config_dir.join(ROTATED_LOG_FILE))?;
+    }
+
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+
+    fs::create_dir_all(config_dir)?;
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)?;
+    file.write_all(&line)
+}
+
+fn read_log(path: PathBuf, records: &mut Vec<TransactionRecord>) {
+    let file = match fs::File::open(&path) {
+        Ok(v) => v,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+        Err(e) => {
+            log::warn!("Could not read transaction history {:?}: {:?}", &path, e);
+            return;
+        }
+    };
+
+    for line in BufReader::new(file).lines() {
+        let line = match line {
+            Ok(v) => v,
+            Err(e) => {
+                log::warn!("Could not read transaction history {:?}: {:?}", &path, e);
+                return;
+            }
+        };
+
+        // A record cut short by a crash is skipped rather than losing the rest.
+        match serde_json::from_str(&line) {
+            Ok(record) => records.push(record),
+            Err(e) => log::warn!("Skipping invalid transaction record: {:?}", e),
+        }
+    }
+}
+
+/// The newest `limit` records in the log in `config_dir`, newest first.
+pub(crate) fn read(config_dir: &Path, limit: usize) -> Vec<TransactionRecord> {
+    let mut records = vec![];
+    read_log(config_dir.join(ROTATED_LOG_FILE), &mut records);
+    read_log(config_dir.join(LOG_FILE), &mut records);
+
+    records.reverse();
+    records.truncate(limit);
+    records
+}
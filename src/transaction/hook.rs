@@ -0,0 +1,70 @@
+use std::fmt;
+use std::sync::Arc;
+
+use crate::PackageKey;
+
+/// The point in a transaction at which a [`Hook`] is invoked.
+#[derive(Debug, Clone)]
+pub enum HookPoint {
+    PreInstall(PackageKey),
+    PostInstall(PackageKey),
+    PreUninstall(PackageKey),
+    PostUninstall(PackageKey),
+}
+
+impl HookPoint {
+    pub fn key(&self) -> &PackageKey {
+        match self {
+            HookPoint::PreInstall(key)
+            | HookPoint::PostInstall(key)
+            | HookPoint::PreUninstall(key)
+            | HookPoint::PostUninstall(key) => key,
+        }
+    }
+
+    pub fn is_pre(&self) -> bool {
+        matches!(self, HookPoint::PreInstall(_) | HookPoint::PreUninstall(_))
+    }
+}
+
+/// Returned by a hook to veto an action.
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct HookError(pub Box<dyn std::error::Error + Send + Sync>);
+
+impl HookError {
+    pub fn new<E: Into<Box<dyn std::error::Error + Send + Sync>>>(err: E) -> HookError {
+        HookError(err.into())
+    }
+}
+
+/// A callback run synchronously around each install and uninstall.
+#[derive(Clone)]
+pub struct Hook(Arc<dyn Fn(&HookPoint) -> Result<(), HookError> + Send + Sync>);
+
+impl Hook {
+    pub fn new<F>(f: F) -> Hook
+    where
+        F: Fn(&HookPoint) -> Result<(), HookError> + Send + Sync + 'static,
+    {
+        Hook(Arc::new(f))
+    }
+
+    pub(crate) fn call(&self, point: &HookPoint) -> Result<(), HookError> {
+        (self.0)(point)
+    }
+}
+
+impl fmt::Debug for Hook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Hook").finish()
+    }
+}
+
+/// Runs every hook for `point`, stopping at the first error.
+pub(crate) fn run_hooks(hooks: &[Hook], point: &HookPoint) -> Result<(), HookError> {
+    for hook in hooks {
+        hook.call(point)?;
+    }
+    Ok(())
+}
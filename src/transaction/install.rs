@@ -1,28 +1,103 @@
+use std::path::PathBuf;
 use std::{io, process};
 
 #[derive(thiserror::Error, Debug)]
 pub enum InstallError {
-    #[error("Payload error")]
+    #[error("Could not resolve payload: {0}")]
     Payload(#[from] crate::repo::PayloadError),
 
     #[error("Wrong payload type")]
     WrongPayloadType,
 
-    #[error("Package not found in cache (not downloaded?)")]
-    PackageNotInCache,
+    #[error("Package not found in cache (not downloaded?): {}", .0.display())]
+    PackageNotInCache(PathBuf),
 
-    #[error("Installation process failed")]
+    #[error("Could not install {}: {0}", .1.display())]
+    Io(#[source] io::Error, PathBuf),
+
+    #[error("Installer failed: {0}")]
     InstallerFailure(#[from] ProcessError),
+
+    #[error("The install stopped unexpectedly: {0}")]
+    Aborted(#[source] tokio::task::JoinError),
+}
+
+impl InstallError {
+    /// Whether the install might succeed if attempted again.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            InstallError::InstallerFailure(e) => e.is_transient(),
+            InstallError::Io(e, _) => crate::download::is_transient_io(e),
+            _ => false,
+        }
+    }
+
+    /// The native installer's exit code, if it ran and failed.
+    pub fn exit_code(&self) -> Option<i32> {
+        match self {
+            InstallError::InstallerFailure(e) => e.exit_code(),
+            _ => None,
+        }
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum ProcessError {
-    #[error("IO error")]
+    #[error("Could not run process: {0}")]
     Io(#[from] io::Error),
 
     #[error("Not found")]
     NotFound,
 
-    #[error("Unknown error")]
+    #[error("{}", describe_output(.0))]
     Unknown(process::Output),
 }
+
+impl ProcessError {
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ProcessError::Io(e) => crate::download::is_transient_io(e),
+            _ => false,
+        }
+    }
+
+    pub fn exit_code(&self) -> Option<i32> {
+        match self {
+            ProcessError::Unknown(output) => output.status.code(),
+            _ => None,
+        }
+    }
+}
+
+/// Meanings of the Windows Installer exit codes users most often run into.
+fn known_exit_code(code: i32) -> Option<&'static str> {
+    Some(match code {
+        5 => "access denied",
+        1602 => "cancelled by the user",
+        1603 => "fatal error during installation",
+        1618 => "another installation is already in progress",
+        1619 => "the installation package could not be opened",
+        1625 => "installation is forbidden by system policy",
+        1633 => "this platform is not supported",
+        _ => return None,
+    })
+}
+
+fn describe_output(output: &process::Output) -> String {
+    let mut message = match output.status.code() {
+        Some(code) => match known_exit_code(code) {
+            Some(meaning) => format!("Process exited with code {}: {}", code, meaning),
+            None => format!("Process exited with code {}", code),
+        },
+        None => "Process was terminated by a signal".to_string(),
+    };
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if let Some(line) = stderr.lines().rev().find(|x| !x.trim().is_empty()) {
+        message.push_str(" (");
+        message.push_str(line.trim());
+        message.push(')');
+    }
+
+    message
+}
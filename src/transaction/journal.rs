@@ -0,0 +1,79 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::{PackageAction, PackageStatusError, ResolvedAction};
+use crate::PackageKey;
+
+#[derive(Debug, thiserror::Error)]
+pub enum JournalError {
+    #[error("Could not read journal: {}", .1.display())]
+    Read(#[source] std::io::Error, PathBuf),
+
+    #[error("Could not write journal: {}", .1.display())]
+    Write(#[source] std::io::Error, PathBuf),
+
+    #[error("Could not parse journal: {}", .1.display())]
+    Parse(#[source] serde_json::Error, PathBuf),
+
+    #[error("Could not serialize journal: {}", .1.display())]
+    Serialize(#[source] serde_json::Error, PathBuf),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ResumeError {
+    #[error("Error loading transaction journal")]
+    Journal(#[from] JournalError),
+
+    #[error("Could not determine status of package: `{0}`")]
+    Status(PackageKey, #[source] PackageStatusError),
+
+    #[error("Package changed since the journal was written: `{0}`")]
+    StateChanged(PackageKey),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Journal {
+    pub requested: Vec<PackageAction>,
+    pub actions: Vec<ResolvedAction>,
+    pub is_reboot_required: bool,
+    /// Number of leading `actions` that have been applied successfully.
+    pub completed: usize,
+}
+
+impl Journal {
+    pub(crate) fn load(path: &Path) -> Result<Journal, JournalError> {
+        let file =
+            fs::read_to_string(path).map_err(|e| JournalError::Read(e, path.to_path_buf()))?;
+        serde_json::from_str(&file).map_err(|e| JournalError::Parse(e, path.to_path_buf()))
+    }
+
+    /// Writes the journal to a sibling temporary file and renames it over `path`,
+    /// so a crash mid-write never leaves a truncated journal behind.
+    pub(crate) fn save(&self, path: &Path) -> Result<(), JournalError> {
+        let b =
+            serde_json::to_vec(self).map_err(|e| JournalError::Serialize(e, path.to_path_buf()))?;
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| JournalError::Write(e, path.to_path_buf()))?;
+        }
+
+        let tmp_path = path.with_extension("tmp");
+        let mut file =
+            fs::File::create(&tmp_path).map_err(|e| JournalError::Write(e, tmp_path.clone()))?;
+        file.write_all(&b)
+            .and_then(|_| file.sync_all())
+            .map_err(|e| JournalError::Write(e, tmp_path.clone()))?;
+        drop(file);
+
+        fs::rename(&tmp_path, path).map_err(|e| JournalError::Write(e, path.to_path_buf()))
+    }
+
+    pub(crate) fn remove(path: &Path) {
+        if let Err(e) = fs::remove_file(path) {
+            log::warn!("Could not remove journal {:?}: {:?}", path, e);
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, thiserror::Error)]
+pub enum LockError {
+    #[error("Another transaction is in progress")]
+    Locked { held_by_pid: Option<u32> },
+
+    #[error("Could not acquire transaction lock")]
+    Io(#[from] io::Error),
+}
+
+/// Where the transaction lock for the config in `config_dir` is kept.
+pub(crate) fn lock_path(config_dir: &Path) -> PathBuf {
+    config_dir.join("transaction.lock")
+}
+
+/// A cross-process advisory lock held for the duration of a transaction.
+///
+/// The operating system releases the lock when the file is closed, so dropping
+/// this value (or the process exiting) always frees it.
+#[derive(Debug)]
+pub(crate) struct TransactionLock {
+    _file: File,
+}
+
+impl TransactionLock {
+    pub(crate) fn acquire(path: &Path) -> Result<TransactionLock, LockError> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = match open_locked(path)? {
+            Some(v) => v,
+            None => {
+                let held_by_pid = fs::read_to_string(path)
+                    .ok()
+                    .and_then(|x| x.trim().parse().ok());
+                return Err(LockError::Locked { held_by_pid });
+            }
+        };
+
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+        file.flush()?;
+
+        Ok(TransactionLock { _file: file })
+    }
+}
+
+#[cfg(unix)]
+fn open_locked(path: &Path) -> io::Result<Option<File>> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(path)?;
+
+    let result = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if result == 0 {
+        return Ok(Some(file));
+    }
+
+    match io::Error::last_os_error() {
+        e if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+        e => Err(e),
+    }
+}
+
+#[cfg(windows)]
+fn open_locked(path: &Path) -> io::Result<Option<File>> {
+    use std::os::windows::fs::OpenOptionsExt;
+
+    const FILE_SHARE_READ: u32 = 0x1;
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+
+    // Other processes may read the holder's pid, but not open the file for writing.
+    match OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .share_mode(FILE_SHARE_READ)
+        .open(path)
+    {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.raw_os_error() == Some(ERROR_SHARING_VIOLATION) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+
+use futures::stream::StreamExt;
+
+use super::{PackageTransaction, TransactionEvent};
+use crate::package_store::Stream;
+use crate::PackageKey;
+
+/// Progress of a whole transaction, as computed by [`TransactionProgress`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct AggregateProgress {
+    /// From 0.0 to 1.0.
+    pub overall_fraction: f64,
+    pub current_package: Option<PackageKey>,
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+}
+
+#[derive(Debug)]
+struct ActionProgress {
+    key: PackageKey,
+    size: u64,
+    downloaded: u64,
+}
+
+/// Folds a transaction's events into one progress figure.
+///
+/// Each action is weighted by its payload size as listed in the index, with
+/// downloading and then installing each counting for half. Payloads that were
+/// already cached count as downloaded once installation starts.
+#[derive(Debug)]
+pub struct TransactionProgress {
+    actions: Vec<ActionProgress>,
+    positions: HashMap<PackageKey, usize>,
+    processed: usize,
+    is_downloaded: bool,
+    current: Option<PackageKey>,
+}
+
+impl TransactionProgress {
+    pub fn new(transaction: &PackageTransaction) -> TransactionProgress {
+        let repos = transaction.store.repos();
+        let repos = repos.read().unwrap();
+        let actions = transaction
+            .actions()
+            .iter()
+            .map(|record| ActionProgress {
+                key: record.action.id.clone(),
+                size: if record.action.is_install()
+                    && !crate::package_store::meta::is_meta_package(&record.action.id, &*repos)
+                {
+                    record.target.payload.size()
+                } else {
+                    0
+                },
+                downloaded: 0,
+            })
+            .collect::<Vec<_>>();
+        let positions = actions
+            .iter()
+            .enumerate()
+            .map(|(i, x)| (x.key.clone(), i))
+            .collect();
+
+        TransactionProgress {
+            actions,
+            positions,
+            processed: 0,
+            is_downloaded: false,
+            current: None,
+        }
+    }
+
+    pub fn update(&mut self, event: &TransactionEvent) -> AggregateProgress {
+        match event {
+            TransactionEvent::Downloading(key, done, total) => {
+                if let Some(&i) = self.positions.get(key) {
+                    let action = &mut self.actions[i];
+                    if *total > 0 {
+                        action.size = *total;
+                    }
+                    action.downloaded = (*done).min(action.size);
+                }
+                self.current = Some(key.clone());
+            }
+            TransactionEvent::Installing(key, position, _)
+            | TransactionEvent::Uninstalling(key, position, _) => {
+                self.is_downloaded = true;
+                self.processed = position.saturating_sub(1);
+                self.current = Some(key.clone());
+            }
+            TransactionEvent::Complete(_) => {
+                self.is_downloaded = true;
+                self.processed = self.actions.len();
+                self.current = None;
+            }
+            _ => {}
+        }
+
+        self.progress()
+    }
+
+    pub fn progress(&self) -> AggregateProgress {
+        let mut done = 0.0;
+        let mut total = 0.0;
+        let mut bytes_done = 0;
+        let mut bytes_total = 0;
+
+        for (i, action) in self.actions.iter().enumerate() {
+            let downloaded = if self.is_downloaded {
+                action.size
+            } else {
+                action.downloaded
+            };
+            let weight = action.size.max(1) as f64;
+            let fraction = if i < self.processed {
+                1.0
+            } else if action.size == 0 {
+                0.0
+            } else {
+                0.5 * downloaded as f64 / action.size as f64
+            };
+
+            done += weight * fraction;
+            total += weight;
+            bytes_done += downloaded;
+            bytes_total += action.size;
+        }
+
+        AggregateProgress {
+            overall_fraction: if total > 0.0 { done / total } else { 1.0 },
+            current_package: self.current.clone(),
+            bytes_done,
+            bytes_total,
+        }
+    }
+
+    /// Pairs every event of `stream` with the progress after it.
+    pub fn wrap(
+        mut self,
+        stream: Stream<TransactionEvent>,
+    ) -> Stream<(TransactionEvent, AggregateProgress)> {
+        Box::pin(stream.map(move |event| {
+            let progress = self.update(&event);
+            (event, progress)
+        }))
+    }
+}
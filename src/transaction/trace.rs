@@ -0,0 +1,92 @@
+//! Spans for the `tracing` feature, so messages can be attributed to a transaction
+//! and the action that produced them. Without the feature these are no-ops.
+//!
+//! Spans are also emitted as `log` records, and installing `tracing_log::LogTracer`
+//! brings this crate's `log` messages into the spans.
+
+use super::PackageAction;
+use crate::package_store::Stream;
+use crate::PackageKey;
+
+#[cfg(feature = "tracing")]
+pub(crate) type Span = tracing::Span;
+
+#[cfg(not(feature = "tracing"))]
+#[derive(Debug, Clone)]
+pub(crate) struct Span;
+
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    #[inline(always)]
+    pub(crate) fn in_scope<F: FnOnce() -> T, T>(&self, f: F) -> T {
+        f()
+    }
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) fn transaction(actions: usize) -> Span {
+    tracing::info_span!("transaction", actions)
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn transaction(_actions: usize) -> Span {
+    Span
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) fn action(action: &PackageAction) -> Span {
+    tracing::info_span!(
+        "action",
+        key = %action.id,
+        action = ?action.action,
+        target = ?action.target,
+    )
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn action(_action: &PackageAction) -> Span {
+    Span
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) fn download(key: &PackageKey) -> Span {
+    tracing::info_span!("download", key = %key)
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn download(_key: &PackageKey) -> Span {
+    Span
+}
+
+/// Enters `span` every time `stream` is polled.
+#[cfg(feature = "tracing")]
+pub(crate) fn instrument<T: 'static>(stream: Stream<T>, span: Span) -> Stream<T> {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    struct Instrumented<T> {
+        inner: Stream<T>,
+        span: Span,
+    }
+
+    impl<T> futures::stream::Stream for Instrumented<T> {
+        type Item = T;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+            let this = &mut *self;
+            let _enter = this.span.enter();
+            this.inner.as_mut().poll_next(cx)
+        }
+    }
+
+    Box::pin(Instrumented {
+        inner: stream,
+        span,
+    })
+}
+
+#[cfg(not(feature = "tracing"))]
+#[inline(always)]
+pub(crate) fn instrument<T>(stream: Stream<T>, _span: Span) -> Stream<T> {
+    stream
+}
@@ -1,3 +1,6 @@
+use std::io;
+use std::path::PathBuf;
+
 use super::install::ProcessError;
 
 #[derive(thiserror::Error, Debug)]
@@ -14,6 +17,19 @@ pub enum UninstallError {
     #[error("Installation process failed")]
     UninstallerFailure(#[from] ProcessError),
 
+    #[error("Could not uninstall {}: {0}", .1.display())]
+    Io(#[source] io::Error, PathBuf),
+
     #[error("The package is not installed")]
     NotInstalled,
 }
+
+impl UninstallError {
+    /// Whether the uninstall might succeed if attempted again.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            UninstallError::UninstallerFailure(e) => e.is_transient(),
+            _ => false,
+        }
+    }
+}